@@ -0,0 +1,293 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Component, Path, PathBuf};
+use serde::Deserialize;
+
+use crate::fs_utils::ServePoint;
+use crate::webserver::models::{AuthenticatedUser, UserRole};
+
+/// Who a rule applies to. A role also covers the roles above it, so "Uploader" includes admins, and groups are the ones
+/// given to users in the credentials file.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Principals {
+    #[serde(default)]
+    pub users: Vec<String>,
+    #[serde(default)]
+    pub groups: Vec<String>,
+    #[serde(default)]
+    pub roles: Vec<UserRole>,
+}
+
+impl Principals {
+    fn is_empty(&self) -> bool {
+        self.users.is_empty() && self.groups.is_empty() && self.roles.is_empty()
+    }
+}
+
+/// Who can and can't see a directory of the share, and everything inside of it
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct AclRule {
+    /// Relative to the root of the share, e.g. "private/mick"
+    pub path: String,
+    #[serde(default)]
+    pub allow: Principals,
+    #[serde(default)]
+    pub deny: Principals,
+}
+
+/// The 'acl' section of the config file, or of one of its mounts. Groups used to be listed here, and are now in the
+/// credentials file, so anything unknown is refused rather than quietly ignored.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct AclConfig {
+    #[serde(default)]
+    pub rules: Vec<AclRule>,
+}
+
+impl AclConfig {
+    /// Add the rules of the mount called 'name', whose paths are relative to the mount
+    pub fn add_mount(&mut self, name: &str, mount: &AclConfig) {
+        for rule in &mount.rules {
+            let path = format!("{}/{}", name, rule.path.trim_start_matches('/'));
+            self.rules.push(AclRule { path, ..rule.clone() });
+        }
+    }
+}
+
+/// Split a path into its normal parts, or None if it tries to climb out of the share
+fn normalise(path: &Path) -> Option<PathBuf> {
+    let mut normalised = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => normalised.push(part),
+            Component::RootDir | Component::CurDir => (),
+            Component::ParentDir | Component::Prefix(_) => return None,
+        }
+    }
+    Some(normalised)
+}
+
+/*
+Access control for the directories of the share. Each rule names a directory, and applies to it and everything below
+it unless a rule further down says otherwise. For a path the rules are tried from the closest directory upwards: a
+user that is denied is refused, a user that is allowed is let in, and a rule with an allow list refuses everyone who
+isn't on it. When no rule decides, the user can see the path, so a share without any rules is open to everyone.
+*/
+#[derive(Debug, Default)]
+pub struct Acl {
+    rules: HashMap<PathBuf, AclRule>,
+}
+
+impl Acl {
+    pub fn new(config: AclConfig) -> Result<Self, String> {
+        let mut rules = HashMap::new();
+        for rule in config.rules {
+            let path = normalise(Path::new(&rule.path))
+                .ok_or_else(|| format!("ACL rule for '{}' is not inside of the share", rule.path))?;
+            if rules.insert(path, rule.clone()).is_some() {
+                return Err(format!("There is more than one ACL rule for '{}'", rule.path));
+            }
+        }
+        Ok(Acl { rules })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /*
+    Check the groups the rules refer to against the ones that 'users' are in. A deny rule for a group that nobody is
+    in keeps nobody out, which is most likely a typo or a group that was taken out of the credentials file, so that is
+    an error. An allow rule for one only lets fewer people in, so that is just a warning.
+    */
+    pub fn check_groups(&self, users: &HashMap<String, AuthenticatedUser>) -> Result<(), String> {
+        let known: HashSet<&String> = users.values().flat_map(|user| &user.groups).collect();
+        let mut rules: Vec<&AclRule> = self.rules.values().collect();
+        rules.sort_by(|a, b| a.path.cmp(&b.path));
+
+        let mut unknown = Vec::new();
+        for rule in rules {
+            for group in rule.allow.groups.iter().filter(|group| !known.contains(group)) {
+                warn!("The ACL rule for '{}' allows the group '{}', which nobody is in", rule.path, group);
+            }
+            for group in rule.deny.groups.iter().filter(|group| !known.contains(group)) {
+                unknown.push(format!("'{}' (for '{}')", group, rule.path));
+            }
+        }
+        if unknown.is_empty() {
+            Ok(())
+        } else {
+            Err(format!("ACL rules deny groups that nobody is in: {}", unknown.join(", ")))
+        }
+    }
+
+    fn matches(&self, principals: &Principals, user: &AuthenticatedUser) -> bool {
+        principals.users.contains(&user.username)
+            || principals.roles.iter().any(|role| user.role >= *role)
+            || principals.groups.iter().any(|group| user.in_group(group))
+    }
+
+    /// Whether 'user' can see the entry at 'path', relative to the root of the share
+    pub fn can_read(&self, user: &AuthenticatedUser, path: &Path) -> bool {
+        if self.is_empty() {
+            return true;
+        }
+        let path = match normalise(path) {
+            Some(path) => path,
+            None => return false,
+        };
+
+        for dir in path.ancestors() {
+            if let Some(rule) = self.rules.get(dir) {
+                if self.matches(&rule.deny, user) {
+                    return false;
+                }
+                if self.matches(&rule.allow, user) {
+                    return true;
+                }
+                if !rule.allow.is_empty() {
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    /*
+    Like 'can_read', for a path inside of the share 'sp', given either relative to the share or as a path on the disk.
+    Both the path as it was asked for and the place it leads to once symlinks are followed have to be allowed, so that
+    a link can't be used to get into a directory the user is kept out of, even from another mount.
+    */
+    pub fn can_read_in(&self, user: &AuthenticatedUser, sp: &ServePoint, path: &Path) -> bool {
+        if self.is_empty() {
+            return true;
+        }
+        let relative = sp.share_path_of(path).unwrap_or_else(|| path.to_owned());
+        if !self.can_read(user, &relative) {
+            return false;
+        }
+        let target = sp.real_path(&relative).and_then(|real| real.canonicalize().ok());
+        match target.and_then(|target| sp.share_path_of(&target)) {
+            Some(target) => self.can_read(user, &target),
+            None => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(username: &str, role: UserRole) -> AuthenticatedUser {
+        AuthenticatedUser::new(username.to_owned(), String::new(), role)
+    }
+
+    fn member(username: &str, group: &str) -> AuthenticatedUser {
+        AuthenticatedUser { groups: vec![group.to_owned()], ..user(username, UserRole::ReadOnly) }
+    }
+
+    fn acl(json: &str) -> Result<Acl, String> {
+        Acl::new(serde_json::from_str(json).unwrap())
+    }
+
+    #[test]
+    fn test_can_read() {
+        let acl = acl(r#"{
+            "rules": [
+                { "path": "private/mick", "allow": { "users": ["mick"] } },
+                { "path": "private/mick/shared", "allow": { "groups": ["family"] } },
+                { "path": "/films/", "deny": { "users": ["kid"] } },
+                { "path": "films/cartoons", "allow": { "users": ["kid"] } },
+                { "path": "admin", "allow": { "roles": ["Uploader"] }, "deny": { "users": ["dave"] } }
+            ]
+        }"#).unwrap();
+        let (mick, mam, kid) = (member("mick", "family"), member("mam", "family"), user("kid", UserRole::ReadOnly));
+        let (uploader, dave) = (user("uploader", UserRole::Uploader), user("dave", UserRole::Admin));
+
+        let cases = vec![
+            (&mick, "private/mick/notes.txt", true),
+            (&mam, "private/mick", false),
+            (&mam, "private/mick/photos/1.jpg", false),
+            (&mam, "private/mick/shared/1.jpg", true),
+            (&kid, "private/mick/shared", false),
+            (&mam, "private", true),
+            (&mam, "private/mam", true),
+            (&kid, "films", false),
+            (&kid, "films/horror/1.mkv", false),
+            (&kid, "films/cartoons/1.mkv", true),
+            (&mam, "films/horror/1.mkv", true),
+            (&mam, "admin", false),
+            (&uploader, "admin/x", true),
+            (&dave, "admin/x", false),
+            (&kid, "", true),
+            (&mick, "private/../private/mick", false),
+            (&mick, "/private/./mick", true),
+        ];
+        for (user, path, expected) in cases {
+            assert_eq!(acl.can_read(user, Path::new(path)), expected, "{} reading {}", user.username, path);
+        }
+        assert!(Acl::default().can_read(&kid, Path::new("anything")));
+    }
+
+    #[test]
+    fn test_invalid_rules() {
+        assert!(acl(r#"{ "rules": [{ "path": "../outside", "allow": { "users": ["mick"] } }] }"#).is_err());
+        assert!(acl(r#"{ "rules": [{ "path": "a" }, { "path": "a/" }] }"#).is_err());
+        assert!(acl(r#"{ "rules": [{ "path": "a", "allow": { "roles": ["Admin"] } }] }"#).is_ok());
+
+        // Groups are in the credentials file now, so the old way of listing them is refused, like any other typo
+        let old = r#"{ "groups": { "family": ["mick"] }, "rules": [] }"#;
+        assert!(serde_json::from_str::<AclConfig>(old).is_err());
+        assert!(serde_json::from_str::<AclConfig>(r#"{ "rules": [{ "path": "a", "alow": { "users": ["mick"] } }] }"#).is_err());
+        assert!(serde_json::from_str::<AclConfig>(r#"{ "rules": [{ "path": "a", "deny": { "group": ["kids"] } }] }"#).is_err());
+
+        // Denying a group that nobody is in keeps nobody out, so it's an error
+        let users: HashMap<String, AuthenticatedUser> = vec![member("mick", "family")].into_iter()
+            .map(|user| (user.username.clone(), user))
+            .collect();
+        let allow_unknown = acl(r#"{ "rules": [{ "path": "a", "allow": { "groups": ["nobody"] } }] }"#).unwrap();
+        assert!(allow_unknown.check_groups(&users).is_ok());
+        let deny_unknown = acl(r#"{ "rules": [{ "path": "a", "deny": { "groups": ["famly"] } }] }"#).unwrap();
+        assert!(deny_unknown.check_groups(&users).is_err());
+        let deny_known = acl(r#"{ "rules": [{ "path": "a", "deny": { "groups": ["family"] } }] }"#).unwrap();
+        assert!(deny_known.check_groups(&users).is_ok());
+    }
+
+    #[test]
+    fn test_add_mount() {
+        let mut config: AclConfig = serde_json::from_str(r#"{ "rules": [{ "path": "photos", "deny": { "users": ["kid"] } }] }"#).unwrap();
+        let mount = serde_json::from_str(r#"{ "rules": [{ "path": "/private", "allow": { "users": ["mick"] } }] }"#).unwrap();
+        config.add_mount("movies", &mount);
+        let acl = Acl::new(config.clone()).unwrap();
+        let (mick, kid) = (user("mick", UserRole::ReadOnly), user("kid", UserRole::ReadOnly));
+        assert!(acl.can_read(&mick, Path::new("movies/private/1.mkv")));
+        assert!(!acl.can_read(&kid, Path::new("movies/private")));
+        assert!(acl.can_read(&kid, Path::new("private")));
+        assert!(!acl.can_read(&kid, Path::new("photos")));
+
+        // A rule can't climb out of its mount into another one
+        let escaping = serde_json::from_str(r#"{ "rules": [{ "path": "../photos" }] }"#).unwrap();
+        config.add_mount("movies", &escaping);
+        assert!(Acl::new(config).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_can_read_in_follows_symlinks() {
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path().canonicalize().unwrap();
+        std::fs::create_dir_all(root.join("private/mick")).unwrap();
+        std::fs::create_dir(root.join("public")).unwrap();
+        std::os::unix::fs::symlink(root.join("private/mick"), root.join("public/link")).unwrap();
+
+        let sp = ServePoint::new(root.clone());
+        let acl = acl(r#"{ "rules": [{ "path": "private/mick", "allow": { "users": ["mick"] } }] }"#).unwrap();
+        let (mick, mam) = (user("mick", UserRole::ReadOnly), user("mam", UserRole::ReadOnly));
+        assert!(acl.can_read(&mam, Path::new("public/link")));
+        assert!(!acl.can_read_in(&mam, &sp, &root.join("public/link")));
+        assert!(!acl.can_read_in(&mam, &sp, Path::new("public/link")));
+        assert!(acl.can_read_in(&mick, &sp, Path::new("public/link")));
+        assert!(acl.can_read_in(&mam, &sp, Path::new("public")));
+    }
+}
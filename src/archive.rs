@@ -0,0 +1,676 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use bytes::Bytes;
+use chrono::{DateTime, Datelike, Timelike, Utc};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::Deserialize;
+use warp::hyper::body::{Body, Sender};
+
+use crate::fs_utils::{natural_cmp, open_checked, ServePoint};
+
+/// How much of an archive is collected before it is sent to the client
+const CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum ArchiveFormat {
+    #[serde(rename = "zip")]
+    Zip,
+    #[serde(rename = "tar")]
+    Tar,
+    #[serde(rename = "tar.gz")]
+    TarGz,
+}
+
+impl ArchiveFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            ArchiveFormat::Zip => "zip",
+            ArchiveFormat::Tar => "tar",
+            ArchiveFormat::TarGz => "tar.gz",
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            ArchiveFormat::Zip => "application/zip",
+            ArchiveFormat::Tar => "application/x-tar",
+            ArchiveFormat::TarGz => "application/gzip",
+        }
+    }
+}
+
+impl std::str::FromStr for ArchiveFormat {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        match s {
+            "zip" => Ok(ArchiveFormat::Zip),
+            "tar" => Ok(ArchiveFormat::Tar),
+            "tar.gz" => Ok(ArchiveFormat::TarGz),
+            _ => Err(()),
+        }
+    }
+}
+
+/// The most that can be put in one archive
+#[derive(Debug, Clone, Copy)]
+pub struct ArchiveLimits {
+    pub max_files: usize,
+    pub max_size: u64,
+}
+
+/// A file or directory to put in an archive
+#[derive(Debug, Clone)]
+pub struct ArchiveEntry {
+    /// Where it is on the disk, canonical for files
+    pub path: PathBuf,
+    /// Its name in the archive, with '/' between the parts
+    pub name: String,
+    pub is_dir: bool,
+}
+
+/*
+Collect everything under 'dir' for an archive, naming the entries from 'prefix'. Symlinks are only included when they
+point to a file and the symlink policy of the share follows them, so that an archive can't be used to reach anything
+else. Symlinked directories are left out entirely, which also keeps a link back up the tree from looping forever.
+Anything the ignore lists leave out of the share is left out of the archive too.
+*/
+pub fn collect_entries(sp: &ServePoint, dir: &Path, prefix: &str) -> Vec<ArchiveEntry> {
+    let mut entries = vec![ArchiveEntry { path: dir.to_owned(), name: format!("{}/", prefix), is_dir: true }];
+    let mut pending = vec![(dir.to_owned(), prefix.to_owned(), sp.ignore_rules_at(dir))];
+
+    while let Some((dir, prefix, rules)) = pending.pop() {
+        let mut children: Vec<(String, PathBuf)> = match fs::read_dir(&dir) {
+            Ok(read_dir) => read_dir.flatten()
+                .filter_map(|e| Some((e.file_name().into_string().ok()?, e.path())))
+                .collect(),
+            Err(e) => {
+                warn!("Could not read {:?} for an archive: {}", dir, e);
+                continue;
+            },
+        };
+        children.sort_by(|a, b| natural_cmp(&a.0, &b.0));
+
+        for (file_name, path) in children {
+            let link_meta = match path.symlink_metadata() {
+                Ok(meta) => meta,
+                Err(_) => continue,
+            };
+            if rules.is_ignored(&file_name, path.is_dir()) {
+                continue;
+            }
+            let name = format!("{}/{}", prefix, file_name);
+
+            if link_meta.file_type().is_symlink() {
+                // What's put in the archive is where the link leads, so it can be checked again when it's opened
+                if let Some(target) = sp.follow_link(&path).filter(|target| target.is_file()) {
+                    entries.push(ArchiveEntry { path: target, name, is_dir: false });
+                }
+            } else if link_meta.is_dir() {
+                entries.push(ArchiveEntry { path: path.clone(), name: format!("{}/", name), is_dir: true });
+                let dir_rules = rules.enter(&file_name, &path);
+                pending.push((path, name, dir_rules));
+            } else if link_meta.is_file() {
+                entries.push(ArchiveEntry { path, name, is_dir: false });
+            }
+        }
+    }
+    entries
+}
+
+/*
+Collect several entries picked from around the share, each one at the top of the archive under its own name. Names
+that clash get a number added, and anything that is inside another selected directory is only included once.
+'paths' must already have been checked to be inside of the share.
+*/
+pub fn collect_selection(sp: &ServePoint, paths: &[PathBuf]) -> Vec<ArchiveEntry> {
+    let mut entries = Vec::new();
+    let mut used_names = HashSet::new();
+    let mut seen = HashSet::new();
+
+    for path in paths {
+        let covered = paths.iter().any(|other| other != path && path.starts_with(other));
+        if covered || !seen.insert(path) {
+            continue;
+        }
+
+        let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("file");
+        let name = unique_name(name, path.is_dir(), &mut used_names);
+        if path.is_dir() {
+            entries.extend(collect_entries(sp, path, &name));
+        } else if path.is_file() {
+            entries.push(ArchiveEntry { path: path.clone(), name, is_dir: false });
+        }
+    }
+    entries
+}
+
+/// 'name', or "name (2).ext" and so on if it's already been used
+fn unique_name(name: &str, is_dir: bool, used: &mut HashSet<String>) -> String {
+    let (stem, ext) = match name.rfind('.') {
+        Some(i) if i > 0 && !is_dir => (&name[..i], &name[i..]),
+        _ => (name, ""),
+    };
+
+    let mut candidate = name.to_owned();
+    let mut n = 2;
+    while !used.insert(candidate.clone()) {
+        candidate = format!("{} ({}){}", stem, n, ext);
+        n += 1;
+    }
+    candidate
+}
+
+/// Make sure an archive wouldn't hold more files, or more data, than the limits allow
+pub fn within_limits(entries: &[ArchiveEntry], limits: ArchiveLimits) -> bool {
+    let files = entries.iter().filter(|e| !e.is_dir);
+    if files.clone().count() > limits.max_files {
+        return false;
+    }
+
+    let mut size: u64 = 0;
+    for file in files {
+        size = size.saturating_add(fs::metadata(&file.path).map(|m| m.len()).unwrap_or(0));
+        if size > limits.max_size {
+            return false;
+        }
+    }
+    true
+}
+
+/// Write an archive of 'entries' to 'out'. Nothing is compressed apart from tar.gz, most of what's shared is video.
+pub fn write_archive<W: Write>(format: ArchiveFormat, entries: &[ArchiveEntry], out: W) -> io::Result<()> {
+    match format {
+        ArchiveFormat::Zip => {
+            let mut zip = ZipStream::new(out);
+            for entry in entries {
+                zip.add(entry)?;
+            }
+            zip.finish()?.flush()
+        },
+        ArchiveFormat::Tar => write_tar(entries, out)?.flush(),
+        ArchiveFormat::TarGz => write_tar(entries, GzEncoder::new(out, Compression::fast()))?.finish()?.flush(),
+    }
+}
+
+fn write_tar<W: Write>(entries: &[ArchiveEntry], out: W) -> io::Result<W> {
+    let mut tar = tar::Builder::new(out);
+    for entry in entries {
+        let name = entry.name.trim_end_matches('/');
+        if entry.is_dir {
+            tar.append_dir(name, &entry.path)?;
+        } else {
+            tar.append_file(name, &mut open_checked(&entry.path)?)?;
+        }
+    }
+    tar.into_inner()
+}
+
+/*
+Stream an archive as the body of a response. It is written on a blocking thread and sent on in chunks as the client
+reads them, so nothing is ever buffered on the disk. If writing fails part way through the body is aborted, so that
+the client sees a failed download rather than an archive that is silently cut short.
+*/
+pub fn stream_archive(format: ArchiveFormat, entries: Vec<ArchiveEntry>) -> Body {
+    let (sender, body) = Body::channel();
+    tokio::task::spawn_blocking(move || {
+        let mut writer = BodyWriter { sender: Some(sender) };
+        let result = write_archive(format, &entries, BufWriter::with_capacity(CHUNK_SIZE, &mut writer));
+        if let Err(e) = result {
+            warn!("Stopped writing an archive: {}", e);
+            if let Some(sender) = writer.sender.take() {
+                sender.abort();
+            }
+        }
+    });
+    body
+}
+
+struct BodyWriter {
+    sender: Option<Sender>,
+}
+
+impl Write for BodyWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let sender = self.sender.as_mut().ok_or_else(|| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        futures::executor::block_on(sender.send_data(Bytes::copy_from_slice(buf)))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "the client went away"))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+const ZIP64_LIMIT: u64 = 0xFFFF_FFFF;
+
+/// Keeps track of how much has been written, for the offsets in the zip directory
+struct CountingWriter<W> {
+    inner: W,
+    count: u64,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+struct CentralEntry {
+    name: String,
+    is_dir: bool,
+    mode: u32,
+    dos_time: (u16, u16),
+    crc: u32,
+    size: u64,
+    offset: u64,
+    /// The sizes are in a zip64 field, as they were in the local header
+    zip64: bool,
+}
+
+/*
+A zip writer that never has to seek, so it can write straight to the network. Every entry is stored as is, with the
+CRC and size following the data in a descriptor. Zip64 fields are only used where they are needed, for files and
+offsets beyond 4 GiB or more than 65535 entries.
+*/
+struct ZipStream<W: Write> {
+    out: CountingWriter<W>,
+    entries: Vec<CentralEntry>,
+}
+
+impl<W: Write> ZipStream<W> {
+    fn new(out: W) -> Self {
+        ZipStream { out: CountingWriter { inner: out, count: 0 }, entries: Vec::new() }
+    }
+
+    fn add(&mut self, entry: &ArchiveEntry) -> io::Result<()> {
+        // Files are opened first, in case they've been swapped for a link since they were collected
+        let file = if entry.is_dir { None } else { Some(open_checked(&entry.path)?) };
+        let meta = match &file {
+            Some(file) => file.metadata()?,
+            None => fs::metadata(&entry.path)?,
+        };
+        let modified = meta.modified().map(DateTime::<Utc>::from).unwrap_or_else(|_| Utc::now());
+        let mut central = CentralEntry {
+            name: entry.name.clone(),
+            is_dir: entry.is_dir,
+            mode: unix_mode(&meta, entry.is_dir),
+            dos_time: dos_time(modified),
+            crc: 0,
+            size: 0,
+            offset: self.out.count,
+            zip64: false,
+        };
+
+        // Files that might not fit in 4 GiB get a zip64 field in the local header, which is what tells readers that
+        // stream the archive that the sizes in the data descriptor are 8 bytes each
+        central.zip64 = !entry.is_dir && meta.len() >= ZIP64_LIMIT;
+        let local_size = if central.zip64 { ZIP64_LIMIT as u32 } else { 0 };
+
+        // Local file header
+        let out = &mut self.out;
+        write_u32(out, 0x0403_4b50)?;
+        write_u16(out, 45)?;
+        write_u16(out, flags(entry.is_dir))?;
+        write_u16(out, 0)?;
+        write_u16(out, central.dos_time.0)?;
+        write_u16(out, central.dos_time.1)?;
+        write_u32(out, 0)?;
+        write_u32(out, local_size)?;
+        write_u32(out, local_size)?;
+        write_u16(out, central.name.len() as u16)?;
+        write_u16(out, if central.zip64 { 20 } else { 0 })?;
+        out.write_all(central.name.as_bytes())?;
+        if central.zip64 {
+            // The sizes aren't known yet, they follow the data
+            write_u16(out, 0x0001)?;
+            write_u16(out, 16)?;
+            write_u64(out, 0)?;
+            write_u64(out, 0)?;
+        }
+
+        if let Some(mut file) = file {
+            let mut hasher = crc32fast::Hasher::new();
+            let mut buf = vec![0; CHUNK_SIZE];
+            loop {
+                let n = file.read(&mut buf)?;
+                if n == 0 {
+                    break;
+                }
+                hasher.update(&buf[..n]);
+                out.write_all(&buf[..n])?;
+                central.size += n as u64;
+            }
+            central.crc = hasher.finalize();
+            if central.size >= ZIP64_LIMIT && !central.zip64 {
+                let msg = format!("{:?} grew past 4 GiB while it was being archived", entry.path);
+                return Err(io::Error::other(msg));
+            }
+
+            // Data descriptor
+            write_u32(out, 0x0807_4b50)?;
+            write_u32(out, central.crc)?;
+            if central.zip64 {
+                write_u64(out, central.size)?;
+                write_u64(out, central.size)?;
+            } else {
+                write_u32(out, central.size as u32)?;
+                write_u32(out, central.size as u32)?;
+            }
+        }
+
+        self.entries.push(central);
+        Ok(())
+    }
+
+    fn finish(mut self) -> io::Result<W> {
+        let directory_offset = self.out.count;
+        for entry in &self.entries {
+            let out = &mut self.out;
+            let mut zip64 = Vec::new();
+            let size = if entry.zip64 {
+                zip64.extend_from_slice(&entry.size.to_le_bytes());
+                zip64.extend_from_slice(&entry.size.to_le_bytes());
+                ZIP64_LIMIT as u32
+            } else {
+                entry.size as u32
+            };
+            if entry.offset >= ZIP64_LIMIT {
+                zip64.extend_from_slice(&entry.offset.to_le_bytes());
+            }
+            let extra_len = if zip64.is_empty() { 0 } else { 4 + zip64.len() };
+
+            write_u32(out, 0x0201_4b50)?;
+            // Made by unix, so that the permissions in the external attributes are used
+            write_u16(out, (3 << 8) | 45)?;
+            write_u16(out, 45)?;
+            write_u16(out, flags(entry.is_dir))?;
+            write_u16(out, 0)?;
+            write_u16(out, entry.dos_time.0)?;
+            write_u16(out, entry.dos_time.1)?;
+            write_u32(out, entry.crc)?;
+            write_u32(out, size)?;
+            write_u32(out, size)?;
+            write_u16(out, entry.name.len() as u16)?;
+            write_u16(out, extra_len as u16)?;
+            write_u16(out, 0)?;
+            write_u16(out, 0)?;
+            write_u16(out, 0)?;
+            write_u32(out, (entry.mode << 16) | if entry.is_dir { 0x10 } else { 0 })?;
+            write_u32(out, entry.offset.min(ZIP64_LIMIT) as u32)?;
+            out.write_all(entry.name.as_bytes())?;
+            if !zip64.is_empty() {
+                write_u16(out, 0x0001)?;
+                write_u16(out, zip64.len() as u16)?;
+                out.write_all(&zip64)?;
+            }
+        }
+
+        let directory_end = self.out.count;
+        let directory_size = directory_end - directory_offset;
+        let count = self.entries.len() as u64;
+        let out = &mut self.out;
+
+        if count >= 0xFFFF || directory_size >= ZIP64_LIMIT || directory_offset >= ZIP64_LIMIT {
+            // Zip64 end of central directory record, then its locator
+            write_u32(out, 0x0606_4b50)?;
+            write_u64(out, 44)?;
+            write_u16(out, (3 << 8) | 45)?;
+            write_u16(out, 45)?;
+            write_u32(out, 0)?;
+            write_u32(out, 0)?;
+            write_u64(out, count)?;
+            write_u64(out, count)?;
+            write_u64(out, directory_size)?;
+            write_u64(out, directory_offset)?;
+
+            write_u32(out, 0x0706_4b50)?;
+            write_u32(out, 0)?;
+            write_u64(out, directory_end)?;
+            write_u32(out, 1)?;
+        }
+
+        write_u32(out, 0x0605_4b50)?;
+        write_u16(out, 0)?;
+        write_u16(out, 0)?;
+        write_u16(out, count.min(0xFFFF) as u16)?;
+        write_u16(out, count.min(0xFFFF) as u16)?;
+        write_u32(out, directory_size.min(ZIP64_LIMIT) as u32)?;
+        write_u32(out, directory_offset.min(ZIP64_LIMIT) as u32)?;
+        write_u16(out, 0)?;
+
+        Ok(self.out.inner)
+    }
+}
+
+/// Names are UTF-8, and files have their CRC and size after the data
+fn flags(is_dir: bool) -> u16 {
+    if is_dir { 0x0800 } else { 0x0808 }
+}
+
+/// The date and time in the format MS-DOS used, which is what zip files store
+fn dos_time(time: DateTime<Utc>) -> (u16, u16) {
+    if time.year() < 1980 {
+        return (0, (1 << 5) | 1);
+    }
+    let dos_time = (time.hour() << 11) | (time.minute() << 5) | (time.second() / 2);
+    let dos_date = (((time.year() - 1980) as u32).min(127) << 9) | (time.month() << 5) | time.day();
+    (dos_time as u16, dos_date as u16)
+}
+
+#[cfg(unix)]
+fn unix_mode(meta: &fs::Metadata, _is_dir: bool) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    meta.permissions().mode()
+}
+
+#[cfg(not(unix))]
+fn unix_mode(_meta: &fs::Metadata, is_dir: bool) -> u32 {
+    if is_dir { 0o40755 } else { 0o100644 }
+}
+
+fn write_u16<W: Write>(out: &mut W, n: u16) -> io::Result<()> {
+    out.write_all(&n.to_le_bytes())
+}
+
+fn write_u32<W: Write>(out: &mut W, n: u32) -> io::Result<()> {
+    out.write_all(&n.to_le_bytes())
+}
+
+fn write_u64<W: Write>(out: &mut W, n: u64) -> io::Result<()> {
+    out.write_all(&n.to_le_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn setup() -> (tempfile::TempDir, PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        let season = root.join("show").join("Season 1");
+        fs::create_dir_all(&season).unwrap();
+        fs::create_dir(root.join("show").join("extras")).unwrap();
+        fs::write(season.join("Episode 10.mkv"), b"ten").unwrap();
+        fs::write(season.join("Episode 2.mkv"), b"two").unwrap();
+        fs::write(root.join("secret.txt"), b"secret").unwrap();
+        (dir, root)
+    }
+
+    fn names(entries: &[ArchiveEntry]) -> Vec<&str> {
+        entries.iter().map(|e| e.name.as_str()).collect()
+    }
+
+    #[test]
+    fn test_collect_entries() {
+        let (_dir, root) = setup();
+        let entries = collect_entries(&ServePoint::new(root.clone()), &root.join("show"), "show");
+        let mut names = names(&entries);
+        names.sort();
+        assert_eq!(names, vec![
+            "show/", "show/Season 1/", "show/Season 1/Episode 10.mkv", "show/Season 1/Episode 2.mkv", "show/extras/",
+        ]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_collect_entries_symlinks() {
+        use std::os::unix::fs::symlink;
+        let (_dir, root) = setup();
+        let other = tempfile::tempdir().unwrap();
+        let outside = other.path().join("outside.txt");
+        fs::write(&outside, b"outside").unwrap();
+
+        let show = root.join("show");
+        symlink(root.join("secret.txt"), show.join("inside.txt")).unwrap();
+        symlink(&outside, show.join("outside.txt")).unwrap();
+        symlink(&root, show.join("loop")).unwrap();
+
+        let entries = collect_entries(&ServePoint::new(root.clone()), &show, "show");
+        let names = names(&entries);
+        assert!(names.contains(&"show/inside.txt"));
+        assert!(!names.contains(&"show/outside.txt"));
+        assert!(!names.iter().any(|n| n.starts_with("show/loop")));
+
+        // A link swapped in after the entries were collected stops the archive
+        fs::remove_file(root.join("secret.txt")).unwrap();
+        symlink(&outside, root.join("secret.txt")).unwrap();
+        for format in &[ArchiveFormat::Zip, ArchiveFormat::Tar] {
+            assert!(write_archive(*format, &entries, io::sink()).is_err());
+        }
+    }
+
+    #[test]
+    fn test_collect_selection() {
+        let (_dir, root) = setup();
+        fs::write(root.join("show").join("secret.txt"), b"another secret").unwrap();
+        let season = root.join("show").join("Season 1");
+
+        let paths = vec![
+            root.join("secret.txt"),
+            root.join("show").join("secret.txt"),
+            season.clone(),
+            season.join("Episode 2.mkv"),
+            season.clone(),
+        ];
+        let entries = collect_selection(&ServePoint::new(root.clone()), &paths);
+        assert_eq!(names(&entries), vec![
+            "secret.txt", "secret (2).txt", "Season 1/", "Season 1/Episode 2.mkv", "Season 1/Episode 10.mkv",
+        ]);
+
+        let limits = ArchiveLimits { max_files: 4, max_size: 100 };
+        assert!(within_limits(&entries, limits));
+        assert!(!within_limits(&entries, ArchiveLimits { max_files: 3, ..limits }));
+        assert!(!within_limits(&entries, ArchiveLimits { max_size: 25, ..limits }));
+    }
+
+    #[test]
+    fn test_zip() {
+        let (_dir, root) = setup();
+        let entries = collect_entries(&ServePoint::new(root.clone()), &root.join("show"), "show");
+        let mut out = Vec::new();
+        write_archive(ArchiveFormat::Zip, &entries, &mut out).unwrap();
+
+        let mut zip = zip::ZipArchive::new(io::Cursor::new(out)).unwrap();
+        assert_eq!(zip.len(), entries.len());
+        let mut contents = String::new();
+        zip.by_name("show/Season 1/Episode 2.mkv").unwrap().read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "two");
+        assert!(zip.by_name("show/extras/").unwrap().is_dir());
+    }
+
+    /// Skips over runs of zeros instead of writing them, so that an archive of a huge sparse file stays sparse too
+    struct SparseWriter(fs::File);
+
+    impl Write for SparseWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if buf.iter().all(|b| *b == 0) {
+                io::Seek::seek(&mut self.0, io::SeekFrom::Current(buf.len() as i64))?;
+                Ok(buf.len())
+            } else {
+                self.0.write(buf)
+            }
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.0.flush()
+        }
+    }
+
+    #[test]
+    fn test_zip64() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        let big_size = ZIP64_LIMIT + 10;
+        fs::File::create(root.join("big.bin")).unwrap().set_len(big_size).unwrap();
+        fs::write(root.join("small.txt"), b"small").unwrap();
+        let entries = vec![
+            ArchiveEntry { path: root.join("big.bin"), name: String::from("big.bin"), is_dir: false },
+            ArchiveEntry { path: root.join("small.txt"), name: String::from("small.txt"), is_dir: false },
+        ];
+
+        let out = tempfile::tempfile().unwrap();
+        write_archive(ArchiveFormat::Zip, &entries, SparseWriter(out.try_clone().unwrap())).unwrap();
+        // Zeros skipped over at the very end have to be filled in
+        out.set_len(io::Seek::stream_position(&mut &out).unwrap()).unwrap();
+
+        // The local header of the big file says its sizes are in a zip64 field
+        let mut header = [0; 50];
+        io::Seek::seek(&mut &out, io::SeekFrom::Start(0)).unwrap();
+        (&out).read_exact(&mut header).unwrap();
+        assert_eq!(&header[18..26], &[0xFF; 8]);
+        assert_eq!(&header[28..30], &20u16.to_le_bytes());
+        assert_eq!(&header[37..41], &[0x01, 0x00, 16, 0]);
+
+        let mut zip = zip::ZipArchive::new(out).unwrap();
+        assert_eq!(zip.by_name("big.bin").unwrap().size(), big_size);
+        let mut contents = String::new();
+        zip.by_name("small.txt").unwrap().read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "small");
+    }
+
+    #[test]
+    fn test_tar() {
+        let (_dir, root) = setup();
+        let entries = collect_entries(&ServePoint::new(root.clone()), &root.join("show"), "show");
+
+        for format in &[ArchiveFormat::Tar, ArchiveFormat::TarGz] {
+            let mut out = Vec::new();
+            write_archive(*format, &entries, &mut out).unwrap();
+
+            let reader: Box<dyn Read> = if *format == ArchiveFormat::TarGz {
+                Box::new(flate2::read::GzDecoder::new(&out[..]))
+            } else {
+                Box::new(&out[..])
+            };
+            let mut tar = tar::Archive::new(reader);
+            let mut found = Vec::new();
+            for entry in tar.entries().unwrap() {
+                let mut entry = entry.unwrap();
+                let mut contents = String::new();
+                entry.read_to_string(&mut contents).unwrap();
+                found.push((entry.path().unwrap().to_str().unwrap().to_owned(), contents));
+            }
+            assert!(found.contains(&(String::from("show/Season 1/Episode 10.mkv"), String::from("ten"))), "{:?}", found);
+            assert_eq!(found.len(), entries.len());
+        }
+    }
+
+    #[test]
+    fn test_dos_time() {
+        let time = DateTime::parse_from_rfc3339("2021-11-29T14:05:31Z").unwrap().with_timezone(&Utc);
+        assert_eq!(dos_time(time), ((14 << 11) | (5 << 5) | 15, (41 << 9) | (11 << 5) | 29));
+        let old = DateTime::parse_from_rfc3339("1970-01-01T00:00:00Z").unwrap().with_timezone(&Utc);
+        assert_eq!(dos_time(old), (0, (1 << 5) | 1));
+    }
+}
@@ -1,285 +1,515 @@
-use clap::{Arg, App, ArgMatches};
-use serde::Deserialize;
-use std::path::Path;
-use std::collections::HashMap;
-use std::fs;
-
-use crate::webserver::models::{AuthenticatedUser, UserRole};
-
-/*
-Config is loaded from JSON file first, those values are used as defaults for
-the rest of the command line arguments.
-
-i.e. use config file for defaults and overwrite using CLI args.
-*/
-
-pub struct Config {
-    pub ipaddr: [u8; 4],
-    pub port: u16,
-    pub sharedir: String,
-    pub users: HashMap<String, AuthenticatedUser>,
-    pub db_url: String,
-    pub check_password: bool,
-    pub encrypt_password: bool,
-}
-
-#[derive(Deserialize, Debug)]
-struct JsonConfig {
-    pub ipaddr: Option<String>,
-    pub port: Option<u16>,
-    pub sharedir: Option<String>,
-    pub users_file: Option<String>,
-    pub db_url: Option<String>,
-}
-
-impl Default for JsonConfig {
-    fn default() -> Self {
-        JsonConfig{
-            ipaddr: None,
-            port: None,
-            sharedir: None,
-            users_file: None,
-            db_url: None,
-        }
-    }
-}
-
-#[derive(Debug)]
-struct CliConfig {
-    pub ipaddr: Option<String>,
-    pub port: Option<u16>,
-    pub sharedir: Option<String>,
-    pub users_file: Option<String>,
-    pub config_file: Option<String>,
-    pub db_url: Option<String>,
-    pub check_password: bool,
-    pub encrypt_password: bool,
-}
-
-fn parse_args<'a>() -> ArgMatches<'a> {
-    App::new("Friendly File Server")
-    .version("1.0")
-    .about("Friendly file server")
-    .arg(Arg::with_name("ipaddr")
-         .long("ipaddr")
-         .help("IP address to bind to")
-         .required(false)
-         .takes_value(true))
-    .arg(Arg::with_name("port")
-         .long("port")
-         .help("The HTTP webserver port to listen to")
-         .default_value("5000")
-         .required(false)
-         .takes_value(true))
-    .arg(Arg::with_name("sharedir")
-         .long("sharedir")
-         .help("The directory to server files from")
-         .required(false)
-         .takes_value(true))
-    .arg(Arg::with_name("credsfile")
-         .long("credsfile")
-         .help("The file containing the credentials")
-         .required(false)
-         .takes_value(true))
-    .arg(Arg::with_name("dburl")
-         .long("dburl")
-         .help("The DB url")
-         .required(false)
-         .takes_value(true))
-    .arg(Arg::with_name("config")
-        .long("config")
-        .help("path to config file")
-        .required(false)
-        .takes_value(true))
-    .arg(Arg::with_name("encrypt_password")
-        .long("encrypt_password")
-        .help("Use this to encrypt a password and see the ciphertext")
-        .required(false)
-        .takes_value(false))
-    .arg(Arg::with_name("check_password")
-        .long("check_password")
-        .help("Use this to verify a password against it's hash")
-        .required(false)
-        .takes_value(false))
-    .get_matches()
-}
-
-fn parse_config_from_json_file(p: &Path) -> Result<JsonConfig, String> {
-    let content = fs::read_to_string(p).map_err(|e| format!("{:?}", e))?;
-    let json_config: JsonConfig = serde_json::from_str(&content).map_err(|e| format!("{:?}", e))?;
-    Ok(json_config)
-}
-
-fn parse_config_from_args() -> Result<CliConfig, String> {
-    let matches = parse_args();
-
-    let ipaddr_str = matches.value_of("ipaddr").map(|s| s.to_owned());
-
-    let db_url = matches.value_of("dburl").map(|s| s.to_owned());
-
-    let mut port = None;
-    if let Some(p) = matches.value_of("port").map(|p| p.parse::<u16>()) {
-        port = Some(p.map_err(|e| format!("{}", e))?);
-    }
-
-    let credsfile = matches.value_of("credsfile").map(|c| c.to_owned());
-
-    let sharedir = matches
-        .value_of("sharedir")
-        .map(|s| s.to_owned());
-
-    let config_file = matches
-        .value_of("config")
-        .map(|s| s.to_owned());
-
-    Ok(CliConfig {
-        ipaddr: ipaddr_str,
-        port: port,
-        sharedir: sharedir,
-        users_file: credsfile,
-        config_file: config_file,
-        db_url: db_url,
-        encrypt_password: matches.is_present("encrypt_password"),
-        check_password: matches.is_present("check_password"),
-    })
-}
-
-pub fn load_config() -> Result<Config, String> {
-    let cli_conf = parse_config_from_args()?;
-    debug!("CLI args = {:?}", cli_conf);
-    let mut json_config = JsonConfig::default();
-
-    // Load the config file if it has been specified
-    if let Some(config_file) = &cli_conf.config_file {
-        let p = Path::new(config_file);
-        debug!("Loading config from {}", config_file);
-        json_config = parse_config_from_json_file(&p)?;
-        debug!("JSON config = {:?}", json_config);
-    }
-
-    // If we are just checking password then default everything and return
-    // a useless config. The app will be exiting once it checks or encrypts the
-    // passwords.
-    if cli_conf.check_password | cli_conf.encrypt_password {
-        return Ok(Config {
-            ipaddr: [0,0,0,0],
-            port: 0,
-            sharedir: String::from(""),
-            users: HashMap::new(),
-            db_url: String::from(""),
-            check_password: cli_conf.check_password,
-            encrypt_password: cli_conf.encrypt_password,
-        })
-    }
-
-    // Merge the values from the json conf and CLI arg
-    let ipaddr_str = cli_conf.ipaddr.or(json_config.ipaddr).ok_or("Please specify IP address.")?;
-    // let db_url = cli_conf.db_url.or(json_config.db_url).ok_or("Please specify DB url")?;
-    let db_url = String::new();
-    let port = cli_conf.port.or(json_config.port).ok_or("Please specify port.")?;
-    let sharedir = cli_conf.sharedir.or(json_config.sharedir).ok_or("Please specify Share Dir.")?;
-    let users_file = cli_conf.users_file.or(json_config.users_file).ok_or("Please specpfy Users File.")?;
-
-    // Do some further processing on some of the args
-    let users_file_contents = fs::read_to_string(&users_file).map_err(|e| format!("{}", e))?;
-    let users = load_users_from_str(&users_file_contents)?;
-    let ipaddr = validate_ip_addr(&ipaddr_str)?;
-
-    Ok(Config { ipaddr, port, sharedir, users, db_url, check_password: cli_conf.check_password, encrypt_password: cli_conf.encrypt_password })
-}
-
-fn validate_ip_addr(ipaddr: &str) -> Result<[u8; 4], String> {
-    let parts: Vec<&str> = ipaddr.split(".").collect();
-    let mut octet_array: [u8; 4] = [0,0,0,0];
-    if parts.len() != 4 {
-        Err(String::from("Incorrect IP address format"))?;
-    }
-
-    for (i, part) in parts.iter().enumerate() {
-        let octet: u8 = part.parse().map_err(|e| format!("{}", e))?;
-        octet_array[i] = octet;
-    }
-    Ok(octet_array)
-}
-
-fn load_users_from_str(contents: &str) -> Result<HashMap<String, AuthenticatedUser>, String> {
-    let mut users = HashMap::new();
-    for line in contents.split("\n") {
-        let line = line.trim();
-        if !line.starts_with(";") && line != "" {
-            let parts: Vec<&str> = line.split(" ").collect();
-            if !(parts.len() == 2 || parts.len() == 3) {
-                return Err(String::from("Error reading credentials file."));
-            }
-
-            let username = parts[0].trim().to_owned();
-            let password = parts[1].trim().to_owned();
-            let role = parts.get(2).map(|r| match r.trim() {
-                "ReadOnly" => UserRole::ReadOnly,
-                "Uploader" => UserRole::Uploader,
-                "Admin" => UserRole::Admin,
-                _ => UserRole::ReadOnly,
-            }).unwrap_or(UserRole::ReadOnly);
-
-            let user = AuthenticatedUser { 
-                username: username.clone(),
-                role,
-                password 
-            };
-
-            users.insert(username, user);
-        }
-    }
-    Ok(users)
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    #[test]
-    fn test_validate_ip_addr() {
-        let data: Vec<(&str, Result<[u8; 4], String>)> = vec![
-            ("192.168.1.1", Ok([192, 168, 1, 1])),
-            ("0.0.0.0", Ok([0, 0, 0, 0])),
-            ("1.2.3", Err(String::from("Incorrect IP address format"))),
-            ("one.two.three.four", Err(String::from("invalid digit found in string"))),
-        ];
-
-        for (ip_str, outcome) in data {
-            assert_eq!(validate_ip_addr(ip_str), outcome);
-        }
-    }
-
-    #[test]
-    fn test_load_users_from_str1() {
-        let good_str  = concat!(
-            "; Users file\n",
-            "username1 pass1\n",
-            "username2 pass2\n",
-            "username3 pass3 ReadOnly\n",
-            "username4 pass4 Admin\n",
-            "username5 pass5 Uploader\n",
-            "username6 pass6 QwErTy\n",
-        );
-
-        let mut expected_usernames = HashMap::new();
-        let users = vec![
-            AuthenticatedUser::new(String::from("username1"), String::from("pass1"), UserRole::ReadOnly),
-            AuthenticatedUser::new(String::from("username2"), String::from("pass2"), UserRole::ReadOnly),
-            AuthenticatedUser::new(String::from("username3"), String::from("pass3"), UserRole::ReadOnly),
-            AuthenticatedUser::new(String::from("username4"), String::from("pass4"), UserRole::Admin),
-            AuthenticatedUser::new(String::from("username5"), String::from("pass5"), UserRole::Uploader),
-            AuthenticatedUser::new(String::from("username6"), String::from("pass6"), UserRole::ReadOnly),
-        ];
-        for user in users {
-            expected_usernames.insert(user.username.clone(), user.clone());
-        }
-
-        assert_eq!(load_users_from_str(good_str), Ok(expected_usernames));
-
-        let bad_str = "; Users file\nuser space pass rubbish";
-        assert_eq!(load_users_from_str(bad_str), Err(String::from("Error reading credentials file.")));
-    }
-
+use clap::{Arg, App, AppSettings, ArgMatches, SubCommand};
+use serde::Deserialize;
+use std::path::Path;
+use std::collections::HashMap;
+use std::fs;
+
+use crate::webserver::models::{AuthenticatedUser, UserRole};
+use crate::credentials::CredentialsFile;
+use crate::sessions::DEFAULT_SESSION_LIFETIME;
+use crate::acl::AclConfig;
+use crate::fs_utils::SymlinkPolicy;
+
+/*
+Config is loaded from JSON file first, those values are used as defaults for
+the rest of the command line arguments.
+
+i.e. use config file for defaults and overwrite using CLI args.
+*/
+
+/// Largest file that can be uploaded in one request, in bytes (10 GiB)
+const DEFAULT_MAX_UPLOAD_SIZE: u64 = 10 * 1024 * 1024 * 1024;
+
+/// Where the server keeps the state it needs across restarts
+const DEFAULT_STATE_DIR: &str = "state";
+
+/// How long an unfinished upload is kept without any activity, in seconds (1 day)
+const DEFAULT_UPLOAD_SESSION_TIMEOUT: u64 = 24 * 60 * 60;
+
+/// How long deleted files are kept in the trash before they are purged
+const DEFAULT_TRASH_RETENTION_DAYS: u32 = 30;
+
+/// The most files that can be downloaded in one archive
+const DEFAULT_MAX_ARCHIVE_FILES: usize = 10_000;
+
+/// The most data that can be downloaded in one archive, in bytes (100 GiB)
+const DEFAULT_MAX_ARCHIVE_SIZE: u64 = 100 * 1024 * 1024 * 1024;
+
+/// What is left out of every mount when the config doesn't say, the junk that desktops leave behind
+const DEFAULT_IGNORE: [&str; 2] = [".DS_Store", "Thumbs.db"];
+
+/// A directory shared under its own name, from the 'mounts' section of the config file
+#[derive(Deserialize, Debug, Clone)]
+pub struct MountConfig {
+    pub name: String,
+    pub path: String,
+    /// Mounts are read only unless they say otherwise
+    #[serde(default)]
+    pub writable: bool,
+    /// Who can see which directories of the mount, relative to the mount
+    #[serde(default)]
+    pub acl: AclConfig,
+}
+
+pub struct Config {
+    pub ipaddr: [u8; 4],
+    pub port: u16,
+    /// Empty when the share is made up of 'mounts' instead
+    pub sharedir: String,
+    pub mounts: Vec<MountConfig>,
+    /// Which symlinks in the share are followed
+    pub symlinks: SymlinkPolicy,
+    /// Patterns for entries to leave out of every mount, on top of any .ffsignore files
+    pub ignore: Vec<String>,
+    pub users: HashMap<String, AuthenticatedUser>,
+    /// Where the users were loaded from, watched so that changes to it take effect straight away
+    pub users_file: String,
+    #[allow(dead_code)] // TODO finish DB work
+    pub db_url: String,
+    pub max_upload_size: u64,
+    pub state_dir: String,
+    pub upload_session_timeout: u64,
+    pub trash_dir: String,
+    pub trash_retention_days: u32,
+    /// Keep an in-memory index of the share, updated from filesystem events
+    pub index: bool,
+    pub max_archive_files: usize,
+    pub max_archive_size: u64,
+    pub session_lifetime: u64,
+    /// Who can see which directories of the share
+    pub acl: AclConfig,
+    pub check_password: bool,
+    pub encrypt_password: bool,
+    /// Set when the server was started to manage the users instead of to serve files
+    pub user_command: Option<UserCommand>,
+}
+
+/// 'user add|passwd|del|list|verify', which edit the credentials file
+#[derive(Debug, Clone, PartialEq)]
+pub enum UserCommand {
+    Add { username: String, role: UserRole },
+    Passwd { username: String },
+    Del { username: String },
+    List,
+    Verify { username: String },
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct JsonConfig {
+    pub ipaddr: Option<String>,
+    pub port: Option<u16>,
+    pub sharedir: Option<String>,
+    pub mounts: Option<Vec<MountConfig>>,
+    pub symlinks: Option<SymlinkPolicy>,
+    pub ignore: Option<Vec<String>>,
+    pub users_file: Option<String>,
+    #[allow(dead_code)] // TODO finish DB work
+    pub db_url: Option<String>,
+    pub max_upload_size: Option<u64>,
+    pub state_dir: Option<String>,
+    pub upload_session_timeout: Option<u64>,
+    pub trash_dir: Option<String>,
+    pub trash_retention_days: Option<u32>,
+    pub index: Option<bool>,
+    pub max_archive_files: Option<usize>,
+    pub max_archive_size: Option<u64>,
+    pub session_lifetime: Option<u64>,
+    pub acl: Option<AclConfig>,
+}
+
+#[derive(Debug)]
+struct CliConfig {
+    pub ipaddr: Option<String>,
+    pub port: Option<u16>,
+    pub sharedir: Option<String>,
+    pub users_file: Option<String>,
+    pub config_file: Option<String>,
+    #[allow(dead_code)] // TODO finish DB work
+    pub db_url: Option<String>,
+    pub max_upload_size: Option<u64>,
+    pub state_dir: Option<String>,
+    pub index: bool,
+    pub check_password: bool,
+    pub encrypt_password: bool,
+    pub user_command: Option<UserCommand>,
+}
+
+fn parse_args<'a>() -> ArgMatches<'a> {
+    App::new("Friendly File Server")
+    .version("1.0")
+    .about("Friendly file server")
+    .arg(Arg::with_name("ipaddr")
+         .long("ipaddr")
+         .help("IP address to bind to")
+         .required(false)
+         .takes_value(true))
+    .arg(Arg::with_name("port")
+         .long("port")
+         .help("The HTTP webserver port to listen to")
+         .default_value("5000")
+         .required(false)
+         .takes_value(true))
+    .arg(Arg::with_name("sharedir")
+         .long("sharedir")
+         .help("The directory to server files from")
+         .required(false)
+         .takes_value(true))
+    .arg(Arg::with_name("credsfile")
+         .long("credsfile")
+         .help("The file containing the credentials")
+         .required(false)
+         .takes_value(true))
+    .arg(Arg::with_name("dburl")
+         .long("dburl")
+         .help("The DB url")
+         .required(false)
+         .takes_value(true))
+    .arg(Arg::with_name("max_upload_size")
+         .long("max_upload_size")
+         .help("The largest file that can be uploaded, in bytes")
+         .required(false)
+         .takes_value(true))
+    .arg(Arg::with_name("statedir")
+         .long("statedir")
+         .help("The directory to keep upload sessions and other server state in")
+         .required(false)
+         .takes_value(true))
+    .arg(Arg::with_name("index")
+         .long("index")
+         .help("Keep an index of the share in memory, so that listings and searches don't read the disk")
+         .required(false)
+         .takes_value(false))
+    .arg(Arg::with_name("config")
+        .long("config")
+        .help("path to config file")
+        .required(false)
+        .takes_value(true))
+    .arg(Arg::with_name("encrypt_password")
+        .long("encrypt_password")
+        .help("Use this to encrypt a password and see the ciphertext")
+        .required(false)
+        .takes_value(false))
+    .arg(Arg::with_name("check_password")
+        .long("check_password")
+        .help("Use this to verify a password against it's hash")
+        .required(false)
+        .takes_value(false))
+    .subcommand(SubCommand::with_name("user")
+        .about("Manage the users in the credentials file. A running server picks the changes up.")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(SubCommand::with_name("add")
+            .about("Add a user, asking for their password")
+            .arg(Arg::with_name("username").required(true))
+            .arg(Arg::with_name("role")
+                .long("role")
+                .help("What the user can do")
+                .possible_values(&["ReadOnly", "Uploader", "Admin"])
+                .default_value("ReadOnly")
+                .takes_value(true)))
+        .subcommand(SubCommand::with_name("passwd")
+            .about("Change the password of a user")
+            .arg(Arg::with_name("username").required(true)))
+        .subcommand(SubCommand::with_name("del")
+            .about("Remove a user")
+            .arg(Arg::with_name("username").required(true)))
+        .subcommand(SubCommand::with_name("list")
+            .about("List the users and their roles"))
+        .subcommand(SubCommand::with_name("verify")
+            .about("Check the password of a user")
+            .arg(Arg::with_name("username").required(true))))
+    .get_matches()
+}
+
+fn parse_user_command(matches: &ArgMatches) -> Result<Option<UserCommand>, String> {
+    let matches = match matches.subcommand_matches("user") {
+        Some(matches) => matches,
+        None => return Ok(None),
+    };
+    let username = |m: &ArgMatches| m.value_of("username").unwrap_or_default().to_owned();
+
+    let command = match matches.subcommand() {
+        ("add", Some(m)) => UserCommand::Add {
+            username: username(m),
+            role: m.value_of("role").unwrap_or("ReadOnly").parse()?,
+        },
+        ("passwd", Some(m)) => UserCommand::Passwd { username: username(m) },
+        ("del", Some(m)) => UserCommand::Del { username: username(m) },
+        ("list", _) => UserCommand::List,
+        ("verify", Some(m)) => UserCommand::Verify { username: username(m) },
+        _ => return Err(String::from("Unknown user command")),
+    };
+    Ok(Some(command))
+}
+
+fn parse_config_from_json_file(p: &Path) -> Result<JsonConfig, String> {
+    let content = fs::read_to_string(p).map_err(|e| format!("{:?}", e))?;
+    let json_config: JsonConfig = serde_json::from_str(&content).map_err(|e| format!("{:?}", e))?;
+    Ok(json_config)
+}
+
+fn parse_config_from_args() -> Result<CliConfig, String> {
+    let matches = parse_args();
+
+    let ipaddr_str = matches.value_of("ipaddr").map(|s| s.to_owned());
+
+    let db_url = matches.value_of("dburl").map(|s| s.to_owned());
+
+    let mut port = None;
+    if let Some(p) = matches.value_of("port").map(|p| p.parse::<u16>()) {
+        port = Some(p.map_err(|e| format!("{}", e))?);
+    }
+
+    let mut max_upload_size = None;
+    if let Some(s) = matches.value_of("max_upload_size").map(|s| s.parse::<u64>()) {
+        max_upload_size = Some(s.map_err(|e| format!("{}", e))?);
+    }
+
+    let credsfile = matches.value_of("credsfile").map(|c| c.to_owned());
+
+    let state_dir = matches.value_of("statedir").map(|s| s.to_owned());
+
+    let sharedir = matches
+        .value_of("sharedir")
+        .map(|s| s.to_owned());
+
+    let config_file = matches
+        .value_of("config")
+        .map(|s| s.to_owned());
+
+    Ok(CliConfig {
+        ipaddr: ipaddr_str,
+        port: port,
+        sharedir: sharedir,
+        users_file: credsfile,
+        config_file: config_file,
+        db_url: db_url,
+        max_upload_size: max_upload_size,
+        state_dir: state_dir,
+        index: matches.is_present("index"),
+        encrypt_password: matches.is_present("encrypt_password"),
+        check_password: matches.is_present("check_password"),
+        user_command: parse_user_command(&matches)?,
+    })
+}
+
+pub fn load_config() -> Result<Config, String> {
+    let cli_conf = parse_config_from_args()?;
+    debug!("CLI args = {:?}", cli_conf);
+    let mut json_config = JsonConfig::default();
+
+    // Load the config file if it has been specified
+    if let Some(config_file) = &cli_conf.config_file {
+        let p = Path::new(config_file);
+        debug!("Loading config from {}", config_file);
+        json_config = parse_config_from_json_file(p)?;
+        debug!("JSON config = {:?}", json_config);
+    }
+
+    // If we are just checking password then default everything and return
+    // a useless config. The app will be exiting once it checks or encrypts the
+    // passwords.
+    if cli_conf.check_password | cli_conf.encrypt_password | cli_conf.user_command.is_some() {
+        let users_file = match cli_conf.user_command {
+            Some(_) => cli_conf.users_file.or(json_config.users_file).ok_or("Please specify Users File.")?,
+            None => String::from(""),
+        };
+        return Ok(Config {
+            ipaddr: [0,0,0,0],
+            port: 0,
+            sharedir: String::from(""),
+            mounts: Vec::new(),
+            symlinks: SymlinkPolicy::default(),
+            ignore: Vec::new(),
+            users: HashMap::new(),
+            users_file,
+            db_url: String::from(""),
+            max_upload_size: 0,
+            state_dir: String::from(""),
+            upload_session_timeout: 0,
+            trash_dir: String::from(""),
+            trash_retention_days: 0,
+            index: false,
+            max_archive_files: 0,
+            max_archive_size: 0,
+            session_lifetime: 0,
+            acl: AclConfig::default(),
+            check_password: cli_conf.check_password,
+            encrypt_password: cli_conf.encrypt_password,
+            user_command: cli_conf.user_command,
+        })
+    }
+
+    // Merge the values from the json conf and CLI arg
+    let ipaddr_str = cli_conf.ipaddr.or(json_config.ipaddr).ok_or("Please specify IP address.")?;
+    // let db_url = cli_conf.db_url.or(json_config.db_url).ok_or("Please specify DB url")?;
+    let db_url = String::new();
+    let port = cli_conf.port.or(json_config.port).ok_or("Please specify port.")?;
+    let (sharedir, mounts) = share_dirs(cli_conf.sharedir, json_config.sharedir, json_config.mounts.unwrap_or_default())?;
+    let symlinks = json_config.symlinks.unwrap_or_default();
+    let ignore = json_config.ignore.unwrap_or_else(|| DEFAULT_IGNORE.iter().map(|p| p.to_string()).collect());
+    let users_file = cli_conf.users_file.or(json_config.users_file).ok_or("Please specpfy Users File.")?;
+    let max_upload_size = cli_conf.max_upload_size.or(json_config.max_upload_size).unwrap_or(DEFAULT_MAX_UPLOAD_SIZE);
+    let state_dir = cli_conf.state_dir.or(json_config.state_dir).unwrap_or_else(|| String::from(DEFAULT_STATE_DIR));
+    let upload_session_timeout = json_config.upload_session_timeout.unwrap_or(DEFAULT_UPLOAD_SESSION_TIMEOUT);
+    let trash_dir = json_config.trash_dir.unwrap_or_else(|| format!("{}/trash", state_dir));
+    let trash_retention_days = json_config.trash_retention_days.unwrap_or(DEFAULT_TRASH_RETENTION_DAYS);
+    let index = cli_conf.index || json_config.index.unwrap_or(false);
+    let max_archive_files = json_config.max_archive_files.unwrap_or(DEFAULT_MAX_ARCHIVE_FILES);
+    let max_archive_size = json_config.max_archive_size.unwrap_or(DEFAULT_MAX_ARCHIVE_SIZE);
+    let session_lifetime = json_config.session_lifetime.unwrap_or(DEFAULT_SESSION_LIFETIME);
+    let acl = json_config.acl.unwrap_or_default();
+
+    // Do some further processing on some of the args
+    let users = load_users_file(Path::new(&users_file))?;
+    let ipaddr = validate_ip_addr(&ipaddr_str)?;
+
+    Ok(Config {
+        ipaddr,
+        port,
+        sharedir,
+        mounts,
+        symlinks,
+        ignore,
+        users,
+        users_file,
+        db_url,
+        max_upload_size,
+        state_dir,
+        upload_session_timeout,
+        trash_dir,
+        trash_retention_days,
+        index,
+        max_archive_files,
+        max_archive_size,
+        session_lifetime,
+        acl,
+        check_password: cli_conf.check_password,
+        encrypt_password: cli_conf.encrypt_password,
+        user_command: None,
+    })
+}
+
+/// Either a single share dir or a list of mounts has to be given. A share dir on the command line replaces the mounts.
+fn share_dirs(cli: Option<String>, json: Option<String>, mounts: Vec<MountConfig>) -> Result<(String, Vec<MountConfig>), String> {
+    match (cli, json) {
+        (Some(sharedir), _) => Ok((sharedir, Vec::new())),
+        (None, Some(_)) if !mounts.is_empty() => Err(String::from("Please specify either a Share Dir or mounts, not both.")),
+        (None, Some(sharedir)) => Ok((sharedir, Vec::new())),
+        (None, None) if !mounts.is_empty() => Ok((String::new(), mounts)),
+        (None, None) => Err(String::from("Please specify Share Dir or mounts.")),
+    }
+}
+
+fn validate_ip_addr(ipaddr: &str) -> Result<[u8; 4], String> {
+    let parts: Vec<&str> = ipaddr.split(".").collect();
+    let mut octet_array: [u8; 4] = [0,0,0,0];
+    if parts.len() != 4 {
+        Err(String::from("Incorrect IP address format"))?;
+    }
+
+    for (i, part) in parts.iter().enumerate() {
+        let octet: u8 = part.parse().map_err(|e| format!("{}", e))?;
+        octet_array[i] = octet;
+    }
+    Ok(octet_array)
+}
+
+/// Read the users from the credentials file at 'path'
+pub fn load_users_file(path: &Path) -> Result<HashMap<String, AuthenticatedUser>, String> {
+    let contents = fs::read_to_string(path).map_err(|e| format!("Could not read {:?}: {}", path, e))?;
+    load_users_from_str(&contents)
+}
+
+fn load_users_from_str(contents: &str) -> Result<HashMap<String, AuthenticatedUser>, String> {
+    CredentialsFile::parse(contents).map(|file| file.users())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_ip_addr() {
+        let data: Vec<(&str, Result<[u8; 4], String>)> = vec![
+            ("192.168.1.1", Ok([192, 168, 1, 1])),
+            ("0.0.0.0", Ok([0, 0, 0, 0])),
+            ("1.2.3", Err(String::from("Incorrect IP address format"))),
+            ("one.two.three.four", Err(String::from("invalid digit found in string"))),
+        ];
+
+        for (ip_str, outcome) in data {
+            assert_eq!(validate_ip_addr(ip_str), outcome);
+        }
+    }
+
+    #[test]
+    fn test_share_dirs() {
+        let mounts: Vec<MountConfig> = serde_json::from_str(r#"[
+            { "name": "movies", "path": "/mnt/a/movies" },
+            { "name": "photos", "path": "/mnt/b", "writable": true, "acl": { "rules": [{ "path": "private" }] } }
+        ]"#).unwrap();
+        assert!(!mounts[0].writable && mounts[1].writable);
+        assert_eq!(mounts[1].acl.rules.len(), 1);
+
+        let dir = |d: &str| Some(String::from(d));
+        let (sharedir, found) = share_dirs(None, None, mounts.clone()).unwrap();
+        assert_eq!((sharedir.as_str(), found.len()), ("", 2));
+        let (sharedir, found) = share_dirs(dir("/cli"), None, mounts.clone()).unwrap();
+        assert_eq!((sharedir.as_str(), found.len()), ("/cli", 0));
+        let (sharedir, found) = share_dirs(None, dir("/json"), Vec::new()).unwrap();
+        assert_eq!((sharedir.as_str(), found.len()), ("/json", 0));
+        assert!(share_dirs(None, dir("/json"), mounts).is_err());
+        assert!(share_dirs(None, None, Vec::new()).is_err());
+    }
+
+    #[test]
+    fn test_symlink_policy() {
+        let config: JsonConfig = serde_json::from_str(r#"{ "symlinks": "deny" }"#).unwrap();
+        assert_eq!(config.symlinks, Some(SymlinkPolicy::Deny));
+        let config: JsonConfig = serde_json::from_str(r#"{ "symlinks": { "allow_targets": ["/mnt/b"] } }"#).unwrap();
+        assert_eq!(config.symlinks, Some(SymlinkPolicy::AllowTargets(vec!["/mnt/b".into()])));
+        let config: JsonConfig = serde_json::from_str(r#"{ "symlinks": "follow_all" }"#).unwrap();
+        assert_eq!(config.symlinks, Some(SymlinkPolicy::FollowAll));
+        assert!(serde_json::from_str::<JsonConfig>(r#"{ "symlinks": "sometimes" }"#).is_err());
+    }
+
+    #[test]
+    fn test_load_users_from_str1() {
+        let good_str  = concat!(
+            "; Users file\n",
+            "username1 pass1\n",
+            "username2 pass2\n",
+            "username3 pass3 ReadOnly\n",
+            "username4 pass4 Admin\n",
+            "username5 pass5 Uploader\n",
+        );
+
+        let mut expected_usernames = HashMap::new();
+        let users = vec![
+            AuthenticatedUser::new(String::from("username1"), String::from("pass1"), UserRole::ReadOnly),
+            AuthenticatedUser::new(String::from("username2"), String::from("pass2"), UserRole::ReadOnly),
+            AuthenticatedUser::new(String::from("username3"), String::from("pass3"), UserRole::ReadOnly),
+            AuthenticatedUser::new(String::from("username4"), String::from("pass4"), UserRole::Admin),
+            AuthenticatedUser::new(String::from("username5"), String::from("pass5"), UserRole::Uploader),
+        ];
+        for user in users {
+            expected_usernames.insert(user.username.clone(), user.clone());
+        }
+
+        assert_eq!(load_users_from_str(good_str), Ok(expected_usernames));
+
+        let bad_str = "; Users file\nuser space pass rubbish";
+        assert_eq!(
+            load_users_from_str(bad_str),
+            Err(String::from("Error reading credentials file, line 2: expected 'username hash [role]'")),
+        );
+
+        // Roles have to be spelled right, rather than quietly becoming ReadOnly
+        let bad_role = "username1 pass1 Admin\nusername6 pass6 QwErTy\n";
+        assert_eq!(
+            load_users_from_str(bad_role),
+            Err(String::from("Error reading credentials file, line 2: Unknown role 'QwErTy', expected ReadOnly, Uploader or Admin")),
+        );
+    }
+
 }
\ No newline at end of file
@@ -0,0 +1,205 @@
+use std::fmt;
+
+/// The credentials from an Authorization header
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Credentials {
+    Basic { username: String, password: String },
+    /// A session token, the same as the value of the session cookie, for API clients that don't keep cookies
+    Bearer(String),
+}
+
+/// Why an Authorization header couldn't be used
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthHeaderError {
+    /// The header has characters that aren't allowed in a header
+    NotAscii,
+    /// There is no scheme, or nothing after it
+    MissingCredentials,
+    /// A scheme other than Basic or Bearer
+    UnsupportedScheme(String),
+    /// The Basic credentials aren't valid base64
+    InvalidBase64,
+    /// The Basic credentials aren't valid UTF-8
+    InvalidUtf8,
+    /// The Basic credentials don't have a ':' between the username and password
+    MissingColon,
+    /// The Bearer token has characters that can't be in a token
+    InvalidToken,
+}
+
+impl AuthHeaderError {
+    /// Whether the client just needs to authenticate another way, rather than having sent a broken header
+    pub fn is_unauthorized(&self) -> bool {
+        matches!(self, AuthHeaderError::UnsupportedScheme(_))
+    }
+}
+
+impl fmt::Display for AuthHeaderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AuthHeaderError::NotAscii => write!(f, "Authorization header is not ASCII"),
+            AuthHeaderError::MissingCredentials => write!(f, "Authorization header has no credentials"),
+            AuthHeaderError::UnsupportedScheme(scheme) => write!(f, "Unsupported authorization scheme '{}'", scheme),
+            AuthHeaderError::InvalidBase64 => write!(f, "Basic credentials are not valid base64"),
+            AuthHeaderError::InvalidUtf8 => write!(f, "Basic credentials are not valid UTF-8"),
+            AuthHeaderError::MissingColon => write!(f, "Basic credentials must be 'username:password'"),
+            AuthHeaderError::InvalidToken => write!(f, "Bearer token is not valid"),
+        }
+    }
+}
+
+/// What can separate the scheme from the credentials
+const WHITESPACE: [char; 2] = [' ', '\t'];
+
+/// The characters of a token68, as in RFC 7235, apart from the '=' padding at the end
+fn is_token_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "-._~+/".contains(c)
+}
+
+fn parse_basic(credentials: &str) -> Result<Credentials, AuthHeaderError> {
+    // Some clients leave the padding off
+    let encoded = credentials.trim_end_matches('=');
+    let decoded = base64::decode_config(encoded, base64::STANDARD_NO_PAD).map_err(|_| AuthHeaderError::InvalidBase64)?;
+    let decoded = String::from_utf8(decoded).map_err(|_| AuthHeaderError::InvalidUtf8)?;
+    let (username, password) = decoded.split_once(':').ok_or(AuthHeaderError::MissingColon)?;
+    Ok(Credentials::Basic { username: username.to_owned(), password: password.to_owned() })
+}
+
+fn parse_bearer(token: &str) -> Result<Credentials, AuthHeaderError> {
+    let unpadded = token.trim_end_matches('=');
+    if unpadded.is_empty() || !unpadded.chars().all(is_token_char) {
+        return Err(AuthHeaderError::InvalidToken);
+    }
+    Ok(Credentials::Bearer(token.to_owned()))
+}
+
+/*
+Parse the value of an Authorization header. The scheme is matched without regard to case, and is separated from the
+credentials by spaces or tabs. Nothing about the header is trusted, so anything that doesn't parse is an error rather
+than a panic.
+*/
+pub fn parse(header: &[u8]) -> Result<Credentials, AuthHeaderError> {
+    let header = std::str::from_utf8(header).map_err(|_| AuthHeaderError::NotAscii)?;
+    if !header.chars().all(|c| c == '\t' || (' '..='~').contains(&c)) {
+        return Err(AuthHeaderError::NotAscii);
+    }
+
+    let header = header.trim_matches(WHITESPACE);
+    let (scheme, credentials) = header.split_once(WHITESPACE).ok_or(AuthHeaderError::MissingCredentials)?;
+    let credentials = credentials.trim_start_matches(WHITESPACE);
+
+    if scheme.eq_ignore_ascii_case("Basic") {
+        parse_basic(credentials)
+    } else if scheme.eq_ignore_ascii_case("Bearer") {
+        parse_bearer(credentials)
+    } else {
+        Err(AuthHeaderError::UnsupportedScheme(scheme.to_owned()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::SmallRng;
+
+    fn basic(username: &str, password: &str) -> Credentials {
+        Credentials::Basic { username: username.to_owned(), password: password.to_owned() }
+    }
+
+    #[test]
+    fn test_parse() {
+        let encoded = base64::encode("reader:pass:word");
+        assert_eq!(parse(format!("Basic {}", encoded).as_bytes()), Ok(basic("reader", "pass:word")));
+        assert_eq!(parse(format!("bAsIc {}", encoded).as_bytes()), Ok(basic("reader", "pass:word")));
+        assert_eq!(parse(format!(" Basic \t {} ", encoded).as_bytes()), Ok(basic("reader", "pass:word")));
+        assert_eq!(parse(format!("Basic {}", encoded.trim_end_matches('=')).as_bytes()), Ok(basic("reader", "pass:word")));
+        assert_eq!(parse(format!("Basic {}", base64::encode(":")).as_bytes()), Ok(basic("", "")));
+        assert_eq!(parse(format!("Basic {}", base64::encode("rëader:pässword")).as_bytes()), Ok(basic("rëader", "pässword")));
+        assert_eq!(parse(b"Bearer abc-_.~+/123=="), Ok(Credentials::Bearer(String::from("abc-_.~+/123=="))));
+        assert_eq!(parse(b"BEARER abc.def"), Ok(Credentials::Bearer(String::from("abc.def"))));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let cases: Vec<(Vec<u8>, AuthHeaderError)> = vec![
+            (b"".to_vec(), AuthHeaderError::MissingCredentials),
+            (b"Basic".to_vec(), AuthHeaderError::MissingCredentials),
+            (b"Basic ".to_vec(), AuthHeaderError::MissingCredentials),
+            (b"   ".to_vec(), AuthHeaderError::MissingCredentials),
+            (b"Basic !!!!".to_vec(), AuthHeaderError::InvalidBase64),
+            (b"Basic a".to_vec(), AuthHeaderError::InvalidBase64),
+            (b"Basic cmVhZGVy OnBhc3M=".to_vec(), AuthHeaderError::InvalidBase64),
+            (format!("Basic {}", base64::encode(b"\xff\xfe:pass")).into_bytes(), AuthHeaderError::InvalidUtf8),
+            (format!("Basic {}", base64::encode("reader")).into_bytes(), AuthHeaderError::MissingColon),
+            (b"Bearer".to_vec(), AuthHeaderError::MissingCredentials),
+            (b"Bearer ===".to_vec(), AuthHeaderError::InvalidToken),
+            (b"Bearer abc def".to_vec(), AuthHeaderError::InvalidToken),
+            (b"Bearer a=b".to_vec(), AuthHeaderError::InvalidToken),
+            (b"Digest username=\"reader\"".to_vec(), AuthHeaderError::UnsupportedScheme(String::from("Digest"))),
+            (b"Basicx cmVhZGVyOnBhc3M=".to_vec(), AuthHeaderError::UnsupportedScheme(String::from("Basicx"))),
+            (b"Basic\xff cmVhZGVyOnBhc3M=".to_vec(), AuthHeaderError::NotAscii),
+            ("Basic é".as_bytes().to_vec(), AuthHeaderError::NotAscii),
+            (b"Basic cmVh\nZGVyOnBhc3M=".to_vec(), AuthHeaderError::NotAscii),
+            (b"Basic \0".to_vec(), AuthHeaderError::NotAscii),
+        ];
+        for (header, error) in cases {
+            assert_eq!(parse(&header), Err(error), "{:?}", String::from_utf8_lossy(&header));
+        }
+        assert!(AuthHeaderError::UnsupportedScheme(String::from("Digest")).is_unauthorized());
+        assert!(!AuthHeaderError::InvalidBase64.is_unauthorized());
+    }
+
+    #[test]
+    fn test_parse_random_input() {
+        let mut rng = SmallRng::seed_from_u64(20);
+        let prefixes: [&[u8]; 5] = [b"", b"Basic ", b"bearer ", b"BASIC\t", b"Bearer  "];
+        for _ in 0..20_000 {
+            let mut header = prefixes[rng.gen_range(0, prefixes.len())].to_vec();
+            let len = rng.gen_range(0, 40);
+            if rng.gen_bool(0.5) {
+                header.extend((0..len).map(|_| rng.gen::<u8>()));
+            } else {
+                // Printable characters get further into the parser
+                header.extend((0..len).map(|_| rng.gen_range(b' ', b'~' + 1)));
+            }
+
+            // Anything goes, as long as it doesn't panic and whatever parses really was in the header
+            match parse(&header) {
+                Ok(Credentials::Basic { username, .. }) => assert!(!username.contains(':')),
+                Ok(Credentials::Bearer(token)) => assert!(!token.is_empty() && token.chars().all(|c| c == '=' || is_token_char(c))),
+                Err(_) => (),
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_round_trip() {
+        let mut rng = SmallRng::seed_from_u64(20);
+        for _ in 0..5_000 {
+            let username: String = (0..rng.gen_range(0, 12))
+                .map(|_| rng.gen::<char>())
+                .filter(|c| *c != ':')
+                .collect();
+            let password: String = (0..rng.gen_range(0, 24)).map(|_| rng.gen::<char>()).collect();
+            let scheme: String = "basic".chars()
+                .map(|c| if rng.gen_bool(0.5) { c.to_ascii_uppercase() } else { c })
+                .collect();
+            let mut encoded = base64::encode(format!("{}:{}", username, password));
+            if rng.gen_bool(0.5) {
+                encoded = encoded.trim_end_matches('=').to_owned();
+            }
+
+            let header = format!("{} {}", scheme, encoded);
+            assert_eq!(parse(header.as_bytes()), Ok(basic(&username, &password)), "{}", header);
+
+            // Damaging the header can make it fail, but never panic
+            let mut damaged = header.into_bytes();
+            let i = rng.gen_range(0, damaged.len());
+            damaged[i] = rng.gen();
+            let _ = parse(&damaged);
+            damaged.truncate(i);
+            let _ = parse(&damaged);
+        }
+    }
+}
@@ -0,0 +1,652 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+use argon2::password_hash::PasswordHash;
+use notify::{EventKind, RecursiveMode, Watcher};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+
+use crate::acl::Acl;
+use crate::args::{load_users_file, UserCommand};
+use crate::fs_utils::TempFile;
+use crate::webserver::models::{AccessRules, AuthenticatedUser, UserMap, UserRole};
+
+/// How long to wait for more events before reading the file, so that a save is read once it has finished
+const DEBOUNCE: Duration = Duration::from_millis(250);
+
+/// The longest a reload is held back while events keep arriving
+const MAX_BATCH_WAIT: Duration = Duration::from_secs(2);
+
+/// A line of the credentials file
+#[derive(Debug, Clone)]
+enum Line {
+    /// Comments and blank lines, kept as they are
+    Other(String),
+    /// The line is kept as it was written until the user is changed
+    User { user: AuthenticatedUser, disabled: bool, text: String },
+    /// A named group and the usernames in it
+    Group { name: String, members: Vec<String>, text: String },
+}
+
+/// Marks a user who can't log in, e.g. '!username hash role'
+const DISABLED_PREFIX: char = '!';
+
+/// Starts a line that names a group, e.g. '@family mick mam'
+const GROUP_PREFIX: char = '@';
+
+/// Usernames can't have spaces in them, or start with ';', '!' or '@', which would make them mean something else
+pub fn is_valid_username(username: &str) -> bool {
+    !username.is_empty()
+        && !username.starts_with(';')
+        && !username.starts_with(DISABLED_PREFIX)
+        && !username.starts_with(GROUP_PREFIX)
+        && !username.chars().any(char::is_whitespace)
+}
+
+fn user_line(user: &AuthenticatedUser, disabled: bool) -> String {
+    let prefix = if disabled { "!" } else { "" };
+    format!("{}{} {} {}", prefix, user.username, user.password, user.role)
+}
+
+/*
+The credentials file, with a 'username hash [role]' line for each user and comments starting with ';'. Users without
+a role are ReadOnly, and users whose line starts with '!' are disabled. Groups are lines of '@name' followed by the
+usernames in the group, and can come before or after the users in them. Comments, blank lines and the order of the
+lines are kept, so that changing one user leaves the rest of the file the way it was written.
+*/
+#[derive(Debug, Clone)]
+pub struct CredentialsFile {
+    lines: Vec<Line>,
+}
+
+impl CredentialsFile {
+    pub fn parse(contents: &str) -> Result<Self, String> {
+        let mut lines = Vec::new();
+        for (number, text) in contents.split('\n').enumerate() {
+            let line = text.trim();
+            if line.is_empty() || line.starts_with(';') {
+                lines.push(Line::Other(text.to_owned()));
+                continue;
+            }
+
+            let error = |reason: String| format!("Error reading credentials file, line {}: {}", number + 1, reason);
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            if let Some(name) = parts[0].strip_prefix(GROUP_PREFIX) {
+                if name.is_empty() {
+                    return Err(error(String::from("expected '@group [username...]'")));
+                }
+                let members = parts[1..].iter().map(|member| member.to_string()).collect();
+                lines.push(Line::Group { name: name.to_owned(), members, text: text.to_owned() });
+                continue;
+            }
+
+            if !(parts.len() == 2 || parts.len() == 3) {
+                return Err(error(String::from("expected 'username hash [role]'")));
+            }
+            let (username, disabled) = match parts[0].strip_prefix(DISABLED_PREFIX) {
+                Some(username) => (username, true),
+                None => (parts[0], false),
+            };
+            if username.is_empty() {
+                return Err(error(String::from("expected 'username hash [role]'")));
+            }
+            let role = match parts.get(2) {
+                Some(role) => role.parse().map_err(error)?,
+                None => UserRole::ReadOnly,
+            };
+            let user = AuthenticatedUser {
+                username: username.to_owned(),
+                role,
+                password: parts[1].to_owned(),
+                groups: Vec::new(),
+            };
+            lines.push(Line::User { user, disabled, text: text.to_owned() });
+        }
+        let mut file = CredentialsFile { lines };
+        file.assign_groups();
+        Ok(file)
+    }
+
+    /// Fill in the groups of every user from the group lines
+    fn assign_groups(&mut self) {
+        let mut groups: HashMap<String, Vec<String>> = HashMap::new();
+        for line in &self.lines {
+            if let Line::Group { name, members, .. } = line {
+                for member in members {
+                    let names = groups.entry(member.clone()).or_default();
+                    if !names.contains(name) {
+                        names.push(name.clone());
+                    }
+                }
+            }
+        }
+        for line in self.lines.iter_mut() {
+            if let Line::User { user, .. } = line {
+                user.groups = groups.get(&user.username).cloned().unwrap_or_default();
+                user.groups.sort();
+            }
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let contents = fs::read_to_string(path).map_err(|e| format!("Could not read {:?}: {}", path, e))?;
+        CredentialsFile::parse(&contents)
+    }
+
+    /// Replace the file at 'path' in one go, keeping its permissions, so that the server never reads half of it
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let write = || {
+            let temp = TempFile::new_for(path);
+            fs::write(temp.path(), self.to_string())?;
+            if let Ok(metadata) = fs::metadata(path) {
+                fs::set_permissions(temp.path(), metadata.permissions())?;
+            }
+            temp.persist(path)
+        };
+        write().map_err(|e: std::io::Error| format!("Could not write {:?}: {}", path, e))
+    }
+
+    /// Every user and whether they are disabled, in the order they are in the file. When a username is in the file
+    /// twice the last one counts.
+    pub fn list(&self) -> Vec<(&AuthenticatedUser, bool)> {
+        let users: Vec<(&AuthenticatedUser, bool)> = self.lines.iter()
+            .filter_map(|line| match line {
+                Line::User { user, disabled, .. } => Some((user, *disabled)),
+                Line::Other(_) | Line::Group { .. } => None,
+            })
+            .collect();
+        users.iter()
+            .enumerate()
+            .filter(|(i, (user, _))| !users[i + 1..].iter().any(|(later, _)| later.username == user.username))
+            .map(|(_, entry)| *entry)
+            .collect()
+    }
+
+    /// The users who can log in
+    pub fn users(&self) -> HashMap<String, AuthenticatedUser> {
+        self.list().into_iter()
+            .filter(|(_, disabled)| !disabled)
+            .map(|(user, _)| (user.username.clone(), user.clone()))
+            .collect()
+    }
+
+    pub fn get(&self, username: &str) -> Option<&AuthenticatedUser> {
+        self.list().into_iter().map(|(user, _)| user).find(|user| user.username == username)
+    }
+
+    /// Add a user at the end of the file
+    pub fn add(&mut self, user: AuthenticatedUser) -> Result<(), String> {
+        if !is_valid_username(&user.username) {
+            return Err(format!("'{}' can't be used as a username", user.username));
+        }
+        if self.get(&user.username).is_some() {
+            return Err(format!("There is already a user called {}", user.username));
+        }
+
+        let line = Line::User { text: user_line(&user, false), user, disabled: false };
+        // Keep the newline at the end of the file, if there is one
+        match self.lines.last() {
+            Some(Line::Other(text)) if text.is_empty() => self.lines.insert(self.lines.len() - 1, line),
+            _ => self.lines.push(line),
+        }
+        // Groups can name a user before they are added
+        self.assign_groups();
+        Ok(())
+    }
+
+    /// Change every line of a user with 'change', and write the lines out again
+    fn update<F: Fn(&mut AuthenticatedUser, &mut bool)>(&mut self, username: &str, change: F) -> Result<(), String> {
+        let mut found = false;
+        for line in self.lines.iter_mut() {
+            if let Line::User { user, disabled, text } = line {
+                if user.username == username {
+                    change(user, disabled);
+                    *text = user_line(user, *disabled);
+                    found = true;
+                }
+            }
+        }
+        if found { Ok(()) } else { Err(format!("There is no user called {}", username)) }
+    }
+
+    /// Change the password hash of a user
+    pub fn set_password(&mut self, username: &str, hash: &str) -> Result<(), String> {
+        self.update(username, |user, _| user.password = hash.to_owned())
+    }
+
+    pub fn set_role(&mut self, username: &str, role: UserRole) -> Result<(), String> {
+        self.update(username, |user, _| user.role = role)
+    }
+
+    /// Disabled users are kept in the file, but can't log in
+    pub fn set_disabled(&mut self, username: &str, disabled: bool) -> Result<(), String> {
+        self.update(username, |_, is_disabled| *is_disabled = disabled)
+    }
+
+    pub fn remove(&mut self, username: &str) -> Result<AuthenticatedUser, String> {
+        let user = self.get(username).cloned().ok_or_else(|| format!("There is no user called {}", username))?;
+        self.lines.retain(|line| !matches!(line, Line::User { user, .. } if user.username == username));
+        Ok(user)
+    }
+}
+
+impl std::fmt::Display for CredentialsFile {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for (i, line) in self.lines.iter().enumerate() {
+            if i > 0 {
+                f.write_str("\n")?;
+            }
+            match line {
+                Line::Other(text) | Line::User { text, .. } | Line::Group { text, .. } => f.write_str(text)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Read a password without showing it as it is typed
+fn read_password(prompt: &str) -> Result<String, String> {
+    rpassword::prompt_password_stderr(prompt).map_err(|e| format!("Could not read the password: {}", e))
+}
+
+/// Read a new password, twice to make sure it was typed right
+fn read_new_password() -> Result<String, String> {
+    let password = read_password("Password: ")?;
+    if password.is_empty() {
+        return Err(String::from("The password can't be empty"));
+    }
+    if read_password("Password again: ")? != password {
+        return Err(String::from("The passwords don't match"));
+    }
+    Ok(password)
+}
+
+/// Run one of the 'user' subcommands against the credentials file at 'path'. A running server picks the changes up.
+pub fn run_command(command: &UserCommand, path: &Path) -> Result<(), String> {
+    match command {
+        UserCommand::Add { username, role } => {
+            let mut file = CredentialsFile::load(path)?;
+            // Checked before asking for a password, which would be wasted otherwise
+            if file.get(username).is_some() {
+                return Err(format!("There is already a user called {}", username));
+            }
+            let password = read_new_password()?;
+            file.add(AuthenticatedUser {
+                username: username.clone(),
+                role: *role,
+                password: crate::hash(password.as_bytes()),
+                groups: Vec::new(),
+            })?;
+            file.save(path)?;
+            println!("Added {} as {}", username, role);
+        },
+        UserCommand::Passwd { username } => {
+            let mut file = CredentialsFile::load(path)?;
+            if file.get(username).is_none() {
+                return Err(format!("There is no user called {}", username));
+            }
+            let password = read_new_password()?;
+            file.set_password(username, &crate::hash(password.as_bytes()))?;
+            file.save(path)?;
+            println!("Changed the password of {}", username);
+        },
+        UserCommand::Del { username } => {
+            let mut file = CredentialsFile::load(path)?;
+            file.remove(username)?;
+            file.save(path)?;
+            println!("Removed {}", username);
+        },
+        UserCommand::List => {
+            for (user, disabled) in CredentialsFile::load(path)?.list() {
+                let groups = if user.groups.is_empty() { String::new() } else { format!(" [{}]", user.groups.join(", ")) };
+                println!("{} {}{}{}", user.username, user.role, groups, if disabled { " (disabled)" } else { "" });
+            }
+        },
+        UserCommand::Verify { username } => {
+            let file = CredentialsFile::load(path)?;
+            let user = file.get(username).ok_or_else(|| format!("There is no user called {}", username))?;
+            let password = read_password("Password: ")?;
+            let valid = PasswordHash::new(&user.password).is_ok() && crate::verify(&user.password, password.as_bytes());
+            if !valid {
+                return Err(format!("That is not the password of {}", username));
+            }
+            println!("The password of {} is correct", username);
+        },
+    }
+    Ok(())
+}
+
+/// What changed between two versions of the credentials file, each list sorted by username
+#[derive(Debug, Default, PartialEq)]
+pub struct UserChanges {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    /// The username, with its old and new roles
+    pub roles_changed: Vec<(String, UserRole, UserRole)>,
+    pub passwords_changed: Vec<String>,
+    pub groups_changed: Vec<String>,
+}
+
+impl UserChanges {
+    pub fn between(old: &HashMap<String, AuthenticatedUser>, new: &HashMap<String, AuthenticatedUser>) -> Self {
+        let mut changes = UserChanges::default();
+        for (username, user) in new {
+            match old.get(username) {
+                None => changes.added.push(username.clone()),
+                Some(before) => {
+                    if before.role != user.role {
+                        changes.roles_changed.push((username.clone(), before.role, user.role));
+                    }
+                    if before.password != user.password {
+                        changes.passwords_changed.push(username.clone());
+                    }
+                    if before.groups != user.groups {
+                        changes.groups_changed.push(username.clone());
+                    }
+                },
+            }
+        }
+        changes.removed = old.keys().filter(|username| !new.contains_key(*username)).cloned().collect();
+
+        changes.added.sort();
+        changes.removed.sort();
+        changes.roles_changed.sort_by(|a, b| a.0.cmp(&b.0));
+        changes.passwords_changed.sort();
+        changes.groups_changed.sort();
+        changes
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.roles_changed.is_empty() && self.passwords_changed.is_empty()
+            && self.groups_changed.is_empty()
+    }
+
+    fn log(&self) {
+        for username in &self.added {
+            info!("User {} was added", username);
+        }
+        for username in &self.removed {
+            info!("User {} was removed", username);
+        }
+        for (username, old, new) in &self.roles_changed {
+            info!("User {} changed role from {:?} to {:?}", username, old, new);
+        }
+        for username in &self.passwords_changed {
+            info!("User {} changed password", username);
+        }
+        for username in &self.groups_changed {
+            info!("User {} changed groups", username);
+        }
+    }
+}
+
+/*
+Read the credentials file again and swap the users for the ones in it, all at once, so that requests see either the
+old users or the new ones. A file that can't be read, or which has nobody in it (most likely because it is half way
+through being saved), is refused and the current users are kept. Whoever was removed or had their password changed
+is logged out.
+*/
+pub async fn reload(users: &UserMap, path: &Path, acl: &Acl) -> Result<UserChanges, String> {
+    let new_accounts = load_users_file(path)?;
+    if new_accounts.is_empty() {
+        return Err(format!("There are no users in {:?}", path));
+    }
+    if let Err(e) = acl.check_groups(&new_accounts) {
+        error!("{} in {:?}", e, path);
+    }
+
+    let changes = {
+        let mut accounts = users.accounts.lock().await;
+        let changes = UserChanges::between(&accounts, &new_accounts);
+        *accounts = new_accounts;
+        changes
+    };
+
+    let mut sessions = users.sessions.lock().await;
+    for username in changes.removed.iter().chain(&changes.passwords_changed) {
+        sessions.remove_user(username);
+    }
+    Ok(changes)
+}
+
+/// Reload the users whenever the credentials file at 'path' changes, or the server is sent SIGHUP
+pub fn start(users: UserMap, path: PathBuf, acl: AccessRules) {
+    let (tx, mut rx) = unbounded_channel();
+
+    let watched = path.clone();
+    let changed = tx.clone();
+    thread::spawn(move || watch(watched, changed));
+    #[cfg(unix)]
+    tokio::spawn(hangups(tx));
+
+    tokio::spawn(async move {
+        while rx.recv().await.is_some() {
+            match reload(&users, &path, &acl).await {
+                Ok(changes) if changes.is_empty() => debug!("Reloaded {:?}, no users changed", path),
+                Ok(changes) => {
+                    info!("Reloaded the users from {:?}", path);
+                    changes.log();
+                },
+                Err(e) => {
+                    let kept = users.accounts.lock().await.len();
+                    error!("Could not reload the users, keeping the {} current user(s): {}", kept, e);
+                },
+            }
+        }
+    });
+}
+
+/// Send a message on 'changed' for every batch of changes to the file at 'path'
+fn watch(path: PathBuf, changed: UnboundedSender<()>) {
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = match notify::recommended_watcher(move |event| { let _ = tx.send(event); }) {
+        Ok(watcher) => watcher,
+        Err(e) => return error!("Could not watch the credentials file, send SIGHUP to reload it: {}", e),
+    };
+    // Editors often save by writing a new file and moving it over the old one, so watch the directory it is in
+    let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or_else(|| Path::new("."));
+    if let Err(e) = watcher.watch(dir, RecursiveMode::NonRecursive) {
+        return error!("Could not watch {:?}, send SIGHUP to reload the users: {}", dir, e);
+    }
+    let name = path.file_name();
+
+    while let Ok(first) = rx.recv() {
+        let mut events = vec![first];
+        let deadline = Instant::now() + MAX_BATCH_WAIT;
+        while Instant::now() < deadline {
+            match rx.recv_timeout(DEBOUNCE) {
+                Ok(event) => events.push(event),
+                Err(_) => break,
+            }
+        }
+
+        // Reading the file makes events of its own, which are left out so that a reload doesn't cause another
+        let touched = events.iter().any(|event| match event {
+            Ok(event) => {
+                matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_))
+                    && (event.need_rescan() || event.paths.iter().any(|p| p.file_name() == name))
+            },
+            Err(e) => {
+                warn!("Error watching the credentials file: {}", e);
+                true
+            },
+        });
+        if touched && changed.send(()).is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(unix)]
+async fn hangups(changed: UnboundedSender<()>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => return error!("Could not listen for SIGHUP: {}", e),
+    };
+    while hangup.recv().await.is_some() {
+        info!("Got SIGHUP, reloading the users");
+        if changed.send(()).is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use chrono::Utc;
+    use crate::webserver::models::{new_access_rules, new_users};
+    use crate::sessions::DEFAULT_SESSION_LIFETIME;
+
+    fn user(username: &str, password: &str, role: UserRole) -> (String, AuthenticatedUser) {
+        (username.to_owned(), AuthenticatedUser::new(username.to_owned(), password.to_owned(), role))
+    }
+
+    #[test]
+    fn test_credentials_file() {
+        let contents = "; Friends\nadmin hash1 Admin\n\n;  reader  is temporary\nreader   hash2\nuploader hash3 Uploader\n";
+        let mut file = CredentialsFile::parse(contents).unwrap();
+        assert_eq!(file.to_string(), contents);
+        let names: Vec<&str> = file.list().iter().map(|(user, _)| user.username.as_str()).collect();
+        assert_eq!(names, vec!["admin", "reader", "uploader"]);
+
+        file.add(AuthenticatedUser::new(String::from("friend"), String::from("hash4"), UserRole::Uploader)).unwrap();
+        file.set_password("reader", "hash5").unwrap();
+        file.remove("uploader").unwrap();
+        assert_eq!(
+            file.to_string(),
+            "; Friends\nadmin hash1 Admin\n\n;  reader  is temporary\nreader hash5 ReadOnly\nfriend hash4 Uploader\n",
+        );
+        assert_eq!(CredentialsFile::parse(&file.to_string()).unwrap().users(), file.users());
+
+        assert!(file.add(AuthenticatedUser::new(String::from("admin"), String::from("hash"), UserRole::Admin)).is_err());
+        for username in &["", "two words", ";comment"] {
+            assert!(file.add(AuthenticatedUser::new(username.to_string(), String::from("hash"), UserRole::Admin)).is_err());
+        }
+        assert!(file.set_password("nobody", "hash").is_err());
+        assert!(file.remove("nobody").is_err());
+        assert!(CredentialsFile::parse("admin hash1 admin\n").is_err());
+
+        file.set_disabled("reader", true).unwrap();
+        file.set_role("friend", UserRole::Admin).unwrap();
+        assert!(file.to_string().ends_with("\n!reader hash5 ReadOnly\nfriend hash4 Admin\n"));
+        assert!(!file.users().contains_key("reader"));
+        assert_eq!(file.get("reader").unwrap().password, "hash5");
+        let reparsed = CredentialsFile::parse(&file.to_string()).unwrap();
+        assert!(reparsed.list().iter().any(|(user, disabled)| *disabled && user.username == "reader"));
+        assert!(CredentialsFile::parse("! hash1 Admin\n").is_err());
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("users");
+        fs::write(&path, contents).unwrap();
+        file.save(&path).unwrap();
+        assert_eq!(load_users_file(&path).unwrap(), file.users());
+    }
+
+    #[test]
+    fn test_groups() {
+        let contents = "@family mick mam
+mick hash1
+; not a group
+mam hash2 Uploader
+@friends mick dave
+@family mick
+";
+        let mut file = CredentialsFile::parse(contents).unwrap();
+        assert_eq!(file.to_string(), contents);
+        assert_eq!(file.get("mick").unwrap().groups, vec!["family", "friends"]);
+        assert_eq!(file.get("mam").unwrap().groups, vec!["family"]);
+        assert!(file.get("mam").unwrap().in_group("family"));
+        assert!(!file.get("mam").unwrap().in_group("friends"));
+
+        // Groups can name users who are added later, and stay with users who are changed
+        file.add(AuthenticatedUser::new(String::from("dave"), String::from("hash3"), UserRole::ReadOnly)).unwrap();
+        assert_eq!(file.get("dave").unwrap().groups, vec!["friends"]);
+        file.set_role("mick", UserRole::Admin).unwrap();
+        assert_eq!(file.users()["mick"].groups, vec!["family", "friends"]);
+        assert_eq!(CredentialsFile::parse(&file.to_string()).unwrap().users(), file.users());
+
+        assert!(CredentialsFile::parse("@ mick
+").is_err());
+        assert!(CredentialsFile::parse("@empty
+").is_ok());
+        assert!(file.add(AuthenticatedUser::new(String::from("@family"), String::from("hash"), UserRole::Admin)).is_err());
+    }
+
+    #[test]
+    fn test_user_changes() {
+        let old: HashMap<String, AuthenticatedUser> = vec![
+            user("admin", "hash1", UserRole::Admin),
+            user("reader", "hash2", UserRole::ReadOnly),
+            user("uploader", "hash3", UserRole::Uploader),
+        ].into_iter().collect();
+        let new: HashMap<String, AuthenticatedUser> = vec![
+            user("admin", "hash1", UserRole::Admin),
+            user("reader", "hash4", UserRole::Uploader),
+            user("friend", "hash5", UserRole::ReadOnly),
+        ].into_iter().collect();
+
+        assert!(UserChanges::between(&old, &old).is_empty());
+        assert_eq!(UserChanges::between(&old, &new), UserChanges {
+            added: vec![String::from("friend")],
+            removed: vec![String::from("uploader")],
+            roles_changed: vec![(String::from("reader"), UserRole::ReadOnly, UserRole::Uploader)],
+            passwords_changed: vec![String::from("reader")],
+            groups_changed: vec![],
+        });
+    }
+
+    #[tokio::test]
+    async fn test_reload() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("users");
+        fs::write(&path, "admin hash1 Admin\nreader hash2\n").unwrap();
+        let users = new_users(load_users_file(&path).unwrap(), DEFAULT_SESSION_LIFETIME);
+        let admin = users.sessions.lock().await.create("admin", Utc::now());
+        let reader = users.sessions.lock().await.create("reader", Utc::now());
+
+        fs::write(&path, "; reader has gone\nadmin hash1 Admin\nfriend hash3 Uploader\n").unwrap();
+        let changes = reload(&users, &path, &Acl::default()).await.unwrap();
+        assert_eq!(changes.added, vec!["friend"]);
+        assert_eq!(changes.removed, vec!["reader"]);
+        assert_eq!(users.accounts.lock().await.get("friend").unwrap().role, UserRole::Uploader);
+        assert!(users.sessions.lock().await.find(&reader, Utc::now()).is_none());
+        assert!(users.sessions.lock().await.find(&admin, Utc::now()).is_some());
+
+        // Broken and empty files leave the users as they were
+        for contents in &["admin hash1 Admin\nfriend hash3 Uploader too many\n", "", "; nobody\n"] {
+            fs::write(&path, contents).unwrap();
+            assert!(reload(&users, &path, &Acl::default()).await.is_err(), "{:?}", contents);
+            assert_eq!(users.accounts.lock().await.len(), 2);
+        }
+        fs::remove_file(&path).unwrap();
+        assert!(reload(&users, &path, &Acl::default()).await.is_err());
+        assert!(users.accounts.lock().await.contains_key("friend"));
+    }
+
+    #[tokio::test]
+    async fn test_users_follow_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("users");
+        fs::write(&path, "admin hash1 Admin\n").unwrap();
+        let users = new_users(load_users_file(&path).unwrap(), DEFAULT_SESSION_LIFETIME);
+        start(users.clone(), path.clone(), new_access_rules(Acl::default()));
+        // Give the watcher time to start
+        tokio::time::delay_for(Duration::from_millis(200)).await;
+
+        // Saved the way editors do it, by moving a new file over the old one
+        fs::write(dir.path().join("users.new"), "admin hash1 Admin\nfriend hash2\n").unwrap();
+        fs::rename(dir.path().join("users.new"), &path).unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !users.accounts.lock().await.contains_key("friend") {
+            assert!(Instant::now() < deadline, "the users were not reloaded");
+            tokio::time::delay_for(Duration::from_millis(20)).await;
+        }
+    }
+}
@@ -1,290 +1,1612 @@
-use std::path::{Path, PathBuf};
-use std::fs;
-use serde::Serialize;
-use chrono::DateTime;
-use chrono::offset::Utc;
-
-
-const UNITS: [&str; 8] = ["B", "KB", "MB", "GB", "TB", "PB" ,"EB", "ZB"];
-
-pub struct ServePoint {
-    root_path: PathBuf,
-}
-
-#[derive(Serialize, Debug, Clone)]
-pub struct DirectoryListing {
-    pub path: String,
-    pub trail: Vec<(String, String)>,
-    pub children: Vec<DirectoryEntry>,
-}
-
-#[derive(Serialize, Debug, Clone)]
-pub struct DirectoryEntry {
-    pub name: String,
-    pub is_file: bool,
-    pub is_dir: bool,
-    pub mtime: String,
-    pub size: String,
-}
-
-fn to_uri_path(p: &Path) -> String {
-    let mut uri_path = String::from("/");
-    for part in p.iter() {
-        let s = part.to_str().unwrap();
-        uri_path.push_str(s);
-        uri_path.push('/');
-    }
-    uri_path
-}
-
-impl ServePoint {
-    pub fn new(p: PathBuf) -> Self {
-        match p.canonicalize() {
-            Ok(root_path) => {
-                if !p.exists() || !p.is_dir() {
-                    panic!("{:?} does not exist or is not a directory", p);
-                }
-
-                return Self {
-                    root_path: root_path,
-                }
-            },
-            _ => panic!("Could not canonicalise path"),
-        };
-    }
-
-    /*
-    Build a path by combining the requested path "p" with the root dir. This path is then normalised or 'canonicalised'
-    in order to resolve links or '..'. Then it's check to make sure that it's still inside of the root directory. This
-    is done by using the 'starts_with' function to ensure that the path starts with the root dir
-    */
-    fn is_subdir(&self, p: &Path) -> bool {
-        if p == Path::new("") || p == Path::new("/") {
-            return true
-        }
-
-        let complete_path: PathBuf = self.root_path.join(p);
-        if complete_path == self.root_path {
-            return false;
-        }
-
-        match complete_path.canonicalize() {
-            Ok(path) => path.starts_with(&self.root_path),
-            _ => false,
-        }
-    }
-
-    pub fn is_file(&self, p: &Path) -> bool {
-        if !self.is_subdir(p) {
-            return false
-        }
-
-        let complete_path: PathBuf = [&self.root_path, p].iter().collect();
-        match complete_path.canonicalize() {
-            Ok(path) => return path.is_file(),
-            _ => return false,
-        }
-    }
-
-    fn create_trail(&self, p: &Path) -> Vec<(String, String)> {
-        let mut trail = Vec::new();
-        if !self.is_subdir(p) { return trail; }
-
-        let complete_path: PathBuf = [&self.root_path, p].iter().collect();
-        match complete_path.canonicalize() {
-            Ok(pathbuf) => {
-                let mut new_path = pathbuf.as_path();
-                loop {
-                    if new_path.parent().is_none() { break }
-                    if !self.is_subdir(new_path) { break }
-                    // If path has no parent then break the loop
-
-                    let name = new_path.file_name().unwrap().to_str().unwrap();
-                    let path = new_path.strip_prefix(&self.root_path).unwrap().to_str().unwrap();
-                    if path == String::from("") { break }
-                    new_path = new_path.parent().unwrap();
-                    trail.push((path.to_owned(), name.to_owned()));
-                }
-            },
-            _ => (),
-        }
-
-
-        trail.reverse();
-        trail
-    }
-
-    /*
-    Given a relative path, return the full path including the root
-    */
-    // pub fn get_full_path(&self, p: &Path) -> Option<PathBuf> {
-    //     if !self.is_subdir(p) { return None; }
-    //     let complete_path: PathBuf = [&self.root_path, p].iter().collect();
-    //     if !complete_path.exists() { return None; }
-    //     Some(complete_path)
-    // }
-
-    pub fn get_directory_listing(&self, p: &Path) -> Option<DirectoryListing> {
-        if !self.is_subdir(p) { return None; }
-
-        // If the path is empty then don't bother appending it to the root
-        let complete_path: PathBuf = if p == Path::new("") || p == Path::new("/") {
-            [&self.root_path].iter().collect()
-        } else {
-            [&self.root_path, p].iter().collect()
-        };
-
-        if !complete_path.exists() { return None; }
-        if !complete_path.is_dir() { return None; }
-
-        let mut dirlisting = DirectoryListing{
-            path: to_uri_path(p),
-            trail: self.create_trail(p),
-            children: Vec::new(),
-        };
-
-        for entry in fs::read_dir(complete_path).unwrap() {
-            let entry = entry.unwrap();
-            let path: PathBuf = entry.path();
-            let meta = path.metadata().unwrap();
-            let mtime_sys = meta.accessed().unwrap();
-            let mtime_chrono: DateTime<Utc> = mtime_sys.into();
-
-            let size = if path.is_dir() {
-                "-".to_owned()
-            } else {
-                sizeof_fmt(meta.len())
-            };
-
-            let name: String = if path.is_dir() {
-                let mut temp = path.file_name().unwrap().to_str().unwrap().to_owned();
-                temp.push('/');
-                temp
-            } else {
-                path.file_name().unwrap().to_str().unwrap().to_owned() 
-            };
-
-            let dir_entry = DirectoryEntry {
-                name: name,
-                is_file: path.is_file(),
-                is_dir: path.is_dir(),
-                mtime: format!("{}", mtime_chrono.format("%d/%m/%Y %H:%M")),
-                size: size.to_owned(),
-            };
-            dirlisting.children.push(dir_entry)
-        }
-
-        // Place directories before files
-        dirlisting.children.sort_by(|a, b| b.is_dir.cmp(&a.is_dir));
-        Some(dirlisting)
-    }
-}
-
-
-fn sizeof_fmt(size: u64) -> String {
-    let mut s: f64 = size as f64;
-    for unit in &UNITS {
-        if s.abs() < 1024.0 {
-            return format!("{:.2} {}", s, unit);
-        }
-        s = s / 1024.0;
-    }
-    return format!("{:.2} {}", s, "YB");
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::path::{Path, PathBuf};
-
-    fn path_string_from_parts(parts: &[&str]) -> String {
-       parts.iter().collect::<PathBuf>().to_str().unwrap().to_owned()
-    }
-
-    #[test]
-    #[should_panic]
-    fn test_new_serve_point_panics() {
-        // Should panic
-        let pb = PathBuf::from("/var/shaaaaaaaare");
-        ServePoint::new(pb);
-    }
-
-    #[test]
-    fn test_create_serve_point() {
-        let pb = PathBuf::from(r"./src");
-        ServePoint::new(pb);
-    }
-
-    #[test]
-    fn test_is_subdir() {
-        let root = PathBuf::from("test/testfolder");
-        let sp = ServePoint::new(root);
-        assert!(sp.is_subdir(Path::new("file1.abc")));
-        assert!(sp.is_subdir(Path::new("folder1")));
-        assert!(sp.is_subdir(&Path::new("folder1").join("file3.abc")));
-        assert!(sp.is_subdir(Path::new("")));
-        assert!(sp.is_subdir(Path::new("/")));
-
-
-        let bad_folder: PathBuf = ["folder1", ".."].iter().collect();
-        assert!( ! sp.is_subdir(&bad_folder));
-    }
-
-    #[test]
-    fn test_is_file() {
-        let root = PathBuf::from("test/testfolder");
-        let sp = ServePoint::new(root);
-        assert!(sp.is_file(Path::new("file1.abc")));
-        assert!(sp.is_file(&Path::new("folder1").join(Path::new("file3.abc"))));
-
-        assert!( ! sp.is_file(Path::new("folder1")));
-        assert!( ! sp.is_file(Path::new("fdsfdf")));
-    }
-
-    #[test]
-    fn test_create_trail() {
-        let root = PathBuf::from("test/testfolder");
-        let sp = ServePoint::new(root);
-
-        let path: PathBuf = ["folder1", "mytestfiles", "testfile1.txt"].iter().collect();
-        let trail = sp.create_trail(&path);
-
-        let expected_trail = vec![
-            (path_string_from_parts(&["folder1"]), "folder1".to_owned()),
-            (path_string_from_parts(&["folder1", "mytestfiles"]), "mytestfiles".to_owned()),
-            (path_string_from_parts(&["folder1", "mytestfiles", "testfile1.txt"]), "testfile1.txt".to_owned()),
-        ];
-        
-        assert_eq!(expected_trail, trail);
-    }
-
-    // #[test]
-    // fn test_get_full_path() {
-    //     let root = PathBuf::from("test/testfolder");
-    //     let sp = ServePoint::new(root);
-
-    //     let path1 = Path::new("file1.abc");
-    //     let expected1: PathBuf = ["test", "testfolder", "file1.abc"].iter().collect();
-    //     assert_eq!(sp.get_full_path(path1).unwrap(), expected1.canonicalize().unwrap());
-
-    //     let path2 = Path::new("folder1").join("mytestfiles").join("testfile1.txt");
-    //     let expected2: PathBuf = ["test", "testfolder", "folder1", "mytestfiles", "testfile1.txt"].iter().collect();
-    //     assert_eq!(sp.get_full_path(&path2).unwrap(), expected2.canonicalize().unwrap());
-
-    //     let bad_path = Path::new("folder1").join("..").join("..");
-    //     assert_eq!(sp.get_full_path(&bad_path), None);
-
-    //     let doesnt_exist = Path::new("folder1").join("woooooooo");
-    //     assert_eq!(sp.get_full_path(&doesnt_exist), None);
-    // }
-
-    #[test]
-    fn test_get_directory_listing() {
-        let root = PathBuf::from("test/testfolder");
-        let sp = ServePoint::new(root);
-        let path1 = Path::new("");
-        let listing1 = sp.get_directory_listing(path1);
-        println!("{:?}", listing1);
-        // assert!(false);
-    }
-}
\ No newline at end of file
+use std::cmp::Ordering;
+use std::ffi::OsStr;
+use std::path::{Component, Path, PathBuf};
+use std::fs;
+use std::io;
+use std::sync::Arc;
+use globset::GlobMatcher;
+use serde::{Deserialize, Serialize};
+use chrono::DateTime;
+use chrono::offset::Utc;
+use rand::Rng;
+
+use crate::ignore::{IgnoreList, IgnoreRules};
+
+
+
+/// One directory that is served, under its name at the top level of the share
+#[derive(Clone, Debug)]
+pub struct Mount {
+    name: String,
+    root_path: PathBuf,
+    writable: bool,
+    symlinks: SymlinkPolicy,
+    /// The global ignore list, which applies to every mount
+    ignore: Arc<IgnoreList>,
+}
+
+/*
+Which symlinks in a mount are followed. A link that isn't followed is still listed, as a symlink rather than as
+whatever it points to, but nothing can be opened through it. '..' is never followed, whatever the policy.
+*/
+#[derive(Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SymlinkPolicy {
+    /// Don't follow any symlinks
+    Deny,
+    /// Follow symlinks that point somewhere inside of the mount
+    #[default]
+    WithinRoot,
+    /// Like 'WithinRoot', but also follow symlinks that point inside of one of these directories
+    AllowTargets(Vec<PathBuf>),
+    /// Follow every symlink, wherever it points
+    FollowAll,
+}
+
+/*
+The directories being served. A share made from a single directory has one mount without a name, whose contents are
+the top level of the share. Otherwise each mount shows up as a directory at the top level, and every path starts
+with the name of the mount it is in, e.g. "movies/old/film.mkv".
+*/
+#[derive(Clone)]
+pub struct ServePoint {
+    mounts: Vec<Mount>,
+}
+
+/// Why a change to the files in the share was refused
+#[derive(Debug)]
+pub enum FsOpError {
+    /// The path doesn't exist, or isn't inside of the root
+    NotFound,
+    AlreadyExists,
+    NotEmpty,
+    /// The operation doesn't make sense, e.g. moving a directory inside of itself
+    Invalid,
+    /// The path is in a mount that can't be changed
+    ReadOnly,
+    Io(io::Error),
+}
+
+impl From<io::Error> for FsOpError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            // Something appeared at the destination after it was checked
+            io::ErrorKind::AlreadyExists => FsOpError::AlreadyExists,
+            _ => FsOpError::Io(e),
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct DirectoryListing {
+    pub path: String,
+    pub trail: Vec<(String, String)>,
+    pub children: Vec<DirectoryEntry>,
+    /// The number of entries that matched the filter, across all of the pages
+    pub total: usize,
+    pub page: usize,
+    pub pages: usize,
+    pub per_page: usize,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SortKey {
+    Name,
+    Size,
+    Mtime,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    Asc,
+    Desc,
+}
+
+/// How to sort, filter and split up the entries of a directory listing
+pub struct ListingOptions {
+    pub sort: SortKey,
+    pub order: SortOrder,
+    /// Only keep entries whose name matches
+    pub filter: Option<GlobMatcher>,
+    /// Pages start at 1
+    pub page: usize,
+    pub per_page: usize,
+}
+
+impl Default for ListingOptions {
+    fn default() -> Self {
+        ListingOptions {
+            sort: SortKey::Name,
+            order: SortOrder::Asc,
+            filter: None,
+            page: 1,
+            per_page: DEFAULT_PER_PAGE,
+        }
+    }
+}
+
+pub const DEFAULT_PER_PAGE: usize = 200;
+pub const MAX_PER_PAGE: usize = 1000;
+
+impl DirectoryListing {
+    /// Filter and sort the children, then keep only the requested page. Directories always come first.
+    pub fn apply(&mut self, options: &ListingOptions) {
+        if let Some(filter) = &options.filter {
+            self.children.retain(|e| filter.is_match(e.name.trim_end_matches('/')));
+        }
+
+        self.children.sort_by(|a, b| {
+            let by_key = match options.sort {
+                SortKey::Name => Ordering::Equal,
+                SortKey::Size => a.size.cmp(&b.size),
+                SortKey::Mtime => a.modified.cmp(&b.modified),
+            }.then_with(|| natural_cmp(&a.name, &b.name));
+
+            let by_key = match options.order {
+                SortOrder::Asc => by_key,
+                SortOrder::Desc => by_key.reverse(),
+            };
+            b.is_dir.cmp(&a.is_dir).then(by_key)
+        });
+
+        let per_page = options.per_page.clamp(1, MAX_PER_PAGE);
+        self.total = self.children.len();
+        self.per_page = per_page;
+        self.pages = std::cmp::max(1, self.total.div_ceil(per_page));
+        self.page = std::cmp::max(1, options.page);
+        self.children = self.children.drain(..)
+            .skip((self.page - 1).saturating_mul(per_page))
+            .take(per_page)
+            .collect();
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum EntryType {
+    File,
+    Directory,
+    /// A symlink that isn't followed, because it's broken or the symlink policy doesn't allow it
+    Symlink,
+    Other,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct DirectoryEntry {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub entry_type: EntryType,
+    pub mime: String,
+    pub is_file: bool,
+    pub is_dir: bool,
+    pub is_symlink: bool,
+    pub readonly: bool,
+    /// The unix permission bits, not available on other platforms
+    pub mode: Option<u32>,
+    /// The size in bytes. Formatting it for display is left to the templates. For directories this is the size of
+    /// everything in them when the index is enabled, and 0 otherwise.
+    pub size: u64,
+    pub modified: Option<DateTime<Utc>>,
+    /// Not every filesystem records when a file was created
+    pub created: Option<DateTime<Utc>>,
+}
+
+fn to_uri_path(p: &Path) -> String {
+    let mut uri_path = String::from("/");
+    for part in p.iter() {
+        let s = part.to_str().unwrap();
+        uri_path.push_str(s);
+        uri_path.push('/');
+    }
+    uri_path
+}
+
+impl DirectoryEntry {
+    /// Describe the entry at 'path'. Directory names end with a '/'.
+    pub fn from_path(path: &Path) -> io::Result<DirectoryEntry> {
+        let link_meta = path.symlink_metadata()?;
+        // Describe what a symlink points to, or the link itself if it's broken
+        match path.metadata() {
+            Ok(meta) => Self::describe(path, &link_meta, &meta),
+            Err(_) => Self::describe(path, &link_meta, &link_meta),
+        }
+    }
+
+    /// Describe the entry at 'path' without following it if it's a symlink
+    fn from_link(path: &Path) -> io::Result<DirectoryEntry> {
+        let link_meta = path.symlink_metadata()?;
+        Self::describe(path, &link_meta, &link_meta)
+    }
+
+    fn describe(path: &Path, link_meta: &fs::Metadata, meta: &fs::Metadata) -> io::Result<DirectoryEntry> {
+        let mut name = path.file_name()
+            .and_then(|n| n.to_str())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "file name is not valid unicode"))?
+            .to_owned();
+        if meta.is_dir() {
+            name.push('/');
+        }
+
+        let entry_type = if meta.is_dir() {
+            EntryType::Directory
+        } else if meta.is_file() {
+            EntryType::File
+        } else if meta.file_type().is_symlink() {
+            EntryType::Symlink
+        } else {
+            EntryType::Other
+        };
+
+        Ok(DirectoryEntry {
+            name: name,
+            entry_type: entry_type,
+            mime: mime_type(path, entry_type),
+            is_file: meta.is_file(),
+            is_dir: meta.is_dir(),
+            is_symlink: link_meta.file_type().is_symlink(),
+            readonly: meta.permissions().readonly(),
+            mode: permission_bits(meta),
+            size: if meta.is_dir() { 0 } else { meta.len() },
+            modified: meta.modified().ok().map(DateTime::<Utc>::from),
+            created: meta.created().ok().map(DateTime::<Utc>::from),
+        })
+    }
+}
+
+impl Mount {
+    /// Check that 'p' is a directory, and resolve it so that paths can be compared against it
+    pub fn new(name: String, p: PathBuf, writable: bool) -> Result<Self, String> {
+        let root_path = p.canonicalize().map_err(|e| format!("Could not canonicalise {:?}: {}", p, e))?;
+        if !root_path.is_dir() {
+            return Err(format!("{:?} is not a directory", p));
+        }
+        Ok(Mount { name, root_path, writable, symlinks: SymlinkPolicy::default(), ignore: Arc::default() })
+    }
+
+    /// Set which symlinks are followed. The directories a policy allows have to exist.
+    pub fn with_symlinks(mut self, policy: SymlinkPolicy) -> Result<Self, String> {
+        self.symlinks = match policy {
+            SymlinkPolicy::AllowTargets(targets) => SymlinkPolicy::AllowTargets(targets.iter()
+                .map(|t| t.canonicalize().map_err(|e| format!("Could not canonicalise symlink target {:?}: {}", t, e)))
+                .collect::<Result<_, _>>()?),
+            policy => policy,
+        };
+        Ok(self)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn root_path(&self) -> &Path {
+        &self.root_path
+    }
+
+    /// Where 'p' would be inside of the mount, without resolving anything. None if it tries to climb out with '..'.
+    fn full_path(&self, p: &Path) -> Option<PathBuf> {
+        let mut path = self.root_path.clone();
+        for part in p.components() {
+            match part {
+                Component::Normal(name) => path.push(name),
+                Component::RootDir | Component::CurDir => {},
+                Component::ParentDir | Component::Prefix(_) => return None,
+            }
+        }
+        Some(path)
+    }
+
+    /*
+    Whether the symlink policy lets 'path', somewhere inside of the mount, be followed to 'canonical'. A path without
+    any symlinks along the way is already canonical, so that is all 'Deny' has to check.
+    */
+    fn allows(&self, path: &Path, canonical: &Path) -> bool {
+        match &self.symlinks {
+            SymlinkPolicy::Deny => path == canonical,
+            SymlinkPolicy::WithinRoot => canonical.starts_with(&self.root_path),
+            SymlinkPolicy::AllowTargets(targets) => {
+                canonical.starts_with(&self.root_path) || targets.iter().any(|t| canonical.starts_with(t))
+            },
+            SymlinkPolicy::FollowAll => true,
+        }
+    }
+
+    /// Resolve 'path', somewhere inside of the mount, if it exists and the symlink policy allows it
+    fn follow(&self, path: &Path) -> Option<PathBuf> {
+        let canonical = path.canonicalize().ok()?;
+        if self.allows(path, &canonical) { Some(canonical) } else { None }
+    }
+
+    /// The ignore rules inside of the directory 'p', which doesn't have to exist
+    fn ignore_rules(&self, p: &Path) -> IgnoreRules {
+        let mut path = self.root_path.clone();
+        let mut rules = IgnoreRules::new(self.ignore.clone(), &path);
+        for part in p.components() {
+            if let Component::Normal(name) = part {
+                path.push(name);
+                rules = rules.enter(&name.to_string_lossy(), &path);
+            }
+        }
+        rules
+    }
+
+    /*
+    Combine the requested path "p" with the root dir, then resolve any links along the way. Whether the links could be
+    followed is up to the symlink policy, and a symlink loop fails to resolve at all. Anything the ignore lists leave
+    out is treated as if it wasn't there. The root itself isn't a subdirectory, but "" and "/" are accepted as meaning
+    the top of the mount.
+    */
+    fn is_subdir(&self, p: &Path) -> bool {
+        if p == Path::new("") || p == Path::new("/") {
+            return true
+        }
+
+        let canonical = match self.full_path(p) {
+            Some(path) if path != self.root_path => self.follow(&path),
+            _ => None,
+        };
+        match (canonical, p.file_name()) {
+            (Some(canonical), Some(name)) => {
+                let parent = p.parent().unwrap_or_else(|| Path::new(""));
+                !self.ignore_rules(parent).is_ignored(&name.to_string_lossy(), canonical.is_dir())
+            },
+            _ => false,
+        }
+    }
+
+    fn is_file(&self, p: &Path) -> bool {
+        if !self.is_subdir(p) {
+            return false
+        }
+        self.full_path(p).and_then(|path| self.follow(&path)).is_some_and(|path| path.is_file())
+    }
+
+    /*
+    Given the relative path of a file that is about to be created, make sure that its parent is a directory inside
+    of the root and return the full path to write to. The last part of the path has to be a plain file name so that
+    it can't be used to climb back out of the parent directory, and it can't be a symlink that isn't followed.
+    */
+    fn get_new_file_path(&self, p: &Path) -> Option<PathBuf> {
+        let file_name = match p.components().next_back() {
+            Some(Component::Normal(name)) => name,
+            _ => return None,
+        };
+
+        let parent = p.parent().unwrap_or_else(|| Path::new(""));
+        let parent_path = self.get_directory_path(parent)?;
+
+        let complete_path = parent_path.join(file_name);
+        if complete_path.is_dir() { return None; }
+        let is_symlink = complete_path.symlink_metadata().is_ok_and(|meta| meta.file_type().is_symlink());
+        if is_symlink && self.follow(&complete_path).is_none() { return None; }
+        Some(complete_path)
+    }
+
+    /*
+    Like 'get_new_file_path', but for an entry that already exists. The entry itself isn't resolved, so that a
+    symlink is renamed or deleted rather than whatever it points to.
+    */
+    fn get_entry_path(&self, p: &Path) -> Option<PathBuf> {
+        let file_name = match p.components().next_back() {
+            Some(Component::Normal(name)) => name,
+            _ => return None,
+        };
+
+        let parent = p.parent().unwrap_or_else(|| Path::new(""));
+        let parent_path = self.get_directory_path(parent)?;
+        let complete_path = parent_path.join(file_name);
+        complete_path.symlink_metadata().ok()?;
+        Some(complete_path)
+    }
+
+    /// The directories leading to 'p', as it was asked for rather than wherever any symlinks lead
+    fn create_trail(&self, p: &Path) -> Vec<(String, String)> {
+        let mut trail = Vec::new();
+        if !self.is_subdir(p) { return trail; }
+
+        let mut path = PathBuf::new();
+        for part in p.components() {
+            if let Component::Normal(name) = part {
+                path.push(name);
+                trail.push((path.to_string_lossy().into_owned(), name.to_string_lossy().into_owned()));
+            }
+        }
+        trail
+    }
+
+    /// The canonical path of the directory at 'p', which may be the root of the mount itself
+    fn get_directory_path(&self, p: &Path) -> Option<PathBuf> {
+        if !self.is_subdir(p) { return None; }
+        let path = self.follow(&self.full_path(p)?)?;
+        if path.is_dir() { Some(path) } else { None }
+    }
+
+    /// The canonical path of the file at 'p'
+    fn get_file_path(&self, p: &Path) -> Option<PathBuf> {
+        if !self.is_subdir(p) { return None; }
+        let path = self.follow(&self.full_path(p)?)?;
+        if path.is_file() { Some(path) } else { None }
+    }
+
+    /*
+    Like get_directory_listing, but 'cached' is asked for the children of the directory first. It is given the
+    canonical path of the directory, and the children are only read from the disk if it returns None. Ignored entries
+    are left out, and symlinks that aren't followed are described as links, whatever they point to.
+    */
+    fn get_directory_listing_with<F>(&self, p: &Path, cached: F) -> Option<DirectoryListing>
+    where F: FnOnce(&Path) -> Option<Vec<DirectoryEntry>> {
+        let canonical_path = self.get_directory_path(p)?;
+
+        let mut dirlisting = DirectoryListing{
+            path: to_uri_path(p),
+            trail: self.create_trail(p),
+            children: Vec::new(),
+            total: 0,
+            page: 1,
+            pages: 1,
+            per_page: 0,
+        };
+
+        dirlisting.children = match cached(&canonical_path) {
+            Some(children) => children,
+            None => read_children(&canonical_path).ok()?,
+        };
+        let rules = self.ignore_rules(p);
+        dirlisting.children.retain(|child| !rules.is_ignored(child.name.trim_end_matches('/'), child.is_dir));
+        for child in dirlisting.children.iter_mut().filter(|child| child.is_symlink) {
+            let link = canonical_path.join(child.name.trim_end_matches('/'));
+            if self.follow(&link).is_none() {
+                if let Ok(entry) = DirectoryEntry::from_link(&link) {
+                    *child = entry;
+                }
+            }
+        }
+
+        // Place directories before files
+        dirlisting.children.sort_by(|a, b| b.is_dir.cmp(&a.is_dir).then_with(|| natural_cmp(&a.name, &b.name)));
+        dirlisting.total = dirlisting.children.len();
+        dirlisting.per_page = dirlisting.total;
+        Some(dirlisting)
+    }
+}
+
+/// A mount name is a single part of a path, e.g. "movies", not "." or "a/b"
+fn is_valid_mount_name(name: &str) -> bool {
+    let mut components = Path::new(name).components();
+    !name.contains('/') && matches!((components.next(), components.next()), (Some(Component::Normal(_)), None))
+}
+
+impl ServePoint {
+    /// Serve a single directory, which can be changed
+    pub fn new(p: PathBuf) -> Self {
+        match Mount::new(String::new(), p, true) {
+            Ok(mount) => ServePoint { mounts: vec![mount] },
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    /// Serve several directories, each under its own name. Names have to be unique and can't contain a '/'.
+    pub fn with_mounts(mounts: Vec<Mount>) -> Result<Self, String> {
+        if mounts.is_empty() {
+            return Err(String::from("There has to be at least one mount"));
+        }
+        for (i, mount) in mounts.iter().enumerate() {
+            if !is_valid_mount_name(&mount.name) {
+                return Err(format!("'{}' can't be used as the name of a mount", mount.name));
+            }
+            if mounts[..i].iter().any(|other| other.name == mount.name) {
+                return Err(format!("There is more than one mount called '{}'", mount.name));
+            }
+        }
+        Ok(ServePoint { mounts })
+    }
+
+    /// Set which symlinks every mount follows
+    pub fn with_symlinks(self, policy: SymlinkPolicy) -> Result<Self, String> {
+        let mounts = self.mounts.into_iter()
+            .map(|mount| mount.with_symlinks(policy.clone()))
+            .collect::<Result<Vec<Mount>, String>>()?;
+        Ok(ServePoint { mounts })
+    }
+
+    /// Leave out whatever matches 'list', in every mount
+    pub fn with_ignore_list(mut self, list: IgnoreList) -> Self {
+        let list = Arc::new(list);
+        for mount in self.mounts.iter_mut() {
+            mount.ignore = list.clone();
+        }
+        self
+    }
+
+    pub fn mounts(&self) -> &[Mount] {
+        &self.mounts
+    }
+
+    /// The canonical directories of all of the mounts
+    pub fn roots(&self) -> Vec<PathBuf> {
+        self.mounts.iter().map(|mount| mount.root_path.clone()).collect()
+    }
+
+    /// Whether the mounts have names, i.e. the top level of the share is made up of them
+    fn is_named(&self) -> bool {
+        !self.mounts[0].name.is_empty()
+    }
+
+    /// Whether 'p' is the top level of the share
+    fn is_top(p: &Path) -> bool {
+        p.components().all(|c| matches!(c, Component::RootDir | Component::CurDir))
+    }
+
+    /// The mount that 'p' is in, and the path inside of that mount
+    fn resolve(&self, p: &Path) -> Option<(&Mount, PathBuf)> {
+        if !self.is_named() {
+            return Some((&self.mounts[0], p.to_owned()));
+        }
+        let mut components = p.components().skip_while(|c| matches!(c, Component::RootDir | Component::CurDir));
+        let name = match components.next()? {
+            Component::Normal(name) => name,
+            _ => return None,
+        };
+        let mount = self.mounts.iter().find(|mount| OsStr::new(&mount.name) == name)?;
+        Some((mount, components.collect()))
+    }
+
+    /// Like 'resolve', for a path that is about to be changed
+    fn resolve_writable(&self, p: &Path) -> Result<(&Mount, PathBuf), FsOpError> {
+        match self.resolve(p) {
+            Some((mount, _)) if !mount.writable => Err(FsOpError::ReadOnly),
+            Some(resolved) => Ok(resolved),
+            None => Err(FsOpError::NotFound),
+        }
+    }
+
+    /// Whether the entry at 'p' is in a mount that can be changed
+    pub fn is_writable(&self, p: &Path) -> bool {
+        self.resolve(p).is_some_and(|(mount, _)| mount.writable)
+    }
+
+    /// Where the entry at 'p' would be on the disk, without following any symlinks. None for the top level of a share
+    /// with named mounts, which isn't anywhere.
+    pub fn real_path(&self, p: &Path) -> Option<PathBuf> {
+        if self.is_named() && Self::is_top(p) {
+            return None;
+        }
+        let (mount, relative) = self.resolve(p)?;
+        Some(mount.root_path.join(relative))
+    }
+
+    /// The mount that an absolute path on the disk is in. Mounts can be inside of each other, so the deepest one wins.
+    fn mount_of(&self, real: &Path) -> Option<&Mount> {
+        self.mounts.iter()
+            .filter(|mount| real.starts_with(&mount.root_path))
+            .max_by_key(|mount| mount.root_path.components().count())
+    }
+
+    /// The opposite of 'real_path', where an absolute path on the disk is in the share
+    pub fn share_path_of(&self, real: &Path) -> Option<PathBuf> {
+        self.mount_of(real)
+            .map(|mount| Path::new(&mount.name).join(real.strip_prefix(&mount.root_path).unwrap()))
+    }
+
+    /// Whether the entry at 'p' is left out by the ignore lists. The mounts themselves never are.
+    pub fn is_ignored(&self, p: &Path) -> bool {
+        let is_dir = self.real_path(p).is_some_and(|path| path.is_dir());
+        self.ignores(p, is_dir)
+    }
+
+    /// Whether the ignore lists leave out an entry at 'p', which is or would be a directory if 'is_dir'. Nothing can
+    /// be made under a name like that, as it would be hidden as soon as it was made.
+    pub fn ignores(&self, p: &Path, is_dir: bool) -> bool {
+        let (mount, relative) = match self.resolve(p) {
+            Some(resolved) => resolved,
+            None => return false,
+        };
+        let name = match relative.file_name() {
+            Some(name) => name.to_string_lossy(),
+            None => return false,
+        };
+        mount.ignore_rules(relative.parent().unwrap_or_else(|| Path::new(""))).is_ignored(&name, is_dir)
+    }
+
+    /*
+    The ignore rules inside of 'dir', an absolute path on the disk. A directory outside of every mount, which was
+    reached through a symlink, only gets the global list and its own .ffsignore.
+    */
+    pub fn ignore_rules_at(&self, dir: &Path) -> IgnoreRules {
+        match self.mount_of(dir) {
+            Some(mount) => mount.ignore_rules(dir.strip_prefix(&mount.root_path).unwrap()),
+            None => IgnoreRules::new(self.mounts[0].ignore.clone(), dir),
+        }
+    }
+
+    /// Where the symlink at 'real', an absolute path on the disk, leads to if the mount it is in follows it
+    pub fn follow_link(&self, real: &Path) -> Option<PathBuf> {
+        self.mount_of(real)?.follow(real)
+    }
+
+    pub fn is_subdir(&self, p: &Path) -> bool {
+        if p == Path::new("") || p == Path::new("/") {
+            return true
+        }
+        match self.resolve(p) {
+            Some((mount, relative)) => mount.is_subdir(&relative),
+            None => false,
+        }
+    }
+
+    pub fn is_file(&self, p: &Path) -> bool {
+        match self.resolve(p) {
+            Some((mount, relative)) => mount.is_file(&relative),
+            None => false,
+        }
+    }
+
+    /*
+    Open the file at 'p' for reading, along with its canonical path. What is opened is the canonical path that was
+    checked, which is then checked again in case a symlink was swapped in along the way.
+    */
+    pub fn open_file(&self, p: &Path) -> io::Result<(fs::File, PathBuf)> {
+        let not_found = || io::Error::new(io::ErrorKind::NotFound, format!("{:?} is not a file in the share", p));
+        let (mount, relative) = self.resolve(p).ok_or_else(not_found)?;
+        let path = mount.get_file_path(&relative).ok_or_else(not_found)?;
+        let file = open_checked(&path)?;
+        Ok((file, path))
+    }
+
+    /// See Mount::get_new_file_path. Nothing can be created in a mount that is read only, or under a name that the
+    /// ignore lists leave out.
+    pub fn get_new_file_path(&self, p: &Path) -> Option<PathBuf> {
+        let (mount, relative) = self.resolve_writable(p).ok()?;
+        if self.ignores(p, false) {
+            return None;
+        }
+        mount.get_new_file_path(&relative)
+    }
+
+    /// See Mount::get_entry_path. The mounts themselves aren't entries, so they can't be renamed or deleted.
+    pub fn get_entry_path(&self, p: &Path) -> Option<PathBuf> {
+        let (mount, relative) = self.resolve(p)?;
+        mount.get_entry_path(&relative)
+    }
+
+    pub fn make_dir(&self, p: &Path) -> Result<PathBuf, FsOpError> {
+        self.resolve_writable(p)?;
+        if self.ignores(p, true) {
+            return Err(FsOpError::Invalid);
+        }
+        if self.get_entry_path(p).is_some() {
+            return Err(FsOpError::AlreadyExists);
+        }
+        let path = self.get_new_file_path(p).ok_or(FsOpError::NotFound)?;
+        fs::create_dir(&path)?;
+        Ok(path)
+    }
+
+    /// Rename or move an entry. Nothing is ever replaced, the destination must not exist yet. Entries can't be moved
+    /// from one mount to another, which are likely to be on different disks.
+    pub fn rename(&self, from: &Path, to: &Path) -> Result<PathBuf, FsOpError> {
+        let (from_mount, _) = self.resolve_writable(from)?;
+        let (to_mount, _) = self.resolve_writable(to)?;
+        if from_mount.name != to_mount.name {
+            return Err(FsOpError::Invalid);
+        }
+        let source = self.get_entry_path(from).ok_or(FsOpError::NotFound)?;
+        if self.ignores(to, source.symlink_metadata()?.is_dir()) {
+            return Err(FsOpError::Invalid);
+        }
+        if self.get_entry_path(to).is_some() {
+            return Err(FsOpError::AlreadyExists);
+        }
+        let destination = self.get_new_file_path(to).ok_or(FsOpError::NotFound)?;
+        if destination.starts_with(&source) {
+            return Err(FsOpError::Invalid);
+        }
+        rename_no_replace(&source, &destination)?;
+        Ok(destination)
+    }
+
+    /// Check that an entry can be deleted and return its full path. Directories have to be empty unless
+    /// 'recursive' is set.
+    pub fn get_path_to_delete(&self, p: &Path, recursive: bool) -> Result<PathBuf, FsOpError> {
+        self.resolve_writable(p)?;
+        let path = self.get_entry_path(p).ok_or(FsOpError::NotFound)?;
+        let meta = path.symlink_metadata()?;
+        if meta.is_dir() && !recursive && fs::read_dir(&path)?.next().is_some() {
+            return Err(FsOpError::NotEmpty);
+        }
+        Ok(path)
+    }
+
+    /// Create any missing directories leading up to 'p', so that something can be put back where it came from. Returns
+    /// the directories that were made, from the top down.
+    pub fn make_parent_dirs(&self, p: &Path) -> Result<Vec<PathBuf>, FsOpError> {
+        self.resolve_writable(p)?;
+        let mut prefix = PathBuf::new();
+        let mut created = Vec::new();
+        let parent = p.parent().unwrap_or_else(|| Path::new(""));
+        for part in parent.components() {
+            prefix.push(part);
+            // Which also covers the mounts, that aren't entries of their own
+            if self.get_directory_path(&prefix).is_some() {
+                continue;
+            }
+            match self.get_entry_path(&prefix) {
+                Some(_) => return Err(FsOpError::AlreadyExists),
+                None => created.push(self.make_dir(&prefix)?),
+            }
+        }
+        Ok(created)
+    }
+
+    /// The canonical path of the directory at 'p', which may be the root of a mount. None for the top level of a share
+    /// with named mounts.
+    pub fn get_directory_path(&self, p: &Path) -> Option<PathBuf> {
+        if self.is_named() && Self::is_top(p) {
+            return None;
+        }
+        let (mount, relative) = self.resolve(p)?;
+        mount.get_directory_path(&relative)
+    }
+
+    pub fn get_directory_listing(&self, p: &Path) -> Option<DirectoryListing> {
+        self.get_directory_listing_with(p, |_| None)
+    }
+
+    /*
+    Like get_directory_listing, but 'cached' is asked for the children of the directory first. It is given the
+    canonical path of the directory, and the children are only read from the disk if it returns None. The top level
+    of a share with named mounts lists the mounts, as if they were directories.
+    */
+    pub fn get_directory_listing_with<F>(&self, p: &Path, cached: F) -> Option<DirectoryListing>
+    where F: FnOnce(&Path) -> Option<Vec<DirectoryEntry>> {
+        if !self.is_named() {
+            return self.mounts[0].get_directory_listing_with(p, cached);
+        }
+        if Self::is_top(p) {
+            return Some(self.list_mounts());
+        }
+
+        let (mount, relative) = self.resolve(p)?;
+        let mut listing = mount.get_directory_listing_with(&relative, cached)?;
+        listing.path = to_uri_path(p);
+        for (path, _) in listing.trail.iter_mut() {
+            *path = format!("{}/{}", mount.name, path);
+        }
+        listing.trail.insert(0, (mount.name.clone(), mount.name.clone()));
+        Some(listing)
+    }
+
+    fn list_mounts(&self) -> DirectoryListing {
+        let mut children: Vec<DirectoryEntry> = self.mounts.iter()
+            .filter_map(|mount| {
+                let mut entry = DirectoryEntry::from_path(&mount.root_path).ok()?;
+                entry.name = format!("{}/", mount.name);
+                entry.readonly = entry.readonly || !mount.writable;
+                Some(entry)
+            })
+            .collect();
+        children.sort_by(|a, b| natural_cmp(&a.name, &b.name));
+        DirectoryListing {
+            path: String::from("/"),
+            trail: Vec::new(),
+            total: children.len(),
+            page: 1,
+            pages: 1,
+            per_page: children.len(),
+            children,
+        }
+    }
+}
+
+/*
+Compare two names the way a person would, so that "Episode 2" comes before "Episode 10". Runs of digits are compared
+by their value and everything else is compared without case. Names that only differ in case or in leading zeros
+still get a consistent order.
+*/
+pub fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let (a_chunks, b_chunks) = (natural_chunks(a), natural_chunks(b));
+    for (x, y) in a_chunks.iter().zip(b_chunks.iter()) {
+        let is_num = |s: &str| s.starts_with(|c: char| c.is_ascii_digit());
+        let ord = if is_num(x) && is_num(y) {
+            let (x, y) = (x.trim_start_matches('0'), y.trim_start_matches('0'));
+            x.len().cmp(&y.len()).then_with(|| x.cmp(y))
+        } else {
+            x.to_lowercase().cmp(&y.to_lowercase())
+        };
+        if ord != Ordering::Equal {
+            return ord;
+        }
+    }
+    a_chunks.len().cmp(&b_chunks.len()).then_with(|| a.cmp(b))
+}
+
+/// Split a string into runs of digits and runs of everything else
+fn natural_chunks(s: &str) -> Vec<&str> {
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    let mut prev_digit = None;
+    for (i, c) in s.char_indices() {
+        let digit = c.is_ascii_digit();
+        if prev_digit.is_some_and(|prev| prev != digit) {
+            chunks.push(&s[start..i]);
+            start = i;
+        }
+        prev_digit = Some(digit);
+    }
+    if start < s.len() {
+        chunks.push(&s[start..]);
+    }
+    chunks
+}
+
+/// Guess the MIME type of an entry from its extension. Directories get the type used by the freedesktop spec.
+fn mime_type(path: &Path, entry_type: EntryType) -> String {
+    match entry_type {
+        EntryType::Directory => String::from("inode/directory"),
+        EntryType::Symlink => String::from("inode/symlink"),
+        _ => mime_guess::from_path(path).first_or_octet_stream().to_string(),
+    }
+}
+
+/// Open 'path', which was canonical when it was checked, as long as it still is and still leads to the file that was opened
+pub fn open_checked(path: &Path) -> io::Result<fs::File> {
+    let file = fs::File::open(path)?;
+    if path.canonicalize()? != path || !same_file(&file.metadata()?, &fs::metadata(path)?) {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, format!("{:?} changed while it was being opened", path)));
+    }
+    Ok(file)
+}
+
+#[cfg(unix)]
+fn same_file(a: &fs::Metadata, b: &fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    a.dev() == b.dev() && a.ino() == b.ino()
+}
+
+/*
+Without inode numbers this can only compare the length and the time the file was last changed, so a link that is
+swapped in to a copy of the same file made at the same time isn't noticed. The file is still the canonical path that
+was checked, that part of the check doesn't depend on the platform.
+*/
+#[cfg(not(unix))]
+fn same_file(a: &fs::Metadata, b: &fs::Metadata) -> bool {
+    a.len() == b.len() && a.modified().ok() == b.modified().ok()
+}
+
+/// Describe every entry in a directory, skipping any that can't be read
+pub fn read_children(dir: &Path) -> io::Result<Vec<DirectoryEntry>> {
+    let mut children = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        match DirectoryEntry::from_path(&path) {
+            Ok(dir_entry) => children.push(dir_entry),
+            Err(e) => warn!("Could not read {:?}: {}", path, e),
+        }
+    }
+    Ok(children)
+}
+
+#[cfg(unix)]
+fn permission_bits(meta: &fs::Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    Some(meta.permissions().mode() & 0o7777)
+}
+
+#[cfg(not(unix))]
+fn permission_bits(_meta: &fs::Metadata) -> Option<u32> {
+    None
+}
+
+/// A random identifier, for naming things the server stores on disk
+pub fn generate_id() -> String {
+    let mut rng = rand::thread_rng();
+    (0..4).map(|_| format!("{:08x}", rng.gen::<u32>())).collect()
+}
+
+/*
+Move a file or directory. When the source and destination are on different disks this falls back to copying the
+entry and then removing the original.
+*/
+pub fn move_entry(from: &Path, to: &Path) -> io::Result<()> {
+    let rename_err = match fs::rename(from, to) {
+        Ok(()) => return Ok(()),
+        Err(e) => e,
+    };
+
+    if from.symlink_metadata().is_err() {
+        return Err(rename_err);
+    }
+
+    debug!("Could not rename {:?} to {:?} ({}), copying it instead", from, to, rename_err);
+    if let Err(e) = copy_entry(from, to) {
+        // Don't leave a partial copy behind
+        let _ = if to.is_dir() { fs::remove_dir_all(to) } else { fs::remove_file(to) };
+        return Err(e);
+    }
+
+    if from.symlink_metadata()?.is_dir() {
+        fs::remove_dir_all(from)
+    } else {
+        fs::remove_file(from)
+    }
+}
+
+/*
+Move a file or directory at 'from' to 'to' on the same disk without ever replacing what is already at 'to', failing
+with AlreadyExists instead. A file is hard linked to its new name, which only works while the name is free, and then
+unlinked from the old one. Where the disk can't do hard links the name is claimed with an empty file first, which the
+rename then replaces.
+*/
+pub fn rename_no_replace(from: &Path, to: &Path) -> io::Result<()> {
+    if from.symlink_metadata()?.is_dir() {
+        return rename_dir_no_replace(from, to);
+    }
+    match fs::hard_link(from, to) {
+        Ok(()) => return fs::remove_file(from),
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => return Err(e),
+        Err(e) => debug!("Could not link {:?} to {:?} ({}), claiming the name instead", from, to, e),
+    }
+    fs::OpenOptions::new().write(true).create_new(true).open(to)?;
+    if let Err(e) = fs::rename(from, to) {
+        let _ = fs::remove_file(to);
+        return Err(e);
+    }
+    Ok(())
+}
+
+/// A directory can't be linked, so its new name is claimed by making an empty directory there, which the rename then
+/// replaces. Anything put into it in the meantime makes the rename fail rather than be lost.
+#[cfg(unix)]
+fn rename_dir_no_replace(from: &Path, to: &Path) -> io::Result<()> {
+    fs::create_dir(to)?;
+    if let Err(e) = fs::rename(from, to) {
+        let _ = fs::remove_dir(to);
+        return Err(e);
+    }
+    Ok(())
+}
+
+/// Windows never moves a directory over an existing entry, so the name doesn't need to be claimed first
+#[cfg(not(unix))]
+fn rename_dir_no_replace(from: &Path, to: &Path) -> io::Result<()> {
+    if to.symlink_metadata().is_ok() {
+        return Err(io::Error::from(io::ErrorKind::AlreadyExists));
+    }
+    fs::rename(from, to)
+}
+
+fn copy_entry(from: &Path, to: &Path) -> io::Result<()> {
+    let meta = from.symlink_metadata()?;
+    if meta.is_dir() {
+        fs::create_dir(to)?;
+        for entry in fs::read_dir(from)? {
+            let entry = entry?;
+            copy_entry(&entry.path(), &to.join(entry.file_name()))?;
+        }
+    } else if meta.file_type().is_symlink() {
+        copy_symlink(&fs::read_link(from)?, to)?;
+    } else {
+        fs::copy(from, to)?;
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn copy_symlink(target: &Path, to: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, to)
+}
+
+#[cfg(not(unix))]
+fn copy_symlink(target: &Path, to: &Path) -> io::Result<()> {
+    fs::copy(target, to).map(|_| ())
+}
+
+/*
+A file that is written next to its final destination and only moved into place once it's complete. If it's
+dropped before 'persist' is called, e.g. because the upload failed or the client went away, the file is removed
+so that half written files are never left behind.
+*/
+pub struct TempFile {
+    path: PathBuf,
+    persisted: bool,
+}
+
+impl TempFile {
+    pub fn new_for(target: &Path) -> Self {
+        let name = target.file_name().and_then(|n| n.to_str()).unwrap_or("file");
+        let suffix: u32 = rand::thread_rng().gen();
+        let path = target.with_file_name(format!(".{}.{:08x}.upload", name, suffix));
+        TempFile { path, persisted: false }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Atomically move the temp file to 'target', replacing anything already there.
+    pub fn persist(mut self, target: &Path) -> io::Result<()> {
+        fs::rename(&self.path, target)?;
+        self.persisted = true;
+        Ok(())
+    }
+
+    /// Move the temp file to 'target', failing with AlreadyExists rather than replace anything already there.
+    pub fn persist_new(mut self, target: &Path) -> io::Result<()> {
+        rename_no_replace(&self.path, target)?;
+        self.persisted = true;
+        Ok(())
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if !self.persisted && self.path.exists() {
+            if let Err(e) = fs::remove_file(&self.path) {
+                warn!("Could not remove temp file {:?}: {}", self.path, e);
+            }
+        }
+    }
+}
+
+/// Replace the contents of 'path' in one go, so that a crash half way through never leaves a truncated file.
+pub fn write_atomic(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let temp = TempFile::new_for(path);
+    fs::write(temp.path(), contents)?;
+    temp.persist(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::{Path, PathBuf};
+
+    fn path_string_from_parts(parts: &[&str]) -> String {
+       parts.iter().collect::<PathBuf>().to_str().unwrap().to_owned()
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_new_serve_point_panics() {
+        // Should panic
+        let pb = PathBuf::from("/var/shaaaaaaaare");
+        ServePoint::new(pb);
+    }
+
+    #[test]
+    fn test_create_serve_point() {
+        let pb = PathBuf::from(r"./src");
+        ServePoint::new(pb);
+    }
+
+    #[test]
+    fn test_is_subdir() {
+        let root = PathBuf::from("test/testfolder");
+        let sp = ServePoint::new(root);
+        assert!(sp.is_subdir(Path::new("file1.abc")));
+        assert!(sp.is_subdir(Path::new("folder1")));
+        assert!(sp.is_subdir(&Path::new("folder1").join("file3.abc")));
+        assert!(sp.is_subdir(Path::new("")));
+        assert!(sp.is_subdir(Path::new("/")));
+
+
+        let bad_folder: PathBuf = ["folder1", ".."].iter().collect();
+        assert!( ! sp.is_subdir(&bad_folder));
+    }
+
+    #[test]
+    fn test_is_file() {
+        let root = PathBuf::from("test/testfolder");
+        let sp = ServePoint::new(root);
+        assert!(sp.is_file(Path::new("file1.abc")));
+        assert!(sp.is_file(&Path::new("folder1").join(Path::new("file3.abc"))));
+
+        assert!( ! sp.is_file(Path::new("folder1")));
+        assert!( ! sp.is_file(Path::new("fdsfdf")));
+    }
+
+    #[test]
+    fn test_get_new_file_path() {
+        let root = PathBuf::from("test/testfolder");
+        let sp = ServePoint::new(root);
+        let canonical_root = PathBuf::from("test/testfolder").canonicalize().unwrap();
+
+        assert_eq!(sp.get_new_file_path(Path::new("new.txt")), Some(canonical_root.join("new.txt")));
+        assert_eq!(sp.get_new_file_path(&Path::new("folder1").join("new.txt")), Some(canonical_root.join("folder1").join("new.txt")));
+        assert_eq!(sp.get_new_file_path(Path::new("file1.abc")), Some(canonical_root.join("file1.abc")));
+
+        // Not inside of an existing directory
+        assert_eq!(sp.get_new_file_path(&Path::new("nope").join("new.txt")), None);
+        assert_eq!(sp.get_new_file_path(&Path::new("file1.abc").join("new.txt")), None);
+        // Escaping the root
+        assert_eq!(sp.get_new_file_path(&Path::new("..").join("new.txt")), None);
+        assert_eq!(sp.get_new_file_path(&Path::new("folder1").join("..").join("..").join("new.txt")), None);
+        assert_eq!(sp.get_new_file_path(&Path::new("folder1").join("..")), None);
+        // Existing directories can't be replaced by a file
+        assert_eq!(sp.get_new_file_path(Path::new("folder1")), None);
+        assert_eq!(sp.get_new_file_path(Path::new("")), None);
+    }
+
+    #[test]
+    fn test_temp_file_removed_unless_persisted() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("video.mp4");
+
+        let temp = TempFile::new_for(&target);
+        fs::write(temp.path(), b"partial").unwrap();
+        let temp_path = temp.path().to_owned();
+        drop(temp);
+        assert!(!temp_path.exists());
+        assert!(!target.exists());
+
+        let temp = TempFile::new_for(&target);
+        fs::write(temp.path(), b"complete").unwrap();
+        let temp_path = temp.path().to_owned();
+        temp.persist(&target).unwrap();
+        assert!(!temp_path.exists());
+        assert_eq!(fs::read(&target).unwrap(), b"complete");
+    }
+
+    #[test]
+    fn test_rename_no_replace() {
+        let dir = tempfile::tempdir().unwrap();
+        let from = dir.path().join("new.mp4");
+        let taken = dir.path().join("taken.mp4");
+        fs::write(&from, b"new").unwrap();
+        fs::write(&taken, b"old").unwrap();
+
+        let err = rename_no_replace(&from, &taken).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(fs::read(&taken).unwrap(), b"old");
+        assert!(from.exists());
+
+        let to = dir.path().join("free.mp4");
+        rename_no_replace(&from, &to).unwrap();
+        assert!(!from.exists());
+        assert_eq!(fs::read(&to).unwrap(), b"new");
+        assert!(rename_no_replace(&from, &dir.path().join("again.mp4")).is_err());
+        assert!(!dir.path().join("again.mp4").exists());
+
+        // Persisting a temp file without replacing leaves the temp file to be cleaned up
+        let temp = TempFile::new_for(&taken);
+        fs::write(temp.path(), b"upload").unwrap();
+        let temp_path = temp.path().to_owned();
+        assert_eq!(temp.persist_new(&taken).unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+        assert!(!temp_path.exists());
+        assert_eq!(fs::read(&taken).unwrap(), b"old");
+
+        // Directories aren't moved over anything either, not even an empty directory
+        let films = dir.path().join("films");
+        fs::create_dir_all(films.join("new")).unwrap();
+        let empty = dir.path().join("empty");
+        fs::create_dir(&empty).unwrap();
+        assert_eq!(rename_no_replace(&films, &empty).unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(rename_no_replace(&films, &taken).unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+        assert!(films.join("new").is_dir());
+        rename_no_replace(&films, &dir.path().join("moved")).unwrap();
+        assert!(dir.path().join("moved").join("new").is_dir());
+        assert!(!films.exists());
+    }
+
+    #[test]
+    fn test_get_entry_path() {
+        let root = PathBuf::from("test/testfolder");
+        let sp = ServePoint::new(root);
+        let canonical_root = PathBuf::from("test/testfolder").canonicalize().unwrap();
+
+        assert_eq!(sp.get_entry_path(Path::new("file1.abc")), Some(canonical_root.join("file1.abc")));
+        assert_eq!(sp.get_entry_path(Path::new("folder1")), Some(canonical_root.join("folder1")));
+        assert_eq!(sp.get_entry_path(Path::new("nope")), None);
+        assert_eq!(sp.get_entry_path(Path::new("")), None);
+        assert_eq!(sp.get_entry_path(&Path::new("folder1").join("..")), None);
+        assert_eq!(sp.get_entry_path(&Path::new("..").join("testfolder")), None);
+    }
+
+    #[test]
+    fn test_file_operations() {
+        let dir = tempfile::tempdir().unwrap();
+        let sp = ServePoint::new(dir.path().to_owned());
+        let root = dir.path().canonicalize().unwrap();
+
+        sp.make_dir(Path::new("shows")).unwrap();
+        assert!(root.join("shows").is_dir());
+        assert!(matches!(sp.make_dir(Path::new("shows")), Err(FsOpError::AlreadyExists)));
+        assert!(matches!(sp.make_dir(&Path::new("nope").join("shows")), Err(FsOpError::NotFound)));
+        assert!(matches!(sp.make_dir(&Path::new("..").join("escaped")), Err(FsOpError::NotFound)));
+
+        fs::write(root.join("episode.mp4"), b"video").unwrap();
+        sp.rename(Path::new("episode.mp4"), &Path::new("shows").join("episode 1.mp4")).unwrap();
+        assert!(root.join("shows").join("episode 1.mp4").is_file());
+        assert!(!root.join("episode.mp4").exists());
+
+        fs::write(root.join("other.mp4"), b"video").unwrap();
+        assert!(matches!(sp.rename(Path::new("other.mp4"), &Path::new("shows").join("episode 1.mp4")), Err(FsOpError::AlreadyExists)));
+        assert!(matches!(sp.rename(Path::new("shows"), &Path::new("shows").join("inside")), Err(FsOpError::Invalid)));
+        assert!(matches!(sp.rename(Path::new("missing"), Path::new("found")), Err(FsOpError::NotFound)));
+        assert!(matches!(sp.rename(Path::new("other.mp4"), &Path::new("..").join("other.mp4")), Err(FsOpError::NotFound)));
+
+        assert!(matches!(sp.get_path_to_delete(Path::new("shows"), false), Err(FsOpError::NotEmpty)));
+        assert_eq!(sp.get_path_to_delete(Path::new("shows"), true).unwrap(), root.join("shows"));
+        assert_eq!(sp.get_path_to_delete(Path::new("other.mp4"), false).unwrap(), root.join("other.mp4"));
+        assert!(matches!(sp.get_path_to_delete(Path::new(""), true), Err(FsOpError::NotFound)));
+
+        let nested = Path::new("a").join("b").join("c.mp4");
+        sp.make_parent_dirs(&nested).unwrap();
+        assert!(root.join("a").join("b").is_dir());
+        assert!(!root.join("a").join("b").join("c.mp4").exists());
+        assert!(matches!(sp.make_parent_dirs(&Path::new("other.mp4").join("x")), Err(FsOpError::AlreadyExists)));
+    }
+
+    #[test]
+    fn test_mounts() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        fs::create_dir_all(root.join("a").join("old")).unwrap();
+        fs::write(root.join("a").join("old").join("film.mkv"), b"film").unwrap();
+        fs::create_dir(root.join("b")).unwrap();
+        fs::write(root.join("b").join("photo.jpg"), b"photo").unwrap();
+        let mount = |name: &str, dir: &str, writable| Mount::new(name.to_owned(), root.join(dir), writable).unwrap();
+        let sp = ServePoint::with_mounts(vec![mount("movies", "a", false), mount("photos", "b", true)]).unwrap();
+
+        let top = sp.get_directory_listing(Path::new("")).unwrap();
+        let names: Vec<&str> = top.children.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(names, vec!["movies/", "photos/"]);
+        assert!(top.children[0].readonly && top.children[0].is_dir);
+        let old = sp.get_directory_listing(&Path::new("movies").join("old")).unwrap();
+        assert_eq!(old.path, "/movies/old/");
+        assert_eq!(old.trail, vec![
+            (String::from("movies"), String::from("movies")),
+            (path_string_from_parts(&["movies", "old"]), String::from("old")),
+        ]);
+        assert!(sp.get_directory_listing(Path::new("music")).is_none());
+
+        assert!(sp.is_subdir(Path::new("movies")));
+        assert!(sp.is_file(&Path::new("movies").join("old").join("film.mkv")));
+        assert!(!sp.is_subdir(&Path::new("movies").join("..").join("b")));
+        assert!(!sp.is_file(Path::new("film.mkv")));
+        assert_eq!(sp.get_directory_path(Path::new("photos")), Some(root.join("b")));
+        assert_eq!(sp.get_directory_path(Path::new("")), None);
+        assert_eq!(sp.real_path(&Path::new("photos").join("photo.jpg")), Some(root.join("b").join("photo.jpg")));
+        assert_eq!(sp.share_path_of(&root.join("b").join("photo.jpg")), Some(Path::new("photos").join("photo.jpg")));
+        assert_eq!(sp.share_path_of(&root.join("c")), None);
+
+        // Only the writable mount can be changed, and nothing can be moved between mounts
+        assert!(!sp.is_writable(Path::new("movies")) && sp.is_writable(Path::new("photos")) && !sp.is_writable(Path::new("")));
+        assert_eq!(sp.get_new_file_path(&Path::new("movies").join("new.mkv")), None);
+        assert_eq!(sp.get_new_file_path(&Path::new("photos").join("new.jpg")), Some(root.join("b").join("new.jpg")));
+        assert!(matches!(sp.make_dir(&Path::new("movies").join("new")), Err(FsOpError::ReadOnly)));
+        assert!(matches!(sp.get_path_to_delete(&Path::new("movies").join("old"), true), Err(FsOpError::ReadOnly)));
+        assert!(matches!(sp.get_path_to_delete(Path::new("photos"), true), Err(FsOpError::NotFound)));
+        assert!(matches!(sp.rename(&Path::new("photos").join("photo.jpg"), &Path::new("movies").join("photo.jpg")), Err(FsOpError::ReadOnly)));
+        sp.make_parent_dirs(&Path::new("photos").join("2020").join("1.jpg")).unwrap();
+        assert!(root.join("b").join("2020").is_dir());
+
+        let both = ServePoint::with_mounts(vec![mount("movies", "a", true), mount("photos", "b", true)]).unwrap();
+        assert!(matches!(both.rename(&Path::new("photos").join("photo.jpg"), &Path::new("movies").join("photo.jpg")), Err(FsOpError::Invalid)));
+
+        for name in &["", ".", "..", "a/b", "/"] {
+            assert!(ServePoint::with_mounts(vec![mount(name, "a", true)]).is_err(), "{}", name);
+        }
+        assert!(ServePoint::with_mounts(vec![mount("x", "a", true), mount("x", "b", true)]).is_err());
+        assert!(ServePoint::with_mounts(Vec::new()).is_err());
+        assert!(Mount::new(String::from("x"), root.join("a").join("old").join("film.mkv"), true).is_err());
+    }
+
+    #[test]
+    fn test_move_entry() {
+        let dir = tempfile::tempdir().unwrap();
+        let from = dir.path().join("from");
+        fs::create_dir_all(from.join("sub")).unwrap();
+        fs::write(from.join("sub").join("file.txt"), b"contents").unwrap();
+
+        let to = dir.path().join("to");
+        move_entry(&from, &to).unwrap();
+        assert!(!from.exists());
+        assert_eq!(fs::read(to.join("sub").join("file.txt")).unwrap(), b"contents");
+
+        assert!(move_entry(&from, &dir.path().join("again")).is_err());
+
+        // The copy used between disks gives the same result
+        copy_entry(&to, &from).unwrap();
+        assert_eq!(fs::read(from.join("sub").join("file.txt")).unwrap(), b"contents");
+    }
+
+    #[test]
+    fn test_create_trail() {
+        let root = PathBuf::from("test/testfolder");
+        let sp = Mount::new(String::new(), root, true).unwrap();
+
+        let path: PathBuf = ["folder1", "mytestfiles", "testfile1.txt"].iter().collect();
+        let trail = sp.create_trail(&path);
+
+        let expected_trail = vec![
+            (path_string_from_parts(&["folder1"]), "folder1".to_owned()),
+            (path_string_from_parts(&["folder1", "mytestfiles"]), "mytestfiles".to_owned()),
+            (path_string_from_parts(&["folder1", "mytestfiles", "testfile1.txt"]), "testfile1.txt".to_owned()),
+        ];
+        
+        assert_eq!(expected_trail, trail);
+    }
+
+    // #[test]
+    // fn test_get_full_path() {
+    //     let root = PathBuf::from("test/testfolder");
+    //     let sp = ServePoint::new(root);
+
+    //     let path1 = Path::new("file1.abc");
+    //     let expected1: PathBuf = ["test", "testfolder", "file1.abc"].iter().collect();
+    //     assert_eq!(sp.get_full_path(path1).unwrap(), expected1.canonicalize().unwrap());
+
+    //     let path2 = Path::new("folder1").join("mytestfiles").join("testfile1.txt");
+    //     let expected2: PathBuf = ["test", "testfolder", "folder1", "mytestfiles", "testfile1.txt"].iter().collect();
+    //     assert_eq!(sp.get_full_path(&path2).unwrap(), expected2.canonicalize().unwrap());
+
+    //     let bad_path = Path::new("folder1").join("..").join("..");
+    //     assert_eq!(sp.get_full_path(&bad_path), None);
+
+    //     let doesnt_exist = Path::new("folder1").join("woooooooo");
+    //     assert_eq!(sp.get_full_path(&doesnt_exist), None);
+    // }
+
+    #[test]
+    fn test_get_directory_listing() {
+        let root = PathBuf::from("test/testfolder");
+        let sp = ServePoint::new(root);
+        let path1 = Path::new("");
+        let listing1 = sp.get_directory_listing(path1);
+        println!("{:?}", listing1);
+        // assert!(false);
+    }
+
+    #[test]
+    fn test_directory_entry_types() {
+        let root = PathBuf::from("test/testfolder");
+        let sp = ServePoint::new(root);
+        let listing = sp.get_directory_listing(&Path::new("folder1").join("mytestfiles")).unwrap();
+        let file = listing.children.iter().find(|c| c.name == "testfile1.txt").unwrap();
+        assert_eq!(file.entry_type, EntryType::File);
+        assert_eq!(file.mime, "text/plain");
+        let meta = fs::metadata("test/testfolder/folder1/mytestfiles/testfile1.txt").unwrap();
+        assert_eq!(file.size, meta.len());
+        assert_eq!(file.modified, Some(DateTime::<Utc>::from(meta.modified().unwrap())));
+        assert!(!file.is_symlink);
+
+        let listing = sp.get_directory_listing(Path::new("folder1")).unwrap();
+        let dir = listing.children.iter().find(|c| c.name == "mytestfiles/").unwrap();
+        assert_eq!(dir.entry_type, EntryType::Directory);
+        assert_eq!(dir.mime, "inode/directory");
+
+        let unknown = listing.children.iter().find(|c| c.name == "file3.abc").unwrap();
+        assert_eq!(unknown.mime, "application/octet-stream");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_directory_entry_symlinks() {
+        use std::os::unix::fs::{symlink, PermissionsExt};
+
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("file.txt"), b"hello").unwrap();
+        fs::set_permissions(dir.path().join("file.txt"), fs::Permissions::from_mode(0o444)).unwrap();
+        symlink(dir.path().join("file.txt"), dir.path().join("link.txt")).unwrap();
+        symlink(dir.path().join("missing"), dir.path().join("broken")).unwrap();
+
+        let sp = ServePoint::new(dir.path().to_owned());
+        let listing = sp.get_directory_listing(Path::new("")).unwrap();
+        let entry = |name: &str| listing.children.iter().find(|c| c.name == name).unwrap().clone();
+
+        let file = entry("file.txt");
+        assert!(file.readonly);
+        assert_eq!(file.mode, Some(0o444));
+        assert!(!file.is_symlink);
+
+        let link = entry("link.txt");
+        assert!(link.is_symlink);
+        assert!(link.is_file);
+        assert_eq!(link.size, 5);
+
+        let broken = entry("broken");
+        assert!(broken.is_symlink);
+        assert_eq!(broken.entry_type, EntryType::Symlink);
+        assert_eq!(broken.mime, "inode/symlink");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlink_policy() {
+        use std::os::unix::fs::symlink;
+
+        let dir = tempfile::tempdir().unwrap();
+        let other = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        fs::create_dir(root.join("films")).unwrap();
+        fs::write(root.join("films").join("film.mkv"), b"film").unwrap();
+        fs::write(other.path().join("film.mkv"), b"other disk").unwrap();
+        symlink(root.join("films"), root.join("inside")).unwrap();
+        symlink(other.path(), root.join("outside")).unwrap();
+
+        let sp = |policy| ServePoint::new(root.clone()).with_symlinks(policy).unwrap();
+        let check = |sp: &ServePoint, expected: [bool; 4]| {
+            assert_eq!([
+                sp.is_file(&Path::new("films").join("film.mkv")),
+                sp.is_file(&Path::new("inside").join("film.mkv")),
+                sp.is_file(&Path::new("outside").join("film.mkv")),
+                sp.is_file(&Path::new("inside").join("..").join("..").join("film.mkv")),
+            ], expected);
+        };
+        check(&sp(SymlinkPolicy::Deny), [true, false, false, false]);
+        check(&sp(SymlinkPolicy::WithinRoot), [true, true, false, false]);
+        check(&sp(SymlinkPolicy::AllowTargets(vec![other.path().to_owned()])), [true, true, true, false]);
+        check(&sp(SymlinkPolicy::FollowAll), [true, true, true, false]);
+        assert!(ServePoint::new(root.clone()).with_symlinks(SymlinkPolicy::AllowTargets(vec![root.join("missing")])).is_err());
+
+        // Links that aren't followed are listed as links, without anything about where they lead
+        let listing = sp(SymlinkPolicy::WithinRoot).get_directory_listing(Path::new("")).unwrap();
+        let entry = |name: &str| listing.children.iter().find(|c| c.name == name).unwrap().clone();
+        assert_eq!(entry("inside/").entry_type, EntryType::Directory);
+        let outside = entry("outside");
+        assert_eq!(outside.entry_type, EntryType::Symlink);
+        assert!(outside.is_symlink && !outside.is_dir);
+        assert!(sp(SymlinkPolicy::WithinRoot).get_directory_listing(Path::new("outside")).is_none());
+
+        let listing = sp(SymlinkPolicy::FollowAll).get_directory_listing(Path::new("outside")).unwrap();
+        assert_eq!(listing.children[0].name, "film.mkv");
+        assert_eq!(listing.trail, vec![(String::from("outside"), String::from("outside"))]);
+
+        // Nothing can be written through a link that isn't followed
+        let sp = sp(SymlinkPolicy::WithinRoot);
+        symlink(other.path().join("film.mkv"), root.join("films").join("link.mkv")).unwrap();
+        assert_eq!(sp.get_new_file_path(&Path::new("films").join("link.mkv")), None);
+        assert_eq!(sp.get_new_file_path(&Path::new("outside").join("new.mkv")), None);
+        assert!(sp.get_path_to_delete(&Path::new("films").join("link.mkv"), false).is_ok());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlink_loops() {
+        use std::os::unix::fs::symlink;
+
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        fs::write(root.join("file.txt"), b"hello").unwrap();
+        symlink(root.join("a"), root.join("b")).unwrap();
+        symlink(root.join("b"), root.join("a")).unwrap();
+        symlink(&root, root.join("up")).unwrap();
+
+        let sp = ServePoint::new(root.clone()).with_symlinks(SymlinkPolicy::FollowAll).unwrap();
+        assert!(!sp.is_subdir(Path::new("a")));
+        assert!(!sp.is_file(&Path::new("a").join("file.txt")));
+        assert!(sp.is_file(&["up", "up", "up", "file.txt"].iter().collect::<PathBuf>()));
+
+        let listing = sp.get_directory_listing(Path::new("")).unwrap();
+        let types: Vec<(&str, EntryType)> = listing.children.iter().map(|c| (c.name.as_str(), c.entry_type)).collect();
+        assert_eq!(types, vec![
+            ("up/", EntryType::Directory), ("a", EntryType::Symlink), ("b", EntryType::Symlink), ("file.txt", EntryType::File),
+        ]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_target_changes_between_check_and_use() {
+        use std::io::Read;
+        use std::os::unix::fs::symlink;
+
+        let dir = tempfile::tempdir().unwrap();
+        let other = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        fs::create_dir(root.join("films")).unwrap();
+        fs::write(root.join("films").join("film.mkv"), b"film").unwrap();
+        fs::write(other.path().join("film.mkv"), b"other disk").unwrap();
+
+        let sp = ServePoint::new(root.clone());
+        let path = Path::new("films").join("film.mkv");
+        let (mut file, real) = sp.open_file(&path).unwrap();
+        let mut contents = String::new();
+        file.read_to_string(&mut contents).unwrap();
+        assert_eq!((contents.as_str(), real.as_path()), ("film", root.join(&path).as_path()));
+
+        // The file itself is swapped for a link
+        let checked = sp.mounts[0].get_file_path(&path).unwrap();
+        fs::remove_file(root.join(&path)).unwrap();
+        symlink(other.path().join("film.mkv"), root.join(&path)).unwrap();
+        assert!(open_checked(&checked).is_err());
+        assert!(sp.open_file(&path).is_err());
+
+        // A directory on the way to it is swapped for a link
+        fs::remove_file(root.join(&path)).unwrap();
+        fs::write(root.join(&path), b"film").unwrap();
+        let checked = sp.mounts[0].get_file_path(&path).unwrap();
+        fs::rename(root.join("films"), root.join("old")).unwrap();
+        symlink(other.path(), root.join("films")).unwrap();
+        assert!(open_checked(&checked).is_err());
+        assert!(sp.open_file(&path).is_err());
+
+        // Replaced with another file that is still inside of the share
+        fs::remove_file(root.join("films")).unwrap();
+        fs::rename(root.join("old"), root.join("films")).unwrap();
+        let checked = sp.mounts[0].get_file_path(&path).unwrap();
+        fs::remove_file(root.join(&path)).unwrap();
+        fs::write(root.join(&path), b"new film").unwrap();
+        assert!(open_checked(&checked).is_ok());
+    }
+
+    #[test]
+    fn test_ignore_lists() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        fs::create_dir_all(root.join("films").join("extras")).unwrap();
+        fs::write(root.join("films").join("film.mkv"), b"film").unwrap();
+        fs::write(root.join("films").join("film.nfo"), b"info").unwrap();
+        fs::write(root.join("films").join("extras").join("trailer.mkv"), b"trailer").unwrap();
+        fs::write(root.join("Thumbs.db"), b"thumbs").unwrap();
+        fs::write(root.join("films").join(crate::ignore::IGNORE_FILE), b"*.nfo\nextras/\n").unwrap();
+
+        let sp = ServePoint::new(root.clone()).with_ignore_list(IgnoreList::new(&[String::from("Thumbs.db")]).unwrap());
+        let names = |p: &str| -> Vec<String> {
+            sp.get_directory_listing(Path::new(p)).unwrap().children.into_iter().map(|c| c.name).collect()
+        };
+        assert_eq!(names(""), vec!["films/"]);
+        assert_eq!(names("films"), vec!["film.mkv"]);
+
+        let extras = Path::new("films").join("extras");
+        assert!(sp.get_directory_listing(&extras).is_none());
+        assert!(!sp.is_file(&extras.join("trailer.mkv")));
+        assert!(sp.is_ignored(&extras.join("trailer.mkv")));
+        assert!(!sp.is_file(&Path::new("films").join("film.nfo")));
+        assert!(!sp.is_file(Path::new("Thumbs.db")));
+        assert!(sp.is_file(&Path::new("films").join("film.mkv")));
+        assert!(!sp.is_ignored(&Path::new("films").join("film.mkv")));
+
+        // Nothing can be made, or renamed, to a name that would be hidden
+        let films = Path::new("films");
+        assert_eq!(sp.get_new_file_path(&films.join(crate::ignore::IGNORE_FILE)), None);
+        assert_eq!(sp.get_new_file_path(&films.join("new.nfo")), None);
+        assert_eq!(sp.get_new_file_path(Path::new("Thumbs.db")), None);
+        assert!(sp.get_new_file_path(&films.join("new.mkv")).is_some());
+        assert!(matches!(sp.make_dir(&films.join("extras")), Err(FsOpError::Invalid)));
+        assert!(matches!(sp.make_dir(&films.join("extras").join("new")), Err(FsOpError::Invalid)));
+        assert!(matches!(sp.rename(&films.join("film.mkv"), &films.join("film.nfo")), Err(FsOpError::Invalid)));
+        assert!(matches!(sp.rename(&films.join("film.mkv"), &films.join(crate::ignore::IGNORE_FILE)), Err(FsOpError::Invalid)));
+    }
+
+    #[test]
+    fn test_natural_cmp() {
+        let mut names = vec!["Episode 10.mkv", "episode 2.mkv", "Episode 1.mkv", "Episode 02.mkv", "Episode.mkv", "a10b2", "a10b10", "a9"];
+        names.sort_by(|a, b| natural_cmp(a, b));
+        assert_eq!(names, vec!["a9", "a10b2", "a10b10", "Episode 1.mkv", "Episode 02.mkv", "episode 2.mkv", "Episode 10.mkv", "Episode.mkv"]);
+        assert_eq!(natural_cmp("file", "file"), Ordering::Equal);
+        assert_eq!(natural_cmp("File", "file"), Ordering::Less);
+    }
+
+    #[test]
+    fn test_listing_options() {
+        let dir = tempfile::tempdir().unwrap();
+        for (name, size) in &[("Episode 10.mkv", 3), ("Episode 2.mkv", 1), ("Episode 1.mkv", 2), ("notes.txt", 10)] {
+            fs::write(dir.path().join(name), vec![0; *size]).unwrap();
+        }
+        fs::create_dir(dir.path().join("extras")).unwrap();
+        let sp = ServePoint::new(dir.path().to_owned());
+        let names = |listing: &DirectoryListing| listing.children.iter().map(|c| c.name.clone()).collect::<Vec<String>>();
+
+        let mut listing = sp.get_directory_listing(Path::new("")).unwrap();
+        listing.apply(&ListingOptions::default());
+        assert_eq!(names(&listing), vec!["extras/", "Episode 1.mkv", "Episode 2.mkv", "Episode 10.mkv", "notes.txt"]);
+        assert_eq!((listing.total, listing.page, listing.pages), (5, 1, 1));
+
+        let mut listing = sp.get_directory_listing(Path::new("")).unwrap();
+        listing.apply(&ListingOptions { sort: SortKey::Size, order: SortOrder::Desc, ..ListingOptions::default() });
+        assert_eq!(names(&listing), vec!["extras/", "notes.txt", "Episode 10.mkv", "Episode 1.mkv", "Episode 2.mkv"]);
+
+        let filter = globset::GlobBuilder::new("episode*").case_insensitive(true).build().unwrap().compile_matcher();
+        let options = ListingOptions { filter: Some(filter), page: 2, per_page: 2, ..ListingOptions::default() };
+        let mut listing = sp.get_directory_listing(Path::new("")).unwrap();
+        listing.apply(&options);
+        assert_eq!(names(&listing), vec!["Episode 10.mkv"]);
+        assert_eq!((listing.total, listing.page, listing.pages, listing.per_page), (3, 2, 2, 2));
+
+        let mut listing = sp.get_directory_listing(Path::new("")).unwrap();
+        listing.apply(&ListingOptions { page: 9, per_page: 0, ..ListingOptions::default() });
+        assert!(listing.children.is_empty());
+        assert_eq!((listing.pages, listing.per_page), (5, 1));
+    }
+}
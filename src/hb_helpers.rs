@@ -1,95 +1,227 @@
-use handlebars::Handlebars;
-use handlebars::{RenderContext, Helper, Context, JsonRender, HelperResult, Output};
-use std::collections::HashMap;
-use serde_json::value::Value;
-use url::form_urlencoded::byte_serialize;
-
-
-lazy_static! {
-    static ref EXT_ICON_MAP: HashMap<&'static str, &'static str> = {
-        let mut m = HashMap::new();
-        m.insert("default", "blank_file_icon.svg");
-        m.insert("jpg", "picture_file_icon.svg");
-        m.insert("jpeg", "picture_file_icon.svg");
-        m.insert("gif", "picture_file_icon.svg");
-        m.insert("png", "picture_file_icon.svg");
-        m.insert("bmp", "picture_file_icon.svg");
-        m.insert("pdf", "text_file_icon.svg");
-        m.insert("docx", "text_file_icon.svg");
-        m.insert("doc", "text_file_icon.svg");
-        m.insert("txt", "text_file_icon.svg");
-        m.insert("srt", "text_file_icon.svg");
-        m
-    };
-}
-
-pub const LISTING_TEMPLATE: &'static str = include_str!("../templates/listing.html.hb");
-pub const CINEMA_TEMPLATE: &'static str = include_str!("../templates/cinema.html.hb");
-
-/*
-If given a json string that ends in ".mp4" reutrn true
-Otherwise return an empty string (which will evaluate to false)
-*/
-pub fn is_mp4(h: &Helper, _: &Handlebars, _: &Context, _: &mut RenderContext, out: &mut dyn Output) -> HelperResult {
-    let param = h.param(0).unwrap();
-    match param.value() {
-        Value::String(s) => {
-            let value = if s.ends_with(".mp4") {
-                Value::Bool(true)
-            } else {
-                Value::String("".to_owned())
-            };
-            out.write(value.render().as_ref())?;
-        },
-        _ => {
-            out.write(Value::String("".to_owned()).render().as_ref())?;
-        }
-    };
-
-    Ok(())
-}
-
-
-pub fn urlencode(h: &Helper, _: &Handlebars, _: &Context, _: &mut RenderContext, out: &mut dyn Output) -> HelperResult {
-    let param = h.param(0).unwrap();
-    match param.value() {
-        Value::String(s) => {
-            let urlencoded: String = byte_serialize(s.as_bytes()).collect();
-            let value = Value::String(urlencoded);
-            out.write(value.render().as_ref())?;
-        },
-        _ => {}
-    };
-    Ok(())
-}
-
-/*
-Look at the extenstion for a filename and find an appropiate file icon
-*/
-pub fn icon_for_ext(h: &Helper, _: &Handlebars, _: &Context, _: &mut RenderContext, out: &mut dyn Output) -> HelperResult {
-    let param = h.param(0).unwrap();
-    match param.value() {
-        Value::String(s) => {
-            let parts = s.split(".").collect::<Vec<&str>>();
-
-            // Get the extension of the string name
-            if let Some(ext) = parts.last() {
-                // Lookup the icon in the map, and return it's name
-                if let Some(icon) = EXT_ICON_MAP.get(ext) {
-                    let s: String = (*icon).to_owned();
-                    out.write(Value::String(s).render().as_ref())?;
-                } else {
-                    let icon = String::from("blank_file_icon.svg");
-                    out.write(Value::String(icon).render().as_ref())?;
-                }
-                return Ok(())
-            }
-            out.write(Value::String("".to_owned()).render().as_ref())?;
-        },
-        _ => {
-            out.write(Value::String("".to_owned()).render().as_ref())?;
-        }
-    };
-
-    Ok(())
-}
\ No newline at end of file
+use handlebars::Handlebars;
+use handlebars::{RenderContext, Helper, Context, JsonRender, HelperResult, Output};
+use std::collections::HashMap;
+use serde_json::value::Value;
+use url::form_urlencoded::byte_serialize;
+use chrono::DateTime;
+
+
+lazy_static! {
+    static ref EXT_ICON_MAP: HashMap<&'static str, &'static str> = {
+        let mut m = HashMap::new();
+        m.insert("default", "blank_file_icon.svg");
+        m.insert("jpg", "picture_file_icon.svg");
+        m.insert("jpeg", "picture_file_icon.svg");
+        m.insert("gif", "picture_file_icon.svg");
+        m.insert("png", "picture_file_icon.svg");
+        m.insert("bmp", "picture_file_icon.svg");
+        m.insert("pdf", "text_file_icon.svg");
+        m.insert("docx", "text_file_icon.svg");
+        m.insert("doc", "text_file_icon.svg");
+        m.insert("txt", "text_file_icon.svg");
+        m.insert("srt", "text_file_icon.svg");
+        m
+    };
+}
+
+/// How a locale writes dates and decimal numbers
+struct LocaleFormat {
+    time: &'static str,
+    decimal: char,
+}
+
+lazy_static! {
+    static ref LOCALE_FORMATS: HashMap<&'static str, LocaleFormat> = {
+        let mut m = HashMap::new();
+        m.insert("en", LocaleFormat { time: "%d/%m/%Y %H:%M", decimal: '.' });
+        m.insert("en-us", LocaleFormat { time: "%m/%d/%Y %I:%M %p", decimal: '.' });
+        m.insert("de", LocaleFormat { time: "%d.%m.%Y %H:%M", decimal: ',' });
+        m.insert("fr", LocaleFormat { time: "%d/%m/%Y %H:%M", decimal: ',' });
+        m.insert("es", LocaleFormat { time: "%d/%m/%Y %H:%M", decimal: ',' });
+        m.insert("it", LocaleFormat { time: "%d/%m/%Y %H:%M", decimal: ',' });
+        m.insert("nl", LocaleFormat { time: "%d-%m-%Y %H:%M", decimal: ',' });
+        m.insert("ja", LocaleFormat { time: "%Y/%m/%d %H:%M", decimal: '.' });
+        m
+    };
+}
+
+pub const DEFAULT_LOCALE: &'static str = "en";
+const UNITS: [&str; 8] = ["B", "KB", "MB", "GB", "TB", "PB" ,"EB", "ZB"];
+
+pub const LISTING_TEMPLATE: &'static str = include_str!("../templates/listing.html.hb");
+pub const CINEMA_TEMPLATE: &'static str = include_str!("../templates/cinema.html.hb");
+pub const SEARCH_TEMPLATE: &'static str = include_str!("../templates/search.html.hb");
+pub const LOGIN_TEMPLATE: &'static str = include_str!("../templates/login.html.hb");
+pub const USERS_TEMPLATE: &'static str = include_str!("../templates/users.html.hb");
+
+/*
+If given a json string that ends in ".mp4" reutrn true
+Otherwise return an empty string (which will evaluate to false)
+*/
+pub fn is_mp4(h: &Helper, _: &Handlebars, _: &Context, _: &mut RenderContext, out: &mut dyn Output) -> HelperResult {
+    let param = h.param(0).unwrap();
+    match param.value() {
+        Value::String(s) => {
+            let value = if s.ends_with(".mp4") {
+                Value::Bool(true)
+            } else {
+                Value::String("".to_owned())
+            };
+            out.write(value.render().as_ref())?;
+        },
+        _ => {
+            out.write(Value::String("".to_owned()).render().as_ref())?;
+        }
+    };
+
+    Ok(())
+}
+
+
+pub fn urlencode(h: &Helper, _: &Handlebars, _: &Context, _: &mut RenderContext, out: &mut dyn Output) -> HelperResult {
+    let param = h.param(0).unwrap();
+    if let Value::String(s) = param.value() {
+        let urlencoded: String = byte_serialize(s.as_bytes()).collect();
+        let value = Value::String(urlencoded);
+        out.write(value.render().as_ref())?;
+    }
+    Ok(())
+}
+
+/*
+Look at the extenstion for a filename and find an appropiate file icon
+*/
+pub fn icon_for_ext(h: &Helper, _: &Handlebars, _: &Context, _: &mut RenderContext, out: &mut dyn Output) -> HelperResult {
+    let param = h.param(0).unwrap();
+    match param.value() {
+        Value::String(s) => {
+            let parts = s.split(".").collect::<Vec<&str>>();
+
+            // Get the extension of the string name
+            if let Some(ext) = parts.last() {
+                // Lookup the icon in the map, and return it's name
+                if let Some(icon) = EXT_ICON_MAP.get(ext) {
+                    let s: String = (*icon).to_owned();
+                    out.write(Value::String(s).render().as_ref())?;
+                } else {
+                    let icon = String::from("blank_file_icon.svg");
+                    out.write(Value::String(icon).render().as_ref())?;
+                }
+                return Ok(())
+            }
+            out.write(Value::String("".to_owned()).render().as_ref())?;
+        },
+        _ => {
+            out.write(Value::String("".to_owned()).render().as_ref())?;
+        }
+    };
+
+    Ok(())
+}
+
+/*
+Pick the best locale we know how to format for from an Accept-Language header, e.g. "de-CH,de;q=0.9,en;q=0.8".
+An exact match for a tag is preferred, then its primary language. Falls back to DEFAULT_LOCALE.
+*/
+pub fn negotiate_locale(accept_language: Option<&str>) -> &'static str {
+    let header = if let Some(h) = accept_language { h } else { return DEFAULT_LOCALE; };
+
+    let mut tags: Vec<(String, f32)> = header.split(',')
+        .filter_map(|part| {
+            let mut params = part.split(';');
+            let tag = params.next()?.trim().to_lowercase();
+            let q = params
+                .filter_map(|p| p.trim().strip_prefix("q="))
+                .next()
+                .map_or(Some(1.0), |q| q.trim().parse::<f32>().ok())?;
+            if tag.is_empty() || q <= 0.0 { None } else { Some((tag, q)) }
+        })
+        .collect();
+    // Stable, so tags with the same weight keep the order they were sent in
+    tags.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+
+    for (tag, _) in &tags {
+        let primary = tag.split('-').next().unwrap_or("");
+        for candidate in &[tag.as_str(), primary] {
+            if let Some((key, _)) = LOCALE_FORMATS.get_key_value(*candidate) {
+                return key;
+            }
+        }
+    }
+    DEFAULT_LOCALE
+}
+
+fn locale_format(h: &Helper) -> &'static LocaleFormat {
+    let locale = h.param(1).and_then(|p| p.value().as_str()).unwrap_or(DEFAULT_LOCALE);
+    LOCALE_FORMATS.get(locale).unwrap_or_else(|| &LOCALE_FORMATS[DEFAULT_LOCALE])
+}
+
+/*
+Format an RFC 3339 timestamp for the locale given as the second parameter, e.g. {{ format_time modified locale }}
+Missing times are shown as "-"
+*/
+pub fn format_time(h: &Helper, _: &Handlebars, _: &Context, _: &mut RenderContext, out: &mut dyn Output) -> HelperResult {
+    let formatted = h.param(0)
+        .and_then(|p| p.value().as_str())
+        .and_then(|s| DateTime::parse_from_rfc3339(s).ok())
+        .map(|t| t.format(locale_format(h).time).to_string())
+        .unwrap_or_else(|| "-".to_owned());
+    out.write(&formatted)?;
+    Ok(())
+}
+
+/*
+Format a number of bytes as a human readable size for the locale given as the second parameter,
+e.g. {{ format_size size locale }}
+*/
+pub fn format_size(h: &Helper, _: &Handlebars, _: &Context, _: &mut RenderContext, out: &mut dyn Output) -> HelperResult {
+    let size = h.param(0).and_then(|p| p.value().as_u64()).unwrap_or(0);
+    out.write(&sizeof_fmt(size, locale_format(h).decimal))?;
+    Ok(())
+}
+
+fn sizeof_fmt(size: u64, decimal: char) -> String {
+    let mut s: f64 = size as f64;
+    let mut unit = "YB";
+    for u in &UNITS {
+        if s.abs() < 1024.0 {
+            unit = u;
+            break;
+        }
+        s /= 1024.0;
+    }
+    format!("{:.2} {}", s, unit).replace('.', &decimal.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_negotiate_locale() {
+        assert_eq!(negotiate_locale(None), "en");
+        assert_eq!(negotiate_locale(Some("")), "en");
+        assert_eq!(negotiate_locale(Some("en-US,en;q=0.5")), "en-us");
+        assert_eq!(negotiate_locale(Some("en-GB")), "en");
+        assert_eq!(negotiate_locale(Some("de-CH, fr;q=0.9")), "de");
+        assert_eq!(negotiate_locale(Some("xx;q=1.0, fr;q=0.2, de;q=0.8")), "de");
+        assert_eq!(negotiate_locale(Some("de;q=0, nl")), "nl");
+        assert_eq!(negotiate_locale(Some("*")), "en");
+        assert_eq!(negotiate_locale(Some(";;q=,, ;q=abc")), "en");
+    }
+
+    #[test]
+    fn test_format_helpers() {
+        let mut hb = Handlebars::new();
+        hb.register_helper("format_time", Box::new(format_time));
+        hb.register_helper("format_size", Box::new(format_size));
+        let template = "{{ format_time t locale }} | {{ format_size s locale }}";
+
+        let render = |data| hb.render_template(template, &data).unwrap();
+        assert_eq!(render(json!({"t": "2021-11-29T14:05:00Z", "s": 1536, "locale": "en"})), "29/11/2021 14:05 | 1.50 KB");
+        assert_eq!(render(json!({"t": "2021-11-29T14:05:00Z", "s": 1536, "locale": "en-us"})), "11/29/2021 02:05 PM | 1.50 KB");
+        assert_eq!(render(json!({"t": "2021-11-29T14:05:00Z", "s": 1536, "locale": "de"})), "29.11.2021 14:05 | 1,50 KB");
+        assert_eq!(render(json!({"t": null, "s": 0, "locale": "unknown"})), "- | 0.00 B");
+    }
+}
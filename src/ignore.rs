@@ -0,0 +1,227 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use globset::{GlobBuilder, GlobMatcher};
+
+use crate::upload_sessions::is_part_file;
+
+/// The file in a directory that lists what to leave out of it
+pub const IGNORE_FILE: &str = ".ffsignore";
+
+/// One line of an ignore list
+#[derive(Debug, Clone)]
+struct Pattern {
+    glob: GlobMatcher,
+    /// Starts with a '!', which brings back something an earlier pattern ignored
+    negated: bool,
+    /// Ends with a '/', so only matches directories
+    dir_only: bool,
+    /// Has a '/' before the end, so is matched against the whole path rather than just the name
+    anchored: bool,
+}
+
+impl Pattern {
+    /// None for blank lines and comments
+    fn parse(line: &str) -> Result<Option<Self>, String> {
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            return Ok(None);
+        }
+
+        let (negated, line) = match line.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, line.strip_prefix('\\').unwrap_or(line)),
+        };
+        let (dir_only, line) = match line.strip_suffix('/') {
+            Some(rest) => (true, rest),
+            None => (false, line),
+        };
+        let anchored = line.contains('/');
+        let line = line.trim_start_matches('/');
+        if line.is_empty() {
+            return Err(String::from("a pattern can't be empty"));
+        }
+
+        let glob = GlobBuilder::new(line)
+            .literal_separator(true)
+            .build()
+            .map_err(|e| e.to_string())?;
+        Ok(Some(Pattern { glob: glob.compile_matcher(), negated, dir_only, anchored }))
+    }
+
+    fn is_match(&self, path: &Path, is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        if self.anchored {
+            self.glob.is_match(path)
+        } else {
+            path.file_name().is_some_and(|name| self.glob.is_match(name))
+        }
+    }
+}
+
+/*
+Patterns for entries to leave out of the share, written like a .gitignore. A pattern without a '/' matches a name
+anywhere below where the list applies, one with a '/' matches a path relative to it, a '/' at the end only matches
+directories, and a '!' at the start brings an entry back. The last pattern that matches decides.
+*/
+#[derive(Debug, Clone, Default)]
+pub struct IgnoreList {
+    patterns: Vec<Pattern>,
+}
+
+impl IgnoreList {
+    /// The global list from the config, which has to be valid
+    pub fn new(patterns: &[String]) -> Result<Self, String> {
+        let mut list = IgnoreList::default();
+        for pattern in patterns {
+            let parsed = Pattern::parse(pattern).map_err(|e| format!("Bad ignore pattern '{}': {}", pattern, e))?;
+            list.patterns.extend(parsed);
+        }
+        Ok(list)
+    }
+
+    /// The .ffsignore file in 'dir', if there is one. Patterns that can't be read are skipped.
+    fn load(dir: &Path) -> Option<Self> {
+        let path = dir.join(IGNORE_FILE);
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return None,
+            Err(e) => {
+                warn!("Could not read {:?}: {}", path, e);
+                return None;
+            },
+        };
+
+        let mut list = IgnoreList::default();
+        for line in contents.lines() {
+            match Pattern::parse(line) {
+                Ok(pattern) => list.patterns.extend(pattern),
+                Err(e) => warn!("Skipping ignore pattern '{}' in {:?}: {}", line, path, e),
+            }
+        }
+        Some(list)
+    }
+
+    /// Whether 'path', relative to where the list applies, is ignored. None if no pattern matches.
+    fn decide(&self, path: &Path, is_dir: bool) -> Option<bool> {
+        self.patterns.iter().rev()
+            .find(|pattern| pattern.is_match(path, is_dir))
+            .map(|pattern| !pattern.negated)
+    }
+}
+
+/*
+The ignore lists that apply inside of one directory: the global list and the .ffsignore files of the directory and of
+every directory above it, up to the top of the mount. Deeper lists have the last word. Once a directory is ignored so
+is everything in it, and nothing inside of it can bring anything back.
+*/
+#[derive(Debug, Clone)]
+pub struct IgnoreRules {
+    /// Each list, with the directory it applies to relative to the top
+    lists: Vec<(PathBuf, Arc<IgnoreList>)>,
+    /// The directory these rules are for, relative to the top
+    dir: PathBuf,
+    ignored: bool,
+}
+
+impl IgnoreRules {
+    /// The rules at the top of a mount, which is at 'path' on the disk
+    pub fn new(global: Arc<IgnoreList>, path: &Path) -> Self {
+        let mut lists = vec![(PathBuf::new(), global)];
+        lists.extend(IgnoreList::load(path).map(|list| (PathBuf::new(), Arc::new(list))));
+        IgnoreRules { lists, dir: PathBuf::new(), ignored: false }
+    }
+
+    /// The rules inside of the directory 'name', which is at 'path' on the disk
+    pub fn enter(&self, name: &str, path: &Path) -> Self {
+        let ignored = self.is_ignored(name, true);
+        let dir = self.dir.join(name);
+        let mut lists = self.lists.clone();
+        if !ignored {
+            lists.extend(IgnoreList::load(path).map(|list| (dir.clone(), Arc::new(list))));
+        }
+        IgnoreRules { lists, dir, ignored }
+    }
+
+    /// Whether the entry called 'name' in the directory is left out. The ignore files themselves always are, and so
+    /// are the part files of uploads that haven't finished.
+    pub fn is_ignored(&self, name: &str, is_dir: bool) -> bool {
+        if self.ignored || name == IGNORE_FILE || is_part_file(name) {
+            return true;
+        }
+        let path = self.dir.join(name);
+        self.lists.iter().rev()
+            .find_map(|(base, list)| list.decide(path.strip_prefix(base).unwrap_or(&path), is_dir))
+            .unwrap_or(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn list(patterns: &[&str]) -> Arc<IgnoreList> {
+        let patterns: Vec<String> = patterns.iter().map(|p| p.to_string()).collect();
+        Arc::new(IgnoreList::new(&patterns).unwrap())
+    }
+
+    #[test]
+    fn test_patterns() {
+        let dir = tempfile::tempdir().unwrap();
+        let rules = IgnoreRules::new(list(&[
+            "# comment", "", ".*", "!.keep", "*.part", "Thumbs.db", "build/", "/top.txt", "docs/*.tmp", "\\!bang",
+        ]), dir.path());
+        let ignored = |name: &str, is_dir: bool| rules.is_ignored(name, is_dir);
+        assert!(ignored(".DS_Store", false));
+        assert!(!ignored(".keep", false));
+        assert!(ignored("film.mkv.part", false));
+        assert!(ignored("Thumbs.db", false));
+        assert!(ignored("build", true));
+        assert!(!ignored("build", false));
+        assert!(ignored("top.txt", false));
+        assert!(ignored("!bang", false));
+        assert!(ignored(IGNORE_FILE, false));
+        assert!(!ignored("film.mkv", false));
+
+        // Part files are hidden even when a '!' pattern would bring them back
+        assert!(ignored(".film.mkv.0123456789abcdef0123456789abcdef.upload", false));
+        let shown = IgnoreRules::new(list(&["!.*"]), dir.path());
+        assert!(shown.is_ignored(".film.mkv.0123456789abcdef0123456789abcdef.upload", false));
+        assert!(!shown.is_ignored(".film.mkv.upload", false));
+        assert!(!shown.is_ignored(".film.mkv.part.upload", false));
+
+        let docs = rules.enter("docs", &dir.path().join("docs"));
+        assert!(docs.is_ignored("notes.tmp", false));
+        assert!(!docs.is_ignored("top.txt", false));
+        assert!(docs.is_ignored(".hidden", false));
+
+        let hidden = rules.enter(".git", &dir.path().join(".git"));
+        assert!(hidden.is_ignored("config", false));
+
+        assert!(IgnoreList::new(&[String::from("a[")]).is_err());
+        assert!(IgnoreList::new(&[String::from("/")]).is_err());
+    }
+
+    #[test]
+    fn test_ignore_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let films = dir.path().join("films");
+        fs::create_dir(&films).unwrap();
+        fs::write(dir.path().join(IGNORE_FILE), "*.nfo\nsamples/\n").unwrap();
+        fs::write(films.join(IGNORE_FILE), "# the box set\n!box.nfo\n*.srt\n[\n").unwrap();
+
+        let top = IgnoreRules::new(list(&["*.srt"]), dir.path());
+        assert!(top.is_ignored("film.nfo", false));
+        assert!(top.is_ignored("film.srt", false));
+
+        let rules = top.enter("films", &films);
+        assert!(rules.is_ignored("film.nfo", false));
+        assert!(!rules.is_ignored("box.nfo", false));
+        assert!(rules.is_ignored("samples", true));
+        assert!(!rules.is_ignored("film.mkv", false));
+        assert!(!top.is_ignored("films", true));
+    }
+}
@@ -0,0 +1,335 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+use notify::{RecursiveMode, Watcher};
+
+use crate::fs_utils::{read_children, DirectoryEntry};
+use crate::search::{share_path, Matcher, SearchResult, SearchResults};
+
+/// How long to wait for more events before applying a batch of changes
+const DEBOUNCE: Duration = Duration::from_millis(250);
+
+/// The longest a batch of changes is held back while events keep arriving
+const MAX_BATCH_WAIT: Duration = Duration::from_secs(2);
+
+pub type SharedIndex = Arc<RwLock<ShareIndex>>;
+
+/*
+An in-memory copy of every directory listing in the share. It is filled by one scan at startup and then kept up to
+date from filesystem events, so that listings and searches don't have to touch the disk. While it is stale (before
+the first scan has finished, or after events may have been lost) it answers nothing and callers read the disk.
+Symlinked directories are listed, but not followed.
+*/
+pub struct ShareIndex {
+    root: PathBuf,
+    /// Where the root is in the share, i.e. the name of its mount
+    prefix: PathBuf,
+    /// The children of every directory, keyed by the path of the directory relative to the root
+    dirs: BTreeMap<PathBuf, Vec<DirectoryEntry>>,
+    stale: bool,
+}
+
+impl ShareIndex {
+    pub fn new(root: PathBuf) -> Self {
+        ShareIndex { root, prefix: PathBuf::new(), dirs: BTreeMap::new(), stale: true }
+    }
+
+    pub fn is_stale(&self) -> bool {
+        self.stale
+    }
+
+    /// Read the whole share from scratch
+    pub fn scan(&mut self) {
+        let started = Instant::now();
+        self.dirs.clear();
+        self.scan_dir(Path::new(""));
+        self.stale = false;
+        info!("Indexed {} directories in {:?}", self.dirs.len(), started.elapsed());
+    }
+
+    fn scan_dir(&mut self, relative: &Path) {
+        let mut pending = vec![relative.to_owned()];
+        while let Some(dir) = pending.pop() {
+            let children = match read_children(&self.root.join(&dir)) {
+                Ok(children) => children,
+                Err(e) => {
+                    warn!("Could not index {:?}: {}", dir, e);
+                    continue;
+                },
+            };
+            pending.extend(children.iter().filter(|c| is_real_dir(c)).map(|c| dir.join(c.name.trim_end_matches('/'))));
+            self.dirs.insert(dir, children);
+        }
+    }
+
+    /// Forget a directory and everything under it
+    fn remove_tree(&mut self, relative: &Path) {
+        let doomed: Vec<PathBuf> = self.dirs.range(relative.to_owned()..)
+            .take_while(|(dir, _)| dir.starts_with(relative))
+            .map(|(dir, _)| dir.clone())
+            .collect();
+        for dir in doomed {
+            self.dirs.remove(&dir);
+        }
+    }
+
+    /// Bring the index up to date with whatever happened to 'path', which may have been created, changed or removed.
+    pub fn apply_change(&mut self, path: &Path) {
+        let relative = match path.strip_prefix(&self.root) {
+            Ok(relative) => relative.to_owned(),
+            Err(_) => return,
+        };
+        let (parent, name) = match (relative.parent(), relative.file_name()) {
+            (Some(parent), Some(name)) => (parent.to_owned(), name.to_string_lossy().into_owned()),
+            _ => return,
+        };
+        // Changes inside a directory we don't know about are picked up when that directory is scanned
+        if !self.dirs.contains_key(&parent) {
+            return;
+        }
+
+        let entry = DirectoryEntry::from_path(path).ok();
+        let siblings = self.dirs.get_mut(&parent).unwrap();
+        siblings.retain(|c| c.name.trim_end_matches('/') != name);
+
+        match entry {
+            Some(entry) => {
+                let is_dir = is_real_dir(&entry);
+                siblings.push(entry);
+                if !is_dir {
+                    self.remove_tree(&relative);
+                } else if !self.dirs.contains_key(&relative) {
+                    self.scan_dir(&relative);
+                }
+            },
+            None => self.remove_tree(&relative),
+        }
+    }
+
+    /// The children of the directory at the canonical path 'dir', with the sizes of directories filled in
+    pub fn children(&self, dir: &Path) -> Option<Vec<DirectoryEntry>> {
+        if self.is_stale() {
+            return None;
+        }
+
+        let relative = dir.strip_prefix(&self.root).ok()?;
+        let mut children = self.dirs.get(relative)?.clone();
+        for child in children.iter_mut().filter(|c| is_real_dir(c)) {
+            child.size = self.total_size(&relative.join(child.name.trim_end_matches('/')));
+        }
+        Some(children)
+    }
+
+    /// The combined size of every file under a directory
+    pub fn total_size(&self, relative: &Path) -> u64 {
+        self.dirs.range(relative.to_owned()..)
+            .take_while(|(dir, _)| dir.starts_with(relative))
+            .flat_map(|(_, children)| children.iter())
+            .filter(|c| !c.is_dir)
+            .map(|c| c.size)
+            .sum()
+    }
+
+    /// The same as search::search, without going to the disk
+    pub fn search<F>(&self, matcher: &Matcher, limit: usize, visible: F) -> Option<SearchResults>
+    where F: Fn(&Path) -> bool {
+        if self.is_stale() {
+            return None;
+        }
+
+        let mut results = SearchResults { results: Vec::new(), truncated: false };
+        for (dir, children) in &self.dirs {
+            for child in children {
+                let name = child.name.trim_end_matches('/');
+                let path = self.prefix.join(dir).join(name);
+                if !matcher.is_match(name) || !visible(&path) {
+                    continue;
+                }
+                if results.results.len() >= limit {
+                    results.truncated = true;
+                    return Some(results);
+                }
+                results.results.push(SearchResult {
+                    path: share_path(&path, child.is_dir),
+                    entry: child.clone(),
+                });
+            }
+        }
+        Some(results)
+    }
+}
+
+/// Search several indexes, one for each mount, as long as none of them are stale
+pub fn search_all<F>(indexes: &[SharedIndex], matcher: &Matcher, limit: usize, visible: F) -> Option<SearchResults>
+where F: Fn(&Path) -> bool {
+    let mut results = SearchResults { results: Vec::new(), truncated: false };
+    for index in indexes {
+        let found = index.read().ok()?.search(matcher, limit - results.results.len(), &visible)?;
+        results.results.extend(found.results);
+        if found.truncated {
+            results.truncated = true;
+            break;
+        }
+    }
+    Some(results)
+}
+
+/// A directory that the index descends into, i.e. not a symlink to one
+fn is_real_dir(entry: &DirectoryEntry) -> bool {
+    entry.is_dir && !entry.is_symlink
+}
+
+/*
+Index the mount at 'root', called 'prefix' in the share, on a background thread, and keep watching it for changes.
+The index is stale until the first scan has finished. If the mount can't be watched the index stays stale, so
+everything falls back to the disk.
+*/
+pub fn start(root: PathBuf, prefix: PathBuf) -> SharedIndex {
+    let root = root.canonicalize().unwrap_or(root);
+    let index = Arc::new(RwLock::new(ShareIndex { prefix, ..ShareIndex::new(root.clone()) }));
+    let updater = index.clone();
+    thread::spawn(move || watch(updater, root));
+    index
+}
+
+fn watch(index: SharedIndex, root: PathBuf) {
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = match notify::recommended_watcher(move |event| { let _ = tx.send(event); }) {
+        Ok(watcher) => watcher,
+        Err(e) => return error!("Could not start the index, listings will be read from the disk: {}", e),
+    };
+    // Start watching before the scan, so that nothing which changes during it is missed
+    if let Err(e) = watcher.watch(&root, RecursiveMode::Recursive) {
+        return error!("Could not watch {:?}, listings will be read from the disk: {}", root, e);
+    }
+    rescan(&index, &root);
+
+    while let Ok(first) = rx.recv() {
+        let mut events = vec![first];
+        let deadline = Instant::now() + MAX_BATCH_WAIT;
+        while Instant::now() < deadline {
+            match rx.recv_timeout(DEBOUNCE) {
+                Ok(event) => events.push(event),
+                Err(_) => break,
+            }
+        }
+
+        let mut changed = BTreeSet::new();
+        let mut lost_events = false;
+        for event in events {
+            match event {
+                Ok(event) => {
+                    lost_events |= event.need_rescan();
+                    changed.extend(event.paths);
+                },
+                Err(e) => {
+                    warn!("Error watching the share: {}", e);
+                    lost_events = true;
+                },
+            }
+        }
+
+        if lost_events {
+            rescan(&index, &root);
+        } else if let Ok(mut index) = index.write() {
+            for path in &changed {
+                index.apply_change(path);
+            }
+        }
+    }
+}
+
+/// Build a new index without holding the lock, so that readers fall back to the disk instead of waiting
+fn rescan(index: &SharedIndex, root: &Path) {
+    if let Ok(mut index) = index.write() {
+        index.stale = true;
+    }
+    let mut fresh = ShareIndex::new(root.to_owned());
+    fresh.scan();
+    if let Ok(mut index) = index.write() {
+        fresh.prefix = std::mem::take(&mut index.prefix);
+        *index = fresh;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use crate::search::SearchMode;
+
+    fn names(children: Option<Vec<DirectoryEntry>>) -> Vec<String> {
+        let mut names: Vec<String> = children.unwrap().into_iter().map(|c| c.name).collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn test_scan_and_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        fs::create_dir_all(root.join("shows").join("season 1")).unwrap();
+        fs::write(root.join("shows").join("season 1").join("e1.mkv"), vec![0; 10]).unwrap();
+        fs::write(root.join("shows").join("notes.txt"), vec![0; 5]).unwrap();
+
+        let mut index = ShareIndex::new(root.clone());
+        assert!(index.children(&root).is_none());
+        index.scan();
+        assert_eq!(names(index.children(&root)), vec!["shows/"]);
+        assert_eq!(index.children(&root).unwrap()[0].size, 15);
+        assert_eq!(names(index.children(&root.join("shows"))), vec!["notes.txt", "season 1/"]);
+
+        // A new directory is scanned along with what's already in it
+        fs::create_dir_all(root.join("films").join("old")).unwrap();
+        fs::write(root.join("films").join("old").join("film.mkv"), vec![0; 7]).unwrap();
+        index.apply_change(&root.join("films"));
+        assert_eq!(names(index.children(&root)), vec!["films/", "shows/"]);
+        assert_eq!(names(index.children(&root.join("films").join("old"))), vec!["film.mkv"]);
+
+        // Changed and removed files
+        fs::write(root.join("shows").join("notes.txt"), vec![0; 50]).unwrap();
+        index.apply_change(&root.join("shows").join("notes.txt"));
+        assert_eq!(index.total_size(Path::new("shows")), 60);
+
+        fs::remove_dir_all(root.join("shows").join("season 1")).unwrap();
+        index.apply_change(&root.join("shows").join("season 1"));
+        assert_eq!(names(index.children(&root.join("shows"))), vec!["notes.txt"]);
+        assert!(index.children(&root.join("shows").join("season 1")).is_none());
+
+        // Renames are reported as the old path and the new one
+        fs::rename(root.join("films"), root.join("movies")).unwrap();
+        index.apply_change(&root.join("films"));
+        index.apply_change(&root.join("movies"));
+        assert_eq!(names(index.children(&root)), vec!["movies/", "shows/"]);
+        assert_eq!(names(index.children(&root.join("movies").join("old"))), vec!["film.mkv"]);
+
+        let matcher = Matcher::new("*.mkv", SearchMode::Glob, false).unwrap();
+        let results = index.search(&matcher, 10, |_| true).unwrap();
+        assert_eq!(results.results.iter().map(|r| r.path.as_str()).collect::<Vec<&str>>(), vec!["/movies/old/film.mkv"]);
+    }
+
+    #[test]
+    fn test_index_follows_the_share() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        let index = start(root.clone(), PathBuf::new());
+
+        let wait_for = |check: &dyn Fn(&ShareIndex) -> bool| {
+            let deadline = Instant::now() + Duration::from_secs(10);
+            while !check(&index.read().unwrap()) {
+                assert!(Instant::now() < deadline, "the index did not catch up");
+                thread::sleep(Duration::from_millis(20));
+            }
+        };
+        wait_for(&|index| !index.is_stale());
+
+        fs::create_dir(root.join("new")).unwrap();
+        fs::write(root.join("new").join("file.txt"), b"hello").unwrap();
+        wait_for(&|index| index.children(&root.join("new")).is_some_and(|c| c.len() == 1 && c[0].size == 5));
+
+        fs::remove_dir_all(root.join("new")).unwrap();
+        wait_for(&|index| index.children(&root).is_some_and(|c| c.is_empty()));
+    }
+}
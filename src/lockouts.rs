@@ -0,0 +1,264 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+/// Failed attempts that are let through without being held back
+const FREE_FAILURES: u32 = 3;
+
+/// The longest an attempt to log in is held back for
+const MAX_DELAY: Duration = Duration::from_secs(8);
+
+/// Failed attempts for one username before it is locked out
+const MAX_USER_FAILURES: u32 = 10;
+
+/// Wrong passwords for one share link before it is locked out
+const MAX_SHARE_FAILURES: u32 = 10;
+
+/// Failed attempts from one address before it is locked out. This is higher than for a username because friends
+/// behind the same router share an address.
+const MAX_IP_FAILURES: u32 = 30;
+
+/// How long a lockout lasts
+const LOCKOUT_DURATION: Duration = Duration::from_secs(15 * 60);
+
+/// Failed attempts are forgotten once there haven't been any for this long
+const FAILURE_WINDOW: Duration = Duration::from_secs(15 * 60);
+
+/// What failed attempts to log in or to unlock a share are counted against
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum LockoutKey {
+    Ip(IpAddr),
+    User(String),
+    /// The id of a share link with a password
+    Share(String),
+}
+
+impl LockoutKey {
+    fn max_failures(&self) -> u32 {
+        match self {
+            LockoutKey::Ip(_) => MAX_IP_FAILURES,
+            LockoutKey::User(_) => MAX_USER_FAILURES,
+            LockoutKey::Share(_) => MAX_SHARE_FAILURES,
+        }
+    }
+}
+
+impl fmt::Display for LockoutKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LockoutKey::Ip(ip) => write!(f, "address {}", ip),
+            LockoutKey::User(username) => write!(f, "user {}", username),
+            LockoutKey::Share(id) => write!(f, "share {}", id),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Failures {
+    count: u32,
+    last: Instant,
+    locked_until: Option<Instant>,
+}
+
+impl Failures {
+    fn is_stale(&self, now: Instant) -> bool {
+        match self.locked_until {
+            Some(until) => until <= now,
+            None => now.duration_since(self.last) >= FAILURE_WINDOW,
+        }
+    }
+}
+
+/// The failed attempts counted against an address or username, for the admins
+#[derive(Debug, Clone, PartialEq)]
+pub struct FailedAttempts {
+    pub key: LockoutKey,
+    pub failures: u32,
+    /// How much longer it is locked out for, if it is
+    pub locked_for: Option<Duration>,
+}
+
+/*
+Counts failed attempts to log in against the address they came from and the username they were for, and wrong share
+passwords against the address and the share. After a few failures each attempt is held back for longer, and after too
+many the address, username or share is locked out for a while, so that passwords can't be guessed quickly and nobody
+can keep the server busy hashing them. Usernames that don't exist are counted the same as ones that do, so the answers
+don't give away which ones exist.
+*/
+#[derive(Default)]
+pub struct Lockouts {
+    failures: HashMap<LockoutKey, Failures>,
+}
+
+fn keys(ip: Option<IpAddr>, target: LockoutKey) -> Vec<LockoutKey> {
+    let mut keys = vec![target];
+    keys.extend(ip.map(LockoutKey::Ip));
+    keys
+}
+
+impl Lockouts {
+    fn expire(&mut self, now: Instant) {
+        self.failures.retain(|_, failures| !failures.is_stale(now));
+    }
+
+    /// How long to hold an attempt to log in back for, or how much longer the address or username is locked out
+    pub fn check(&mut self, ip: Option<IpAddr>, username: &str, now: Instant) -> Result<Duration, Duration> {
+        self.check_keys(keys(ip, LockoutKey::User(username.to_owned())), now)
+    }
+
+    /// How long to hold an attempt to unlock a share back for, or how much longer the address or share is locked out
+    pub fn check_share(&mut self, ip: Option<IpAddr>, id: &str, now: Instant) -> Result<Duration, Duration> {
+        self.check_keys(keys(ip, LockoutKey::Share(id.to_owned())), now)
+    }
+
+    fn check_keys(&mut self, keys: Vec<LockoutKey>, now: Instant) -> Result<Duration, Duration> {
+        self.expire(now);
+        let attempts: Vec<&Failures> = keys.iter().filter_map(|key| self.failures.get(key)).collect();
+
+        if let Some(until) = attempts.iter().filter_map(|failures| failures.locked_until).max() {
+            return Err(until - now);
+        }
+        let failures = attempts.iter().map(|failures| failures.count).max().unwrap_or(0);
+        if failures < FREE_FAILURES {
+            return Ok(Duration::from_secs(0));
+        }
+        let doublings = (failures - FREE_FAILURES).min(16);
+        Ok(Duration::from_secs(1 << doublings).min(MAX_DELAY))
+    }
+
+    /// Count a failed attempt, locking out the address or username if it has had too many
+    pub fn record_failure(&mut self, ip: Option<IpAddr>, username: &str, now: Instant) {
+        self.record_failures(keys(ip, LockoutKey::User(username.to_owned())), now);
+    }
+
+    /// Count a wrong share password, locking out the address or share if it has had too many
+    pub fn record_share_failure(&mut self, ip: Option<IpAddr>, id: &str, now: Instant) {
+        self.record_failures(keys(ip, LockoutKey::Share(id.to_owned())), now);
+    }
+
+    fn record_failures(&mut self, keys: Vec<LockoutKey>, now: Instant) {
+        self.expire(now);
+        for key in keys {
+            let failures = self.failures.entry(key.clone())
+                .or_insert(Failures { count: 0, last: now, locked_until: None });
+            failures.count += 1;
+            failures.last = now;
+            if failures.count >= key.max_failures() && failures.locked_until.is_none() {
+                failures.locked_until = Some(now + LOCKOUT_DURATION);
+                warn!("Locked out {} for {:?} after {} failed attempts", key, LOCKOUT_DURATION, failures.count);
+            }
+        }
+    }
+
+    /// Forget the failures for a username once its password has been given. Those from the address are kept, so
+    /// that someone with an account can't use it to keep guessing the passwords of others.
+    pub fn record_success(&mut self, username: &str) {
+        self.failures.remove(&LockoutKey::User(username.to_owned()));
+    }
+
+    /// Every address and username with failed attempts against it, the most failures first
+    pub fn list(&mut self, now: Instant) -> Vec<FailedAttempts> {
+        self.expire(now);
+        let mut list: Vec<FailedAttempts> = self.failures.iter()
+            .map(|(key, failures)| FailedAttempts {
+                key: key.clone(),
+                failures: failures.count,
+                locked_for: failures.locked_until.map(|until| until - now),
+            })
+            .collect();
+        list.sort_by_key(|attempts| std::cmp::Reverse(attempts.failures));
+        list
+    }
+
+    /// Forget the failures for one address or username, or for all of them, returning how many were forgotten
+    pub fn clear(&mut self, key: Option<&LockoutKey>) -> usize {
+        match key {
+            Some(key) => self.failures.remove(key).map_or(0, |_| 1),
+            None => {
+                let count = self.failures.len();
+                self.failures.clear();
+                count
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IP: Option<IpAddr> = Some(IpAddr::V4(std::net::Ipv4Addr::new(192, 168, 1, 2)));
+
+    #[test]
+    fn test_delays_and_lockout() {
+        let mut lockouts = Lockouts::default();
+        let now = Instant::now();
+        for _ in 0..FREE_FAILURES {
+            assert_eq!(lockouts.check(IP, "reader", now), Ok(Duration::from_secs(0)));
+            lockouts.record_failure(IP, "reader", now);
+        }
+
+        // Attempts are held back for longer and longer, for the username and for the address
+        assert_eq!(lockouts.check(IP, "reader", now), Ok(Duration::from_secs(1)));
+        assert_eq!(lockouts.check(None, "reader", now), Ok(Duration::from_secs(1)));
+        assert_eq!(lockouts.check(IP, "admin", now), Ok(Duration::from_secs(1)));
+        lockouts.record_failure(IP, "reader", now);
+        assert_eq!(lockouts.check(IP, "reader", now), Ok(Duration::from_secs(2)));
+        for _ in FREE_FAILURES + 1..MAX_USER_FAILURES - 1 {
+            lockouts.record_failure(IP, "reader", now);
+        }
+        assert_eq!(lockouts.check(IP, "reader", now), Ok(MAX_DELAY));
+
+        lockouts.record_failure(IP, "reader", now);
+        assert_eq!(lockouts.check(None, "reader", now), Err(LOCKOUT_DURATION));
+        assert_eq!(lockouts.check(IP, "admin", now), Ok(MAX_DELAY));
+        assert_eq!(lockouts.check(None, "admin", now), Ok(Duration::from_secs(0)));
+
+        // Lockouts end, and failures are forgotten after a while
+        assert!(lockouts.check(IP, "reader", now + LOCKOUT_DURATION).is_ok());
+        assert_eq!(lockouts.check(IP, "admin", now + FAILURE_WINDOW), Ok(Duration::from_secs(0)));
+        assert!(lockouts.list(now + FAILURE_WINDOW).is_empty());
+    }
+
+    #[test]
+    fn test_lockouts_per_address() {
+        let mut lockouts = Lockouts::default();
+        let now = Instant::now();
+        for i in 0..MAX_IP_FAILURES {
+            lockouts.record_failure(IP, &format!("user{}", i), now);
+        }
+        assert_eq!(lockouts.check(IP, "someone", now), Err(LOCKOUT_DURATION));
+        assert_eq!(lockouts.check(None, "someone", now), Ok(Duration::from_secs(0)));
+
+        let list = lockouts.list(now);
+        assert_eq!(list.len(), MAX_IP_FAILURES as usize + 1);
+        assert_eq!(list[0], FailedAttempts { key: LockoutKey::Ip(IP.unwrap()), failures: MAX_IP_FAILURES, locked_for: Some(LOCKOUT_DURATION) });
+
+        // Logging in clears the username but not the address
+        lockouts.record_success("user0");
+        assert!(!lockouts.list(now).iter().any(|attempts| attempts.key == LockoutKey::User(String::from("user0"))));
+        assert!(lockouts.check(IP, "user0", now).is_err());
+
+        assert_eq!(lockouts.clear(Some(&LockoutKey::Ip(IP.unwrap()))), 1);
+        assert_eq!(lockouts.check(IP, "someone", now), Ok(Duration::from_secs(0)));
+        assert_eq!(lockouts.clear(None), MAX_IP_FAILURES as usize - 1);
+        assert!(lockouts.list(now).is_empty());
+    }
+
+    #[test]
+    fn test_share_lockouts() {
+        let mut lockouts = Lockouts::default();
+        let now = Instant::now();
+        for _ in 0..MAX_SHARE_FAILURES {
+            assert!(lockouts.check_share(IP, "abc", now).is_ok());
+            lockouts.record_share_failure(IP, "abc", now);
+        }
+        assert_eq!(lockouts.check_share(None, "abc", now), Err(LOCKOUT_DURATION));
+        assert_eq!(lockouts.check_share(IP, "def", now), Ok(MAX_DELAY));
+        // A share and a user with the same name are counted apart
+        assert_eq!(lockouts.check(None, "abc", now), Ok(Duration::from_secs(0)));
+        assert_eq!(lockouts.clear(Some(&LockoutKey::Share(String::from("abc")))), 1);
+        assert!(lockouts.check_share(None, "abc", now).is_ok());
+    }
+}
@@ -1,8 +1,10 @@
+#![allow(clippy::needless_return, clippy::redundant_field_names, clippy::redundant_static_lifetimes)]
+
 use std::error::Error;
 use std::env;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 use warp::Filter;
-use warp::http::header::{HeaderMap, HeaderValue};
 use argon2::{
     password_hash::{
         rand_core::OsRng,
@@ -19,12 +21,28 @@ extern crate lazy_static;
 extern crate log;
 
 mod fs_utils;
+mod ignore;
 mod hb_helpers;
+mod acl;
 mod args;
+mod auth_header;
+mod credentials;
+mod upload_sessions;
+mod trash;
+mod search;
+mod index;
+mod archive;
+mod signing;
+mod sessions;
+mod lockouts;
+mod shares;
+mod short_links;
 mod webserver;
 // mod db;
 // mod db_models;
 
+use crate::fs_utils::{Mount, ServePoint};
+use crate::ignore::IgnoreList;
 use crate::webserver::{models, filters, websocket};
 
 #[tokio::main]
@@ -47,10 +65,13 @@ async fn main() -> Result<(), Box<dyn Error>> {
         return Ok(());
     }
 
-    let root_path = PathBuf::from(&config.sharedir);
+    if let Some(command) = &config.user_command {
+        credentials::run_command(command, Path::new(&config.users_file))?;
+        return Ok(());
+    }
 
-    let mut headers = HeaderMap::new();
-    headers.insert("Content-Disposition", HeaderValue::from_static("attachement"));
+    let serve_point = serve_point(&config)?;
+    lazy_static::initialize(&DUMMY_HASH);
 
     // TODO finish DB work
     // let db_conn_str = format!("host={} user=postgres password=mysecretpassword dbname=catalogue", &config.db_url);
@@ -63,27 +84,77 @@ async fn main() -> Result<(), Box<dyn Error>> {
     // }); 
 
     // Data models
-    let sp = models::new_serve_point(root_path.clone());
+    let sp = models::new_serve_point(serve_point.clone());
     let hba = models::new_handlebars_arc();
-    let users = models::new_users(config.users.clone());
+    let users = models::new_users(config.users.clone(), config.session_lifetime);
+    let users_file = models::new_users_file(PathBuf::from(&config.users_file));
     let rooms = models::Rooms::default();
     let room_cleaner = models::new_room_cleaner();
-    let urls = models::Urls::default();
+    let links = short_links::ShortLinks::load(PathBuf::from(&config.state_dir).join("links.json"))?;
+    let links = models::new_links(links);
+    let upload_sessions = upload_sessions::UploadSessions::load(
+        PathBuf::from(&config.state_dir).join("uploads"),
+        Duration::from_secs(config.upload_session_timeout),
+    )?;
+    let uploads = models::new_uploads(upload_sessions);
+    tokio::spawn(upload_sessions::expire_sessions(uploads.clone()));
+    let trash = trash::Trash::load(PathBuf::from(&config.trash_dir), &serve_point.roots(), config.trash_retention_days)?;
+    let trash = models::new_trash_bin(trash);
+    tokio::spawn(trash::purge_expired_items(trash.clone()));
+    let shares = shares::Shares::load(PathBuf::from(&config.state_dir).join("shares"))?;
+    let shares = models::new_share_links(shares);
+    tokio::spawn(shares::purge_expired_shares(shares.clone()));
+    let mut acl_config = config.acl.clone();
+    for mount in &config.mounts {
+        acl_config.add_mount(&mount.name, &mount.acl);
+    }
+    let acl = acl::Acl::new(acl_config)?;
+    acl.check_groups(&config.users)?;
+    let acl = models::new_access_rules(acl);
+    credentials::start(users.clone(), PathBuf::from(&config.users_file), acl.clone());
+    let share_index = if config.index {
+        Some(serve_point.mounts().iter().map(|mount| index::start(mount.root_path().to_owned(), PathBuf::from(mount.name()))).collect())
+    } else {
+        None
+    };
 
     // TODO finish DB work
     // let db_client = models::new_db_client(client);
 
     // Filters
     // The 'cinema' page, i.e. where users can 
-    let cinema = filters::render_cinema_page(sp.clone(), hba.clone() , users.clone());
-    let listing = filters::render_file_listing(sp, hba, users.clone());
-    let static_files = warp::path("static")
-                        .and(filters::auth(users.clone()))
-                        .and(warp::fs::dir("static"))
-                        .map(|_ : filters::Authenticated, file| file);
+    let cinema = filters::render_cinema_page(sp.clone(), acl.clone(), hba.clone() , users.clone());
+    let listing = filters::render_file_listing(sp.clone(), share_index.clone(), acl.clone(), hba.clone(), users.clone());
+    let static_files = filters::static_files(users.clone());
+    let api_list = filters::list_directory_json(sp.clone(), share_index.clone(), acl.clone(), users.clone());
+
+    // Downloading whole directories as an archive
+    let archive_limits = archive::ArchiveLimits {
+        max_files: config.max_archive_files,
+        max_size: config.max_archive_size,
+    };
+    let archive = filters::archive_filters(sp.clone(), acl.clone(), users.clone(), archive_limits);
+
+    // Links that give people without an account access to one file or directory
+    let share_api = filters::share_filters(users.clone(), sp.clone(), acl.clone(), shares.clone());
+    let share_links = filters::share_link_filters(sp.clone(), shares, users.clone(), acl.clone(), archive_limits);
+
+    // Searching for files by name, as a page and as JSON
+    let search = filters::search_page(sp.clone(), share_index.clone(), acl.clone(), hba.clone(), users.clone());
+    let api_search = filters::search_json(sp.clone(), share_index, acl.clone(), users.clone());
 
     // The endpoint used to create a Websocket cinema room
-    let create_room = filters::create_room_filter(users.clone(), rooms.clone(), room_cleaner.clone(), urls.clone());
+    let create_room = filters::create_room_filter(users.clone(), rooms.clone(), room_cleaner.clone(), links.clone());
+
+    // The endpoint used to upload files into the share
+    let upload = filters::upload_file_filter(users.clone(), sp.clone(), acl.clone(), config.max_upload_size);
+
+    // The endpoints for resumable uploads of very large files
+    let resumable_upload = filters::resumable_upload_filters(users.clone(), sp.clone(), acl.clone(), uploads, config.max_upload_size);
+
+    // The endpoints admins use to manage the files in the share
+    let admin_fs = filters::admin_fs_filters(users.clone(), sp.clone(), acl.clone(), trash.clone());
+    let admin_trash = filters::admin_trash_filters(users.clone(), sp.clone(), trash);
 
     // Endpoint to check if room exists
     let check_room = filters::check_room_filter(users.clone(), rooms.clone());
@@ -91,11 +162,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
     // The endpoint to serve files. Should be used AFTER the 'api' filter, in order 
     // to ensure that Directories get rendered as an index, and that this serves 
     // the files
-    let files = warp::path("browse")
-                    .and(filters::auth(users.clone()))
-                    .and(warp::fs::dir(root_path.clone()))
-                    .map(|_: filters::Authenticated, file| file )
-                    .with(warp::reply::with::headers(headers));
+    let files = filters::serve_files(serve_point, acl.clone(), users.clone());
 
     // The websocket endpoint used to join the rooms
     let websocket = warp::path("rooms")
@@ -112,22 +179,44 @@ async fn main() -> Result<(), Box<dyn Error>> {
     // let get_catalogue = filters::get_catalogue(users.clone(), db_client.clone());
                         
 
+    // Logging in and out with a session cookie, for browsers
+    let login = filters::login_filters(users.clone(), hba.clone());
+
     // Redirect index to browse endpoint
     let redirect = warp::path::end().map(|| warp::redirect(Uri::from_static("/browse/")));
 
     // Redirect the shortened URLS
-    let wwf_redirect = filters::wwf_redirect(users.clone(), urls);
+    let wwf_redirect = filters::wwf_redirect(users.clone(), links.clone());
+
+    // Making and managing the short links
+    let short_links = filters::short_link_filters(users.clone(), sp.clone(), acl.clone(), links);
+    let admin_users = filters::admin_users_filters(users.clone(), users_file, acl, hba.clone());
+    let lockouts = filters::lockout_filters(users.clone());
 
     // TODO finish DB work
     // let api_routes = warp::path("api").and(get_catalogue);
 
-    let routes = listing.recover(filters::recover_auth)
+    let routes = login.recover(filters::recover_auth)
+                   .or(listing.recover(filters::recover_auth))
+                   .or(api_list.recover(filters::recover_auth))
+                   .or(search.recover(filters::recover_auth))
+                   .or(archive.recover(filters::recover_auth))
+                   .or(share_api.recover(filters::recover_auth))
+                   .or(share_links.recover(filters::recover_auth))
+                   .or(api_search.recover(filters::recover_auth))
                    .or(cinema.recover(filters::recover_auth))
                    .or(create_room.recover(filters::recover_auth))
                    .or(check_room.recover(filters::recover_auth))
                    .or(files.recover(filters::recover_auth))
+                   .or(upload.recover(filters::recover_auth))
+                   .or(resumable_upload.recover(filters::recover_auth))
+                   .or(admin_fs.recover(filters::recover_auth))
+                   .or(admin_trash.recover(filters::recover_auth))
                    .or(static_files.recover(filters::recover_auth))
                    .or(wwf_redirect.recover(filters::recover_auth))
+                   .or(short_links.recover(filters::recover_auth))
+                   .or(admin_users.recover(filters::recover_auth))
+                   .or(lockouts.recover(filters::recover_auth))
                 //    .or(api_routes.recover(filters::recover_auth))
                    .or(websocket)
                    .or(redirect);
@@ -144,12 +233,32 @@ async fn main() -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
+/// The share dir, or the mounts from the config file
+fn serve_point(config: &args::Config) -> Result<ServePoint, String> {
+    let serve_point = if config.mounts.is_empty() {
+        ServePoint::new(PathBuf::from(&config.sharedir))
+    } else {
+        let mounts = config.mounts.iter()
+            .map(|mount| Mount::new(mount.name.clone(), PathBuf::from(&mount.path), mount.writable))
+            .collect::<Result<Vec<Mount>, String>>()?;
+        ServePoint::with_mounts(mounts)?
+    };
+    let ignore = IgnoreList::new(&config.ignore)?;
+    Ok(serve_point.with_symlinks(config.symlinks.clone())?.with_ignore_list(ignore))
+}
+
 pub fn hash(password: &[u8]) -> String {
     let salt = SaltString::generate(&mut OsRng);
     let argon2 = Argon2::default();
     argon2.hash_password(password, &salt).unwrap().to_string()
 }
 
+lazy_static! {
+    /// Checked when someone tries to log in as a user that doesn't exist, so that it takes as long to say no as it
+    /// does for a wrong password
+    pub static ref DUMMY_HASH: String = hash(b"not anybody's password");
+}
+
 pub fn verify(hash: &str, password: &[u8]) -> bool {
     let parsed_hash = PasswordHash::new(hash).unwrap();
     let argon2 = Argon2::default();
@@ -162,7 +271,7 @@ pub fn check_password() {
     println!("Enter ciphertext and plaintext separated by a space");
     let mut s = String::new();
     stdin().read_line(&mut s).expect("Did not enter a correct string");
-    let strings: Vec<&str> = s.trim().split(" ").into_iter().collect();
+    let strings: Vec<&str> = s.trim().split(' ').collect();
     if strings.len() != 2 {
         panic!("Expected two words separated by spaces. Got {}", strings.len())
     }
@@ -0,0 +1,192 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use globset::{GlobBuilder, GlobMatcher};
+use serde::{Deserialize, Serialize};
+
+use crate::fs_utils::{DirectoryEntry, Mount, ServePoint};
+
+/// The most results a single search can return
+pub const MAX_SEARCH_RESULTS: usize = 500;
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SearchMode {
+    /// The name contains the query
+    Substring,
+    /// The whole name matches the query as a glob, e.g. "*.mkv"
+    Glob,
+}
+
+/// Decides which names a search matches
+pub enum Matcher {
+    Substring { needle: String, case_sensitive: bool },
+    Glob(GlobMatcher),
+}
+
+impl Matcher {
+    pub fn new(query: &str, mode: SearchMode, case_sensitive: bool) -> Result<Self, globset::Error> {
+        match mode {
+            SearchMode::Substring => {
+                let needle = if case_sensitive { query.to_owned() } else { query.to_lowercase() };
+                Ok(Matcher::Substring { needle, case_sensitive })
+            },
+            SearchMode::Glob => {
+                let glob = GlobBuilder::new(query)
+                    .case_insensitive(!case_sensitive)
+                    .literal_separator(true)
+                    .build()?;
+                Ok(Matcher::Glob(glob.compile_matcher()))
+            },
+        }
+    }
+
+    pub fn is_match(&self, name: &str) -> bool {
+        match self {
+            Matcher::Substring { needle, case_sensitive: true } => name.contains(needle.as_str()),
+            Matcher::Substring { needle, case_sensitive: false } => name.to_lowercase().contains(needle.as_str()),
+            Matcher::Glob(glob) => glob.is_match(name),
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct SearchResult {
+    /// Where the entry is in the share, e.g. "/shows/season 1/episode 1.mkv"
+    pub path: String,
+    #[serde(flatten)]
+    pub entry: DirectoryEntry,
+}
+
+#[derive(Serialize, Debug)]
+pub struct SearchResults {
+    pub results: Vec<SearchResult>,
+    /// There were more matches than the limit allowed
+    pub truncated: bool,
+}
+
+/*
+Walk the whole share looking for entries whose name matches. This reads every directory, so it is blocking and
+should be run away from the async runtime. Symlinked directories are listed but not followed, so a link that points
+back up the tree can't send the walk round in circles. Every mount is searched, and only entries whose path in the
+share is 'visible' are returned.
+*/
+pub fn search<F>(sp: &ServePoint, matcher: &Matcher, limit: usize, visible: F) -> SearchResults
+where F: Fn(&Path) -> bool {
+    let mut results = SearchResults { results: Vec::new(), truncated: false };
+    let mut pending: Vec<(PathBuf, &Mount)> = sp.mounts().iter()
+        .map(|mount| (mount.root_path().to_owned(), mount))
+        .collect();
+
+    while let Some((dir, mount)) = pending.pop() {
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("Could not search {:?}: {}", dir, e);
+                continue;
+            },
+        };
+
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if entry.file_type().map(|t| t.is_dir()).unwrap_or(false) {
+                pending.push((path.clone(), mount));
+            }
+
+            let name = entry.file_name();
+            if !name.to_str().is_some_and(|name| matcher.is_match(name)) {
+                continue;
+            }
+            let relative = Path::new(mount.name()).join(path.strip_prefix(mount.root_path()).unwrap_or(&path));
+            if !visible(&relative) {
+                continue;
+            }
+
+            if results.results.len() >= limit {
+                results.truncated = true;
+                return results;
+            }
+
+            if let Ok(dir_entry) = DirectoryEntry::from_path(&path) {
+                results.results.push(SearchResult {
+                    path: share_path(&relative, dir_entry.is_dir),
+                    entry: dir_entry,
+                });
+            }
+        }
+    }
+    results
+}
+
+/// The path of an entry as it appears in the URLs of the share, given its path relative to the root
+pub fn share_path(relative: &Path, is_dir: bool) -> String {
+    let mut share_path = String::new();
+    for part in relative.iter() {
+        share_path.push('/');
+        share_path.push_str(&part.to_string_lossy());
+    }
+    if is_dir {
+        share_path.push('/');
+    }
+    share_path
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn paths(results: &SearchResults) -> Vec<String> {
+        let mut paths: Vec<String> = results.results.iter().map(|r| r.path.clone()).collect();
+        paths.sort();
+        paths
+    }
+
+    #[test]
+    fn test_matcher() {
+        let m = Matcher::new("Test", SearchMode::Substring, false).unwrap();
+        assert!(m.is_match("mytestfile.txt"));
+        assert!(!m.is_match("file.txt"));
+
+        let m = Matcher::new("Test", SearchMode::Substring, true).unwrap();
+        assert!(!m.is_match("mytestfile.txt"));
+        assert!(m.is_match("myTestfile.txt"));
+
+        let m = Matcher::new("*.TXT", SearchMode::Glob, false).unwrap();
+        assert!(m.is_match("file.txt"));
+        assert!(!m.is_match("file.txt.bak"));
+        assert!(Matcher::new("*.TXT", SearchMode::Glob, true).unwrap().is_match("FILE.TXT"));
+        assert!(!Matcher::new("*.TXT", SearchMode::Glob, true).unwrap().is_match("file.txt"));
+
+        assert!(Matcher::new("[a", SearchMode::Glob, false).is_err());
+    }
+
+    #[test]
+    fn test_search() {
+        let sp = ServePoint::new(PathBuf::from("test/testfolder"));
+
+        let results = search(&sp, &Matcher::new("file", SearchMode::Substring, false).unwrap(), 100, |_| true);
+        assert_eq!(paths(&results), vec![
+            "/file1.abc", "/file2.abc", "/folder1/file3.abc",
+            "/folder1/mytestfiles/", "/folder1/mytestfiles/testfile1.txt", "/folder1/mytestfiles/testfile2.txt",
+        ]);
+        assert!(!results.truncated);
+
+        let results = search(&sp, &Matcher::new("*.txt", SearchMode::Glob, false).unwrap(), 1, |_| true);
+        assert_eq!(results.results.len(), 1);
+        assert!(results.truncated);
+        assert_eq!(results.results[0].entry.mime, "text/plain");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_search_does_not_follow_symlinked_dirs() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("films")).unwrap();
+        fs::write(dir.path().join("films").join("film.mkv"), b"").unwrap();
+        std::os::unix::fs::symlink(dir.path(), dir.path().join("films").join("loop")).unwrap();
+
+        let sp = ServePoint::new(dir.path().to_owned());
+        let results = search(&sp, &Matcher::new("*", SearchMode::Glob, false).unwrap(), 100, |_| true);
+        assert_eq!(paths(&results), vec!["/films/", "/films/film.mkv", "/films/loop/"]);
+    }
+}
@@ -0,0 +1,193 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use chrono::{DateTime, Utc};
+
+use crate::fs_utils::generate_id;
+use crate::signing::Signer;
+
+/// The cookie that holds the session of a logged in user
+pub const SESSION_COOKIE: &str = "ffs_session";
+
+/// How long a login lasts, in seconds (7 days)
+pub const DEFAULT_SESSION_LIFETIME: u64 = 7 * 24 * 60 * 60;
+
+/// How long a username and password that have been checked are trusted for without checking them again
+const CREDENTIAL_CACHE_TTL: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Debug, Clone)]
+pub struct Session {
+    pub id: String,
+    pub username: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+/*
+The sessions of the users that have logged in through the login page. The cookie handed out is the id of the
+session signed with a key that only lives as long as the server, so a cookie can't be made up, and restarting the
+server logs everybody out.
+*/
+pub struct Sessions {
+    signer: Signer,
+    lifetime: chrono::Duration,
+    sessions: HashMap<String, Session>,
+}
+
+impl Sessions {
+    /// 'lifetime' is in seconds
+    pub fn new(lifetime: u64) -> Self {
+        let lifetime = chrono::Duration::seconds(lifetime.min(i64::MAX as u64) as i64);
+        Sessions { signer: Signer::random(), lifetime, sessions: HashMap::new() }
+    }
+
+    pub fn lifetime(&self) -> chrono::Duration {
+        self.lifetime
+    }
+
+    /// Start a session for 'username', returning the value of its cookie
+    pub fn create(&mut self, username: &str, now: DateTime<Utc>) -> String {
+        self.expire(now);
+        let session = Session {
+            id: generate_id(),
+            username: username.to_owned(),
+            expires_at: now + self.lifetime,
+        };
+        let cookie = self.signer.sign(&session.id);
+        self.sessions.insert(session.id.clone(), session);
+        cookie
+    }
+
+    /// The session a cookie belongs to, if it is genuine and hasn't expired
+    pub fn find(&self, cookie: &str, now: DateTime<Utc>) -> Option<&Session> {
+        let id = self.signer.verify(cookie)?;
+        self.sessions.get(id).filter(|session| session.expires_at > now)
+    }
+
+    /// End the session a cookie belongs to
+    pub fn remove(&mut self, cookie: &str) -> Option<Session> {
+        let id = self.signer.verify(cookie)?;
+        self.sessions.remove(id)
+    }
+
+    /// End every session of 'username', returning how many there were
+    pub fn remove_user(&mut self, username: &str) -> usize {
+        let before = self.sessions.len();
+        self.sessions.retain(|_, session| session.username != username);
+        before - self.sessions.len()
+    }
+
+    /*
+    A token for the forms on the pages shown to 'username', which has to be posted back with them, so that a form on
+    another site can't make changes with the user's cookie or saved Basic auth. It lasts as long as the server.
+    */
+    pub fn form_token(&self, username: &str) -> String {
+        self.signer.sign(&format!("form:{}", username))
+    }
+
+    pub fn check_form_token(&self, username: &str, token: &str) -> bool {
+        self.signer.verify(token) == Some(format!("form:{}", username).as_str())
+    }
+
+    fn expire(&mut self, now: DateTime<Utc>) {
+        self.sessions.retain(|_, session| session.expires_at > now);
+    }
+}
+
+struct CachedCredentials {
+    /// The password hash from the credentials file at the time, so that changing the password invalidates this
+    hash: String,
+    digest: Vec<u8>,
+    checked_at: Instant,
+}
+
+/*
+Remembers the Basic auth credentials that have recently been verified, so that scripts which send them with every
+request don't pay for an Argon2 hash each time. Passwords aren't kept, only a keyed digest of them, and the key is
+made fresh every time the server starts.
+*/
+pub struct CredentialCache {
+    signer: Signer,
+    ttl: Duration,
+    entries: HashMap<String, CachedCredentials>,
+}
+
+impl Default for CredentialCache {
+    fn default() -> Self {
+        CredentialCache { signer: Signer::random(), ttl: CREDENTIAL_CACHE_TTL, entries: HashMap::new() }
+    }
+}
+
+impl CredentialCache {
+    fn digest_input(username: &str, password: &str) -> Vec<u8> {
+        [username.as_bytes(), b"\0", password.as_bytes()].concat()
+    }
+
+    /// Whether 'password' was verified against 'hash' for 'username' recently
+    pub fn check(&self, username: &str, hash: &str, password: &str, now: Instant) -> bool {
+        match self.entries.get(username) {
+            Some(entry) => entry.hash == hash
+                && now.duration_since(entry.checked_at) < self.ttl
+                && self.signer.verify_signature(&Self::digest_input(username, password), &entry.digest),
+            None => false,
+        }
+    }
+
+    /// Remember that 'password' is right for 'username', whose password hash is 'hash'
+    pub fn remember(&mut self, username: &str, hash: &str, password: &str, now: Instant) {
+        let ttl = self.ttl;
+        self.entries.retain(|_, entry| now.duration_since(entry.checked_at) < ttl);
+        let digest = self.signer.signature(&Self::digest_input(username, password));
+        self.entries.insert(username.to_owned(), CachedCredentials { hash: hash.to_owned(), digest, checked_at: now });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sessions() {
+        let mut sessions = Sessions::new(60);
+        let now = Utc::now();
+        let cookie = sessions.create("reader", now);
+        assert_eq!(sessions.find(&cookie, now).unwrap().username, "reader");
+
+        // Cookies can't be forged or used after they expire
+        let (id, _) = cookie.split_once('.').unwrap();
+        assert!(sessions.find(id, now).is_none());
+        assert!(sessions.find(&format!("{}.forged", id), now).is_none());
+        assert!(Sessions::new(60).find(&cookie, now).is_none());
+        assert!(sessions.find(&cookie, now + chrono::Duration::seconds(61)).is_none());
+
+        // Logging out ends the session
+        assert!(sessions.remove(&cookie).is_some());
+        assert!(sessions.find(&cookie, now).is_none());
+
+        sessions.create("reader", now);
+        sessions.create("reader", now);
+        let admin = sessions.create("admin", now);
+        assert_eq!(sessions.remove_user("reader"), 2);
+        assert!(sessions.find(&admin, now).is_some());
+
+        // Form tokens only work for the user they were made for, and can't be used as cookies
+        let token = sessions.form_token("admin");
+        assert!(sessions.check_form_token("admin", &token));
+        assert!(!sessions.check_form_token("reader", &token));
+        assert!(!sessions.check_form_token("admin", &admin));
+        assert!(!Sessions::new(60).check_form_token("admin", &token));
+        assert!(sessions.find(&token, now).is_none());
+    }
+
+    #[test]
+    fn test_credential_cache() {
+        let mut cache = CredentialCache::default();
+        let now = Instant::now();
+        assert!(!cache.check("reader", "hash", "password", now));
+
+        cache.remember("reader", "hash", "password", now);
+        assert!(cache.check("reader", "hash", "password", now));
+        assert!(!cache.check("reader", "hash", "wrong", now));
+        assert!(!cache.check("reader", "new hash", "password", now));
+        assert!(!cache.check("admin", "hash", "password", now));
+        assert!(!cache.check("reader", "hash", "password", now + CREDENTIAL_CACHE_TTL));
+    }
+}
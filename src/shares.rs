@@ -0,0 +1,268 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use tokio::time;
+
+use crate::fs_utils::{generate_id, write_atomic};
+use crate::signing::Signer;
+
+/// How long a share link lasts when no expiry is given, in seconds (7 days)
+pub const DEFAULT_SHARE_LIFETIME: i64 = 7 * 24 * 60 * 60;
+
+/// The longest a share link can last, in seconds (1 year)
+pub const MAX_SHARE_LIFETIME: i64 = 365 * 24 * 60 * 60;
+
+/// How often to look for share links that have expired, in seconds
+const PURGE_CHECK_INTERVAL: u64 = 60 * 60;
+
+const SECRET_FILE: &str = "secret";
+const SHARES_FILE: &str = "shares.json";
+
+/// A link that gives anyone who has it read access to one file or directory, without an account.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Share {
+    pub id: String,
+    /// What is shared, relative to the share root
+    pub path: PathBuf,
+    pub is_dir: bool,
+    pub created_by: String,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub max_downloads: Option<u32>,
+    pub downloads: u32,
+    /// The Argon2 hash of the password, if the link needs one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub password_hash: Option<String>,
+}
+
+impl Share {
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at <= now
+    }
+
+    pub fn is_used_up(&self) -> bool {
+        self.max_downloads.is_some_and(|max| self.downloads >= max)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ShareError {
+    /// The token is forged, or the share has been revoked
+    NotFound,
+    /// The share has expired or has been downloaded as many times as it is allowed to be
+    Expired,
+    Io(String),
+}
+
+/*
+The share links live in their own directory in the state dir. Each link is known by a random id, and the token that
+is handed out is the id signed with a secret that never leaves the server, so that tokens can't be guessed or made
+up and a revoked link is dead for good. The secret is created the first time the server runs, and the details of
+every link are kept in an index file so that they survive a restart.
+*/
+pub struct Shares {
+    dir: PathBuf,
+    signer: Signer,
+    shares: HashMap<String, Share>,
+}
+
+impl Shares {
+    /// Load the share links kept in 'dir', creating the directory and the secret if they don't exist yet.
+    pub fn load(dir: PathBuf) -> Result<Self, String> {
+        fs::create_dir_all(&dir).map_err(|e| format!("Could not create shares directory {:?}: {}", dir, e))?;
+
+        let signer = Signer::load_or_create(&dir.join(SECRET_FILE))?;
+
+        let shares_path = dir.join(SHARES_FILE);
+        let shares = if shares_path.exists() {
+            let contents = fs::read_to_string(&shares_path).map_err(|e| format!("{}", e))?;
+            serde_json::from_str(&contents).map_err(|e| format!("Error reading {:?}: {}", shares_path, e))?
+        } else {
+            HashMap::new()
+        };
+
+        Ok(Shares { dir, signer, shares })
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let json = serde_json::to_vec_pretty(&self.shares).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        write_atomic(&self.dir.join(SHARES_FILE), &json)
+    }
+
+    /// The token handed out for a share, '<id>.<signature>'
+    pub fn token(&self, share: &Share) -> String {
+        self.signer.sign(&share.id)
+    }
+
+    pub fn create(
+        &mut self,
+        path: PathBuf,
+        is_dir: bool,
+        created_by: &str,
+        expires_at: DateTime<Utc>,
+        max_downloads: Option<u32>,
+        password_hash: Option<String>,
+    ) -> io::Result<Share> {
+        let share = Share {
+            id: generate_id(),
+            path,
+            is_dir,
+            created_by: created_by.to_owned(),
+            created_at: Utc::now(),
+            expires_at,
+            max_downloads,
+            downloads: 0,
+            password_hash,
+        };
+
+        self.shares.insert(share.id.clone(), share.clone());
+        if let Err(e) = self.save() {
+            self.shares.remove(&share.id);
+            return Err(e);
+        }
+        Ok(share)
+    }
+
+    /// The share a token points to, as long as it can still be used
+    pub fn find(&self, token: &str, now: DateTime<Utc>) -> Result<&Share, ShareError> {
+        let id = self.signer.verify(token).ok_or(ShareError::NotFound)?;
+        let share = self.shares.get(id).ok_or(ShareError::NotFound)?;
+        if share.is_expired(now) || share.is_used_up() {
+            return Err(ShareError::Expired);
+        }
+        Ok(share)
+    }
+
+    /// Count a download of the share a token points to, returning the share if it could still be used
+    pub fn record_download(&mut self, token: &str, now: DateTime<Utc>) -> Result<Share, ShareError> {
+        let id = self.find(token, now)?.id.clone();
+        let share = self.shares.get_mut(&id).ok_or(ShareError::NotFound)?;
+        share.downloads += 1;
+        let share = share.clone();
+        self.save().map_err(|e| ShareError::Io(e.to_string()))?;
+        Ok(share)
+    }
+
+    pub fn get(&self, id: &str) -> Option<&Share> {
+        self.shares.get(id)
+    }
+
+    /// Revoke a share, after which its token no longer works
+    pub fn revoke(&mut self, id: &str) -> io::Result<Option<Share>> {
+        let share = self.shares.remove(id);
+        if share.is_some() {
+            self.save()?;
+        }
+        Ok(share)
+    }
+
+    /// The shares made by 'created_by', or every share when it is None, newest first
+    pub fn list(&self, created_by: Option<&str>) -> Vec<Share> {
+        let mut shares: Vec<Share> = self.shares.values()
+            .filter(|share| created_by.is_none_or(|user| share.created_by == user))
+            .cloned()
+            .collect();
+        shares.sort_by_key(|share| std::cmp::Reverse(share.created_at));
+        shares
+    }
+
+    /// Forget about shares that have expired, returning them
+    pub fn purge_expired(&mut self, now: DateTime<Utc>) -> Vec<Share> {
+        let expired: Vec<String> = self.shares.values()
+            .filter(|share| share.is_expired(now))
+            .map(|share| share.id.clone())
+            .collect();
+        let expired: Vec<Share> = expired.iter().filter_map(|id| self.shares.remove(id)).collect();
+
+        if !expired.is_empty() {
+            if let Err(e) = self.save() {
+                warn!("Could not save the share links: {}", e);
+            }
+        }
+        expired
+    }
+}
+
+/// Periodically forget share links that have expired.
+pub async fn purge_expired_shares(shares: Arc<Mutex<Shares>>) {
+    let mut interval = time::interval(time::Duration::from_secs(PURGE_CHECK_INTERVAL));
+    loop {
+        interval.tick().await;
+        shares.lock().await.purge_expired(Utc::now());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    fn create(shares: &mut Shares, max_downloads: Option<u32>) -> Share {
+        let expires_at = Utc::now() + Duration::hours(1);
+        shares.create(PathBuf::from("film.mkv"), false, "uploader", expires_at, max_downloads, None).unwrap()
+    }
+
+    #[test]
+    fn test_tokens() {
+        let state = tempfile::tempdir().unwrap();
+        let mut shares = Shares::load(state.path().to_owned()).unwrap();
+        let share = create(&mut shares, None);
+        let token = shares.token(&share);
+        assert_eq!(shares.find(&token, Utc::now()).unwrap().path, PathBuf::from("film.mkv"));
+
+        // Forged and mangled tokens are refused
+        let forged = format!("{}.{}", share.id, base64::encode_config([0u8; 32], base64::URL_SAFE_NO_PAD));
+        for token in &[forged.as_str(), share.id.as_str(), "", ".", "a.b", &token[..token.len() - 1]] {
+            assert_eq!(shares.find(token, Utc::now()).unwrap_err(), ShareError::NotFound, "{}", token);
+        }
+
+        // Tokens from another server don't work either
+        let other_state = tempfile::tempdir().unwrap();
+        let other = Shares::load(other_state.path().to_owned()).unwrap();
+        assert_ne!(other.token(&share), token);
+        assert_eq!(shares.find(&other.token(&share), Utc::now()).unwrap_err(), ShareError::NotFound);
+    }
+
+    #[test]
+    fn test_shares_survive_reload() {
+        let state = tempfile::tempdir().unwrap();
+        let mut shares = Shares::load(state.path().to_owned()).unwrap();
+        let share = create(&mut shares, Some(2));
+        let token = shares.token(&share);
+        shares.record_download(&token, Utc::now()).unwrap();
+
+        let mut reloaded = Shares::load(state.path().to_owned()).unwrap();
+        assert_eq!(reloaded.token(&share), token);
+        assert_eq!(reloaded.get(&share.id).unwrap().downloads, 1);
+        assert_eq!(reloaded.record_download(&token, Utc::now()).unwrap().downloads, 2);
+        assert_eq!(reloaded.record_download(&token, Utc::now()).unwrap_err(), ShareError::Expired);
+    }
+
+    #[test]
+    fn test_expiry_and_revoking() {
+        let state = tempfile::tempdir().unwrap();
+        let mut shares = Shares::load(state.path().to_owned()).unwrap();
+        let share = create(&mut shares, None);
+        let token = shares.token(&share);
+
+        let later = Utc::now() + Duration::hours(2);
+        assert_eq!(shares.find(&token, later).unwrap_err(), ShareError::Expired);
+        assert!(shares.purge_expired(Utc::now()).is_empty());
+        assert_eq!(shares.purge_expired(later).len(), 1);
+        assert_eq!(shares.find(&token, Utc::now()).unwrap_err(), ShareError::NotFound);
+
+        let share = create(&mut shares, None);
+        let token = shares.token(&share);
+        assert_eq!(shares.list(Some("uploader")).len(), 1);
+        assert!(shares.list(Some("someone else")).is_empty());
+        assert!(shares.revoke(&share.id).unwrap().is_some());
+        assert!(shares.revoke(&share.id).unwrap().is_none());
+        assert_eq!(shares.find(&token, Utc::now()).unwrap_err(), ShareError::NotFound);
+        assert!(Shares::load(state.path().to_owned()).unwrap().list(None).is_empty());
+    }
+}
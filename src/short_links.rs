@@ -0,0 +1,250 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use chrono::{DateTime, Utc};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::fs_utils::write_atomic;
+
+const SLUG_CHARSET: &[u8] = b"abcdefghijkmnpqrstuvwxyzABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+const RANDOM_SLUG_LEN: usize = 6;
+const MAX_SLUG_LEN: usize = 64;
+
+/// A short url, '/wwf/<slug>', that redirects to somewhere on the server
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ShortLink {
+    pub slug: String,
+    /// Where the link redirects to, always a path on this server
+    pub target: String,
+    pub created_by: String,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub hits: u64,
+}
+
+impl ShortLink {
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum LinkError {
+    /// Slugs are made of letters, digits, '-' and '_'
+    InvalidSlug,
+    /// Links can only point to paths on this server
+    InvalidTarget,
+    SlugTaken,
+    Io(String),
+}
+
+/// Whether a slug can be used in a url as it is
+pub fn is_valid_slug(slug: &str) -> bool {
+    !slug.is_empty()
+        && slug.len() <= MAX_SLUG_LEN
+        && slug.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+/// A path like '/browse/film.mkv', but not '//elsewhere.com/', which browsers treat as another site
+pub fn is_local_target(target: &str) -> bool {
+    target.starts_with('/') && !target.starts_with("//") && !target.contains('\\')
+}
+
+/*
+Every short link, kept in a JSON file in the state dir so that links outlive the server. Links are made for the
+watch with friends rooms and by users for any path in the share, and can be given an expiry.
+*/
+pub struct ShortLinks {
+    file: PathBuf,
+    links: HashMap<String, ShortLink>,
+}
+
+impl ShortLinks {
+    /// Load the links saved in 'file', if it exists yet
+    pub fn load(file: PathBuf) -> Result<Self, String> {
+        if let Some(dir) = file.parent() {
+            fs::create_dir_all(dir).map_err(|e| format!("Could not create {:?}: {}", dir, e))?;
+        }
+
+        let links = if file.exists() {
+            let contents = fs::read_to_string(&file).map_err(|e| format!("{}", e))?;
+            serde_json::from_str(&contents).map_err(|e| format!("Error reading {:?}: {}", file, e))?
+        } else {
+            HashMap::new()
+        };
+
+        info!("Loaded {} short link(s) from {:?}", links.len(), file);
+        Ok(ShortLinks { file, links })
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let json = serde_json::to_vec_pretty(&self.links).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        write_atomic(&self.file, &json)
+    }
+
+    pub fn contains(&self, slug: &str) -> bool {
+        self.links.contains_key(slug)
+    }
+
+    fn random_slug(&self) -> String {
+        let mut rng = rand::thread_rng();
+        loop {
+            let slug: String = (0..RANDOM_SLUG_LEN)
+                .map(|_| SLUG_CHARSET[rng.gen_range(0, SLUG_CHARSET.len())] as char)
+                .collect();
+            if !self.contains(&slug) {
+                return slug;
+            }
+        }
+    }
+
+    /// Add a link to 'target'. A random slug is made up when none is given.
+    pub fn create(
+        &mut self,
+        slug: Option<&str>,
+        target: &str,
+        created_by: &str,
+        expires_at: Option<DateTime<Utc>>,
+    ) -> Result<ShortLink, LinkError> {
+        if !is_local_target(target) {
+            return Err(LinkError::InvalidTarget);
+        }
+        let slug = match slug {
+            Some(slug) if !is_valid_slug(slug) => return Err(LinkError::InvalidSlug),
+            Some(slug) if self.links.get(slug).is_some_and(|link| !link.is_expired(Utc::now())) => return Err(LinkError::SlugTaken),
+            Some(slug) => slug.to_owned(),
+            None => self.random_slug(),
+        };
+
+        let link = ShortLink {
+            slug: slug.clone(),
+            target: target.to_owned(),
+            created_by: created_by.to_owned(),
+            created_at: Utc::now(),
+            expires_at,
+            hits: 0,
+        };
+        let replaced = self.links.insert(slug.clone(), link.clone());
+        if let Err(e) = self.save() {
+            match replaced {
+                Some(old) => self.links.insert(slug, old),
+                None => self.links.remove(&slug),
+            };
+            return Err(LinkError::Io(e.to_string()));
+        }
+        Ok(link)
+    }
+
+    /// Where a slug redirects to, counting the hit. Expired links don't go anywhere.
+    pub fn resolve(&mut self, slug: &str, now: DateTime<Utc>) -> Option<String> {
+        let link = self.links.get_mut(slug).filter(|link| !link.is_expired(now))?;
+        link.hits += 1;
+        let target = link.target.clone();
+
+        // Losing a hit isn't worth failing the redirect over
+        if let Err(e) = self.save() {
+            warn!("Could not save the short links: {}", e);
+        }
+        Some(target)
+    }
+
+    pub fn delete(&mut self, slug: &str) -> io::Result<Option<ShortLink>> {
+        let link = self.links.remove(slug);
+        if link.is_some() {
+            self.save()?;
+        }
+        Ok(link)
+    }
+
+    /// Every link, newest first
+    pub fn list(&self) -> Vec<ShortLink> {
+        let mut links: Vec<ShortLink> = self.links.values().cloned().collect();
+        links.sort_by_key(|link| std::cmp::Reverse(link.created_at));
+        links
+    }
+
+    /// Forget about links that have expired, returning them
+    pub fn purge_expired(&mut self, now: DateTime<Utc>) -> Vec<ShortLink> {
+        let expired: Vec<String> = self.links.values()
+            .filter(|link| link.is_expired(now))
+            .map(|link| link.slug.clone())
+            .collect();
+        let expired: Vec<ShortLink> = expired.iter().filter_map(|slug| self.links.remove(slug)).collect();
+
+        if !expired.is_empty() {
+            if let Err(e) = self.save() {
+                warn!("Could not save the short links: {}", e);
+            }
+        }
+        expired
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    #[test]
+    fn test_slugs() {
+        assert!(is_valid_slug("film-night_2"));
+        assert!(!is_valid_slug(""));
+        assert!(!is_valid_slug("a/b"));
+        assert!(!is_valid_slug("film night"));
+        assert!(!is_valid_slug(&"a".repeat(MAX_SLUG_LEN + 1)));
+
+        assert!(is_local_target("/browse/film.mkv"));
+        assert!(!is_local_target("//example.com/"));
+        assert!(!is_local_target("/\\example.com/"));
+        assert!(!is_local_target("https://example.com/"));
+    }
+
+    #[test]
+    fn test_links_survive_reload() {
+        let state = tempfile::tempdir().unwrap();
+        let file = state.path().join("links.json");
+        let mut links = ShortLinks::load(file.clone()).unwrap();
+
+        let custom = links.create(Some("films"), "/browse/films/", "uploader", None).unwrap();
+        let random = links.create(None, "/browse/film.mkv", "admin", None).unwrap();
+        assert_eq!(random.slug.len(), RANDOM_SLUG_LEN);
+        assert!(is_valid_slug(&random.slug));
+        assert_eq!(links.create(Some("films"), "/browse/", "admin", None).unwrap_err(), LinkError::SlugTaken);
+        assert_eq!(links.create(Some("a b"), "/browse/", "admin", None).unwrap_err(), LinkError::InvalidSlug);
+        assert_eq!(links.create(None, "http://example.com", "admin", None).unwrap_err(), LinkError::InvalidTarget);
+
+        assert_eq!(links.resolve("films", Utc::now()), Some(String::from("/browse/films/")));
+        assert_eq!(links.resolve("missing", Utc::now()), None);
+
+        let mut reloaded = ShortLinks::load(file.clone()).unwrap();
+        let list = reloaded.list();
+        assert_eq!(list.len(), 2);
+        let films = list.iter().find(|link| link.slug == custom.slug).unwrap();
+        assert_eq!(films.hits, 1);
+        assert_eq!(films.created_by, "uploader");
+
+        assert!(reloaded.delete("films").unwrap().is_some());
+        assert!(reloaded.delete("films").unwrap().is_none());
+        assert!(!ShortLinks::load(file).unwrap().contains("films"));
+    }
+
+    #[test]
+    fn test_links_expire() {
+        let state = tempfile::tempdir().unwrap();
+        let mut links = ShortLinks::load(state.path().join("links.json")).unwrap();
+        links.create(Some("room"), "/cinema/film.mkv", "reader", Some(Utc::now() + Duration::hours(1))).unwrap();
+
+        let later = Utc::now() + Duration::hours(2);
+        assert_eq!(links.resolve("room", later), None);
+        assert!(links.purge_expired(Utc::now()).is_empty());
+        assert_eq!(links.purge_expired(later).len(), 1);
+
+        // An expired slug can be used again, even before it has been purged
+        links.create(Some("room"), "/cinema/film.mkv", "reader", Some(Utc::now() - Duration::hours(1))).unwrap();
+        assert_eq!(links.resolve("room", Utc::now()), None);
+        links.create(Some("room"), "/cinema/other.mkv", "reader", None).unwrap();
+        assert_eq!(links.resolve("room", later), Some(String::from("/cinema/other.mkv")));
+    }
+}
@@ -0,0 +1,106 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use hmac::{Hmac, Mac, NewMac};
+use rand::RngCore;
+use sha2::Sha256;
+
+const KEY_LEN: usize = 32;
+
+/*
+Signs values with a key that never leaves the server, so that a value handed to a client, e.g. in a url or a
+cookie, can be trusted when it comes back. A signed value is '<value>.<signature>'.
+*/
+pub struct Signer {
+    key: Vec<u8>,
+}
+
+impl Signer {
+    /// A signer with a new key, for values that don't need to outlive the server
+    pub fn random() -> Self {
+        let mut key = vec![0u8; KEY_LEN];
+        rand::thread_rng().fill_bytes(&mut key);
+        Signer { key }
+    }
+
+    /// A signer with the key kept in 'path', which is made the first time the server runs
+    pub fn load_or_create(path: &Path) -> Result<Self, String> {
+        match fs::read(path) {
+            Ok(key) if key.len() >= KEY_LEN => Ok(Signer { key }),
+            Ok(_) => Err(format!("The key {:?} is too short", path)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let signer = Signer::random();
+                write_key(path, &signer.key).map_err(|e| format!("Could not write {:?}: {}", path, e))?;
+                info!("Created a new key in {:?}", path);
+                Ok(signer)
+            },
+            Err(e) => Err(format!("Could not read {:?}: {}", path, e)),
+        }
+    }
+
+    fn mac(&self) -> Hmac<Sha256> {
+        Hmac::<Sha256>::new_varkey(&self.key).expect("HMAC takes keys of any length")
+    }
+
+    pub fn signature(&self, data: &[u8]) -> Vec<u8> {
+        let mut mac = self.mac();
+        mac.update(data);
+        mac.finalize().into_bytes().to_vec()
+    }
+
+    /// Whether 'signature' was made for 'data' by this signer. The comparison takes the same time however much of
+    /// the signature is right.
+    pub fn verify_signature(&self, data: &[u8], signature: &[u8]) -> bool {
+        let mut mac = self.mac();
+        mac.update(data);
+        mac.verify(signature).is_ok()
+    }
+
+    pub fn sign(&self, value: &str) -> String {
+        let signature = base64::encode_config(self.signature(value.as_bytes()), base64::URL_SAFE_NO_PAD);
+        format!("{}.{}", value, signature)
+    }
+
+    /// The value that was signed, if the signature is good
+    pub fn verify<'a>(&self, signed: &'a str) -> Option<&'a str> {
+        let (value, signature) = signed.rsplit_once('.')?;
+        let signature = base64::decode_config(signature, base64::URL_SAFE_NO_PAD).ok()?;
+        if self.verify_signature(value.as_bytes(), &signature) { Some(value) } else { None }
+    }
+}
+
+/// The key is only readable by the server
+fn write_key(path: &Path, key: &[u8]) -> io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    io::Write::write_all(&mut options.open(path)?, key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_signing() {
+        let dir = tempfile::tempdir().unwrap();
+        let signer = Signer::load_or_create(&dir.path().join("key")).unwrap();
+        let signed = signer.sign("abc");
+        assert_eq!(signer.verify(&signed), Some("abc"));
+
+        // The key is kept, and nobody else's key works
+        assert_eq!(Signer::load_or_create(&dir.path().join("key")).unwrap().verify(&signed), Some("abc"));
+        assert_eq!(Signer::random().verify(&signed), None);
+
+        for forged in &["abc", "abc.", ".", "", "abd.xyz", &signed[..signed.len() - 1], &signed.replace("abc", "abd")] {
+            assert_eq!(signer.verify(forged), None, "{}", forged);
+        }
+
+        fs::write(dir.path().join("short"), b"key").unwrap();
+        assert!(Signer::load_or_create(&dir.path().join("short")).is_err());
+    }
+}
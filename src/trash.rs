@@ -0,0 +1,404 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use tokio::task;
+use tokio::time;
+
+use crate::fs_utils::{generate_id, move_entry, write_atomic, FsOpError, ServePoint};
+
+/// How often to look for trash items that are old enough to be purged, in seconds
+const PURGE_CHECK_INTERVAL: u64 = 60 * 60;
+
+const INDEX_FILE: &str = "index.json";
+
+/// Something that was deleted through the server, and can still be restored.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TrashItem {
+    pub id: String,
+    /// Where the entry used to be, relative to the share root
+    pub original_path: PathBuf,
+    pub is_dir: bool,
+    pub deleted_by: String,
+    pub deleted_at: DateTime<Utc>,
+}
+
+/*
+The trash lives in its own directory outside of the share, so that nothing in it can be browsed. Each deleted
+entry is kept in a subdirectory named after its id, and the details of every item are kept in an index file.
+*/
+pub struct Trash {
+    dir: PathBuf,
+    retention: Duration,
+    items: HashMap<String, TrashItem>,
+    /// The original paths of the items that are being moved into or out of the trash right now
+    moving: HashSet<PathBuf>,
+}
+
+/*
+An entry on its way into or out of the trash. Moving it can take a long time when it has to be copied to another
+disk, so it's done without holding on to the trash or the serve point, between a start and a finish.
+*/
+pub struct PendingMove {
+    item: TrashItem,
+    from: PathBuf,
+    to: PathBuf,
+    /// The directories that were made for a restore, to be removed again if it fails
+    created: Vec<PathBuf>,
+}
+
+impl PendingMove {
+    /// Move the entry. This blocks, so shouldn't be run on the server's own thread.
+    pub fn run(&self) -> io::Result<()> {
+        move_entry(&self.from, &self.to)
+    }
+}
+
+/// An item on its way out of the trash for good, which like a move is done between a start and a finish
+pub struct PendingPurge {
+    item: TrashItem,
+    dir: PathBuf,
+}
+
+impl PendingPurge {
+    /// Remove what is kept of the item. This blocks, so shouldn't be run on the server's own thread.
+    pub fn run(&self) -> io::Result<()> {
+        if self.dir.exists() {
+            fs::remove_dir_all(&self.dir)?;
+        }
+        Ok(())
+    }
+}
+
+/// Remove directories that were made, deepest first, as long as nothing has been put in them since
+fn remove_dirs(dirs: &[PathBuf]) {
+    for dir in dirs.iter().rev() {
+        let _ = fs::remove_dir(dir);
+    }
+}
+
+impl Trash {
+    /// Load the trash kept in 'dir', creating it if needed. 'dir' must not be inside of any of the 'share_roots'.
+    pub fn load(dir: PathBuf, share_roots: &[PathBuf], retention_days: u32) -> Result<Self, String> {
+        fs::create_dir_all(&dir).map_err(|e| format!("Could not create trash directory {:?}: {}", dir, e))?;
+        let dir = dir.canonicalize().map_err(|e| format!("{}", e))?;
+        for share_root in share_roots {
+            let share_root = share_root.canonicalize().map_err(|e| format!("{}", e))?;
+            if dir.starts_with(&share_root) {
+                return Err(format!("The trash directory {:?} must be outside of the share {:?}", dir, share_root));
+            }
+        }
+
+        let index_path = dir.join(INDEX_FILE);
+        let items = if index_path.exists() {
+            let contents = fs::read_to_string(&index_path).map_err(|e| format!("{}", e))?;
+            serde_json::from_str(&contents).map_err(|e| format!("Error reading {:?}: {}", index_path, e))?
+        } else {
+            HashMap::new()
+        };
+
+        Ok(Trash { dir, retention: Duration::days(retention_days.into()), items, moving: HashSet::new() })
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let json = serde_json::to_vec_pretty(&self.items).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        write_atomic(&self.dir.join(INDEX_FILE), &json)
+    }
+
+    fn item_dir(&self, id: &str) -> PathBuf {
+        self.dir.join(id)
+    }
+
+    fn item_path(&self, item: &TrashItem) -> PathBuf {
+        let name = item.original_path.file_name().map(|n| n.to_owned()).unwrap_or_default();
+        self.item_dir(&item.id).join(name)
+    }
+
+    /// All the items in the trash, most recently deleted first
+    pub fn items(&self) -> Vec<TrashItem> {
+        let mut items: Vec<TrashItem> = self.items.values().cloned().collect();
+        items.sort_by_key(|item| std::cmp::Reverse(item.deleted_at));
+        items
+    }
+
+    /// Get ready to move an entry out of the share and into the trash. Directories have to be empty unless
+    /// 'recursive' is set.
+    pub fn start_delete(&mut self, sp: &ServePoint, p: &Path, recursive: bool, user: &str) -> Result<PendingMove, FsOpError> {
+        if self.moving.contains(p) {
+            return Err(FsOpError::NotFound);
+        }
+        let path = sp.get_path_to_delete(p, recursive)?;
+        let item = TrashItem {
+            id: generate_id(),
+            original_path: p.to_owned(),
+            is_dir: path.symlink_metadata()?.is_dir(),
+            deleted_by: user.to_owned(),
+            deleted_at: Utc::now(),
+        };
+
+        fs::create_dir(self.item_dir(&item.id))?;
+        self.moving.insert(item.original_path.clone());
+        let to = self.item_path(&item);
+        Ok(PendingMove { item, from: path, to, created: Vec::new() })
+    }
+
+    /// Add the item once it has been moved, or clean up after a move that failed
+    pub fn finish_delete(&mut self, pending: PendingMove, result: io::Result<()>) -> Result<TrashItem, FsOpError> {
+        let item = pending.item;
+        self.moving.remove(&item.original_path);
+        if let Err(e) = result {
+            let _ = fs::remove_dir(self.item_dir(&item.id));
+            return Err(e.into());
+        }
+
+        self.items.insert(item.id.clone(), item.clone());
+        self.save()?;
+        Ok(item)
+    }
+
+    /// Get ready to put an item back where it was deleted from. Nothing that has been created there since is replaced.
+    /// The item is left out of the trash until the move is finished.
+    pub fn start_restore(&mut self, sp: &ServePoint, id: &str) -> Result<PendingMove, FsOpError> {
+        let item = self.items.get(id).cloned().ok_or(FsOpError::NotFound)?;
+        if self.moving.contains(&item.original_path) || sp.get_entry_path(&item.original_path).is_some() {
+            return Err(FsOpError::AlreadyExists);
+        }
+
+        let created = sp.make_parent_dirs(&item.original_path)?;
+        let to = match sp.get_new_file_path(&item.original_path) {
+            Some(to) => to,
+            None => {
+                remove_dirs(&created);
+                return Err(FsOpError::NotFound);
+            },
+        };
+
+        self.items.remove(id);
+        self.moving.insert(item.original_path.clone());
+        let from = self.item_path(&item);
+        Ok(PendingMove { item, from, to, created })
+    }
+
+    /// Forget the item once it has been put back, or return it to the trash if that failed
+    pub fn finish_restore(&mut self, pending: PendingMove, result: io::Result<()>) -> Result<TrashItem, FsOpError> {
+        let item = pending.item;
+        self.moving.remove(&item.original_path);
+        if let Err(e) = result {
+            remove_dirs(&pending.created);
+            self.items.insert(item.id.clone(), item);
+            return Err(e.into());
+        }
+
+        let _ = fs::remove_dir(self.item_dir(&item.id));
+        self.save()?;
+        Ok(item)
+    }
+
+    /// Get ready to delete an item for good. The item is left out of the trash until the purge is finished.
+    pub fn start_purge(&mut self, id: &str) -> Result<PendingPurge, FsOpError> {
+        let item = self.items.remove(id).ok_or(FsOpError::NotFound)?;
+        let dir = self.item_dir(id);
+        Ok(PendingPurge { item, dir })
+    }
+
+    /// Forget the item once it has been removed, or return it to the trash if that failed
+    pub fn finish_purge(&mut self, pending: PendingPurge, result: io::Result<()>) -> Result<TrashItem, FsOpError> {
+        let item = pending.item;
+        if let Err(e) = result {
+            self.items.insert(item.id.clone(), item);
+            return Err(e.into());
+        }
+
+        self.save()?;
+        Ok(item)
+    }
+
+    /// Get ready to purge every item that has been in the trash for longer than the retention period
+    pub fn start_purge_expired(&mut self, now: DateTime<Utc>) -> Vec<PendingPurge> {
+        let expired: Vec<String> = self.items.values()
+            .filter(|item| item.deleted_at + self.retention <= now)
+            .map(|item| item.id.clone())
+            .collect();
+        expired.iter().filter_map(|id| self.start_purge(id).ok()).collect()
+    }
+
+    /// Finish the purges of expired items, returning the items that are gone
+    pub fn finish_purge_expired(&mut self, purges: Vec<(PendingPurge, io::Result<()>)>) -> Vec<TrashItem> {
+        let mut purged = Vec::new();
+        for (pending, result) in purges {
+            let id = pending.item.id.clone();
+            match self.finish_purge(pending, result) {
+                Ok(item) => {
+                    info!("Purged {:?} from the trash", item.original_path);
+                    purged.push(item);
+                },
+                Err(e) => error!("Could not purge trash item {}: {:?}", id, e),
+            }
+        }
+        purged
+    }
+}
+
+/// Remove the items one after the other. This blocks, so shouldn't be run on the server's own thread.
+fn run_purges(pending: Vec<PendingPurge>) -> Vec<(PendingPurge, io::Result<()>)> {
+    pending.into_iter()
+        .map(|pending| {
+            let result = pending.run();
+            (pending, result)
+        })
+        .collect()
+}
+
+/// Periodically purge items that have been in the trash for too long. The files are removed without holding on to
+/// the trash.
+pub async fn purge_expired_items(trash: Arc<Mutex<Trash>>) {
+    let mut interval = time::interval(time::Duration::from_secs(PURGE_CHECK_INTERVAL));
+    loop {
+        interval.tick().await;
+        let pending = trash.lock().await.start_purge_expired(Utc::now());
+        if pending.is_empty() {
+            continue;
+        }
+        match task::spawn_blocking(move || run_purges(pending)).await {
+            Ok(purges) => {
+                trash.lock().await.finish_purge_expired(purges);
+            },
+            Err(e) => error!("Could not purge the trash: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn setup() -> (tempfile::TempDir, ServePoint, PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        let share = dir.path().join("share");
+        fs::create_dir_all(share.join("shows").join("season 1")).unwrap();
+        fs::write(share.join("shows").join("season 1").join("episode.mp4"), b"video").unwrap();
+        let sp = ServePoint::new(share.clone());
+        (dir, sp, share)
+    }
+
+    fn delete(trash: &mut Trash, sp: &ServePoint, p: &Path, recursive: bool, user: &str) -> Result<TrashItem, FsOpError> {
+        let pending = trash.start_delete(sp, p, recursive, user)?;
+        let result = pending.run();
+        trash.finish_delete(pending, result)
+    }
+
+    fn restore(trash: &mut Trash, sp: &ServePoint, id: &str) -> Result<TrashItem, FsOpError> {
+        let pending = trash.start_restore(sp, id)?;
+        let result = pending.run();
+        trash.finish_restore(pending, result)
+    }
+
+    fn purge_expired(trash: &mut Trash, now: DateTime<Utc>) -> Vec<TrashItem> {
+        let pending = trash.start_purge_expired(now);
+        trash.finish_purge_expired(run_purges(pending))
+    }
+
+    #[test]
+    fn test_trash_must_be_outside_share() {
+        let (_dir, _sp, share) = setup();
+        assert!(Trash::load(share.join("trash"), std::slice::from_ref(&share), 30).is_err());
+    }
+
+    #[test]
+    fn test_delete_and_restore() {
+        let (dir, sp, share) = setup();
+        let mut trash = Trash::load(dir.path().join("trash"), std::slice::from_ref(&share), 30).unwrap();
+
+        let episode = Path::new("shows").join("season 1").join("episode.mp4");
+        let item = delete(&mut trash, &sp, &episode, false, "admin").unwrap();
+        assert_eq!(item.original_path, episode);
+        assert_eq!(item.deleted_by, "admin");
+        assert!(!item.is_dir);
+        assert!(!share.join(&episode).exists());
+
+        assert!(matches!(delete(&mut trash, &sp, Path::new("shows"), false, "admin"), Err(FsOpError::NotEmpty)));
+        let season = delete(&mut trash, &sp, Path::new("shows"), true, "admin").unwrap();
+        assert!(season.is_dir);
+        assert!(!share.join("shows").exists());
+        assert_eq!(trash.items().len(), 2);
+
+        // The directory it came from is recreated
+        restore(&mut trash, &sp, &item.id).unwrap();
+        assert_eq!(fs::read(share.join(&episode)).unwrap(), b"video");
+        assert_eq!(trash.items().len(), 1);
+
+        // ...but it isn't merged into one that now exists again
+        assert!(matches!(restore(&mut trash, &sp, &season.id), Err(FsOpError::AlreadyExists)));
+        assert!(matches!(restore(&mut trash, &sp, "nope"), Err(FsOpError::NotFound)));
+    }
+
+    #[test]
+    fn test_moves_in_progress() {
+        let (dir, sp, share) = setup();
+        let mut trash = Trash::load(dir.path().join("trash"), std::slice::from_ref(&share), 30).unwrap();
+        let episode = Path::new("shows").join("season 1").join("episode.mp4");
+
+        // Nothing else can happen to an entry while it's on its way to the trash
+        let pending = trash.start_delete(&sp, &episode, false, "admin").unwrap();
+        assert!(matches!(trash.start_delete(&sp, &episode, false, "admin"), Err(FsOpError::NotFound)));
+        assert!(trash.items().is_empty());
+        let result = pending.run();
+        let item = trash.finish_delete(pending, result).unwrap();
+        delete(&mut trash, &sp, Path::new("shows"), true, "admin").unwrap();
+
+        // ...or on its way back, when it can't be purged either
+        let pending = trash.start_restore(&sp, &item.id).unwrap();
+        assert!(matches!(trash.start_restore(&sp, &item.id), Err(FsOpError::NotFound)));
+        assert!(matches!(trash.start_purge(&item.id), Err(FsOpError::NotFound)));
+        assert!(share.join("shows").join("season 1").is_dir());
+
+        // A restore that fails removes the directories it made, and leaves the item in the trash
+        fs::remove_file(dir.path().join("trash").join(&item.id).join("episode.mp4")).unwrap();
+        let result = pending.run();
+        assert!(trash.finish_restore(pending, result).is_err());
+        assert!(!share.join("shows").exists());
+        assert_eq!(trash.items().len(), 2);
+    }
+
+    #[test]
+    fn test_purge() {
+        let (dir, sp, share) = setup();
+        let mut trash = Trash::load(dir.path().join("trash"), std::slice::from_ref(&share), 30).unwrap();
+        let item = delete(&mut trash, &sp, Path::new("shows"), true, "admin").unwrap();
+
+        let reloaded = Trash::load(dir.path().join("trash"), std::slice::from_ref(&share), 30).unwrap();
+        assert_eq!(reloaded.items().len(), 1);
+        assert_eq!(reloaded.items()[0].id, item.id);
+
+        assert!(purge_expired(&mut trash, Utc::now()).is_empty());
+
+        // An item being purged is gone from the trash straight away, and can't be restored
+        let pending = trash.start_purge_expired(Utc::now() + Duration::days(31));
+        assert_eq!(pending.len(), 1);
+        assert!(trash.items().is_empty());
+        assert!(matches!(trash.start_restore(&sp, &item.id), Err(FsOpError::NotFound)));
+        let purged = trash.finish_purge_expired(run_purges(pending));
+        assert_eq!(purged.len(), 1);
+        assert!(trash.items().is_empty());
+        assert!(!dir.path().join("trash").join(&item.id).exists());
+
+        // A purge that fails leaves the item in the trash
+        fs::write(share.join("film.mkv"), b"film").unwrap();
+        let item = delete(&mut trash, &sp, Path::new("film.mkv"), false, "admin").unwrap();
+        let pending = trash.start_purge(&item.id).unwrap();
+        let failed = Err(io::Error::new(io::ErrorKind::PermissionDenied, "denied"));
+        assert!(trash.finish_purge(pending, failed).is_err());
+        assert_eq!(trash.items().len(), 1);
+        let pending = trash.start_purge(&item.id).unwrap();
+        let result = pending.run();
+        trash.finish_purge(pending, result).unwrap();
+        assert!(trash.items().is_empty());
+
+        let reloaded = Trash::load(dir.path().join("trash"), std::slice::from_ref(&share), 30).unwrap();
+        assert!(reloaded.items().is_empty());
+    }
+}
@@ -0,0 +1,248 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use tokio::time;
+
+use crate::fs_utils::{generate_id, write_atomic};
+
+/// How often to look for upload sessions that have gone idle
+const EXPIRY_CHECK_INTERVAL: u64 = 60;
+
+/// The part file that an upload of the file called 'name' collects its data in
+fn part_file_name(name: &str, id: &str) -> String {
+    format!(".{}.{}.upload", name, id)
+}
+
+/// Whether 'name' is the part file of an upload, which is never shown or served
+pub fn is_part_file(name: &str) -> bool {
+    name.strip_prefix('.')
+        .and_then(|rest| rest.strip_suffix(".upload"))
+        .and_then(|rest| rest.rsplit_once('.'))
+        .is_some_and(|(name, id)| !name.is_empty() && id.len() == 32 && id.bytes().all(|b| b.is_ascii_hexdigit()))
+}
+
+/*
+A resumable upload. The bytes received so far are collected in 'part_path', which sits next to the final
+destination so that finishing the upload is a single atomic rename. The session itself is saved as JSON in the
+sessions directory so that it survives a restart of the server.
+*/
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UploadSession {
+    pub id: String,
+    /// Where the file ends up, relative to the share root
+    pub path: PathBuf,
+    pub part_path: PathBuf,
+    pub size: u64,
+    pub owner: String,
+    pub overwrite: bool,
+    pub last_activity: DateTime<Utc>,
+    #[serde(skip)]
+    busy: Arc<AtomicBool>,
+}
+
+impl UploadSession {
+    /// The number of bytes received so far. The part file is the source of truth, so that bytes which were
+    /// written just before a crash are not lost.
+    pub fn offset(&self) -> u64 {
+        fs::metadata(&self.part_path).map(|m| m.len()).unwrap_or(0)
+    }
+
+    /// Mark the session as busy for as long as the returned guard lives. Returns None if another request is
+    /// already writing to it.
+    pub fn lock_for_writing(&self) -> Option<BusyGuard> {
+        if self.busy.swap(true, Ordering::SeqCst) {
+            return None;
+        }
+        Some(BusyGuard { busy: self.busy.clone() })
+    }
+}
+
+/// Clears the busy flag of a session when dropped, even if the request writing to it was cancelled.
+pub struct BusyGuard {
+    busy: Arc<AtomicBool>,
+}
+
+impl Drop for BusyGuard {
+    fn drop(&mut self) {
+        self.busy.store(false, Ordering::SeqCst);
+    }
+}
+
+pub struct UploadSessions {
+    dir: PathBuf,
+    idle_timeout: Duration,
+    sessions: HashMap<String, UploadSession>,
+}
+
+impl UploadSessions {
+    /// Load the sessions saved in 'dir', creating the directory if it doesn't exist yet.
+    pub fn load(dir: PathBuf, idle_timeout: Duration) -> io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+        let mut sessions = HashMap::new();
+
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("json") {
+                continue;
+            }
+
+            let session: Option<UploadSession> = fs::read_to_string(&path)
+                .ok()
+                .and_then(|contents| serde_json::from_str(&contents).ok());
+            match session {
+                Some(session) => { sessions.insert(session.id.clone(), session); },
+                None => warn!("Ignoring unreadable upload session {:?}", path),
+            }
+        }
+
+        info!("Loaded {} upload session(s) from {:?}", sessions.len(), dir);
+        Ok(UploadSessions { dir, idle_timeout, sessions })
+    }
+
+    fn session_file(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", id))
+    }
+
+    fn save(&self, session: &UploadSession) -> io::Result<()> {
+        let json = serde_json::to_vec_pretty(session).map_err(io::Error::other)?;
+        write_atomic(&self.session_file(&session.id), &json)
+    }
+
+    /// Start a new upload of 'size' bytes to 'path', collecting the data in a part file next to 'target'.
+    pub fn create(&mut self, path: PathBuf, target: &Path, size: u64, owner: &str, overwrite: bool) -> io::Result<UploadSession> {
+        let id = generate_id();
+        let name = target.file_name().and_then(|n| n.to_str()).unwrap_or("file");
+        let part_path = target.with_file_name(part_file_name(name, &id));
+        fs::OpenOptions::new().write(true).create_new(true).open(&part_path)?;
+
+        let session = UploadSession {
+            id: id.clone(),
+            path,
+            part_path,
+            size,
+            owner: owner.to_owned(),
+            overwrite,
+            last_activity: Utc::now(),
+            busy: Arc::new(AtomicBool::new(false)),
+        };
+
+        if let Err(e) = self.save(&session) {
+            let _ = fs::remove_file(&session.part_path);
+            return Err(e);
+        }
+        self.sessions.insert(id, session.clone());
+        Ok(session)
+    }
+
+    pub fn get(&self, id: &str) -> Option<&UploadSession> {
+        self.sessions.get(id)
+    }
+
+    /// Record that the session has just been used, pushing back its expiry.
+    pub fn touch(&mut self, id: &str) -> io::Result<()> {
+        if let Some(session) = self.sessions.get_mut(id) {
+            session.last_activity = Utc::now();
+            let session = session.clone();
+            self.save(&session)?;
+        }
+        Ok(())
+    }
+
+    /// Forget about a session. The part file is removed too unless it has already been moved into place.
+    pub fn remove(&mut self, id: &str) -> Option<UploadSession> {
+        let session = self.sessions.remove(id)?;
+        if session.part_path.exists() {
+            if let Err(e) = fs::remove_file(&session.part_path) {
+                warn!("Could not remove {:?}: {}", session.part_path, e);
+            }
+        }
+        if let Err(e) = fs::remove_file(self.session_file(id)) {
+            warn!("Could not remove upload session {}: {}", id, e);
+        }
+        Some(session)
+    }
+
+    pub fn expires_at(&self, session: &UploadSession) -> DateTime<Utc> {
+        let timeout = chrono::Duration::from_std(self.idle_timeout).unwrap_or_else(|_| chrono::Duration::max_value());
+        session.last_activity + timeout
+    }
+
+    /// Remove every session that has been idle for longer than the timeout, returning their ids.
+    pub fn expire(&mut self, now: DateTime<Utc>) -> Vec<String> {
+        let expired: Vec<String> = self.sessions.values()
+            .filter(|s| self.expires_at(s) <= now && !s.busy.load(Ordering::SeqCst))
+            .map(|s| s.id.clone())
+            .collect();
+
+        for id in &expired {
+            info!("Upload session {} has expired", id);
+            self.remove(id);
+        }
+        expired
+    }
+}
+
+/// Periodically clear out upload sessions that have been abandoned.
+pub async fn expire_sessions(uploads: Arc<Mutex<UploadSessions>>) {
+    let mut interval = time::interval(time::Duration::from_secs(EXPIRY_CHECK_INTERVAL));
+    loop {
+        interval.tick().await;
+        uploads.lock().await.expire(Utc::now());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sessions_survive_reload() {
+        let state = tempfile::tempdir().unwrap();
+        let share = tempfile::tempdir().unwrap();
+        let target = share.path().join("film.mkv");
+
+        let mut sessions = UploadSessions::load(state.path().to_owned(), Duration::from_secs(60)).unwrap();
+        let session = sessions.create(PathBuf::from("film.mkv"), &target, 10, "uploader", false).unwrap();
+        assert_eq!(session.offset(), 0);
+        assert!(is_part_file(session.part_path.file_name().unwrap().to_str().unwrap()));
+        assert!(!is_part_file("film.mkv"));
+        fs::write(&session.part_path, b"12345").unwrap();
+
+        let reloaded = UploadSessions::load(state.path().to_owned(), Duration::from_secs(60)).unwrap();
+        let session = reloaded.get(&session.id).unwrap();
+        assert_eq!(session.path, PathBuf::from("film.mkv"));
+        assert_eq!(session.size, 10);
+        assert_eq!(session.owner, "uploader");
+        assert_eq!(session.offset(), 5);
+    }
+
+    #[test]
+    fn test_sessions_expire() {
+        let state = tempfile::tempdir().unwrap();
+        let share = tempfile::tempdir().unwrap();
+
+        let mut sessions = UploadSessions::load(state.path().to_owned(), Duration::from_secs(60)).unwrap();
+        let old = sessions.create(PathBuf::from("a"), &share.path().join("a"), 10, "uploader", false).unwrap();
+        let busy = sessions.create(PathBuf::from("b"), &share.path().join("b"), 10, "uploader", false).unwrap();
+        let _guard = busy.lock_for_writing().unwrap();
+        assert!(busy.lock_for_writing().is_none());
+
+        assert!(sessions.expire(Utc::now()).is_empty());
+
+        let later = Utc::now() + chrono::Duration::seconds(61);
+        assert_eq!(sessions.expire(later), vec![old.id.clone()]);
+        assert!(sessions.get(&old.id).is_none());
+        assert!(!old.part_path.exists());
+        assert!(sessions.get(&busy.id).is_some());
+
+        let reloaded = UploadSessions::load(state.path().to_owned(), Duration::from_secs(60)).unwrap();
+        assert!(reloaded.get(&old.id).is_none());
+    }
+}
@@ -1,220 +1,2404 @@
-use crate::verify;
-use super::handlers;
-use super::rejections;
-use super::models::{Sp,
-    Hba,
-    UserMap,
-    Rooms,
-    Urls,
-    UrlQuery,
-    RoomCodeQuery,
-    RoomCleaner,
-    // DbClientArc,
-    AuthenticatedUser,
-    UserRole,
-};
-
-use warp::Filter;
-use warp::http::StatusCode;
-use base64;
-use std::str;
-
-const FORBIDDEN: &'static str = "
-<!DOCTYPE html>
-<html>
-    <body>
-        <h1>403 Forbidden</h1>
-        <h3>You are not authorized to visit this page</h3>
-    </body>
-</html> 
-";
-
-#[derive(Clone)]
-pub struct Authenticated;
-
-pub fn render_file_listing<'a>(
-    sp: Sp,
-    hba: Hba<'a>,
-    users: UserMap,
-) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone + 'a {
-    warp::path!("browse" / ..)
-        .and(warp::get())
-        // .and(auth_restricted(users, UserRole::Admin))
-        .and(auth(users))
-        .and(with_sp(sp))
-        .and(with_hba(hba))
-        .and(warp::path::full())
-        .and_then(handlers::render_index)
-}
-
-pub fn render_cinema_page<'a>(
-    sp: Sp,
-    hba: Hba<'a>,
-    users: UserMap,
-) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone + 'a {
-    warp::path!("cinema" / ..)
-        .and(warp::get())
-        .and(auth(users))
-        .and(with_sp(sp))
-        .and(with_hba(hba))
-        .and(warp::path::full())
-        .and_then(handlers::render_cinema)
-}
-
-pub fn create_room_filter(
-    users: UserMap,
-    rooms: Rooms,
-    rooms_cleaner: RoomCleaner,
-    urls: Urls,
-) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-    warp::path("createroom")
-        .and(warp::path::end())
-        .and(warp::get())
-        .and(auth(users))
-        .and(with_rooms(rooms))
-        .and(with_room_cleaner(rooms_cleaner))
-        .and(with_urls(urls))
-        .and(warp::query::<UrlQuery>())
-        .and_then(handlers::create_room)
-}
-
-pub fn check_room_filter(
-    users: UserMap,
-    rooms: Rooms,
-) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-    warp::path("checkroom")
-        .and(warp::path::end())
-        .and(warp::get())
-        .and(auth(users))
-        .and(with_rooms(rooms))
-        .and(warp::query::<RoomCodeQuery>())
-        .and_then(handlers::check_room)
-}
-
-pub fn wwf_redirect(
-    users: UserMap,
-    urls: Urls,
-) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-    warp::path("wwf")
-        .and(auth(users))
-        .and(warp::path::param::<String>())
-        .and(with_urls(urls))
-        .and_then(handlers::wwf_lookup_redirect)
-}
-
-// pub fn get_catalogue(
-//     users: UserMap,
-//     client: DbClientArc,
-// ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
-//     warp::get()
-//         .and(warp::path("catalogue"))
-//         .and(warp::path::end())
-//         .and(auth(users))
-//         .and(with_db_client(client))
-//         .and_then(handlers::get_catalogue)
-// }
-
-fn with_sp(sp: Sp) -> impl Filter<Extract = (Sp,), Error = std::convert::Infallible> + Clone {
-    warp::any().map(move || sp.clone())
-}
-
-fn with_hba(hba: Hba) -> impl Filter<Extract = (Hba,), Error = std::convert::Infallible> + Clone {
-    warp::any().map(move || hba.clone())
-}
-
-fn with_users_map(users: UserMap) -> impl Filter<Extract = (UserMap,), Error = std::convert::Infallible> + Clone {
-    warp::any().map(move || users.clone())
-}
-
-pub fn with_rooms(rooms: Rooms) -> impl Filter<Extract = (Rooms,), Error = std::convert::Infallible> + Clone {
-    warp::any().map(move || rooms.clone())
-}
-
-pub fn with_room_cleaner(cleaner: RoomCleaner) -> impl Filter<Extract = (RoomCleaner,), Error = std::convert::Infallible> + Clone {
-    warp::any().map(move || cleaner.clone())
-}
-
-fn with_urls(urls: Urls) -> impl Filter<Extract = (Urls,), Error = std::convert::Infallible> + Clone {
-    warp::any().map(move || urls.clone())
-}
-
-// fn with_db_client(client: DbClientArc) -> impl Filter<Extract = (DbClientArc,), Error = std::convert::Infallible> + Clone {
-//     warp::any().map(move || client.clone())
-// }
-
-async fn check_auth(header: &str, users: &UserMap) -> Option<AuthenticatedUser> {
-    let (u,p) = parse_auth_header(header);
-    debug!("Verifying password for user {}", u);
-    let users =  users.lock().await;
-
-    if let Some(user) = users.get(&u) {
-        if verify(&user.password, p.as_bytes()) {
-            return Some(user.clone());
-        }
-    }
-    return None;
-}
-
-pub fn auth(users: UserMap) -> impl Filter<Extract = (Authenticated,), Error = warp::Rejection> + Clone {
-    warp::header::<String>("Authorization")
-        .and(with_users_map(users))
-        .and_then(|header: String, users: UserMap| async move {
-            let user = check_auth(&header, &users).await;
-            if user.is_some() {
-                return Ok(Authenticated);
-            }
-
-            Err(warp::reject::custom(rejections::InvalidCredentials))
-        })
-}
-
-/// Requires that the user had the AT LEAST the role that is provided to this
-/// function in order to access the resource.
-// pub fn auth_restricted(users: UserMap, role: UserRole) -> impl Filter<Extract = (Authenticated,), Error = warp::Rejection> + Clone {
-//     warp::header::<String>("Authorization")
-//         .and(with_users_map(users))
-//         .and_then(move |header: String, users: UserMap| async move {
-//             let user_opt = check_auth(&header, &users).await;
-//             if let Some(user) = user_opt {
-//                 if user.role >= role {
-//                     return Ok(Authenticated);
-//                 } else {
-//                     return Err(warp::reject::custom(rejections::Forbidden));
-//                 }
-//             }
-
-//             Err(warp::reject::custom(rejections::InvalidCredentials))
-//         })
-// }
-
-fn parse_auth_header(header: &str) -> (String, String) {
-    let header_parts: Vec<&str> = header.split(" ").collect();
-    let b64auth = header_parts[1];
-    let decoded = base64::decode(b64auth.as_bytes()).unwrap();
-    let decoded_str = std::str::from_utf8(decoded.as_slice()).unwrap();
-    let auth_parts: Vec<&str> = decoded_str.splitn(2, ":").collect();
-    let username = auth_parts[0];
-    let password = auth_parts[1];
-    return (username.to_owned(), password.to_owned());
-}
-
-pub async fn recover_auth(err: warp::Rejection) -> Result<Box<dyn warp::Reply>, warp::reject::Rejection> {
-    type RetVal = Result<Box<dyn warp::Reply>, warp::reject::Rejection>;
-    let error_response : RetVal = if let Some(_) = err.find::<rejections::InvalidCredentials>() {
-        let msg = "Access Denied. Incorrect username or password";
-        let with_header = warp::reply::with_header(msg, "Www-Authenticate", r#"Basic realm="Authentication Required""#);
-        let with_header_and_status = warp::reply::with_status(with_header, StatusCode::UNAUTHORIZED);
-        Ok(Box::new(with_header_and_status))
-    } else if let Some(_) = err.find::<warp::reject::MissingHeader>() {
-        let msg = "Missing Header";
-        let with_header = warp::reply::with_header(msg, "Www-Authenticate", r#"Basic realm="Authentication Required""#);
-        let with_header_and_status = warp::reply::with_status(with_header, StatusCode::UNAUTHORIZED);
-        Ok(Box::new(with_header_and_status))
-    } else if let Some(_) = err.find::<rejections::Forbidden>() {
-        let msg = warp::reply::html(FORBIDDEN);
-        Ok(Box::new(warp::reply::with_status(msg, StatusCode::FORBIDDEN)))
-    } else {
-        Err(warp::reject())
-    };
-    return error_response ;
-}
\ No newline at end of file
+use super::handlers;
+use super::rejections;
+use super::models::{Sp,
+    Hba,
+    UserMap,
+    UsersFile,
+    LockoutQuery,
+    LoginQuery,
+    Rooms,
+    Links,
+    NewLinkRequest,
+    UrlQuery,
+    RoomCodeQuery,
+    RoomCleaner,
+    UploadQuery,
+    ListingQuery,
+    SearchQuery,
+    ArchiveQuery,
+    Uploads,
+    NewUploadRequest,
+    MakeDirRequest,
+    RenameRequest,
+    DeleteRequest,
+    TrashBin,
+    ShareLinks,
+    NewShareRequest,
+    Index,
+    // DbClientArc,
+    AuthenticatedUser,
+    UserRole,
+    AccessRules,
+};
+
+use warp::Filter;
+use warp::path::FullPath;
+use std::net::SocketAddr;
+use chrono::Utc;
+use crate::archive::ArchiveLimits;
+use crate::fs_utils::ServePoint;
+use crate::sessions::SESSION_COOKIE;
+use crate::auth_header::{self, Credentials};
+use warp::http::StatusCode;
+use warp::http::header::{HeaderMap, HeaderValue};
+use std::str;
+
+/// Largest JSON request body accepted by the API endpoints
+const JSON_BODY_LIMIT: u64 = 16 * 1024;
+
+/// The largest form that can be posted, e.g. to select files for a download
+const FORM_BODY_LIMIT: u64 = 1024 * 1024;
+
+const FORBIDDEN: &'static str = "
+<!DOCTYPE html>
+<html>
+    <body>
+        <h1>403 Forbidden</h1>
+        <h3>You are not authorized to visit this page</h3>
+    </body>
+</html> 
+";
+
+const SHARE_PASSWORD: &'static str = "
+<!DOCTYPE html>
+<html>
+    <body>
+        <h3>This link is protected by a password</h3>
+        <form method=\"post\">
+            <input type=\"password\" name=\"password\" autofocus>
+            <button type=\"submit\">Download</button>
+        </form>
+    </body>
+</html>
+";
+
+pub fn render_file_listing<'a>(
+    sp: Sp,
+    index: Index,
+    acl: AccessRules,
+    hba: Hba<'a>,
+    users: UserMap,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone + 'a {
+    warp::path!("browse" / ..)
+        .and(warp::get())
+        .and(auth_restricted(users, UserRole::ReadOnly))
+        .and(with_sp(sp))
+        .and(with_index(index))
+        .and(with_acl(acl))
+        .and(with_hba(hba))
+        .and(warp::path::full())
+        .and(warp::header::optional::<String>("accept-language"))
+        .and(listing_query())
+        .and_then(handlers::render_index)
+}
+
+/// The sorting, filtering and paging options for a listing. A query that can't be parsed is a bad request.
+fn listing_query() -> impl Filter<Extract = (ListingQuery,), Error = warp::Rejection> + Clone {
+    warp::query::<ListingQuery>()
+        .or_else(|_| async { Err(warp::reject::custom(rejections::InvalidQuery)) })
+}
+
+/// 'GET /api/list/<path>' returns the listing of a directory as JSON
+pub fn list_directory_json(
+    sp: Sp,
+    index: Index,
+    acl: AccessRules,
+    users: UserMap,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("api" / "list" / ..)
+        .and(warp::get())
+        .and(auth_restricted(users, UserRole::ReadOnly))
+        .and(with_sp(sp))
+        .and(with_index(index))
+        .and(with_acl(acl))
+        .and(warp::path::tail())
+        .and(listing_query())
+        .and_then(handlers::list_directory)
+}
+
+/// 'GET /search?q=<name>' searches the whole share for entries by name
+pub fn search_page<'a>(
+    sp: Sp,
+    index: Index,
+    acl: AccessRules,
+    hba: Hba<'a>,
+    users: UserMap,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone + 'a {
+    warp::path!("search")
+        .and(warp::get())
+        .and(auth_restricted(users, UserRole::ReadOnly))
+        .and(with_sp(sp))
+        .and(with_index(index))
+        .and(with_acl(acl))
+        .and(with_hba(hba))
+        .and(warp::header::optional::<String>("accept-language"))
+        .and(search_query())
+        .and_then(handlers::render_search)
+}
+
+/// 'GET /api/search?q=<name>' is the same search, returning JSON
+pub fn search_json(
+    sp: Sp,
+    index: Index,
+    acl: AccessRules,
+    users: UserMap,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("api" / "search")
+        .and(warp::get())
+        .and(auth_restricted(users, UserRole::ReadOnly))
+        .and(with_sp(sp))
+        .and(with_index(index))
+        .and(with_acl(acl))
+        .and(search_query())
+        .and_then(handlers::search_files)
+}
+
+fn search_query() -> impl Filter<Extract = (SearchQuery,), Error = warp::Rejection> + Clone {
+    warp::query::<SearchQuery>()
+        .or_else(|_| async { Err(warp::reject::custom(rejections::InvalidQuery)) })
+}
+
+/*
+'GET /archive/<path>?format=zip|tar|tar.gz' downloads a whole directory as one archive
+'POST /archive' downloads the entries selected on a listing page, sent as a form with a 'path' for each of them
+and the 'format'
+*/
+pub fn archive_filters(
+    sp: Sp,
+    acl: AccessRules,
+    users: UserMap,
+    limits: ArchiveLimits,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let directory = warp::path!("archive" / ..)
+        .and(warp::get())
+        .and(auth_restricted(users.clone(), UserRole::ReadOnly))
+        .and(with_sp(sp.clone()))
+        .and(with_acl(acl.clone()))
+        .and(warp::any().map(move || limits))
+        .and(warp::path::tail())
+        .and(warp::query::<ArchiveQuery>().or_else(|_| async { Err(warp::reject::custom(rejections::InvalidQuery)) }))
+        .and_then(handlers::download_archive);
+
+    let selection = warp::path!("archive")
+        .and(warp::post())
+        .and(auth_restricted(users, UserRole::ReadOnly))
+        .and(with_sp(sp))
+        .and(with_acl(acl))
+        .and(warp::any().map(move || limits))
+        .and(warp::body::content_length_limit(FORM_BODY_LIMIT))
+        .and(warp::body::bytes())
+        .and_then(handlers::download_selection);
+
+    directory.or(selection)
+}
+
+pub fn render_cinema_page<'a>(
+    sp: Sp,
+    acl: AccessRules,
+    hba: Hba<'a>,
+    users: UserMap,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone + 'a {
+    warp::path!("cinema" / ..)
+        .and(warp::get())
+        .and(auth_restricted(users, UserRole::ReadOnly))
+        .and(with_sp(sp))
+        .and(with_acl(acl))
+        .and(with_hba(hba))
+        .and(warp::path::full())
+        .and_then(handlers::render_cinema)
+}
+
+pub fn create_room_filter(
+    users: UserMap,
+    rooms: Rooms,
+    rooms_cleaner: RoomCleaner,
+    links: Links,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path("createroom")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(auth_restricted(users, UserRole::ReadOnly))
+        .and(with_rooms(rooms))
+        .and(with_room_cleaner(rooms_cleaner))
+        .and(with_links(links))
+        .and(warp::query::<UrlQuery>())
+        .and_then(handlers::create_room)
+}
+
+pub fn check_room_filter(
+    users: UserMap,
+    rooms: Rooms,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path("checkroom")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(auth_restricted(users, UserRole::ReadOnly))
+        .and(with_rooms(rooms))
+        .and(warp::query::<RoomCodeQuery>())
+        .and_then(handlers::check_room)
+}
+
+pub fn wwf_redirect(
+    users: UserMap,
+    links: Links,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path("wwf")
+        .and(auth_restricted(users, UserRole::ReadOnly))
+        .and(warp::path::param::<String>())
+        .and(with_links(links))
+        .and_then(handlers::wwf_lookup_redirect)
+}
+
+/// Short links, which redirect '/wwf/<slug>' to somewhere in the share:
+///  * 'POST /api/links' with '{"path": ..., "slug": ..., "expires_in": <seconds>}' makes one, with a random slug if none is given
+///  * 'GET /admin/links' lists every link
+///  * 'DELETE /admin/links/<slug>' deletes one
+pub fn short_link_filters(
+    users: UserMap,
+    sp: Sp,
+    acl: AccessRules,
+    links: Links,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let create = warp::path!("api" / "links")
+        .and(warp::post())
+        .and(auth_restricted(users.clone(), UserRole::Uploader))
+        .and(with_sp(sp))
+        .and(with_acl(acl))
+        .and(with_links(links.clone()))
+        .and(warp::body::content_length_limit(JSON_BODY_LIMIT))
+        .and(warp::body::json::<NewLinkRequest>())
+        .and_then(handlers::create_link);
+
+    let list = warp::path!("admin" / "links")
+        .and(warp::get())
+        .and(auth_restricted(users.clone(), UserRole::Admin))
+        .and(with_links(links.clone()))
+        .and_then(handlers::list_links);
+
+    let delete = warp::path!("admin" / "links" / String)
+        .and(warp::delete())
+        .and(auth_restricted(users, UserRole::Admin))
+        .map(|slug, user| (user, slug))
+        .untuple_one()
+        .and(with_links(links))
+        .and_then(handlers::delete_link);
+
+    create.or(list).or(delete)
+}
+
+/// Accepts a file uploaded with 'PUT /browse/<dir>/<filename>'. Existing files are only replaced when
+/// '?overwrite=true' is given.
+pub fn upload_file_filter(
+    users: UserMap,
+    sp: Sp,
+    acl: AccessRules,
+    max_upload_size: u64,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path("browse")
+        .and(warp::put())
+        .and(auth_restricted(users, UserRole::Uploader))
+        .and(with_sp(sp))
+        .and(with_acl(acl))
+        .and(warp::any().map(move || max_upload_size))
+        .and(warp::path::tail())
+        .and(warp::query::<UploadQuery>())
+        .and(warp::header::optional::<u64>("content-length"))
+        .and(warp::body::stream())
+        .and_then(handlers::upload_file)
+}
+
+/// The resumable upload protocol, for files too big to send in one go:
+///  * 'POST /uploads' with '{"path": ..., "size": ...}' starts an upload
+///  * 'GET /uploads/<id>' reports the offset to resume from
+///  * 'PATCH /uploads/<id>' with an 'Upload-Offset' header appends a chunk
+///  * 'POST /uploads/<id>/finish' moves the completed file into the share
+///  * 'DELETE /uploads/<id>' abandons the upload
+pub fn resumable_upload_filters(
+    users: UserMap,
+    sp: Sp,
+    acl: AccessRules,
+    uploads: Uploads,
+    max_upload_size: u64,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let create = warp::path!("uploads")
+        .and(warp::post())
+        .and(auth_restricted(users.clone(), UserRole::Uploader))
+        .and(with_sp(sp.clone()))
+        .and(with_acl(acl.clone()))
+        .and(with_uploads(uploads.clone()))
+        .and(warp::any().map(move || max_upload_size))
+        .and(warp::body::content_length_limit(JSON_BODY_LIMIT))
+        .and(warp::body::json::<NewUploadRequest>())
+        .and_then(handlers::create_upload);
+
+    let status = warp::path!("uploads" / String)
+        .and(warp::get())
+        .and(auth_restricted(users.clone(), UserRole::Uploader))
+        .map(|id, user| (user, id))
+        .untuple_one()
+        .and(with_uploads(uploads.clone()))
+        .and_then(handlers::get_upload);
+
+    let chunk = warp::path!("uploads" / String)
+        .and(warp::patch())
+        .and(auth_restricted(users.clone(), UserRole::Uploader))
+        .map(|id, user| (user, id))
+        .untuple_one()
+        .and(with_sp(sp.clone()))
+        .and(with_acl(acl.clone()))
+        .and(with_uploads(uploads.clone()))
+        .and(warp::header::optional::<u64>("upload-offset"))
+        .and(warp::body::stream())
+        .and_then(handlers::upload_chunk);
+
+    let finish = warp::path!("uploads" / String / "finish")
+        .and(warp::post())
+        .and(auth_restricted(users.clone(), UserRole::Uploader))
+        .map(|id, user| (user, id))
+        .untuple_one()
+        .and(with_sp(sp))
+        .and(with_acl(acl))
+        .and(with_uploads(uploads.clone()))
+        .and_then(handlers::finish_upload);
+
+    let cancel = warp::path!("uploads" / String)
+        .and(warp::delete())
+        .and(auth_restricted(users, UserRole::Uploader))
+        .map(|id, user| (user, id))
+        .untuple_one()
+        .and(with_uploads(uploads))
+        .and_then(handlers::cancel_upload);
+
+    create.or(status).or(chunk).or(finish).or(cancel)
+}
+
+/// File management for admins:
+///  * 'POST /admin/fs/mkdir' with '{"path": ...}'
+///  * 'POST /admin/fs/rename' with '{"from": ..., "to": ...}', which also moves entries between directories
+///  * 'POST /admin/fs/delete' with '{"path": ..., "recursive": true|false}', which moves the entry to the trash
+pub fn admin_fs_filters(
+    users: UserMap,
+    sp: Sp,
+    acl: AccessRules,
+    trash: TrashBin,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let mkdir = warp::path!("admin" / "fs" / "mkdir")
+        .and(warp::post())
+        .and(auth_restricted(users.clone(), UserRole::Admin))
+        .and(with_sp(sp.clone()))
+        .and(with_acl(acl.clone()))
+        .and(warp::body::content_length_limit(JSON_BODY_LIMIT))
+        .and(warp::body::json::<MakeDirRequest>())
+        .and_then(handlers::make_dir);
+
+    let rename = warp::path!("admin" / "fs" / "rename")
+        .and(warp::post())
+        .and(auth_restricted(users.clone(), UserRole::Admin))
+        .and(with_sp(sp.clone()))
+        .and(with_acl(acl.clone()))
+        .and(warp::body::content_length_limit(JSON_BODY_LIMIT))
+        .and(warp::body::json::<RenameRequest>())
+        .and_then(handlers::rename_entry);
+
+    let delete = warp::path!("admin" / "fs" / "delete")
+        .and(warp::post())
+        .and(auth_restricted(users, UserRole::Admin))
+        .and(with_sp(sp))
+        .and(with_acl(acl))
+        .and(with_trash(trash))
+        .and(warp::body::content_length_limit(JSON_BODY_LIMIT))
+        .and(warp::body::json::<DeleteRequest>())
+        .and_then(handlers::delete_entry);
+
+    mkdir.or(rename).or(delete)
+}
+
+/// Managing the trash:
+///  * 'GET /admin/trash' lists the deleted entries
+///  * 'POST /admin/trash/<id>/restore' puts an entry back where it was
+///  * 'DELETE /admin/trash/<id>' deletes it for good
+pub fn admin_trash_filters(
+    users: UserMap,
+    sp: Sp,
+    trash: TrashBin,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let list = warp::path!("admin" / "trash")
+        .and(warp::get())
+        .and(auth_restricted(users.clone(), UserRole::Admin))
+        .and(with_trash(trash.clone()))
+        .and_then(handlers::list_trash);
+
+    let restore = warp::path!("admin" / "trash" / String / "restore")
+        .and(warp::post())
+        .and(auth_restricted(users.clone(), UserRole::Admin))
+        .map(|id, user| (user, id))
+        .untuple_one()
+        .and(with_sp(sp))
+        .and(with_trash(trash.clone()))
+        .and_then(handlers::restore_trash_item);
+
+    let purge = warp::path!("admin" / "trash" / String)
+        .and(warp::delete())
+        .and(auth_restricted(users, UserRole::Admin))
+        .map(|id, user| (user, id))
+        .untuple_one()
+        .and(with_trash(trash))
+        .and_then(handlers::purge_trash_item);
+
+    list.or(restore).or(purge)
+}
+
+/// Share links, which give anyone who has one read access to a single file or directory:
+///  * 'POST /api/shares' with '{"path": ..., "expires_in": <seconds>, "max_downloads": N, "password": ...}' makes one
+///  * 'GET /api/shares' lists the links the user has made, or all of them for admins
+///  * 'DELETE /api/shares/<id>' revokes one
+pub fn share_filters(
+    users: UserMap,
+    sp: Sp,
+    acl: AccessRules,
+    shares: ShareLinks,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let create = warp::path!("api" / "shares")
+        .and(warp::post())
+        .and(auth_restricted(users.clone(), UserRole::Uploader))
+        .and(with_sp(sp))
+        .and(with_acl(acl))
+        .and(with_shares(shares.clone()))
+        .and(warp::body::content_length_limit(JSON_BODY_LIMIT))
+        .and(warp::body::json::<NewShareRequest>())
+        .and_then(handlers::create_share);
+
+    let list = warp::path!("api" / "shares")
+        .and(warp::get())
+        .and(auth_restricted(users.clone(), UserRole::Uploader))
+        .and(with_shares(shares.clone()))
+        .and_then(handlers::list_shares);
+
+    let revoke = warp::path!("api" / "shares" / String)
+        .and(warp::delete())
+        .and(auth_restricted(users, UserRole::Uploader))
+        .map(|id, user| (user, id))
+        .untuple_one()
+        .and(with_shares(shares))
+        .and_then(handlers::revoke_share);
+
+    create.or(list).or(revoke)
+}
+
+/*
+'GET /s/<token>' downloads what a share link points to without any credentials, a directory as an archive in the
+'?format' asked for. When the link has a password a form is shown instead, which posts it back to the same url.
+*/
+pub fn share_link_filters(
+    sp: Sp,
+    shares: ShareLinks,
+    users: UserMap,
+    acl: AccessRules,
+    limits: ArchiveLimits,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let open = warp::path!("s" / String)
+        .and(warp::get())
+        .and(with_sp(sp.clone()))
+        .and(with_shares(shares.clone()))
+        .and(with_users_map(users.clone()))
+        .and(with_acl(acl.clone()))
+        .and(warp::any().map(move || limits))
+        .and(warp::query::<ArchiveQuery>().or_else(|_| async { Err(warp::reject::custom(rejections::InvalidQuery)) }))
+        .and_then(handlers::open_share);
+
+    let unlock = warp::path!("s" / String)
+        .and(warp::post())
+        .and(with_sp(sp))
+        .and(with_shares(shares))
+        .and(with_users_map(users))
+        .and(with_acl(acl))
+        .and(warp::any().map(move || limits))
+        .and(warp::addr::remote())
+        .and(warp::query::<ArchiveQuery>().or_else(|_| async { Err(warp::reject::custom(rejections::InvalidQuery)) }))
+        .and(warp::body::content_length_limit(JSON_BODY_LIMIT))
+        .and(warp::body::bytes())
+        .and_then(handlers::unlock_share);
+
+    open.or(unlock)
+}
+
+/// The login page, for browsers. Scripts can keep using Basic auth.
+///  * 'GET /login?next=<path>' shows the form
+///  * 'POST /login' with the username, password and next path starts a session and sets its cookie
+///  * 'POST /logout' ends the session
+pub fn login_filters<'a>(
+    users: UserMap,
+    hba: Hba<'a>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone + 'a {
+    let page = warp::path!("login")
+        .and(warp::get())
+        .and(with_hba(hba.clone()))
+        .and(warp::query::<LoginQuery>().or_else(|_| async { Err(warp::reject::custom(rejections::InvalidQuery)) }))
+        .and_then(handlers::render_login);
+
+    let login = warp::path!("login")
+        .and(warp::post())
+        .and(with_users_map(users.clone()))
+        .and(with_hba(hba))
+        .and(warp::addr::remote())
+        .and(warp::header::optional::<String>("x-forwarded-proto"))
+        .and(warp::body::content_length_limit(JSON_BODY_LIMIT))
+        .and(warp::body::bytes())
+        .and_then(handlers::login);
+
+    let logout = warp::path!("logout")
+        .and(warp::post())
+        .and(with_users_map(users))
+        .and(warp::cookie::optional(SESSION_COOKIE))
+        .and_then(handlers::logout);
+
+    page.or(login).or(logout)
+}
+
+/// Lets admins see who has been getting their password wrong, and let them try again
+pub fn lockout_filters(
+    users: UserMap,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let list = warp::path!("admin" / "lockouts")
+        .and(warp::get())
+        .and(auth_restricted(users.clone(), UserRole::Admin))
+        .and(with_users_map(users.clone()))
+        .and_then(handlers::list_lockouts);
+
+    let clear = warp::path!("admin" / "lockouts")
+        .and(warp::delete())
+        .and(auth_restricted(users.clone(), UserRole::Admin))
+        .and(with_users_map(users))
+        .and(warp::query::<LockoutQuery>().or_else(|_| async { Err(warp::reject::custom(rejections::InvalidQuery)) }))
+        .and_then(handlers::clear_lockouts);
+
+    list.or(clear)
+}
+
+/// The page where admins manage the users in the credentials file
+pub fn admin_users_filters<'a>(
+    users: UserMap,
+    users_file: UsersFile,
+    acl: AccessRules,
+    hba: Hba<'a>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone + 'a {
+    let page = warp::path!("admin" / "users")
+        .and(warp::get())
+        .and(auth_restricted(users.clone(), UserRole::Admin))
+        .and(with_users_map(users.clone()))
+        .and(with_users_file(users_file.clone()))
+        .and(with_hba(hba.clone()))
+        .and_then(handlers::render_users);
+
+    let change = warp::path!("admin" / "users")
+        .and(warp::post())
+        .and(auth_restricted(users.clone(), UserRole::Admin))
+        .and(with_users_map(users))
+        .and(with_users_file(users_file))
+        .and(with_acl(acl))
+        .and(with_hba(hba))
+        .and(warp::body::content_length_limit(FORM_BODY_LIMIT))
+        .and(warp::body::bytes())
+        .and_then(handlers::change_user);
+
+    page.or(change)
+}
+
+/// Serves the CSS, JS & icons used by the rendered pages.
+pub fn static_files(
+    users: UserMap,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path("static")
+        .and(auth_restricted(users, UserRole::ReadOnly))
+        .and(warp::fs::dir("static"))
+        .map(|_: AuthenticatedUser, file| file)
+}
+
+/// The endpoint to serve files. Should be used AFTER the 'render_file_listing'
+/// filter, in order to ensure that Directories get rendered as an index, and
+/// that this serves the files. Files the user isn't allowed to see are not found.
+pub fn serve_files(
+    sp: ServePoint,
+    acl: AccessRules,
+    users: UserMap,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let mut headers = HeaderMap::new();
+    headers.insert("Content-Disposition", HeaderValue::from_static("attachement"));
+
+    warp::get().or(warp::head()).unify()
+        .and(warp::path("browse"))
+        .and(auth_restricted(users, UserRole::ReadOnly))
+        .and(warp::any().map(move || sp.clone()))
+        .and(with_acl(acl))
+        .and(warp::path::tail())
+        .and(warp::header::optional::<String>("range"))
+        .and_then(handlers::browse_file)
+        .with(warp::reply::with::headers(headers))
+}
+
+// pub fn get_catalogue(
+//     users: UserMap,
+//     client: DbClientArc,
+// ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+//     warp::get()
+//         .and(warp::path("catalogue"))
+//         .and(warp::path::end())
+//         .and(auth(users))
+//         .and(with_db_client(client))
+//         .and_then(handlers::get_catalogue)
+// }
+
+fn with_sp(sp: Sp) -> impl Filter<Extract = (Sp,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || sp.clone())
+}
+
+fn with_hba(hba: Hba) -> impl Filter<Extract = (Hba,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || hba.clone())
+}
+
+fn with_users_map(users: UserMap) -> impl Filter<Extract = (UserMap,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || users.clone())
+}
+
+fn with_users_file(users_file: UsersFile) -> impl Filter<Extract = (UsersFile,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || users_file.clone())
+}
+
+pub fn with_rooms(rooms: Rooms) -> impl Filter<Extract = (Rooms,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || rooms.clone())
+}
+
+pub fn with_room_cleaner(cleaner: RoomCleaner) -> impl Filter<Extract = (RoomCleaner,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || cleaner.clone())
+}
+
+fn with_uploads(uploads: Uploads) -> impl Filter<Extract = (Uploads,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || uploads.clone())
+}
+
+fn with_trash(trash: TrashBin) -> impl Filter<Extract = (TrashBin,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || trash.clone())
+}
+
+fn with_shares(shares: ShareLinks) -> impl Filter<Extract = (ShareLinks,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || shares.clone())
+}
+
+fn with_acl(acl: AccessRules) -> impl Filter<Extract = (AccessRules,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || acl.clone())
+}
+
+fn with_index(index: Index) -> impl Filter<Extract = (Index,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || index.clone())
+}
+
+fn with_links(links: Links) -> impl Filter<Extract = (Links,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || links.clone())
+}
+
+// fn with_db_client(client: DbClientArc) -> impl Filter<Extract = (DbClientArc,), Error = std::convert::Infallible> + Clone {
+//     warp::any().map(move || client.clone())
+// }
+
+/// Check the credentials from an Authorization header. Basic credentials that were verified recently aren't hashed
+/// again, and a Bearer token is looked up in the sessions.
+async fn check_auth(header: &[u8], addr: Option<SocketAddr>, users: &UserMap) -> Result<AuthenticatedUser, warp::Rejection> {
+    match auth_header::parse(header) {
+        Ok(Credentials::Basic { username, password }) => handlers::authenticate(users, addr.map(|addr| addr.ip()), &username, &password).await,
+        Ok(Credentials::Bearer(token)) => session_user(&token, users).await
+            .ok_or_else(|| warp::reject::custom(rejections::InvalidCredentials)),
+        Err(error) => {
+            debug!("Bad Authorization header from {:?}: {}", addr, error);
+            Err(warp::reject::custom(rejections::InvalidAuthorization { error }))
+        },
+    }
+}
+
+/// The user whose session a cookie belongs to, as long as they still have an account
+async fn session_user(cookie: &str, users: &UserMap) -> Option<AuthenticatedUser> {
+    let username = users.sessions.lock().await.find(cookie, Utc::now())?.username.clone();
+    users.accounts.lock().await.get(&username).cloned()
+}
+
+/// Where to send a browser to log in, so that it comes back to the page it asked for
+fn login_url(path: &FullPath, query: &str) -> String {
+    let next = if query.is_empty() { path.as_str().to_owned() } else { format!("{}?{}", path.as_str(), query) };
+    let next: String = url::form_urlencoded::byte_serialize(next.as_bytes()).collect();
+    format!("/login?next={}", next)
+}
+
+/// Authenticates the request using the session cookie or the Basic auth header, and passes the matching user on
+/// to the handler.
+pub fn auth(users: UserMap) -> impl Filter<Extract = (AuthenticatedUser,), Error = warp::Rejection> + Clone {
+    warp::header::headers_cloned().map(|headers: HeaderMap| headers.get("Authorization").cloned())
+        .and(warp::cookie::optional(SESSION_COOKIE))
+        .and(warp::header::optional::<String>("accept"))
+        .and(warp::path::full())
+        .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+        .and(warp::addr::remote())
+        .and(with_users_map(users))
+        .and_then(|header: Option<HeaderValue>, cookie: Option<String>, accept: Option<String>, path: FullPath, query: String, addr: Option<SocketAddr>, users: UserMap| async move {
+            if let Some(cookie) = cookie {
+                if let Some(user) = session_user(&cookie, &users).await {
+                    return Ok(user);
+                }
+            }
+
+            match header {
+                Some(header) => check_auth(header.as_bytes(), addr, &users).await,
+                None => {
+                    let is_browser = accept.is_some_and(|accept| accept.contains("text/html"));
+                    let login = if is_browser { Some(login_url(&path, &query)) } else { None };
+                    Err(warp::reject::custom(rejections::LoginRequired { login }))
+                },
+            }
+        })
+}
+
+/// Requires that the user had the AT LEAST the role that is provided to this
+/// function in order to access the resource.
+pub fn auth_restricted(users: UserMap, role: UserRole) -> impl Filter<Extract = (AuthenticatedUser,), Error = warp::Rejection> + Clone {
+    auth(users)
+        .and_then(move |user: AuthenticatedUser| async move {
+            if user.role >= role {
+                return Ok(user);
+            }
+
+            debug!("User {} has role {:?}, {:?} is required", user.username, user.role, role);
+            Err(warp::reject::custom(rejections::Forbidden))
+        })
+}
+
+pub async fn recover_auth(err: warp::Rejection) -> Result<Box<dyn warp::Reply>, warp::reject::Rejection> {
+    type RetVal = Result<Box<dyn warp::Reply>, warp::reject::Rejection>;
+    let error_response : RetVal = if err.find::<rejections::InvalidCredentials>().is_some() {
+        let msg = "Access Denied. Incorrect username or password";
+        let with_header = warp::reply::with_header(msg, "Www-Authenticate", r#"Basic realm="Authentication Required""#);
+        let with_header_and_status = warp::reply::with_status(with_header, StatusCode::UNAUTHORIZED);
+        Ok(Box::new(with_header_and_status))
+    } else if let Some(invalid) = err.find::<rejections::InvalidAuthorization>() {
+        let msg = invalid.error.to_string();
+        if invalid.error.is_unauthorized() {
+            let with_header = warp::reply::with_header(msg, "Www-Authenticate", r#"Basic realm="Authentication Required""#);
+            Ok(Box::new(warp::reply::with_status(with_header, StatusCode::UNAUTHORIZED)))
+        } else {
+            Ok(Box::new(warp::reply::with_status(msg, StatusCode::BAD_REQUEST)))
+        }
+    } else if let Some(too_many) = err.find::<rejections::TooManyAttempts>() {
+        let msg = "Too many failed attempts, try again later";
+        let with_header = warp::reply::with_header(msg, "Retry-After", too_many.retry_after.to_string());
+        Ok(Box::new(warp::reply::with_status(with_header, StatusCode::TOO_MANY_REQUESTS)))
+    } else if let Some(rejections::LoginRequired { login: Some(login) }) = err.find::<rejections::LoginRequired>() {
+        let with_header = warp::reply::with_header(warp::reply(), "Location", login.as_str());
+        Ok(Box::new(warp::reply::with_status(with_header, StatusCode::SEE_OTHER)))
+    } else if err.find::<rejections::LoginRequired>().is_some() || err.find::<warp::reject::MissingHeader>().is_some() {
+        let msg = "Missing Header";
+        let with_header = warp::reply::with_header(msg, "Www-Authenticate", r#"Basic realm="Authentication Required""#);
+        let with_header_and_status = warp::reply::with_status(with_header, StatusCode::UNAUTHORIZED);
+        Ok(Box::new(with_header_and_status))
+    } else if err.find::<rejections::Forbidden>().is_some() {
+        let msg = warp::reply::html(FORBIDDEN);
+        Ok(Box::new(warp::reply::with_status(msg, StatusCode::FORBIDDEN)))
+    } else if err.find::<rejections::ReadOnly>().is_some() {
+        Ok(Box::new(warp::reply::with_status("This folder is read only", StatusCode::FORBIDDEN)))
+    } else if err.find::<rejections::NotADirectory>().is_some() {
+        Ok(Box::new(warp::reply::with_status("Directory not found", StatusCode::NOT_FOUND)))
+    } else if err.find::<rejections::NotFound>().is_some() {
+        Ok(Box::new(warp::reply::with_status("Not found", StatusCode::NOT_FOUND)))
+    } else if err.find::<rejections::FileExists>().is_some() {
+        Ok(Box::new(warp::reply::with_status("File already exists", StatusCode::CONFLICT)))
+    } else if err.find::<rejections::DirectoryNotEmpty>().is_some() {
+        Ok(Box::new(warp::reply::with_status("Directory is not empty", StatusCode::CONFLICT)))
+    } else if err.find::<rejections::InvalidPath>().is_some() {
+        Ok(Box::new(warp::reply::with_status("Invalid path", StatusCode::BAD_REQUEST)))
+    } else if err.find::<rejections::InvalidQuery>().is_some() {
+        Ok(Box::new(warp::reply::with_status("Invalid query", StatusCode::BAD_REQUEST)))
+    } else if err.find::<rejections::ArchiveTooLarge>().is_some() {
+        Ok(Box::new(warp::reply::with_status("Too many files, or too much data, for one download", StatusCode::BAD_REQUEST)))
+    } else if err.find::<rejections::InvalidShare>().is_some() {
+        Ok(Box::new(warp::reply::with_status("Invalid share settings", StatusCode::BAD_REQUEST)))
+    } else if err.find::<rejections::ShareExpired>().is_some() {
+        Ok(Box::new(warp::reply::with_status("This link has expired", StatusCode::GONE)))
+    } else if err.find::<rejections::SharePasswordRequired>().is_some() {
+        let msg = warp::reply::html(SHARE_PASSWORD);
+        Ok(Box::new(warp::reply::with_status(msg, StatusCode::UNAUTHORIZED)))
+    } else if err.find::<rejections::InvalidLink>().is_some() {
+        Ok(Box::new(warp::reply::with_status("Invalid short link", StatusCode::BAD_REQUEST)))
+    } else if err.find::<rejections::SlugTaken>().is_some() {
+        Ok(Box::new(warp::reply::with_status("That short link is already taken", StatusCode::CONFLICT)))
+    } else if err.find::<rejections::OperationFailed>().is_some() {
+        Ok(Box::new(warp::reply::with_status("Operation failed", StatusCode::INTERNAL_SERVER_ERROR)))
+    } else if err.find::<rejections::PayloadTooLarge>().is_some() {
+        Ok(Box::new(warp::reply::with_status("File is too large", StatusCode::PAYLOAD_TOO_LARGE)))
+    } else if err.find::<rejections::UploadNotFound>().is_some() {
+        Ok(Box::new(warp::reply::with_status("Upload not found", StatusCode::NOT_FOUND)))
+    } else if let Some(mismatch) = err.find::<rejections::OffsetMismatch>() {
+        let msg = format!("Expected a chunk starting at offset {}", mismatch.offset);
+        let with_header = warp::reply::with_header(msg, "Upload-Offset", mismatch.offset.to_string());
+        Ok(Box::new(warp::reply::with_status(with_header, StatusCode::CONFLICT)))
+    } else if err.find::<rejections::UploadBusy>().is_some() {
+        Ok(Box::new(warp::reply::with_status("Upload is already in progress", StatusCode::CONFLICT)))
+    } else if err.find::<rejections::UploadIncomplete>().is_some() {
+        Ok(Box::new(warp::reply::with_status("Upload is not complete", StatusCode::CONFLICT)))
+    } else if err.find::<rejections::UploadFailed>().is_some() {
+        Ok(Box::new(warp::reply::with_status("Upload failed", StatusCode::INTERNAL_SERVER_ERROR)))
+    } else {
+        Err(warp::reject())
+    };
+    return error_response ;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::webserver::models;
+    use crate::short_links::ShortLinks;
+    use crate::acl::Acl;
+    use argon2::{Algorithm, Argon2, Params, PasswordHasher, Version};
+    use argon2::password_hash::{rand_core::OsRng, SaltString};
+    use std::collections::HashMap;
+    use std::path::PathBuf;
+    use std::time::Instant;
+    use crate::verify;
+
+    const PASSWORD: &str = "password";
+
+    /// Hash with tiny cost parameters so the tests don't spend their time in Argon2.
+    fn cheap_hash(password: &str) -> String {
+        let params = Params::new(8, 1, 1, None).unwrap();
+        let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, params);
+        let salt = SaltString::generate(&mut OsRng);
+        argon2.hash_password(password.as_bytes(), &salt).unwrap().to_string()
+    }
+
+    fn test_users() -> UserMap {
+        let mut users = HashMap::new();
+        for (name, role) in &[("reader", UserRole::ReadOnly), ("uploader", UserRole::Uploader), ("admin", UserRole::Admin)] {
+            let user = AuthenticatedUser::new(name.to_string(), cheap_hash(PASSWORD), *role);
+            users.insert(name.to_string(), user);
+        }
+        models::new_users(users, crate::sessions::DEFAULT_SESSION_LIFETIME)
+    }
+
+    fn basic(username: &str, password: &str) -> String {
+        format!("Basic {}", base64::encode(format!("{}:{}", username, password)))
+    }
+
+    fn test_sp() -> Sp {
+        models::new_serve_point(ServePoint::new(PathBuf::from("test/testfolder")))
+    }
+
+    fn no_acl() -> AccessRules {
+        models::new_access_rules(Acl::default())
+    }
+
+    const TEST_LIMITS: ArchiveLimits = ArchiveLimits { max_files: 100, max_size: 1024 * 1024 };
+
+    type Routes = Vec<(&'static str, String, warp::filters::BoxedFilter<(Box<dyn warp::Reply>,)>)>;
+
+    /// Every route (and a request that it should accept) that sits behind the auth filter, along with the state
+    /// directory they keep their links in.
+    async fn routes(users: &UserMap) -> (tempfile::TempDir, Routes) {
+        let rooms = Rooms::default();
+        let state = tempfile::tempdir().unwrap();
+        let links = models::new_links(ShortLinks::load(state.path().join("links.json")).unwrap());
+        links.lock().await.create(Some("ABCD"), "/cinema/file1.abc", "admin", None).unwrap();
+        let cleaner = models::new_room_cleaner();
+        let hba = models::new_handlebars_arc();
+
+        fn boxed<F>(f: F) -> warp::filters::BoxedFilter<(Box<dyn warp::Reply>,)>
+        where F: Filter<Error = warp::Rejection> + Clone + Send + Sync + 'static, F::Extract: warp::Reply + Send {
+            f.recover(recover_auth)
+             .map(|r| Box::new(r) as Box<dyn warp::Reply>)
+             .boxed()
+        }
+
+        let routes = vec![
+            ("listing", String::from("/browse/"), boxed(render_file_listing(test_sp(), None, no_acl(), hba.clone(), users.clone()))),
+            ("api_list", String::from("/api/list/"), boxed(list_directory_json(test_sp(), None, no_acl(), users.clone()))),
+            ("search", String::from("/search?q=file"), boxed(search_page(test_sp(), None, no_acl(), hba.clone(), users.clone()))),
+            ("api_search", String::from("/api/search?q=file"), boxed(search_json(test_sp(), None, no_acl(), users.clone()))),
+            ("archive", String::from("/archive/folder1/"), boxed(archive_filters(test_sp(), no_acl(), users.clone(), TEST_LIMITS))),
+            ("cinema", String::from("/cinema/file1.abc"), boxed(render_cinema_page(test_sp(), no_acl(), hba, users.clone()))),
+            ("createroom", format!("/createroom?url={}", base64::encode("/cinema/file1.abc")),
+                boxed(create_room_filter(users.clone(), rooms.clone(), cleaner, links.clone()))),
+            ("checkroom", String::from("/checkroom?room=ABCD"), boxed(check_room_filter(users.clone(), rooms))),
+            ("wwf", String::from("/wwf/ABCD"), boxed(wwf_redirect(users.clone(), links))),
+            ("static", String::from("/static/listing.css"), boxed(static_files(users.clone()))),
+            ("files", String::from("/browse/file1.abc"), boxed(serve_files(ServePoint::new(PathBuf::from("test/testfolder")), no_acl(), users.clone()))),
+        ];
+        (state, routes)
+    }
+
+    #[tokio::test]
+    async fn test_routes_require_credentials() {
+        let users = test_users();
+        let (_state, routes) = routes(&users).await;
+        for (name, path, route) in routes {
+            // So that the failures for the other routes don't hold these ones back
+            users.lockouts.lock().await.clear(None);
+            let resp = warp::test::request().path(&path).reply(&route).await;
+            assert_eq!(resp.status(), StatusCode::UNAUTHORIZED, "route {} without credentials", name);
+
+            let resp = warp::test::request()
+                .path(&path)
+                .header("Authorization", basic("reader", "wrong"))
+                .reply(&route)
+                .await;
+            assert_eq!(resp.status(), StatusCode::UNAUTHORIZED, "route {} with a bad password", name);
+
+            let resp = warp::test::request()
+                .path(&path)
+                .header("Authorization", basic("nobody", PASSWORD))
+                .reply(&route)
+                .await;
+            assert_eq!(resp.status(), StatusCode::UNAUTHORIZED, "route {} with an unknown user", name);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_routes_allow_every_role() {
+        let users = test_users();
+        let (_state, routes) = routes(&users).await;
+        for (name, path, route) in routes {
+            for username in &["reader", "uploader", "admin"] {
+                let resp = warp::test::request()
+                    .path(&path)
+                    .header("Authorization", basic(username, PASSWORD))
+                    .reply(&route)
+                    .await;
+                assert!(resp.status().is_success() || resp.status().is_redirection(),
+                    "route {} as {} returned {}", name, username, resp.status());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_auth_yields_user() {
+        let users = test_users();
+        let filter = auth(users).map(|user: AuthenticatedUser| format!("{} {:?}", user.username, user.role));
+        for (username, expected) in &[("reader", "reader ReadOnly"), ("uploader", "uploader Uploader"), ("admin", "admin Admin")] {
+            let resp = warp::test::request()
+                .header("Authorization", basic(username, PASSWORD))
+                .reply(&filter)
+                .await;
+            assert_eq!(resp.status(), StatusCode::OK);
+            assert_eq!(resp.body(), expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_auth_restricted() {
+        let users = test_users();
+        let cases = vec![
+            (UserRole::ReadOnly, vec![("reader", StatusCode::OK), ("uploader", StatusCode::OK), ("admin", StatusCode::OK)]),
+            (UserRole::Uploader, vec![("reader", StatusCode::FORBIDDEN), ("uploader", StatusCode::OK), ("admin", StatusCode::OK)]),
+            (UserRole::Admin, vec![("reader", StatusCode::FORBIDDEN), ("uploader", StatusCode::FORBIDDEN), ("admin", StatusCode::OK)]),
+        ];
+
+        for (role, expectations) in cases {
+            let filter = auth_restricted(users.clone(), role)
+                .map(|user: AuthenticatedUser| user.username)
+                .recover(recover_auth);
+            for (username, status) in expectations {
+                let resp = warp::test::request()
+                    .header("Authorization", basic(username, PASSWORD))
+                    .reply(&filter)
+                    .await;
+                assert_eq!(resp.status(), status, "{} accessing a {:?} resource", username, role);
+                if status == StatusCode::OK {
+                    assert_eq!(resp.body(), username);
+                }
+            }
+
+            let resp = warp::test::request()
+                .header("Authorization", basic("admin", "wrong"))
+                .reply(&filter)
+                .await;
+            assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_malformed_authorization() {
+        let users = test_users();
+        let filter = auth(users.clone())
+            .map(|user: AuthenticatedUser| user.username)
+            .recover(recover_auth);
+        let request = |header: HeaderValue| warp::test::request().header("Authorization", header);
+
+        let cases: Vec<(&[u8], StatusCode)> = vec![
+            (b"Basic", StatusCode::BAD_REQUEST),
+            (b"Basic !!!!", StatusCode::BAD_REQUEST),
+            (b"Basic cmVhZGVy", StatusCode::BAD_REQUEST),
+            (b"Basic //79", StatusCode::BAD_REQUEST),
+            (b"Basic \xff\xfe", StatusCode::BAD_REQUEST),
+            (b"Bearer not a token", StatusCode::BAD_REQUEST),
+            (b"Digest username=\"reader\"", StatusCode::UNAUTHORIZED),
+            (b"Bearer forged.token", StatusCode::UNAUTHORIZED),
+        ];
+        for (header, status) in cases {
+            let resp = request(HeaderValue::from_bytes(header).unwrap()).reply(&filter).await;
+            assert_eq!(resp.status(), status, "{:?}", String::from_utf8_lossy(header));
+            if status == StatusCode::UNAUTHORIZED {
+                assert!(resp.headers().contains_key("www-authenticate"));
+            }
+        }
+
+        // The scheme is case insensitive, and API clients can use a session token instead of a password
+        let header = basic("reader", PASSWORD).replace("Basic", "bASIC");
+        let resp = request(HeaderValue::from_str(&header).unwrap()).reply(&filter).await;
+        assert_eq!(resp.body(), "reader");
+        let token = users.sessions.lock().await.create("admin", Utc::now());
+        let resp = request(HeaderValue::from_str(&format!("Bearer {}", token)).unwrap()).reply(&filter).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.body(), "admin");
+    }
+
+    fn upload_route(users: &UserMap, root: &std::path::Path, max_size: u64) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        upload_file_filter(users.clone(), models::new_serve_point(ServePoint::new(root.to_owned())), no_acl(), max_size).recover(recover_auth)
+    }
+
+    #[tokio::test]
+    async fn test_upload_file() {
+        let users = test_users();
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("videos")).unwrap();
+        let route = upload_route(&users, dir.path(), 1024);
+
+        let resp = warp::test::request()
+            .method("PUT")
+            .path("/browse/videos/my%20video.mp4")
+            .header("Authorization", basic("uploader", PASSWORD))
+            .body("some video")
+            .reply(&route)
+            .await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        assert_eq!(std::fs::read(dir.path().join("videos").join("my video.mp4")).unwrap(), b"some video");
+
+        // Existing files are left alone unless overwrite is set
+        let resp = warp::test::request()
+            .method("PUT")
+            .path("/browse/videos/my%20video.mp4")
+            .header("Authorization", basic("admin", PASSWORD))
+            .body("another video")
+            .reply(&route)
+            .await;
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+        assert_eq!(std::fs::read(dir.path().join("videos").join("my video.mp4")).unwrap(), b"some video");
+
+        let resp = warp::test::request()
+            .method("PUT")
+            .path("/browse/videos/my%20video.mp4?overwrite=true")
+            .header("Authorization", basic("admin", PASSWORD))
+            .body("another video")
+            .reply(&route)
+            .await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        assert_eq!(std::fs::read(dir.path().join("videos").join("my video.mp4")).unwrap(), b"another video");
+
+        // No temp files are left behind
+        assert_eq!(std::fs::read_dir(dir.path().join("videos")).unwrap().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_upload_file_rejected() {
+        let users = test_users();
+        let dir = tempfile::tempdir().unwrap();
+        let share = dir.path().join("share");
+        std::fs::create_dir(&share).unwrap();
+        let route = upload_route(&users, &share, 8);
+
+        let resp = warp::test::request()
+            .method("PUT")
+            .path("/browse/file.txt")
+            .header("Authorization", basic("reader", PASSWORD))
+            .body("text")
+            .reply(&route)
+            .await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        let resp = warp::test::request()
+            .method("PUT")
+            .path("/browse/file.txt")
+            .header("Authorization", basic("uploader", PASSWORD))
+            .body("far too much text")
+            .reply(&route)
+            .await;
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        let resp = warp::test::request()
+            .method("PUT")
+            .path("/browse/missing/file.txt")
+            .header("Authorization", basic("uploader", PASSWORD))
+            .body("text")
+            .reply(&route)
+            .await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        for path in &["/browse/../escaped.txt", "/browse/%2E%2E/escaped.txt", "/browse/%2E%2E%2Fescaped.txt"] {
+            let resp = warp::test::request()
+                .method("PUT")
+                .path(path)
+                .header("Authorization", basic("uploader", PASSWORD))
+                .body("text")
+                .reply(&route)
+                .await;
+            assert!(resp.status().is_client_error(), "{} returned {}", path, resp.status());
+        }
+
+        assert!(!dir.path().join("escaped.txt").exists());
+        assert_eq!(std::fs::read_dir(&share).unwrap().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_resumable_upload() {
+        use crate::upload_sessions::UploadSessions;
+        use std::time::Duration;
+
+        let users = test_users();
+        let share = tempfile::tempdir().unwrap();
+        let state = tempfile::tempdir().unwrap();
+        let sessions = UploadSessions::load(state.path().to_owned(), Duration::from_secs(60)).unwrap();
+        let route = resumable_upload_filters(
+            users.clone(),
+            models::new_serve_point(ServePoint::new(share.path().to_owned())),
+            no_acl(),
+            models::new_uploads(sessions),
+            1024,
+        ).recover(recover_auth);
+
+        let resp = warp::test::request()
+            .method("POST")
+            .path("/uploads")
+            .header("Authorization", basic("uploader", PASSWORD))
+            .json(&serde_json::json!({"path": "film.mkv", "size": 10}))
+            .reply(&route)
+            .await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let created: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        let id = created["id"].as_str().unwrap().to_owned();
+        assert_eq!(created["offset"], 0);
+
+        let patch = |offset: &str, body: &'static str| warp::test::request()
+            .method("PATCH")
+            .path(&format!("/uploads/{}", id))
+            .header("Authorization", basic("uploader", PASSWORD))
+            .header("Upload-Offset", offset)
+            .body(body);
+
+        let resp = patch("0", "01234").reply(&route).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()["Upload-Offset"], "5");
+
+        // Resending the same chunk is refused, and tells the client where to carry on from
+        let resp = patch("0", "01234").reply(&route).await;
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+        assert_eq!(resp.headers()["Upload-Offset"], "5");
+
+        // Other uploaders can't touch the upload
+        let resp = warp::test::request()
+            .path(&format!("/uploads/{}", id))
+            .header("Authorization", basic("reader", PASSWORD))
+            .reply(&route)
+            .await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        let resp = warp::test::request()
+            .path(&format!("/uploads/{}", id))
+            .header("Authorization", basic("uploader", PASSWORD))
+            .reply(&route)
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()["Upload-Offset"], "5");
+
+        let finish = || warp::test::request()
+            .method("POST")
+            .path(&format!("/uploads/{}/finish", id))
+            .header("Authorization", basic("uploader", PASSWORD));
+
+        let resp = finish().reply(&route).await;
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+        assert!(!share.path().join("film.mkv").exists());
+
+        // More than the announced size is refused
+        let resp = patch("5", "56789XXXXX").reply(&route).await;
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        let resp = patch("5", "56789").reply(&route).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        // A file that turned up at the path in the meantime isn't replaced, and the upload can still be finished
+        std::fs::write(share.path().join("film.mkv"), b"someone else's").unwrap();
+        let resp = finish().reply(&route).await;
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+        assert_eq!(std::fs::read(share.path().join("film.mkv")).unwrap(), b"someone else's");
+        std::fs::remove_file(share.path().join("film.mkv")).unwrap();
+
+        let resp = finish().reply(&route).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        assert_eq!(std::fs::read(share.path().join("film.mkv")).unwrap(), b"0123456789");
+        assert_eq!(std::fs::read_dir(share.path()).unwrap().count(), 1);
+
+        let resp = finish().reply(&route).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    fn test_trash(share: &std::path::Path, dir: &std::path::Path) -> TrashBin {
+        models::new_trash_bin(crate::trash::Trash::load(dir.to_owned(), &[share.to_owned()], 30).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_admin_fs() {
+        let users = test_users();
+        let share = tempfile::tempdir().unwrap();
+        let state = tempfile::tempdir().unwrap();
+        let trash = test_trash(share.path(), state.path());
+        let route = admin_fs_filters(users.clone(), models::new_serve_point(ServePoint::new(share.path().to_owned())), no_acl(), trash.clone())
+            .recover(recover_auth);
+
+        let request = |username: &str, action: &str, body: serde_json::Value| warp::test::request()
+            .method("POST")
+            .path(&format!("/admin/fs/{}", action))
+            .header("Authorization", basic(username, PASSWORD))
+            .json(&body);
+
+        for username in &["reader", "uploader"] {
+            let resp = request(username, "mkdir", serde_json::json!({"path": "shows"})).reply(&route).await;
+            assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        }
+        assert!(!share.path().join("shows").exists());
+
+        let resp = request("admin", "mkdir", serde_json::json!({"path": "/shows/"})).reply(&route).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        assert!(share.path().join("shows").is_dir());
+
+        std::fs::write(share.path().join("episode.mp4"), b"video").unwrap();
+        let resp = request("admin", "rename", serde_json::json!({"from": "/episode.mp4", "to": "shows/episode 1.mp4"})).reply(&route).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(share.path().join("shows").join("episode 1.mp4").is_file());
+
+        let resp = request("admin", "rename", serde_json::json!({"from": "shows", "to": "../shows"})).reply(&route).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        let resp = request("admin", "delete", serde_json::json!({"path": "/shows/"})).reply(&route).await;
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+
+        let resp = request("admin", "delete", serde_json::json!({"path": "/shows/", "recursive": true})).reply(&route).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(!share.path().join("shows").exists());
+        assert_eq!(trash.lock().await.items().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_admin_trash() {
+        let users = test_users();
+        let share = tempfile::tempdir().unwrap();
+        let state = tempfile::tempdir().unwrap();
+        let trash = test_trash(share.path(), state.path());
+        let sp = models::new_serve_point(ServePoint::new(share.path().to_owned()));
+        let route = admin_trash_filters(users.clone(), sp.clone(), trash.clone()).recover(recover_auth);
+
+        std::fs::write(share.path().join("a.mp4"), b"a").unwrap();
+        std::fs::write(share.path().join("b.mp4"), b"b").unwrap();
+        let (a, b) = {
+            let (mut bin, sp) = (trash.lock().await, sp.lock().await);
+            let mut delete = |name: &str| {
+                let pending = bin.start_delete(&sp, std::path::Path::new(name), false, "admin").unwrap();
+                let result = pending.run();
+                bin.finish_delete(pending, result).unwrap()
+            };
+            (delete("a.mp4"), delete("b.mp4"))
+        };
+
+        let resp = warp::test::request()
+            .path("/admin/trash")
+            .header("Authorization", basic("uploader", PASSWORD))
+            .reply(&route)
+            .await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        let resp = warp::test::request()
+            .path("/admin/trash")
+            .header("Authorization", basic("admin", PASSWORD))
+            .reply(&route)
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let items: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(items.as_array().unwrap().len(), 2);
+        assert_eq!(items[0]["deleted_by"], "admin");
+
+        let resp = warp::test::request()
+            .method("POST")
+            .path(&format!("/admin/trash/{}/restore", a.id))
+            .header("Authorization", basic("admin", PASSWORD))
+            .reply(&route)
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(std::fs::read(share.path().join("a.mp4")).unwrap(), b"a");
+
+        let resp = warp::test::request()
+            .method("DELETE")
+            .path(&format!("/admin/trash/{}", b.id))
+            .header("Authorization", basic("admin", PASSWORD))
+            .reply(&route)
+            .await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        assert!(!share.path().join("b.mp4").exists());
+        assert!(trash.lock().await.items().is_empty());
+
+        let resp = warp::test::request()
+            .method("DELETE")
+            .path(&format!("/admin/trash/{}", b.id))
+            .header("Authorization", basic("admin", PASSWORD))
+            .reply(&route)
+            .await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_listing_actions_only_for_admins() {
+        let users = test_users();
+        let route = render_file_listing(test_sp(), None, no_acl(), models::new_handlebars_arc(), users);
+
+        for (username, is_admin) in &[("reader", false), ("uploader", false), ("admin", true)] {
+            let resp = warp::test::request()
+                .path("/browse/")
+                .header("Authorization", basic(username, PASSWORD))
+                .reply(&route)
+                .await;
+            let body = std::str::from_utf8(resp.body()).unwrap();
+            assert_eq!(body.contains("data-action=\"delete\""), *is_admin, "listing for {}", username);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_list_directory_json() {
+        let users = test_users();
+        let route = list_directory_json(test_sp(), None, no_acl(), users).recover(recover_auth);
+
+        let resp = warp::test::request()
+            .path("/api/list/folder1/")
+            .header("Authorization", basic("reader", PASSWORD))
+            .reply(&route)
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()["content-type"], "application/json");
+
+        let listing: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        let children = listing["children"].as_array().unwrap();
+        let dir = children.iter().find(|c| c["name"] == "mytestfiles/").unwrap();
+        assert_eq!(dir["type"], "directory");
+        assert_eq!(dir["mime"], "inode/directory");
+
+        let file = children.iter().find(|c| c["name"] == "file3.abc").unwrap();
+        assert_eq!(file["type"], "file");
+        assert_eq!(file["size"], 0);
+        let modified = file["modified"].as_str().unwrap();
+        assert!(chrono::DateTime::parse_from_rfc3339(modified).is_ok(), "{} is not RFC 3339", modified);
+
+        for path in &["/api/list/nope/", "/api/list/file1.abc", "/api/list/..%2F"] {
+            let resp = warp::test::request()
+                .path(path)
+                .header("Authorization", basic("reader", PASSWORD))
+                .reply(&route)
+                .await;
+            assert_eq!(resp.status(), StatusCode::NOT_FOUND, "{}", path);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_listing_query() {
+        let users = test_users();
+        let get = |path: &str| warp::test::request().path(path).header("Authorization", basic("reader", PASSWORD));
+
+        let api = list_directory_json(test_sp(), None, no_acl(), users.clone()).recover(recover_auth);
+        let resp = get("/api/list/?sort=name&order=desc&per_page=1&page=2").reply(&api).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let listing: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(listing["children"][0]["name"], "file2.abc");
+        assert_eq!((listing["total"].as_u64(), listing["pages"].as_u64()), (Some(3), Some(3)));
+
+        let resp = get("/api/list/?filter=*1.abc").reply(&api).await;
+        let listing: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(listing["total"], 1);
+        assert_eq!(listing["children"][0]["name"], "file1.abc");
+
+        for query in &["sort=colour", "order=up", "page=abc", "filter=[a"] {
+            let resp = get(&format!("/api/list/?{}", query)).reply(&api).await;
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{}", query);
+        }
+
+        let html = render_file_listing(test_sp(), None, no_acl(), models::new_handlebars_arc(), users).recover(recover_auth);
+        let resp = get("/browse/?per_page=1&page=2&sort=size").reply(&html).await;
+        let body = std::str::from_utf8(resp.body()).unwrap();
+        assert!(body.contains("Page 2 of 3"), "{}", body);
+        // Handlebars escapes the '=' too
+        let link = |query: &str| format!("href=\"?{}\"", query.replace('&', "&amp;").replace('=', "&#x3D;"));
+        assert!(body.contains(&link("sort=size&per_page=1")), "{}", body);
+        assert!(body.contains(&link("sort=size&per_page=1&page=3")), "{}", body);
+
+        let resp = get("/browse/missing/").reply(&html).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_search() {
+        let users = test_users();
+        let get = |path: &str| warp::test::request().path(path).header("Authorization", basic("reader", PASSWORD));
+
+        let api = search_json(test_sp(), None, no_acl(), users.clone()).recover(recover_auth);
+        let resp = get("/api/search?q=TESTFILE").reply(&api).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let found: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        let mut paths: Vec<&str> = found["results"].as_array().unwrap().iter().map(|r| r["path"].as_str().unwrap()).collect();
+        paths.sort();
+        assert_eq!(paths, vec!["/folder1/mytestfiles/", "/folder1/mytestfiles/testfile1.txt", "/folder1/mytestfiles/testfile2.txt"]);
+        let dir = found["results"].as_array().unwrap().iter().find(|r| r["path"] == "/folder1/mytestfiles/").unwrap();
+        assert_eq!(dir["type"], "directory");
+
+        let resp = get("/api/search?q=TESTFILE&case_sensitive=true").reply(&api).await;
+        let found: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert!(found["results"].as_array().unwrap().is_empty());
+
+        let resp = get("/api/search?q=*.abc&mode=glob&limit=2").reply(&api).await;
+        let found: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(found["results"].as_array().unwrap().len(), 2);
+        assert_eq!(found["truncated"], true);
+
+        for query in &["", "?q=", "?q=[a&mode=glob", "?q=a&mode=regex"] {
+            let resp = get(&format!("/api/search{}", query)).reply(&api).await;
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{}", query);
+        }
+
+        let page = search_page(test_sp(), None, no_acl(), models::new_handlebars_arc(), users).recover(recover_auth);
+        let resp = get("/search?q=file3").reply(&page).await;
+        let body = std::str::from_utf8(resp.body()).unwrap();
+        assert!(body.contains("href=\"/browse/folder1/file3.abc\""), "{}", body);
+
+        let resp = get("/search").reply(&page).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(!std::str::from_utf8(resp.body()).unwrap().contains("Nothing found"));
+    }
+
+    #[tokio::test]
+    async fn test_mounts() {
+        use crate::fs_utils::Mount;
+        let users = test_users();
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("a").join("private")).unwrap();
+        std::fs::write(dir.path().join("a").join("film.mkv"), b"film").unwrap();
+        std::fs::write(dir.path().join("a").join("private").join("home.mkv"), b"home").unwrap();
+        std::fs::create_dir(dir.path().join("b")).unwrap();
+        let serve_point = ServePoint::with_mounts(vec![
+            Mount::new(String::from("movies"), dir.path().join("a"), false).unwrap(),
+            Mount::new(String::from("photos"), dir.path().join("b"), true).unwrap(),
+        ]).unwrap();
+        let mut config = crate::acl::AclConfig::default();
+        config.add_mount("movies", &serde_json::from_str(r#"{ "rules": [{ "path": "private", "allow": { "users": ["admin"] } }] }"#).unwrap());
+        let acl = models::new_access_rules(Acl::new(config).unwrap());
+        let sp = models::new_serve_point(serve_point.clone());
+        let route = list_directory_json(sp.clone(), None, acl.clone(), users.clone())
+            .or(upload_file_filter(users.clone(), sp, acl.clone(), 1024))
+            .or(serve_files(serve_point, acl, users.clone()))
+            .recover(recover_auth);
+        let get = |username: &str, path: &str| warp::test::request().path(path).header("Authorization", basic(username, PASSWORD));
+        let names = |body: &[u8]| -> Vec<String> {
+            let json: serde_json::Value = serde_json::from_slice(body).unwrap();
+            json["children"].as_array().unwrap().iter().map(|c| c["name"].as_str().unwrap().to_owned()).collect()
+        };
+
+        // The mounts are the top level of the share
+        let resp = get("reader", "/api/list/").reply(&route).await;
+        assert_eq!(names(resp.body()), vec!["movies/", "photos/"]);
+        let resp = get("reader", "/api/list/movies/").reply(&route).await;
+        assert_eq!(names(resp.body()), vec!["film.mkv"]);
+        let resp = get("admin", "/api/list/movies/").reply(&route).await;
+        assert_eq!(names(resp.body()), vec!["private/", "film.mkv"]);
+        assert_eq!(get("reader", "/api/list/music/").reply(&route).await.status(), StatusCode::NOT_FOUND);
+
+        let resp = get("reader", "/browse/movies/film.mkv").reply(&route).await;
+        assert_eq!(resp.body(), "film");
+        assert_eq!(get("reader", "/browse/movies/private/home.mkv").reply(&route).await.status(), StatusCode::NOT_FOUND);
+        assert_eq!(get("admin", "/browse/movies/private/home.mkv").reply(&route).await.body(), "home");
+        assert_eq!(get("reader", "/browse/photos/film.mkv").reply(&route).await.status(), StatusCode::NOT_FOUND);
+
+        // Only the writable mount takes uploads
+        let upload = |path: &str| warp::test::request()
+            .method("PUT")
+            .path(path)
+            .header("Authorization", basic("uploader", PASSWORD))
+            .body("upload");
+        assert_eq!(upload("/browse/movies/new.mkv").reply(&route).await.status(), StatusCode::FORBIDDEN);
+        assert!(!dir.path().join("a").join("new.mkv").exists());
+        assert_eq!(upload("/browse/photos/new.jpg").reply(&route).await.status(), StatusCode::CREATED);
+        assert_eq!(std::fs::read(dir.path().join("b").join("new.jpg")).unwrap(), b"upload");
+        assert_eq!(upload("/browse/new.jpg").reply(&route).await.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_symlinks() {
+        use crate::fs_utils::SymlinkPolicy;
+        use std::os::unix::fs::symlink;
+        let users = test_users();
+        let dir = tempfile::tempdir().unwrap();
+        let other = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("film.mkv"), b"film").unwrap();
+        std::fs::write(other.path().join("secret.txt"), b"secret").unwrap();
+        symlink(dir.path().join("film.mkv"), dir.path().join("inside.mkv")).unwrap();
+        symlink(other.path().join("secret.txt"), dir.path().join("outside.txt")).unwrap();
+        let acl = models::new_access_rules(Acl::new(crate::acl::AclConfig::default()).unwrap());
+        let get = |path: &str| warp::test::request().path(path).header("Authorization", basic("reader", PASSWORD));
+
+        let route = serve_files(ServePoint::new(dir.path().to_owned()), acl.clone(), users.clone()).recover(recover_auth);
+        assert_eq!(get("/browse/inside.mkv").reply(&route).await.body(), "film");
+        assert_eq!(get("/browse/outside.txt").reply(&route).await.status(), StatusCode::NOT_FOUND);
+
+        let serve_point = ServePoint::new(dir.path().to_owned()).with_symlinks(SymlinkPolicy::Deny).unwrap();
+        let route = serve_files(serve_point, acl.clone(), users.clone()).recover(recover_auth);
+        assert_eq!(get("/browse/film.mkv").reply(&route).await.body(), "film");
+        assert_eq!(get("/browse/inside.mkv").reply(&route).await.status(), StatusCode::NOT_FOUND);
+
+        let serve_point = ServePoint::new(dir.path().to_owned())
+            .with_symlinks(SymlinkPolicy::AllowTargets(vec![other.path().to_owned()])).unwrap();
+        let route = serve_files(serve_point, acl, users).recover(recover_auth);
+        assert_eq!(get("/browse/outside.txt").reply(&route).await.body(), "secret");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_browse_swapped_links() {
+        use std::os::unix::fs::symlink;
+        let users = test_users();
+        let dir = tempfile::tempdir().unwrap();
+        let other = tempfile::tempdir().unwrap();
+        let films = dir.path().join("films");
+        std::fs::create_dir(&films).unwrap();
+        std::fs::write(films.join("film.mkv"), b"film").unwrap();
+        std::fs::write(other.path().join("film.mkv"), b"secret").unwrap();
+        let route = serve_files(ServePoint::new(dir.path().to_owned()), no_acl(), users).recover(recover_auth);
+        let get = |path: &str| warp::test::request().path(path).header("Authorization", basic("reader", PASSWORD));
+        assert_eq!(get("/browse/films/film.mkv").reply(&route).await.body(), "film");
+
+        // The file is swapped for a link to somewhere outside of the share after the server started
+        std::fs::remove_file(films.join("film.mkv")).unwrap();
+        symlink(other.path().join("film.mkv"), films.join("film.mkv")).unwrap();
+        assert_eq!(get("/browse/films/film.mkv").reply(&route).await.status(), StatusCode::NOT_FOUND);
+
+        // ...and so is a directory on the way to it
+        std::fs::remove_file(films.join("film.mkv")).unwrap();
+        std::fs::remove_dir(&films).unwrap();
+        symlink(other.path(), &films).unwrap();
+        assert_eq!(get("/browse/films/film.mkv").reply(&route).await.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_browse_ranges() {
+        let users = test_users();
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("film.mkv"), b"0123456789").unwrap();
+        let route = serve_files(ServePoint::new(dir.path().to_owned()), no_acl(), users).recover(recover_auth);
+        let get = |range: &str| warp::test::request()
+            .path("/browse/film.mkv")
+            .header("Authorization", basic("reader", PASSWORD))
+            .header("Range", range);
+
+        let resp = warp::test::request().path("/browse/film.mkv").header("Authorization", basic("reader", PASSWORD)).reply(&route).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()["Content-Type"], "video/x-matroska");
+        assert_eq!(resp.headers()["Accept-Ranges"], "bytes");
+        assert_eq!(resp.body(), "0123456789");
+
+        let resp = get("bytes=2-5").reply(&route).await;
+        assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(resp.headers()["Content-Range"], "bytes 2-5/10");
+        assert_eq!(resp.body(), "2345");
+        assert_eq!(get("bytes=7-").reply(&route).await.body(), "789");
+        assert_eq!(get("bytes=-3").reply(&route).await.body(), "789");
+        assert_eq!(get("bytes=8-100").reply(&route).await.body(), "89");
+
+        // Ranges that can't be parsed, or ask for several parts, get the whole file
+        assert_eq!(get("bytes=5-2").reply(&route).await.body(), "0123456789");
+        assert_eq!(get("bytes=0-1,4-5").reply(&route).await.body(), "0123456789");
+        assert_eq!(get("lines=1-2").reply(&route).await.status(), StatusCode::OK);
+
+        let resp = get("bytes=10-").reply(&route).await;
+        assert_eq!(resp.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(resp.headers()["Content-Range"], "bytes */10");
+    }
+
+    #[tokio::test]
+    async fn test_ignore_lists() {
+        use crate::ignore::{IgnoreList, IGNORE_FILE};
+        let users = test_users();
+        let dir = tempfile::tempdir().unwrap();
+        let films = dir.path().join("films");
+        std::fs::create_dir_all(films.join("samples")).unwrap();
+        std::fs::write(films.join("film.mkv"), b"film").unwrap();
+        std::fs::write(films.join("film.mkv.part"), b"fi").unwrap();
+        std::fs::write(films.join(".DS_Store"), b"junk").unwrap();
+        std::fs::write(films.join("samples").join("sample.mkv"), b"sample").unwrap();
+        std::fs::write(films.join(IGNORE_FILE), b"samples/\n").unwrap();
+        let serve_point = ServePoint::new(dir.path().to_owned())
+            .with_ignore_list(IgnoreList::new(&[String::from(".*"), String::from("*.part")]).unwrap());
+        let sp = models::new_serve_point(serve_point.clone());
+        let state = tempfile::tempdir().unwrap();
+        let trash = test_trash(dir.path(), &state.path().join("trash"));
+        let route = list_directory_json(sp.clone(), None, no_acl(), users.clone())
+            .or(search_json(sp.clone(), None, no_acl(), users.clone()))
+            .or(archive_filters(sp.clone(), no_acl(), users.clone(), TEST_LIMITS))
+            .or(upload_file_filter(users.clone(), sp.clone(), no_acl(), 1024))
+            .or(admin_fs_filters(users.clone(), sp, no_acl(), trash))
+            .or(serve_files(serve_point, no_acl(), users))
+            .recover(recover_auth);
+        let get = |path: &str| warp::test::request().path(path).header("Authorization", basic("reader", PASSWORD));
+        let names = |body: &[u8], key: &str, field: &str| -> Vec<String> {
+            let json: serde_json::Value = serde_json::from_slice(body).unwrap();
+            json[key].as_array().unwrap().iter().map(|c| c[field].as_str().unwrap().to_owned()).collect()
+        };
+
+        let resp = get("/api/list/films/").reply(&route).await;
+        assert_eq!(names(resp.body(), "children", "name"), vec!["film.mkv"]);
+        let resp = get("/api/search?q=film").reply(&route).await;
+        assert_eq!(names(resp.body(), "results", "path"), vec!["/films/", "/films/film.mkv"]);
+        let resp = get("/api/search?q=sample").reply(&route).await;
+        assert!(names(resp.body(), "results", "path").is_empty());
+
+        let resp = get("/archive/films/").reply(&route).await;
+        let zip = zip::ZipArchive::new(std::io::Cursor::new(resp.body().to_vec())).unwrap();
+        let mut archived: Vec<&str> = zip.file_names().collect();
+        archived.sort();
+        assert_eq!(archived, vec!["films/", "films/film.mkv"]);
+
+        assert_eq!(get("/browse/films/film.mkv").reply(&route).await.body(), "film");
+        for path in &["/browse/films/.DS_Store", "/browse/films/film.mkv.part", "/browse/films/samples/sample.mkv",
+                      "/browse/films/.ffsignore", "/api/list/films/samples/", "/archive/films/samples/"] {
+            assert_eq!(get(path).reply(&route).await.status(), StatusCode::NOT_FOUND, "{}", path);
+        }
+
+        // Nothing can be uploaded, made or renamed under a name that would be hidden
+        let upload = |path: &str| warp::test::request()
+            .method("PUT")
+            .path(path)
+            .header("Authorization", basic("uploader", PASSWORD))
+            .body("junk");
+        for path in &["/browse/films/.ffsignore", "/browse/films/.hidden", "/browse/films/new.part"] {
+            assert_eq!(upload(path).reply(&route).await.status(), StatusCode::BAD_REQUEST, "{}", path);
+        }
+        assert_eq!(upload("/browse/films/new.mkv").reply(&route).await.status(), StatusCode::CREATED);
+        let post = |path: &str, body: serde_json::Value| warp::test::request()
+            .method("POST")
+            .path(path)
+            .header("Authorization", basic("admin", PASSWORD))
+            .json(&body);
+        for (path, body) in &[
+            ("/admin/fs/mkdir", serde_json::json!({ "path": "films/.git" })),
+            ("/admin/fs/rename", serde_json::json!({ "from": "films/new.mkv", "to": "films/.ffsignore" })),
+            ("/admin/fs/rename", serde_json::json!({ "from": "films/new.mkv", "to": "films/new.mkv.part" })),
+        ] {
+            assert_eq!(post(path, body.clone()).reply(&route).await.status(), StatusCode::BAD_REQUEST, "{}", body);
+        }
+        assert!(!films.join(".git").exists());
+        assert_eq!(std::fs::read_to_string(films.join(IGNORE_FILE)).unwrap(), "samples/\n");
+    }
+
+    #[tokio::test]
+    async fn test_acl() {
+        let users = test_users();
+        let config = serde_json::from_value(serde_json::json!({
+            "rules": [
+                { "path": "folder1/mytestfiles", "allow": { "users": ["admin"] } },
+                { "path": "file2.abc", "deny": { "users": ["reader"] } },
+            ]
+        })).unwrap();
+        let acl = models::new_access_rules(Acl::new(config).unwrap());
+        let hba = models::new_handlebars_arc();
+        let route = list_directory_json(test_sp(), None, acl.clone(), users.clone())
+            .or(search_json(test_sp(), None, acl.clone(), users.clone()))
+            .or(archive_filters(test_sp(), acl.clone(), users.clone(), TEST_LIMITS))
+            .or(render_cinema_page(test_sp(), acl.clone(), hba, users.clone()))
+            .or(serve_files(ServePoint::new(PathBuf::from("test/testfolder")), acl.clone(), users.clone()))
+            .recover(recover_auth);
+        let get = |username: &str, path: &str| warp::test::request().path(path).header("Authorization", basic(username, PASSWORD));
+        let names = |body: &[u8], key: &str, field: &str| -> Vec<String> {
+            let json: serde_json::Value = serde_json::from_slice(body).unwrap();
+            json[key].as_array().unwrap().iter().map(|c| c[field].as_str().unwrap().to_owned()).collect()
+        };
+
+        // Entries the reader can't see are left out of listings and searches
+        let resp = get("reader", "/api/list/").reply(&route).await;
+        assert_eq!(names(resp.body(), "children", "name"), vec!["folder1/", "file1.abc"]);
+        let resp = get("reader", "/api/list/folder1/").reply(&route).await;
+        assert_eq!(names(resp.body(), "children", "name"), vec!["file3.abc"]);
+        let resp = get("reader", "/api/search?q=testfile").reply(&route).await;
+        assert!(names(resp.body(), "results", "path").is_empty());
+        let resp = get("admin", "/api/search?q=testfile").reply(&route).await;
+        assert_eq!(names(resp.body(), "results", "path").len(), 3);
+
+        let resp = get("reader", "/api/list/folder1/..%2Ffolder1/mytestfiles/").reply(&route).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        for path in &["/api/list/folder1/mytestfiles/", "/browse/file2.abc",
+                      "/browse/folder1/mytestfiles/testfile1.txt", "/cinema/file2.abc", "/archive/folder1/mytestfiles/"] {
+            assert_eq!(get("reader", path).reply(&route).await.status(), StatusCode::NOT_FOUND, "{}", path);
+            assert_eq!(get("admin", path).reply(&route).await.status(), StatusCode::OK, "{}", path);
+        }
+
+        let resp = get("reader", "/archive/folder1/").reply(&route).await;
+        let zip = zip::ZipArchive::new(std::io::Cursor::new(resp.body().to_vec())).unwrap();
+        let mut names: Vec<&str> = zip.file_names().collect();
+        names.sort_unstable();
+        assert_eq!(names, vec!["folder1/", "folder1/file3.abc"]);
+        let resp = warp::test::request()
+            .method("POST")
+            .path("/archive")
+            .header("Authorization", basic("reader", PASSWORD))
+            .body("path=file1.abc&path=file2.abc")
+            .reply(&route)
+            .await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        // A share link only hands out what the user that made it can read
+        let state = tempfile::tempdir().unwrap();
+        let shares = models::new_share_links(crate::shares::Shares::load(state.path().to_owned()).unwrap());
+        let api = share_filters(users.clone(), test_sp(), acl.clone(), shares.clone()).recover(recover_auth);
+        let links = share_link_filters(test_sp(), shares, users.clone(), acl.clone(), TEST_LIMITS).recover(recover_auth);
+        let share = |username: &str| warp::test::request()
+            .method("POST")
+            .path("/api/shares")
+            .header("Authorization", basic(username, PASSWORD))
+            .json(&serde_json::json!({"path": "/folder1/"}));
+        let shared = |resp: warp::http::Response<bytes::Bytes>| {
+            let json: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+            warp::test::request().path(json["url"].as_str().unwrap())
+        };
+        let resp = shared(share("uploader").reply(&api).await).reply(&links).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let zip = zip::ZipArchive::new(std::io::Cursor::new(resp.body().to_vec())).unwrap();
+        let mut names: Vec<&str> = zip.file_names().collect();
+        names.sort_unstable();
+        assert_eq!(names, vec!["folder1/", "folder1/file3.abc"]);
+        let resp = shared(share("admin").reply(&api).await).reply(&links).await;
+        let zip = zip::ZipArchive::new(std::io::Cursor::new(resp.body().to_vec())).unwrap();
+        assert!(zip.file_names().any(|name| name.starts_with("folder1/mytestfiles/")));
+    }
+
+    #[tokio::test]
+    async fn test_acl_changes() {
+        use crate::upload_sessions::UploadSessions;
+        let users = test_users();
+        let share = tempfile::tempdir().unwrap();
+        let state = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(share.path().join("private")).unwrap();
+        std::fs::create_dir_all(share.path().join("public")).unwrap();
+        std::fs::write(share.path().join("private").join("x.txt"), b"private").unwrap();
+        std::fs::write(share.path().join("public").join("a.txt"), b"public").unwrap();
+        let config = serde_json::from_value(serde_json::json!({
+            "rules": [{ "path": "private", "deny": { "users": ["uploader", "admin"] } }]
+        })).unwrap();
+        let acl = models::new_access_rules(Acl::new(config).unwrap());
+
+        let sp = models::new_serve_point(ServePoint::new(share.path().to_owned()));
+        let mut sessions = UploadSessions::load(state.path().join("uploads"), std::time::Duration::from_secs(60)).unwrap();
+        let started = sessions.create(
+            PathBuf::from("private/big.bin"), &share.path().join("private").join("big.bin"), 4, "uploader", false,
+        ).unwrap();
+        let shares = models::new_share_links(crate::shares::Shares::load(state.path().join("shares")).unwrap());
+        let links = models::new_links(ShortLinks::load(state.path().join("links.json")).unwrap());
+        let trash = test_trash(share.path(), &state.path().join("trash"));
+        let route = share_filters(users.clone(), sp.clone(), acl.clone(), shares)
+            .or(short_link_filters(users.clone(), sp.clone(), acl.clone(), links))
+            .or(upload_file_filter(users.clone(), sp.clone(), acl.clone(), 1024))
+            .or(resumable_upload_filters(users.clone(), sp.clone(), acl.clone(), models::new_uploads(sessions), 1024))
+            .or(admin_fs_filters(users.clone(), sp, acl, trash))
+            .recover(recover_auth);
+        let post = |username: &str, path: &str, body: serde_json::Value| warp::test::request()
+            .method("POST")
+            .path(path)
+            .header("Authorization", basic(username, PASSWORD))
+            .json(&body);
+
+        for (path, body) in &[
+            ("/api/shares", serde_json::json!({ "path": "private/x.txt" })),
+            ("/api/links", serde_json::json!({ "path": "private/x.txt" })),
+            ("/api/links", serde_json::json!({ "path": "private/missing.txt" })),
+            ("/uploads", serde_json::json!({ "path": "private/new.bin", "size": 4 })),
+        ] {
+            assert_eq!(post("uploader", path, body.clone()).reply(&route).await.status(), StatusCode::NOT_FOUND, "{}", body);
+        }
+        assert_eq!(post("uploader", "/api/shares", serde_json::json!({ "path": "public/a.txt" })).reply(&route).await.status(), StatusCode::CREATED);
+
+        let put = warp::test::request()
+            .method("PUT")
+            .path("/browse/private/new.txt")
+            .header("Authorization", basic("uploader", PASSWORD))
+            .body("upload");
+        assert_eq!(put.reply(&route).await.status(), StatusCode::NOT_FOUND);
+        let chunk = warp::test::request()
+            .method("PATCH")
+            .path(&format!("/uploads/{}", started.id))
+            .header("Authorization", basic("uploader", PASSWORD))
+            .header("Upload-Offset", "0")
+            .body("data");
+        assert_eq!(chunk.reply(&route).await.status(), StatusCode::NOT_FOUND);
+        let finish = warp::test::request()
+            .method("POST")
+            .path(&format!("/uploads/{}/finish", started.id))
+            .header("Authorization", basic("uploader", PASSWORD));
+        assert_eq!(finish.reply(&route).await.status(), StatusCode::NOT_FOUND);
+
+        // Admins are kept out by the rules as well, both as the source and the destination of a move
+        for (path, body) in &[
+            ("/admin/fs/mkdir", serde_json::json!({ "path": "private/new" })),
+            ("/admin/fs/rename", serde_json::json!({ "from": "private/x.txt", "to": "public/x.txt" })),
+            ("/admin/fs/rename", serde_json::json!({ "from": "public/a.txt", "to": "private/a.txt" })),
+            ("/admin/fs/delete", serde_json::json!({ "path": "private/x.txt" })),
+        ] {
+            assert_eq!(post("admin", path, body.clone()).reply(&route).await.status(), StatusCode::NOT_FOUND, "{}", body);
+        }
+        let rename = serde_json::json!({ "from": "public/a.txt", "to": "public/b.txt" });
+        assert_eq!(post("admin", "/admin/fs/rename", rename).reply(&route).await.status(), StatusCode::OK);
+
+        let mut private: Vec<String> = std::fs::read_dir(share.path().join("private")).unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        private.retain(|name| !name.ends_with(".upload"));
+        assert_eq!(private, vec!["x.txt"]);
+        assert!(!share.path().join("public").join("x.txt").exists());
+    }
+
+    #[tokio::test]
+    async fn test_listing_uses_index() {
+        use crate::index::ShareIndex;
+        use std::sync::{Arc, RwLock};
+
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        std::fs::create_dir(root.join("films")).unwrap();
+        std::fs::write(root.join("films").join("film.mkv"), vec![0; 42]).unwrap();
+
+        let index = Arc::new(RwLock::new(ShareIndex::new(root.clone())));
+        index.write().unwrap().scan();
+        let route = list_directory_json(models::new_serve_point(ServePoint::new(root.clone())), Some(vec![index.clone()]), no_acl(), test_users());
+        let list = || async {
+            let resp = warp::test::request()
+                .path("/api/list/")
+                .header("Authorization", basic("reader", PASSWORD))
+                .reply(&route)
+                .await;
+            let listing: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+            listing["children"].as_array().unwrap().iter()
+                .map(|c| (c["name"].as_str().unwrap().to_owned(), c["size"].as_u64().unwrap()))
+                .collect::<Vec<(String, u64)>>()
+        };
+
+        // Served from memory, so a file the index hasn't heard about yet isn't there
+        std::fs::write(root.join("late.txt"), b"").unwrap();
+        assert_eq!(list().await, vec![(String::from("films/"), 42)]);
+
+        // A stale index is ignored
+        *index.write().unwrap() = ShareIndex::new(root.clone());
+        assert_eq!(list().await, vec![(String::from("films/"), 0), (String::from("late.txt"), 0)]);
+    }
+
+    #[tokio::test]
+    async fn test_archive() {
+        use std::io::Read;
+
+        let route = archive_filters(test_sp(), no_acl(), test_users(), TEST_LIMITS).recover(recover_auth);
+        let get = |path: &str| warp::test::request().path(path).header("Authorization", basic("reader", PASSWORD));
+
+        let resp = get("/archive/folder1/").reply(&route).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()["content-type"], "application/zip");
+        assert_eq!(resp.headers()["content-disposition"], "attachment; filename=\"folder1.zip\"; filename*=UTF-8''folder1%2Ezip");
+        let mut zip = zip::ZipArchive::new(std::io::Cursor::new(resp.body().to_vec())).unwrap();
+        let mut contents = String::new();
+        zip.by_name("folder1/mytestfiles/testfile1.txt").unwrap().read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, std::fs::read_to_string("test/testfolder/folder1/mytestfiles/testfile1.txt").unwrap());
+
+        let resp = get("/archive/?format=tar.gz").reply(&route).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()["content-type"], "application/gzip");
+        let mut tar = tar::Archive::new(flate2::read::GzDecoder::new(&resp.body()[..]));
+        let paths: Vec<String> = tar.entries().unwrap().map(|e| e.unwrap().path().unwrap().to_str().unwrap().to_owned()).collect();
+        assert!(paths.contains(&String::from("testfolder/folder1/file3.abc")), "{:?}", paths);
+
+        for (path, status) in &[
+            ("/archive/file1.abc", StatusCode::NOT_FOUND),
+            ("/archive/missing/", StatusCode::NOT_FOUND),
+            ("/archive/..%2F..%2F", StatusCode::NOT_FOUND),
+            ("/archive/folder1/?format=rar", StatusCode::BAD_REQUEST),
+        ] {
+            let resp = get(path).reply(&route).await;
+            assert_eq!(resp.status(), *status, "{}", path);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_archive_selection() {
+        let post = |form: &str| warp::test::request()
+            .method("POST")
+            .path("/archive")
+            .header("Authorization", basic("reader", PASSWORD))
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .body(form);
+
+        let route = archive_filters(test_sp(), no_acl(), test_users(), TEST_LIMITS).recover(recover_auth);
+        let resp = post("path=%2Ffile1.abc&path=%2Ffolder1%2F&path=%2Ffolder1%2Ffile3.abc").reply(&route).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()["content-type"], "application/zip");
+        assert_eq!(resp.headers()["content-disposition"], "attachment; filename=\"download.zip\"; filename*=UTF-8''download%2Ezip");
+        let zip = zip::ZipArchive::new(std::io::Cursor::new(resp.body().to_vec())).unwrap();
+        let mut names: Vec<&str> = zip.file_names().collect();
+        names.sort_unstable();
+        assert_eq!(names, vec![
+            "file1.abc", "folder1/", "folder1/file3.abc", "folder1/mytestfiles/",
+            "folder1/mytestfiles/testfile1.txt", "folder1/mytestfiles/testfile2.txt",
+        ]);
+
+        let resp = post("path=%2Ffile1.abc&format=tar").reply(&route).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()["content-type"], "application/x-tar");
+
+        for (form, status) in &[
+            ("", StatusCode::BAD_REQUEST),
+            ("format=zip", StatusCode::BAD_REQUEST),
+            ("path=%2Ffile1.abc&format=rar", StatusCode::BAD_REQUEST),
+            ("path=%2Fmissing.abc", StatusCode::NOT_FOUND),
+            ("path=%2F..%2F..%2FCargo.toml", StatusCode::NOT_FOUND),
+            ("path=%2F", StatusCode::NOT_FOUND),
+        ] {
+            let resp = post(form).reply(&route).await;
+            assert_eq!(resp.status(), *status, "{}", form);
+        }
+
+        // The limits apply to the files in the selected directories too
+        let limits = ArchiveLimits { max_files: 3, ..TEST_LIMITS };
+        let route = archive_filters(test_sp(), no_acl(), test_users(), limits).recover(recover_auth);
+        let resp = post("path=%2Ffile1.abc&path=%2Ffolder1%2F").reply(&route).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let resp = post("path=%2Ffolder1%2Fmytestfiles%2F").reply(&route).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_share_links() {
+        let share = tempfile::tempdir().unwrap();
+        std::fs::write(share.path().join("film.mkv"), b"a film").unwrap();
+        std::fs::create_dir(share.path().join("photos")).unwrap();
+        std::fs::write(share.path().join("photos").join("cat.jpg"), b"a cat").unwrap();
+        let state = tempfile::tempdir().unwrap();
+
+        let sp = models::new_serve_point(ServePoint::new(share.path().to_owned()));
+        let shares = models::new_share_links(crate::shares::Shares::load(state.path().to_owned()).unwrap());
+        let users = test_users();
+        let api = share_filters(users.clone(), sp.clone(), no_acl(), shares.clone()).recover(recover_auth);
+        let links = share_link_filters(sp, shares, users.clone(), no_acl(), TEST_LIMITS).recover(recover_auth);
+
+        let create = |user: &str, body: serde_json::Value| warp::test::request()
+            .method("POST")
+            .path("/api/shares")
+            .header("Authorization", basic(user, PASSWORD))
+            .json(&body);
+        let url = |resp: &warp::http::Response<bytes::Bytes>| {
+            let json: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+            json["url"].as_str().unwrap().to_owned()
+        };
+
+        // Only uploaders and admins can share, and only things inside the share
+        let resp = warp::test::request().method("POST").path("/api/shares").json(&serde_json::json!({"path": "/film.mkv"})).reply(&api).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let resp = create("reader", serde_json::json!({"path": "/film.mkv"})).reply(&api).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        for (body, status) in &[
+            (serde_json::json!({"path": "/"}), StatusCode::NOT_FOUND),
+            (serde_json::json!({"path": "/../"}), StatusCode::NOT_FOUND),
+            (serde_json::json!({"path": "/missing.mkv"}), StatusCode::NOT_FOUND),
+            (serde_json::json!({"path": "/film.mkv", "expires_in": 0}), StatusCode::BAD_REQUEST),
+            (serde_json::json!({"path": "/film.mkv", "max_downloads": 0}), StatusCode::BAD_REQUEST),
+        ] {
+            let resp = create("uploader", body.clone()).reply(&api).await;
+            assert_eq!(resp.status(), *status, "{}", body);
+        }
+
+        // A file can be downloaded without credentials, as many times as allowed
+        let resp = create("uploader", serde_json::json!({"path": "/film.mkv", "max_downloads": 2})).reply(&api).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let film = url(&resp);
+        for _ in 0..2 {
+            let resp = warp::test::request().path(&film).reply(&links).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+            assert_eq!(resp.body().as_ref(), b"a film");
+            assert_eq!(resp.headers()["content-disposition"], "attachment; filename=\"film.mkv\"; filename*=UTF-8''film%2Emkv");
+        }
+        let resp = warp::test::request().path(&film).reply(&links).await;
+        assert_eq!(resp.status(), StatusCode::GONE);
+
+        // A directory is downloaded as an archive
+        let resp = create("uploader", serde_json::json!({"path": "/photos/"})).reply(&api).await;
+        let resp = warp::test::request().path(&format!("{}?format=tar", url(&resp))).reply(&links).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()["content-type"], "application/x-tar");
+        let mut tar = tar::Archive::new(&resp.body()[..]);
+        let paths: Vec<String> = tar.entries().unwrap().map(|e| e.unwrap().path().unwrap().to_str().unwrap().to_owned()).collect();
+        assert_eq!(paths, vec!["photos", "photos/cat.jpg"]);
+
+        // A link with a password asks for it
+        let resp = create("admin", serde_json::json!({"path": "/film.mkv", "password": "secret"})).reply(&api).await;
+        let locked = url(&resp);
+        let resp = warp::test::request().path(&locked).reply(&links).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        assert!(String::from_utf8_lossy(resp.body()).contains("name=\"password\""));
+        let addr: SocketAddr = "192.168.1.2:50000".parse().unwrap();
+        let unlock = |password: &str| warp::test::request()
+            .method("POST")
+            .path(&locked)
+            .remote_addr(addr)
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .body(format!("password={}", password));
+        assert_eq!(unlock("wrong").reply(&links).await.status(), StatusCode::UNAUTHORIZED);
+
+        // Wrong passwords are counted against the share, which is locked out after too many
+        let share_id = locked.trim_start_matches("/s/").split_once('.').unwrap().0.to_owned();
+        let attempts = users.lockouts.lock().await.list(std::time::Instant::now());
+        assert!(attempts.iter().any(|a| a.key == crate::lockouts::LockoutKey::Share(share_id.clone()) && a.failures == 1));
+        for _ in 0..9 {
+            users.lockouts.lock().await.record_share_failure(None, &share_id, std::time::Instant::now());
+        }
+        let resp = unlock("secret").reply(&links).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(resp.headers().contains_key("retry-after"));
+        users.lockouts.lock().await.clear(None);
+
+        let resp = unlock("secret").reply(&links).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.body().as_ref(), b"a film");
+
+        // Forged tokens don't work
+        let (id, _) = locked.split_once('.').unwrap();
+        let resp = warp::test::request().path(&format!("{}.forged", id)).reply(&links).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        // Uploaders see their own links, admins see all of them
+        let list = |user: &str| warp::test::request().path("/api/shares").header("Authorization", basic(user, PASSWORD));
+        let listed = |resp: warp::http::Response<bytes::Bytes>| serde_json::from_slice::<Vec<serde_json::Value>>(resp.body()).unwrap();
+        assert_eq!(listed(list("uploader").reply(&api).await).len(), 2);
+        let all = listed(list("admin").reply(&api).await);
+        assert_eq!(all.len(), 3);
+        assert_eq!(all[0]["has_password"], true);
+        assert!(all[0].get("password_hash").is_none());
+
+        // Revoking a link stops it working
+        let revoke = |user: &str| warp::test::request()
+            .method("DELETE")
+            .path(&format!("/api/shares/{}", all[0]["id"].as_str().unwrap()))
+            .header("Authorization", basic(user, PASSWORD));
+        assert_eq!(revoke("uploader").reply(&api).await.status(), StatusCode::FORBIDDEN);
+        assert_eq!(revoke("admin").reply(&api).await.status(), StatusCode::NO_CONTENT);
+        assert_eq!(revoke("admin").reply(&api).await.status(), StatusCode::NOT_FOUND);
+        let resp = warp::test::request().path(&locked).reply(&links).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_short_links() {
+        let state = tempfile::tempdir().unwrap();
+        let links = models::new_links(ShortLinks::load(state.path().join("links.json")).unwrap());
+        let route = short_link_filters(test_users(), test_sp(), no_acl(), links.clone())
+            .or(wwf_redirect(test_users(), links.clone()))
+            .recover(recover_auth);
+
+        let create = |user: &str, body: serde_json::Value| warp::test::request()
+            .method("POST")
+            .path("/api/links")
+            .header("Authorization", basic(user, PASSWORD))
+            .json(&body);
+        let follow = |slug: &str| warp::test::request().path(&format!("/wwf/{}", slug)).header("Authorization", basic("reader", PASSWORD));
+
+        let resp = create("reader", serde_json::json!({"path": "/folder1/"})).reply(&route).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        let resp = create("uploader", serde_json::json!({"path": "/folder1/mytestfiles/", "slug": "tests"})).reply(&route).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let resp = follow("tests").reply(&route).await;
+        assert!(resp.status().is_redirection());
+        assert_eq!(resp.headers()["location"], "/browse/folder1/mytestfiles/");
+
+        let resp = create("uploader", serde_json::json!({"path": "/file1.abc", "expires_in": 60})).reply(&route).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let json: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        let resp = follow(json["slug"].as_str().unwrap()).reply(&route).await;
+        assert_eq!(resp.headers()["location"], "/browse/file1.abc");
+        assert!(json["expires"].is_string());
+
+        for (body, status) in &[
+            (serde_json::json!({"path": "/file1.abc", "slug": "tests"}), StatusCode::CONFLICT),
+            (serde_json::json!({"path": "/file1.abc", "slug": "../tests"}), StatusCode::BAD_REQUEST),
+            (serde_json::json!({"path": "/file1.abc", "expires_in": -1}), StatusCode::BAD_REQUEST),
+            (serde_json::json!({"path": "/missing.abc"}), StatusCode::NOT_FOUND),
+            (serde_json::json!({"path": "/../"}), StatusCode::NOT_FOUND),
+        ] {
+            let resp = create("uploader", body.clone()).reply(&route).await;
+            assert_eq!(resp.status(), *status, "{}", body);
+        }
+        assert_eq!(follow("missing").reply(&route).await.status(), StatusCode::NOT_FOUND);
+
+        // Only admins can see and delete the links
+        let list = |user: &str| warp::test::request().path("/admin/links").header("Authorization", basic(user, PASSWORD));
+        assert_eq!(list("uploader").reply(&route).await.status(), StatusCode::FORBIDDEN);
+        let resp = list("admin").reply(&route).await;
+        let listed: Vec<serde_json::Value> = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(listed.len(), 2);
+        let tests = listed.iter().find(|link| link["slug"] == "tests").unwrap();
+        assert_eq!(tests["hits"], 1);
+        assert_eq!(tests["created_by"], "uploader");
+
+        let delete = warp::test::request().method("DELETE").path("/admin/links/tests").header("Authorization", basic("admin", PASSWORD));
+        assert_eq!(delete.reply(&route).await.status(), StatusCode::NO_CONTENT);
+        assert_eq!(follow("tests").reply(&route).await.status(), StatusCode::NOT_FOUND);
+
+        // Links are kept on disk
+        let reloaded = ShortLinks::load(state.path().join("links.json")).unwrap();
+        assert_eq!(reloaded.list().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_room_links() {
+        let state = tempfile::tempdir().unwrap();
+        let links = models::new_links(ShortLinks::load(state.path().join("links.json")).unwrap());
+        let route = create_room_filter(test_users(), Rooms::default(), models::new_room_cleaner(), links.clone()).recover(recover_auth);
+
+        let resp = warp::test::request()
+            .path(&format!("/createroom?url={}", base64::encode("/cinema/file1.abc")))
+            .header("Authorization", basic("reader", PASSWORD))
+            .reply(&route)
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let json: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        let room = json["room"].as_str().unwrap();
+
+        let link = links.lock().await.list().pop().unwrap();
+        assert_eq!(link.slug, room);
+        assert_eq!(link.target, format!("/cinema/file1.abc?cinema=1&room={}", room));
+        assert!(link.expires_at.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_login() {
+        let users = test_users();
+        let route = login_filters(users.clone(), models::new_handlebars_arc())
+            .or(list_directory_json(test_sp(), None, no_acl(), users.clone()))
+            .recover(recover_auth);
+
+        // Browsers are sent to the login page, which brings them back afterwards
+        let resp = warp::test::request().path("/api/list/folder1/?sort=size").header("Accept", "text/html").reply(&route).await;
+        assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+        assert_eq!(resp.headers()["location"], "/login?next=%2Fapi%2Flist%2Ffolder1%2F%3Fsort%3Dsize");
+        let resp = warp::test::request().path("/login?next=%2Fapi%2Flist%2Ffolder1%2F").reply(&route).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(String::from_utf8_lossy(resp.body()).contains("value=\"/api/list/folder1/\""));
+
+        let login = |username: &str, password: &str, next: &str| warp::test::request()
+            .method("POST")
+            .path("/login")
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .body(format!("username={}&password={}&next={}", username, password, next));
+
+        for (username, password) in &[("reader", "wrong"), ("nobody", PASSWORD), ("", "")] {
+            let resp = login(username, password, "%2F").reply(&route).await;
+            assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+            assert!(resp.headers().get("set-cookie").is_none());
+            assert!(String::from_utf8_lossy(resp.body()).contains("Incorrect username or password"));
+        }
+
+        // Only paths on this server are followed after logging in
+        let resp = login("reader", PASSWORD, "%2F%2Fexample.com%2F").reply(&route).await;
+        assert_eq!(resp.headers()["location"], "/browse/");
+
+        let resp = login("reader", PASSWORD, "%2Fapi%2Flist%2F").reply(&route).await;
+        assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+        assert_eq!(resp.headers()["location"], "/api/list/");
+        let set_cookie = resp.headers()["set-cookie"].to_str().unwrap();
+        assert!(set_cookie.contains("HttpOnly"));
+        assert!(set_cookie.contains("SameSite=Strict"));
+        assert!(!set_cookie.contains("Secure"));
+        let cookie = set_cookie.split(';').next().unwrap().to_owned();
+
+        // Behind a proxy that serves https, the cookie is only sent over https
+        let resp = login("reader", PASSWORD, "%2F").header("X-Forwarded-Proto", "https").reply(&route).await;
+        assert!(resp.headers()["set-cookie"].to_str().unwrap().ends_with("; Secure"));
+
+        // The cookie works without any credentials
+        let list = |cookie: &str| warp::test::request().path("/api/list/").header("Cookie", cookie);
+        assert_eq!(list(&cookie).reply(&route).await.status(), StatusCode::OK);
+        assert_eq!(list("ffs_session=forged.cookie").reply(&route).await.status(), StatusCode::UNAUTHORIZED);
+
+        // Logging out ends the session, and clears the cookie
+        let resp = warp::test::request().method("POST").path("/logout").header("Cookie", &cookie).reply(&route).await;
+        assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+        assert!(resp.headers()["set-cookie"].to_str().unwrap().contains("Max-Age=0"));
+        assert_eq!(list(&cookie).reply(&route).await.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_basic_credentials_are_cached() {
+        let users = test_users();
+        let filter = auth(users.clone());
+        let request = || warp::test::request().header("Authorization", basic("reader", PASSWORD));
+        assert!(request().filter(&filter).await.is_ok());
+
+        // Swapping the stored hash for one that doesn't verify shows whether the password is hashed again
+        users.accounts.lock().await.get_mut("reader").unwrap().password = cheap_hash("other");
+        assert!(request().filter(&filter).await.is_err());
+        let hash = users.accounts.lock().await["reader"].password.clone();
+        users.verified.lock().await.remember("reader", &hash, PASSWORD, Instant::now());
+        assert!(request().filter(&filter).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_admin_users() {
+        let state = tempfile::tempdir().unwrap();
+        let path = state.path().join("users");
+        let contents = format!(
+            "; Friends\nreader {}\nuploader {} Uploader\nadmin {} Admin\n",
+            cheap_hash(PASSWORD), cheap_hash(PASSWORD), cheap_hash(PASSWORD),
+        );
+        std::fs::write(&path, &contents).unwrap();
+        let users = models::new_users(crate::args::load_users_file(&path).unwrap(), crate::sessions::DEFAULT_SESSION_LIFETIME);
+        let route = admin_users_filters(users.clone(), models::new_users_file(path.clone()), no_acl(), models::new_handlebars_arc())
+            .recover(recover_auth);
+
+        for (username, status) in &[("reader", StatusCode::FORBIDDEN), ("uploader", StatusCode::FORBIDDEN), ("admin", StatusCode::OK)] {
+            let resp = warp::test::request().path("/admin/users").header("Authorization", basic(username, PASSWORD)).reply(&route).await;
+            assert_eq!(resp.status(), *status, "{}", username);
+        }
+        let resp = warp::test::request().path("/admin/users").header("Authorization", basic("admin", PASSWORD)).reply(&route).await;
+        let page = String::from_utf8_lossy(resp.body()).into_owned();
+        assert!(page.contains("uploader"));
+
+        // Every form carries a token, without which nothing can be changed
+        let token = users.sessions.lock().await.form_token("admin");
+        let token_field = format!("name=\"token\" value=\"{}\"", token);
+        assert_eq!(page.matches(&token_field).count(), page.matches("<form method=\"post\" action=\"/admin/users\"").count() + 1);
+        let post = |form: &str| warp::test::request()
+            .method("POST")
+            .path("/admin/users")
+            .header("Authorization", basic("admin", PASSWORD))
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .body(form);
+        let before = std::fs::read_to_string(&path).unwrap();
+        let stolen = users.sessions.lock().await.form_token("reader");
+        for form in &[String::from("action=role&username=reader&role=Admin"), format!("action=role&username=reader&role=Admin&token={}", stolen)] {
+            assert_eq!(post(form).reply(&route).await.status(), StatusCode::FORBIDDEN);
+        }
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), before);
+
+        let token = percent_encoding::utf8_percent_encode(&token, percent_encoding::NON_ALPHANUMERIC).to_string();
+        let change = |form: &str| post(&format!("{}&token={}", form, token));
+
+        // Changes are written to the file and take effect straight away
+        let resp = change("action=add&username=friend&password=secret&role=Uploader").reply(&route).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(String::from_utf8_lossy(resp.body()).contains("Added friend as Uploader"));
+        let friend = users.accounts.lock().await["friend"].clone();
+        assert_eq!(friend.role, UserRole::Uploader);
+        assert!(verify(&friend.password, b"secret"));
+
+        assert_eq!(change("action=role&username=reader&role=Admin").reply(&route).await.status(), StatusCode::OK);
+        assert_eq!(users.accounts.lock().await["reader"].role, UserRole::Admin);
+
+        let old_hash = users.accounts.lock().await["uploader"].password.clone();
+        assert_eq!(change("action=password&username=uploader&password=changed").reply(&route).await.status(), StatusCode::OK);
+        assert_ne!(users.accounts.lock().await["uploader"].password, old_hash);
+
+        assert_eq!(change("action=disable&username=reader").reply(&route).await.status(), StatusCode::OK);
+        assert!(!users.accounts.lock().await.contains_key("reader"));
+        let resp = warp::test::request().path("/admin/users").header("Authorization", basic("reader", PASSWORD)).reply(&route).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let saved = std::fs::read_to_string(&path).unwrap();
+        assert!(saved.starts_with("; Friends\n!reader "));
+        assert!(saved.contains(" Admin\nfriend "));
+        assert_eq!(crate::args::load_users_file(&path).unwrap(), *users.accounts.lock().await);
+
+        assert_eq!(change("action=enable&username=reader").reply(&route).await.status(), StatusCode::OK);
+        assert!(users.accounts.lock().await.contains_key("reader"));
+
+        // Admins can't lock themselves out, and nothing changes when a change is refused
+        for form in &[
+            "action=disable&username=admin",
+            "action=role&username=admin&role=Uploader",
+            "action=role&username=reader&role=Boss",
+            "action=add&username=friend&password=secret&role=ReadOnly",
+            "action=add&username=two%20words&password=secret&role=ReadOnly",
+            "action=add&username=new&password=&role=ReadOnly",
+            "action=password&username=nobody&password=secret",
+            "action=rename&username=reader",
+        ] {
+            let before = std::fs::read_to_string(&path).unwrap();
+            let resp = change(form).reply(&route).await;
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{}", form);
+            assert_eq!(std::fs::read_to_string(&path).unwrap(), before, "{}", form);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_lockouts() {
+        let users = test_users();
+        let route = lockout_filters(users.clone())
+            .or(login_filters(users.clone(), models::new_handlebars_arc()))
+            .or(list_directory_json(test_sp(), None, no_acl(), users.clone()))
+            .recover(recover_auth);
+        let addr: SocketAddr = "192.168.1.2:50000".parse().unwrap();
+        let list = |username: &str, password: &str| warp::test::request()
+            .path("/api/list/")
+            .remote_addr(addr)
+            .header("Authorization", basic(username, password));
+
+        let resp = list("reader", "wrong").reply(&route).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        for _ in 0..9 {
+            users.lockouts.lock().await.record_failure(Some(addr.ip()), "reader", std::time::Instant::now());
+        }
+
+        // Once locked out, even the right password is refused, from anywhere
+        let resp = list("reader", PASSWORD).reply(&route).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(resp.headers()["retry-after"].to_str().unwrap().parse::<u64>().unwrap() > 0);
+        let resp = warp::test::request()
+            .method("POST")
+            .path("/login")
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .body(format!("username=reader&password={}", PASSWORD))
+            .reply(&route)
+            .await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(resp.headers().get("set-cookie").is_none());
+
+        // Admins can see who is locked out and let them back in
+        let admin = |method: &str, path: &str| warp::test::request()
+            .method(method)
+            .path(path)
+            .header("Authorization", basic("admin", PASSWORD));
+        let resp = admin("GET", "/admin/lockouts").reply(&route).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let lockouts: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        let reader = lockouts.as_array().unwrap().iter().find(|l| l["username"] == "reader").unwrap();
+        assert_eq!(reader["failures"], 10);
+        assert!(reader["locked_for"].as_u64().unwrap() > 0);
+        assert!(lockouts.as_array().unwrap().iter().any(|l| l["ip"] == "192.168.1.2"));
+
+        let resp = list("reader", PASSWORD).reply(&route).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(admin("DELETE", "/admin/lockouts?username=reader").reply(&route).await.status(), StatusCode::NO_CONTENT);
+        assert_eq!(admin("DELETE", "/admin/lockouts?username=reader").reply(&route).await.status(), StatusCode::NOT_FOUND);
+        assert_eq!(admin("DELETE", "/admin/lockouts?ip=nonsense").reply(&route).await.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(list("reader", PASSWORD).reply(&route).await.status(), StatusCode::OK);
+
+        let resp = warp::test::request().method("DELETE").path("/admin/lockouts").header("Authorization", basic("reader", PASSWORD)).reply(&route).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        assert_eq!(admin("DELETE", "/admin/lockouts").reply(&route).await.status(), StatusCode::NO_CONTENT);
+        assert!(users.lockouts.lock().await.list(std::time::Instant::now()).is_empty());
+    }
+}
@@ -1,154 +1,1491 @@
-use std::collections::HashMap;
-use std::path::PathBuf;
-use warp::path::FullPath;
-use warp::http::Uri;
-use url::form_urlencoded::parse;
-use rand::Rng;
-use base64::decode;
-use tokio::task;
-
-use super::websocket::delete_from_rooms;
-use super::models::{Hba, Sp, Rooms, Room, Urls, UrlQuery, RoomCodeQuery, RoomCleaner};
-use super::filters::Authenticated;
-// use crate::db;
-
-const CHARSET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ";
-const ROOM_CODE_LEN: usize = 4;
-
-pub fn decode_url(fp: &FullPath) -> String {
-    parse(fp.as_str().as_bytes())
-        .map(|(key, val)| [key, val].concat())
-        .collect()
-}
-
-pub async fn render_index<'a>(_: Authenticated, sp: Sp, hba: Hba<'a>, fp: warp::path::FullPath) -> Result<impl warp::Reply, warp::Rejection> {
-    let path_str = if cfg!(target_os = "windows") {
-        decode_url(&fp).replace("/browse/", "").replace("/", "\\")
-    } else {
-        decode_url(&fp).replace("/browse/", "")
-    };
-
-    let path = PathBuf::from(&path_str);
-    let sp = sp.lock().await;
-
-    if sp.is_file(&path) {
-        return Err(warp::reject())
-    }
-
-    let listing = sp.get_directory_listing(&path).unwrap();
-    let mut data = HashMap::new();
-    data.insert(String::from("listing"), listing);
-
-    let render = hba.hba.lock().await
-        .render("listing.html", &data)
-        .unwrap_or_else(|err| err.to_string());
-    Ok(warp::reply::html(render))
-}
-
-
-pub async fn render_cinema<'a>(_: Authenticated, sp: Sp, hba: Hba<'a>, fp: warp::path::FullPath) -> Result<impl warp::Reply, warp::Rejection> {
-    let path_str = decode_url(&fp).replace("/cinema/", "/browse/");
-    let path: PathBuf = path_str.replace("/browse/", "").split("/").collect();
-    let sp = sp.lock().await;
-
-    if !sp.is_file(&path) {
-        error!("Rejecting, not a path... {:?}", path);
-        return Err(warp::reject())
-    }
-
-    let file_name = path.file_name().unwrap().to_str().unwrap();
-
-    let mut data = HashMap::new();
-    data.insert("mp4_path", path_str.as_str());
-    data.insert("mp4_name", file_name);
-
-    let render = hba.hba.lock().await
-        .render("cinema.html", &data)
-        .unwrap_or_else(|err| err.to_string());
-    Ok(warp::reply::html(render))
-}
-
-fn generate_room_code() -> String {
-    let mut rng = rand::thread_rng();
-    (0..ROOM_CODE_LEN)
-        .map(|_| {
-            let idx = rng.gen_range(0, CHARSET.len());
-            CHARSET[idx] as char
-        })
-        .collect()
-}
-
-pub async fn create_room(_: Authenticated, rooms_arc: Rooms, cleaner: RoomCleaner, urls: Urls, b64url: UrlQuery) -> Result<impl warp::Reply, warp::Rejection> {
-    use std::str::from_utf8;
-    let mut code;
-    let decoded = decode(b64url.url.as_bytes()).map_err(|_| warp::reject())?;
-    let url = from_utf8(decoded.as_slice()).map_err(|_| warp::reject())?;
-
-    // Extra scope to limit length of rooms and urls mutex lock
-    {
-        let mut rooms = rooms_arc.lock().await;
-        let mut urls = urls.lock().await;
-
-        loop {
-            code = generate_room_code();
-            if !rooms.contains_key(&code) {
-                break;
-            }
-        }
-        let url_with_query = format!("{}?cinema=1&room={}", url, code);
-        let room = Room::new(code.clone());
-        rooms.insert(code.clone(), room);
-        urls.insert(code.clone(), url_with_query.to_owned());
-    }
-
-    let mut resp_map = HashMap::new();
-    resp_map.insert("room", code.clone());
-
-    // Start the task to delete the room, in case no one joins it.
-    task::spawn(async {
-        delete_from_rooms(rooms_arc, cleaner, code).await;//.await;
-    });
-
-    Ok(warp::reply::json(&resp_map))
-}
-
-pub async fn check_room(_: Authenticated, rooms: Rooms, room_code: RoomCodeQuery) -> Result<impl warp::Reply, warp::Rejection> {
-    let mut resp_map = HashMap::new();
-    let rooms = rooms.lock().await;
-    if rooms.contains_key(&room_code.room) {
-        resp_map.insert("exists", true);
-    } else {
-        resp_map.insert("exists", false);
-    }
-    Ok(warp::reply::json(&resp_map))
-}
-
-pub async fn wwf_lookup_redirect(
-    _: Authenticated,
-    code: String,
-    urls: Urls
-) -> Result<impl warp::Reply, warp::Rejection> {
-    use std::str::FromStr;
-
-    let urls = urls.lock().await;
-    if let Some(url) = urls.get(&code) {
-        let uri = Uri::from_str(url).unwrap();
-        let redirect = warp::redirect(uri);
-        Ok(redirect)
-    } else {
-        Err(warp::reject())
-    }
-}
-
-// pub async fn get_catalogue(
-//     _: Authenticated,
-//     c: DbClientArc
-// ) -> Result<impl warp::Reply, warp::Rejection> {
-//     let client = c.lock().await;
-
-//     match db::get_catalogue(&client).await {
-//         Ok(catalogue) => return Ok(warp::reply::json(&catalogue)),
-//         Err(e) => error!("Error: {}", e),
-//     };
-//     Err(warp::reject())
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use warp::path::{FullPath, Tail};
+use warp::http::{StatusCode, Uri};
+use url::form_urlencoded::parse;
+use percent_encoding::percent_decode_str;
+use rand::Rng;
+use base64::decode;
+use bytes::Buf;
+use futures::{Stream, StreamExt};
+use tokio::task;
+use tokio::fs;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use chrono::Utc;
+use warp::hyper::Body;
+
+use super::websocket::delete_from_rooms;
+use super::models::{Hba, Sp, Rooms, Room, Links, UrlQuery, RoomCodeQuery, RoomCleaner, AuthenticatedUser, UploadQuery,
+    ListingQuery, SearchQuery, ArchiveQuery, Index, Uploads, NewUploadRequest, UserRole, MakeDirRequest, RenameRequest, DeleteRequest, TrashBin,
+    ShareLinks, NewShareRequest, NewLinkRequest, UserMap, UsersFile, LoginQuery, LockoutQuery, AccessRules};
+use super::rejections;
+use crate::hb_helpers;
+use crate::fs_utils::{rename_no_replace, FsOpError, ServePoint, TempFile, DirectoryListing, ListingOptions, SortKey, SortOrder, DEFAULT_PER_PAGE};
+use crate::upload_sessions::{UploadSession, UploadSessions};
+use crate::archive::{self, ArchiveEntry, ArchiveFormat, ArchiveLimits};
+use crate::index;
+use crate::search::{self, Matcher, SearchMode, SearchResults, MAX_SEARCH_RESULTS};
+use crate::short_links::{self, LinkError, ShortLink};
+use crate::sessions::SESSION_COOKIE;
+use crate::credentials::{self, CredentialsFile};
+use crate::trash::PendingMove;
+use crate::lockouts::LockoutKey;
+use crate::shares::{Share, ShareError, Shares, DEFAULT_SHARE_LIFETIME, MAX_SHARE_LIFETIME};
+// use crate::db;
+
+const CHARSET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ";
+const ROOM_CODE_LEN: usize = 4;
+
+/// How long the short link to a watch with friends room lasts, in hours
+const ROOM_LINK_LIFETIME: i64 = 24;
+
+/// The characters escaped in each part of a path in a url
+const PATH_SEGMENT: &percent_encoding::AsciiSet = &percent_encoding::CONTROLS
+    .add(b' ').add(b'"').add(b'#').add(b'%').add(b'/').add(b'<').add(b'>').add(b'?').add(b'`').add(b'{').add(b'}');
+
+/// How much of a file is read at a time when streaming it
+const FILE_CHUNK_SIZE: usize = 64 * 1024;
+
+pub fn decode_url(fp: &FullPath) -> String {
+    parse(fp.as_str().as_bytes())
+        .map(|(key, val)| [key, val].concat())
+        .collect()
+}
+
+/// Decode a percent encoded request path, e.g. the tail of a '/browse/' url, into a relative path
+pub fn decode_path(encoded: &str) -> Option<PathBuf> {
+    let decoded = percent_decode_str(encoded).decode_utf8().ok()?;
+    Some(decoded.split('/').filter(|part| !part.is_empty()).collect())
+}
+
+/// Turn the query of a listing request into options, rejecting globs that don't compile
+fn listing_options(query: &ListingQuery) -> Result<ListingOptions, warp::Rejection> {
+    let filter = match &query.filter {
+        Some(glob) if !glob.is_empty() => {
+            let glob = globset::GlobBuilder::new(glob)
+                .case_insensitive(true)
+                .literal_separator(true)
+                .build()
+                .map_err(|_| warp::reject::custom(rejections::InvalidQuery))?;
+            Some(glob.compile_matcher())
+        },
+        _ => None,
+    };
+
+    Ok(ListingOptions {
+        sort: query.sort.unwrap_or(SortKey::Name),
+        order: query.order.unwrap_or(SortOrder::Asc),
+        filter: filter,
+        page: query.page.unwrap_or(1),
+        per_page: query.per_page.unwrap_or(DEFAULT_PER_PAGE),
+    })
+}
+
+/*
+Build the query string for a link from the listing page, keeping the current options apart from the ones being
+changed. Options that are still at their defaults are left out so that the links stay short.
+*/
+fn listing_link(query: &ListingQuery, sort: Option<SortKey>, order: Option<SortOrder>, page: usize) -> String {
+    let mut serializer = url::form_urlencoded::Serializer::new(String::new());
+    match sort {
+        Some(SortKey::Size) => { serializer.append_pair("sort", "size"); },
+        Some(SortKey::Mtime) => { serializer.append_pair("sort", "mtime"); },
+        _ => (),
+    };
+    if order == Some(SortOrder::Desc) {
+        serializer.append_pair("order", "desc");
+    }
+    if let Some(filter) = query.filter.as_deref().filter(|f| !f.is_empty()) {
+        serializer.append_pair("filter", filter);
+    }
+    if let Some(per_page) = query.per_page {
+        serializer.append_pair("per_page", &per_page.to_string());
+    }
+    if page > 1 {
+        serializer.append_pair("page", &page.to_string());
+    }
+    format!("?{}", serializer.finish())
+}
+
+/// Links for the column headers. Clicking the column that's already sorted on flips the order.
+fn sort_links(query: &ListingQuery) -> serde_json::Value {
+    let current = query.sort.unwrap_or(SortKey::Name);
+    let link = |key: SortKey| {
+        let order = if key == current && query.order != Some(SortOrder::Desc) {
+            SortOrder::Desc
+        } else {
+            SortOrder::Asc
+        };
+        listing_link(query, Some(key), Some(order), 1)
+    };
+    serde_json::json!({
+        "name": link(SortKey::Name),
+        "size": link(SortKey::Size),
+        "mtime": link(SortKey::Mtime),
+    })
+}
+
+/*
+A directory listing, read from the index when it is turned on and up to date. Directories the user isn't allowed to
+see aren't listed at all, and the entries inside them that the user isn't allowed to see are left out.
+*/
+async fn directory_listing(sp: &Sp, index: &Index, acl: &AccessRules, user: &AuthenticatedUser, path: &std::path::Path) -> Option<DirectoryListing> {
+    let sp = sp.lock().await;
+    if !acl.can_read_in(user, &sp, path) {
+        debug!("User {} is not allowed to list {:?}", user.username, path);
+        return None;
+    }
+
+    let mut listing = match index {
+        Some(indexes) => sp.get_directory_listing_with(path, |dir| indexes.iter().find_map(|index| index.read().ok()?.children(dir))),
+        None => sp.get_directory_listing(path),
+    }?;
+    if !acl.is_empty() {
+        listing.children.retain(|child| acl.can_read_in(user, &sp, &path.join(child.name.trim_end_matches('/'))));
+    }
+    Some(listing)
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn render_index<'a>(
+    user: AuthenticatedUser,
+    sp: Sp,
+    index: Index,
+    acl: AccessRules,
+    hba: Hba<'a>,
+    fp: warp::path::FullPath,
+    accept_language: Option<String>,
+    query: ListingQuery,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let path_str = if cfg!(target_os = "windows") {
+        decode_url(&fp).replace("/browse/", "").replace("/", "\\")
+    } else {
+        decode_url(&fp).replace("/browse/", "")
+    };
+
+    let path = PathBuf::from(&path_str);
+    let writable = {
+        let sp = sp.lock().await;
+        if sp.is_file(&path) {
+            return Err(warp::reject())
+        }
+        sp.is_writable(&path)
+    };
+
+    let options = listing_options(&query)?;
+    let mut listing = directory_listing(&sp, &index, &acl, &user, &path).await
+        .ok_or_else(|| warp::reject::custom(rejections::NotFound))?;
+    listing.apply(&options);
+
+    let page_link = |page: usize| listing_link(&query, query.sort, query.order, page);
+    let data = serde_json::json!({
+        "listing": listing,
+        // Admins can only change the files in mounts that aren't read only
+        "can_change": user.role >= UserRole::Admin && writable,
+        "locale": hb_helpers::negotiate_locale(accept_language.as_deref()),
+        "sort_links": sort_links(&query),
+        "filter": query.filter,
+        "pagination": {
+            "show": listing.pages > 1,
+            "prev": if listing.page > 1 { Some(page_link(listing.page.min(listing.pages + 1) - 1)) } else { None },
+            "next": if listing.page < listing.pages { Some(page_link(listing.page + 1)) } else { None },
+        },
+    });
+
+    let render = hba.hba.lock().await
+        .render("listing.html", &data)
+        .unwrap_or_else(|err| err.to_string());
+    Ok(warp::reply::html(render))
+}
+
+
+pub async fn render_cinema<'a>(user: AuthenticatedUser, sp: Sp, acl: AccessRules, hba: Hba<'a>, fp: warp::path::FullPath) -> Result<impl warp::Reply, warp::Rejection> {
+    let path_str = decode_url(&fp).replace("/cinema/", "/browse/");
+    let path: PathBuf = path_str.replace("/browse/", "").split("/").collect();
+    let sp = sp.lock().await;
+
+    if !sp.is_file(&path) {
+        error!("Rejecting, not a path... {:?}", path);
+        return Err(warp::reject())
+    }
+    if !acl.can_read_in(&user, &sp, &path) {
+        debug!("User {} is not allowed to watch {:?}", user.username, path);
+        return Err(warp::reject::custom(rejections::NotFound));
+    }
+
+    let file_name = path.file_name().unwrap().to_str().unwrap();
+
+    let mut data = HashMap::new();
+    data.insert("mp4_path", path_str.as_str());
+    data.insert("mp4_name", file_name);
+
+    let render = hba.hba.lock().await
+        .render("cinema.html", &data)
+        .unwrap_or_else(|err| err.to_string());
+    Ok(warp::reply::html(render))
+}
+
+fn upload_failed<E: std::fmt::Display>(e: E) -> warp::Rejection {
+    error!("Upload failed: {}", e);
+    warp::reject::custom(rejections::UploadFailed)
+}
+
+/// Move a finished upload into place on a thread where blocking is fine. A file that is already there is a conflict.
+async fn persist_upload<F>(persist: F) -> Result<(), warp::Rejection>
+where
+    F: FnOnce() -> std::io::Result<()> + Send + 'static,
+{
+    match task::spawn_blocking(persist).await.map_err(upload_failed)? {
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => Err(warp::reject::custom(rejections::FileExists)),
+        result => result.map_err(upload_failed),
+    }
+}
+
+/// Append the request body to 'file', giving up once more than 'limit' bytes have been received.
+/// Returns the number of bytes written.
+async fn write_body<S, B>(file: &mut fs::File, body: S, limit: u64) -> Result<u64, warp::Rejection>
+where
+    S: Stream<Item = Result<B, warp::Error>>,
+    B: Buf,
+{
+    let mut written: u64 = 0;
+
+    futures::pin_mut!(body);
+    while let Some(chunk) = body.next().await {
+        let mut chunk = chunk.map_err(upload_failed)?;
+        if written + chunk.remaining() as u64 > limit {
+            return Err(warp::reject::custom(rejections::PayloadTooLarge));
+        }
+
+        while chunk.has_remaining() {
+            let len = chunk.bytes().len();
+            file.write_all(chunk.bytes()).await.map_err(upload_failed)?;
+            chunk.advance(len);
+            written += len as u64;
+        }
+    }
+    Ok(written)
+}
+
+/*
+Stream the request body into a temp file next to the target, and only move it into place once the whole body
+has been received. The temp file is removed again if anything goes wrong along the way.
+*/
+#[allow(clippy::too_many_arguments)]
+pub async fn upload_file<S, B>(
+    user: AuthenticatedUser,
+    sp: Sp,
+    acl: AccessRules,
+    max_size: u64,
+    tail: Tail,
+    query: UploadQuery,
+    content_length: Option<u64>,
+    body: S,
+) -> Result<impl warp::Reply, warp::Rejection>
+where
+    S: Stream<Item = Result<B, warp::Error>>,
+    B: Buf,
+{
+    let path = decode_path(tail.as_str()).ok_or_else(warp::reject)?;
+    let overwrite = query.overwrite.unwrap_or(false);
+    let target = {
+        let sp = sp.lock().await;
+        check_access(&acl, &user, &sp, &path)?;
+        new_file_path(&sp, &path)?
+    };
+
+    if target.exists() && !overwrite {
+        return Err(warp::reject::custom(rejections::FileExists));
+    }
+
+    if content_length.is_some_and(|len| len > max_size) {
+        return Err(warp::reject::custom(rejections::PayloadTooLarge));
+    }
+
+    let temp = TempFile::new_for(&target);
+    let mut file = fs::File::create(temp.path()).await.map_err(upload_failed)?;
+    let written = write_body(&mut file, body, max_size).await?;
+    file.sync_all().await.map_err(upload_failed)?;
+    drop(file);
+
+    // Another upload to the same path might have finished while this one was running, which is only replaced if asked
+    persist_upload(move || if overwrite { temp.persist(&target) } else { temp.persist_new(&target) }).await?;
+    info!("User {} uploaded {:?} ({} bytes)", user.username, path, written);
+
+    let resp = serde_json::json!({
+        "path": path.to_string_lossy(),
+        "size": written,
+    });
+    Ok(warp::reply::with_status(warp::reply::json(&resp), StatusCode::CREATED))
+}
+
+/// Where an upload to 'path' goes, as long as it is in a directory that can be changed
+fn new_file_path(sp: &ServePoint, path: &std::path::Path) -> Result<PathBuf, warp::Rejection> {
+    if !sp.is_writable(path) {
+        return Err(warp::reject::custom(rejections::ReadOnly));
+    }
+    // It would be hidden as soon as it was uploaded
+    if sp.ignores(path, false) {
+        return Err(warp::reject::custom(rejections::InvalidPath));
+    }
+    sp.get_new_file_path(path).ok_or_else(|| warp::reject::custom(rejections::NotADirectory))
+}
+
+/// Refuse a path that the user isn't allowed to see, as if it wasn't there
+fn check_access(acl: &AccessRules, user: &AuthenticatedUser, sp: &ServePoint, path: &std::path::Path) -> Result<(), warp::Rejection> {
+    if acl.can_read_in(user, sp, path) {
+        return Ok(());
+    }
+    debug!("User {} is not allowed to change or share {:?}", user.username, path);
+    Err(warp::reject::custom(rejections::NotFound))
+}
+
+/// Split a '/' separated path from a request into a relative path
+fn relative_path(path: &str) -> PathBuf {
+    path.split('/').filter(|part| !part.is_empty()).collect()
+}
+
+fn upload_status(uploads: &UploadSessions, session: &UploadSession) -> serde_json::Value {
+    serde_json::json!({
+        "id": session.id,
+        "path": session.path.to_string_lossy(),
+        "size": session.size,
+        "offset": session.offset(),
+        "expires": uploads.expires_at(session).to_rfc3339(),
+    })
+}
+
+/// Find an upload session, making sure it belongs to the user (or that they are an admin)
+fn find_upload(uploads: &UploadSessions, id: &str, user: &AuthenticatedUser) -> Result<UploadSession, warp::Rejection> {
+    let session = uploads.get(id).ok_or_else(|| warp::reject::custom(rejections::UploadNotFound))?;
+    if session.owner != user.username && user.role < UserRole::Admin {
+        return Err(warp::reject::custom(rejections::Forbidden));
+    }
+    Ok(session.clone())
+}
+
+/// Start a resumable upload. The data is then sent with 'upload_chunk' and moved into place with 'finish_upload'.
+pub async fn create_upload(
+    user: AuthenticatedUser,
+    sp: Sp,
+    acl: AccessRules,
+    uploads: Uploads,
+    max_size: u64,
+    req: NewUploadRequest,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let path = relative_path(&req.path);
+    let overwrite = req.overwrite.unwrap_or(false);
+    if req.size > max_size {
+        return Err(warp::reject::custom(rejections::PayloadTooLarge));
+    }
+
+    let target = {
+        let sp = sp.lock().await;
+        check_access(&acl, &user, &sp, &path)?;
+        new_file_path(&sp, &path)?
+    };
+    if target.exists() && !overwrite {
+        return Err(warp::reject::custom(rejections::FileExists));
+    }
+
+    let mut uploads = uploads.lock().await;
+    let session = uploads.create(path, &target, req.size, &user.username, overwrite).map_err(upload_failed)?;
+    info!("User {} started upload {} of {:?} ({} bytes)", user.username, session.id, session.path, session.size);
+
+    let location = format!("/uploads/{}", session.id);
+    let reply = warp::reply::json(&upload_status(&uploads, &session));
+    let reply = warp::reply::with_header(reply, "Location", location);
+    Ok(warp::reply::with_status(reply, StatusCode::CREATED))
+}
+
+/// Report how much of an upload has been received, so that the client knows where to resume from
+pub async fn get_upload(user: AuthenticatedUser, id: String, uploads: Uploads) -> Result<impl warp::Reply, warp::Rejection> {
+    let uploads = uploads.lock().await;
+    let session = find_upload(&uploads, &id, &user)?;
+    let reply = warp::reply::json(&upload_status(&uploads, &session));
+    Ok(warp::reply::with_header(reply, "Upload-Offset", session.offset().to_string()))
+}
+
+/// Append a chunk of data to an upload. The 'Upload-Offset' header has to match the number of bytes received
+/// so far, so that a chunk is never written twice or out of order. The access rules are checked again, in case they
+/// changed since the upload was started.
+#[allow(clippy::too_many_arguments)]
+pub async fn upload_chunk<S, B>(
+    user: AuthenticatedUser,
+    id: String,
+    sp: Sp,
+    acl: AccessRules,
+    uploads: Uploads,
+    offset: Option<u64>,
+    body: S,
+) -> Result<impl warp::Reply, warp::Rejection>
+where
+    S: Stream<Item = Result<B, warp::Error>>,
+    B: Buf,
+{
+    let session = find_upload(&*uploads.lock().await, &id, &user)?;
+    check_access(&acl, &user, &*sp.lock().await, &session.path)?;
+    let _guard = session.lock_for_writing().ok_or_else(|| warp::reject::custom(rejections::UploadBusy))?;
+
+    let current = session.offset();
+    if offset != Some(current) {
+        return Err(warp::reject::custom(rejections::OffsetMismatch { offset: current }));
+    }
+
+    let mut file = fs::OpenOptions::new()
+        .append(true)
+        .open(&session.part_path)
+        .await
+        .map_err(upload_failed)?;
+
+    // Whatever made it to disk counts, even if the transfer was cut short.
+    let result = write_body(&mut file, body, session.size - current).await;
+    file.sync_all().await.map_err(upload_failed)?;
+    uploads.lock().await.touch(&id).map_err(upload_failed)?;
+    result?;
+
+    let new_offset = session.offset();
+    let resp = serde_json::json!({ "offset": new_offset });
+    Ok(warp::reply::with_header(warp::reply::json(&resp), "Upload-Offset", new_offset.to_string()))
+}
+
+/// Move a completely received upload into its place in the share
+pub async fn finish_upload(user: AuthenticatedUser, id: String, sp: Sp, acl: AccessRules, uploads: Uploads) -> Result<impl warp::Reply, warp::Rejection> {
+    let session = find_upload(&*uploads.lock().await, &id, &user)?;
+    check_access(&acl, &user, &*sp.lock().await, &session.path)?;
+    let _guard = session.lock_for_writing().ok_or_else(|| warp::reject::custom(rejections::UploadBusy))?;
+
+    if session.offset() != session.size {
+        return Err(warp::reject::custom(rejections::UploadIncomplete));
+    }
+
+    let target = new_file_path(&*sp.lock().await, &session.path)?;
+    let (part_path, overwrite) = (session.part_path.clone(), session.overwrite);
+    persist_upload(move || {
+        if overwrite { std::fs::rename(&part_path, &target) } else { rename_no_replace(&part_path, &target) }
+    }).await?;
+    uploads.lock().await.remove(&id);
+    info!("User {} finished upload {} of {:?} ({} bytes)", user.username, id, session.path, session.size);
+
+    let resp = serde_json::json!({
+        "path": session.path.to_string_lossy(),
+        "size": session.size,
+    });
+    Ok(warp::reply::with_status(warp::reply::json(&resp), StatusCode::CREATED))
+}
+
+/// Abandon an upload, deleting whatever has been received so far
+pub async fn cancel_upload(user: AuthenticatedUser, id: String, uploads: Uploads) -> Result<impl warp::Reply, warp::Rejection> {
+    let mut uploads = uploads.lock().await;
+    let session = find_upload(&uploads, &id, &user)?;
+    let _guard = session.lock_for_writing().ok_or_else(|| warp::reject::custom(rejections::UploadBusy))?;
+    uploads.remove(&id);
+    info!("User {} cancelled upload {}", user.username, id);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+fn fs_op_rejection(e: FsOpError) -> warp::Rejection {
+    match e {
+        FsOpError::NotFound => warp::reject::custom(rejections::NotFound),
+        FsOpError::AlreadyExists => warp::reject::custom(rejections::FileExists),
+        FsOpError::NotEmpty => warp::reject::custom(rejections::DirectoryNotEmpty),
+        FsOpError::Invalid => warp::reject::custom(rejections::InvalidPath),
+        FsOpError::ReadOnly => warp::reject::custom(rejections::ReadOnly),
+        FsOpError::Io(e) => {
+            error!("File operation failed: {}", e);
+            warp::reject::custom(rejections::OperationFailed)
+        },
+    }
+}
+
+/// Run a file operation on a thread where blocking is fine, without holding on to the ServePoint
+async fn run_fs_op<T, F>(op: F) -> Result<T, warp::Rejection>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T, FsOpError> + Send + 'static,
+{
+    task::spawn_blocking(op)
+        .await
+        .map_err(|_| warp::reject::custom(rejections::OperationFailed))?
+        .map_err(fs_op_rejection)
+}
+
+pub async fn make_dir(user: AuthenticatedUser, sp: Sp, acl: AccessRules, req: MakeDirRequest) -> Result<impl warp::Reply, warp::Rejection> {
+    let path = relative_path(&req.path);
+    let sp = sp.lock().await.clone();
+    check_access(&acl, &user, &sp, &path)?;
+    let dir = path.clone();
+    run_fs_op(move || sp.make_dir(&dir)).await?;
+    info!("User {} created directory {:?}", user.username, path);
+
+    let resp = serde_json::json!({ "path": path.to_string_lossy() });
+    Ok(warp::reply::with_status(warp::reply::json(&resp), StatusCode::CREATED))
+}
+
+/// Both where the entry is and where it goes have to be visible to the user
+pub async fn rename_entry(user: AuthenticatedUser, sp: Sp, acl: AccessRules, req: RenameRequest) -> Result<impl warp::Reply, warp::Rejection> {
+    let from = relative_path(&req.from);
+    let to = relative_path(&req.to);
+    let sp = sp.lock().await.clone();
+    check_access(&acl, &user, &sp, &from)?;
+    check_access(&acl, &user, &sp, &to)?;
+    let (source, destination) = (from.clone(), to.clone());
+    run_fs_op(move || sp.rename(&source, &destination)).await?;
+    info!("User {} moved {:?} to {:?}", user.username, from, to);
+
+    let resp = serde_json::json!({ "from": from.to_string_lossy(), "to": to.to_string_lossy() });
+    Ok(warp::reply::json(&resp))
+}
+
+/// The same data as the HTML listing, as JSON. Timestamps are RFC 3339 and sizes are in bytes.
+pub async fn list_directory(
+    user: AuthenticatedUser,
+    sp: Sp,
+    index: Index,
+    acl: AccessRules,
+    tail: Tail,
+    query: ListingQuery,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let options = listing_options(&query)?;
+    let path = decode_path(tail.as_str()).ok_or_else(|| warp::reject::custom(rejections::NotFound))?;
+    let mut listing = directory_listing(&sp, &index, &acl, &user, &path).await
+        .ok_or_else(|| warp::reject::custom(rejections::NotFound))?;
+    listing.apply(&options);
+    Ok(warp::reply::json(&listing))
+}
+
+/// Search the index, or the share itself on a blocking thread, for entries the user can see and that aren't ignored.
+/// Returns None if the query is empty.
+async fn run_search(user: AuthenticatedUser, sp: Sp, index: Index, acl: AccessRules, query: &SearchQuery) -> Result<Option<SearchResults>, warp::Rejection> {
+    let q = match query.q.as_deref().map(str::trim) {
+        Some(q) if !q.is_empty() => q,
+        _ => return Ok(None),
+    };
+
+    let mode = query.mode.unwrap_or(SearchMode::Substring);
+    let matcher = Matcher::new(q, mode, query.case_sensitive.unwrap_or(false))
+        .map_err(|_| warp::reject::custom(rejections::InvalidQuery))?;
+    let limit = query.limit.unwrap_or(MAX_SEARCH_RESULTS).clamp(1, MAX_SEARCH_RESULTS);
+
+    // Search a copy, so that nothing else has to wait for the walk to finish
+    let sp = sp.lock().await.clone();
+    let results = task::spawn_blocking(move || {
+        let visible = |path: &std::path::Path| !sp.is_ignored(path) && acl.can_read_in(&user, &sp, path);
+        index.and_then(|indexes| index::search_all(&indexes, &matcher, limit, visible))
+            .unwrap_or_else(|| search::search(&sp, &matcher, limit, visible))
+    })
+        .await
+        .map_err(|_| warp::reject::custom(rejections::OperationFailed))?;
+    Ok(Some(results))
+}
+
+pub async fn render_search<'a>(
+    user: AuthenticatedUser,
+    sp: Sp,
+    index: Index,
+    acl: AccessRules,
+    hba: Hba<'a>,
+    accept_language: Option<String>,
+    query: SearchQuery,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let results = run_search(user, sp, index, acl, &query).await?;
+    let data = serde_json::json!({
+        "q": query.q,
+        "glob": query.mode == Some(SearchMode::Glob),
+        "case_sensitive": query.case_sensitive.unwrap_or(false),
+        "searched": results.is_some(),
+        "search": results,
+        "locale": hb_helpers::negotiate_locale(accept_language.as_deref()),
+    });
+
+    let render = hba.hba.lock().await
+        .render("search.html", &data)
+        .unwrap_or_else(|err| err.to_string());
+    Ok(warp::reply::html(render))
+}
+
+pub async fn search_files(user: AuthenticatedUser, sp: Sp, index: Index, acl: AccessRules, query: SearchQuery) -> Result<impl warp::Reply, warp::Rejection> {
+    let results = run_search(user, sp, index, acl, &query).await?
+        .ok_or_else(|| warp::reject::custom(rejections::InvalidQuery))?;
+    Ok(warp::reply::json(&results))
+}
+
+/// A Content-Disposition header that makes the browser save the response as 'filename'
+fn attachment(filename: &str) -> String {
+    let fallback: String = filename.chars()
+        .map(|c| if c.is_ascii_graphic() && c != '"' && c != '\\' || c == ' ' { c } else { '_' })
+        .collect();
+    let encoded = percent_encoding::utf8_percent_encode(filename, percent_encoding::NON_ALPHANUMERIC);
+    format!("attachment; filename=\"{}\"; filename*=UTF-8''{}", fallback, encoded)
+}
+
+/// Collect what goes in an archive on a blocking thread, rejecting it if it's over the limits
+async fn collect_archive<F>(limits: ArchiveLimits, collect: F) -> Result<Vec<ArchiveEntry>, warp::Rejection>
+where F: FnOnce() -> Vec<ArchiveEntry> + Send + 'static {
+    task::spawn_blocking(move || {
+        let entries = collect();
+        if archive::within_limits(&entries, limits) { Some(entries) } else { None }
+    })
+        .await
+        .map_err(|_| warp::reject::custom(rejections::OperationFailed))?
+        .ok_or_else(|| warp::reject::custom(rejections::ArchiveTooLarge))
+}
+
+fn archive_reply(format: ArchiveFormat, name: &str, entries: Vec<ArchiveEntry>) -> Result<warp::http::Response<Body>, warp::Rejection> {
+    warp::http::Response::builder()
+        .header("Content-Type", format.content_type())
+        .header("Content-Disposition", attachment(&format!("{}.{}", name, format.extension())))
+        .body(archive::stream_archive(format, entries))
+        .map_err(|_| warp::reject::custom(rejections::OperationFailed))
+}
+
+async fn archive_response<F>(format: ArchiveFormat, name: &str, limits: ArchiveLimits, collect: F) -> Result<impl warp::Reply, warp::Rejection>
+where F: FnOnce() -> Vec<ArchiveEntry> + Send + 'static {
+    let entries = collect_archive(limits, collect).await?;
+    archive_reply(format, name, entries)
+}
+
+pub async fn download_archive(
+    user: AuthenticatedUser,
+    sp: Sp,
+    acl: AccessRules,
+    limits: ArchiveLimits,
+    tail: Tail,
+    query: ArchiveQuery,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let format = query.format.unwrap_or(ArchiveFormat::Zip);
+    let path = decode_path(tail.as_str()).ok_or_else(|| warp::reject::custom(rejections::NotFound))?;
+    let sp = sp.lock().await.clone();
+    let dir = sp.get_directory_path(&path).ok_or_else(|| warp::reject::custom(rejections::NotADirectory))?;
+    if !acl.can_read_in(&user, &sp, &dir) {
+        return Err(warp::reject::custom(rejections::NotADirectory));
+    }
+
+    // The share itself is named after the directory it is served from
+    let name = dir.file_name().and_then(|n| n.to_str()).unwrap_or("share").to_owned();
+    let prefix = name.clone();
+    archive_response(format, &name, limits, move || {
+        let mut entries = archive::collect_entries(&sp, &dir, &prefix);
+        entries.retain(|entry| acl.can_read_in(&user, &sp, &entry.path));
+        entries
+    }).await
+}
+
+/// Every selected path has to be inside the share, if any of them isn't nothing is downloaded
+pub async fn download_selection(user: AuthenticatedUser, sp: Sp, acl: AccessRules, limits: ArchiveLimits, form: bytes::Bytes) -> Result<impl warp::Reply, warp::Rejection> {
+    let mut format = ArchiveFormat::Zip;
+    let mut paths = Vec::new();
+    for (key, value) in parse(&form) {
+        match key.as_ref() {
+            "path" => paths.push(relative_path(&value)),
+            "format" => format = value.parse().map_err(|_| warp::reject::custom(rejections::InvalidQuery))?,
+            _ => (),
+        }
+    }
+    if paths.is_empty() {
+        return Err(warp::reject::custom(rejections::InvalidQuery));
+    }
+
+    let sp = sp.lock().await.clone();
+    let mut selected = Vec::new();
+    for path in paths {
+        if path.as_os_str().is_empty() || !sp.is_subdir(&path) || !acl.can_read_in(&user, &sp, &path) {
+            return Err(warp::reject::custom(rejections::NotFound));
+        }
+        let canonical = sp.real_path(&path)
+            .and_then(|path| path.canonicalize().ok())
+            .ok_or_else(|| warp::reject::custom(rejections::NotFound))?;
+        selected.push(canonical);
+    }
+
+    archive_response(format, "download", limits, move || {
+        let mut entries = archive::collect_selection(&sp, &selected);
+        entries.retain(|entry| acl.can_read_in(&user, &sp, &entry.path));
+        entries
+    }).await
+}
+
+/// What the API tells about a link, leaving out its password hash
+fn share_status(shares: &Shares, share: &Share) -> serde_json::Value {
+    let token = shares.token(share);
+    serde_json::json!({
+        "id": share.id,
+        "path": search::share_path(&share.path, share.is_dir),
+        "created_by": share.created_by,
+        "created_at": share.created_at.to_rfc3339(),
+        "expires": share.expires_at.to_rfc3339(),
+        "max_downloads": share.max_downloads,
+        "downloads": share.downloads,
+        "has_password": share.password_hash.is_some(),
+        "url": format!("/s/{}", token),
+        "token": token,
+    })
+}
+
+fn share_rejection(e: ShareError) -> warp::Rejection {
+    match e {
+        ShareError::NotFound => warp::reject::custom(rejections::NotFound),
+        ShareError::Expired => warp::reject::custom(rejections::ShareExpired),
+        ShareError::Io(e) => {
+            error!("Could not save the share links: {}", e);
+            warp::reject::custom(rejections::OperationFailed)
+        },
+    }
+}
+
+/// Whether the entry at 'path' is a directory, which the top level of the share always is
+fn entry_is_dir(sp: &ServePoint, path: &std::path::Path) -> Result<bool, warp::Rejection> {
+    match sp.real_path(path) {
+        Some(real) => real.metadata().map(|meta| meta.is_dir()).map_err(|_| warp::reject::custom(rejections::NotFound)),
+        None => Ok(true),
+    }
+}
+
+/// Create a link that gives read access to a file or directory to anyone who has it
+pub async fn create_share(user: AuthenticatedUser, sp: Sp, acl: AccessRules, shares: ShareLinks, req: NewShareRequest) -> Result<impl warp::Reply, warp::Rejection> {
+    let path = relative_path(&req.path);
+    let sp = sp.lock().await.clone();
+    // Sharing the whole share would let anybody in
+    if path.as_os_str().is_empty() || !sp.is_subdir(&path) {
+        return Err(warp::reject::custom(rejections::NotFound));
+    }
+    check_access(&acl, &user, &sp, &path)?;
+    let is_dir = entry_is_dir(&sp, &path)?;
+
+    let lifetime = req.expires_in.unwrap_or(DEFAULT_SHARE_LIFETIME);
+    if lifetime <= 0 || lifetime > MAX_SHARE_LIFETIME || req.max_downloads == Some(0) {
+        return Err(warp::reject::custom(rejections::InvalidShare));
+    }
+    let expires_at = Utc::now() + chrono::Duration::seconds(lifetime);
+
+    let password_hash = match req.password {
+        Some(password) if !password.is_empty() => Some(
+            task::spawn_blocking(move || crate::hash(password.as_bytes()))
+                .await
+                .map_err(|_| warp::reject::custom(rejections::OperationFailed))?
+        ),
+        _ => None,
+    };
+
+    let mut shares = shares.lock().await;
+    let share = shares.create(path, is_dir, &user.username, expires_at, req.max_downloads, password_hash)
+        .map_err(|e| share_rejection(ShareError::Io(e.to_string())))?;
+    info!("User {} shared {:?} until {}", user.username, share.path, share.expires_at);
+
+    let reply = warp::reply::json(&share_status(&shares, &share));
+    let reply = warp::reply::with_header(reply, "Location", format!("/api/shares/{}", share.id));
+    Ok(warp::reply::with_status(reply, StatusCode::CREATED))
+}
+
+/// The links a user has made, or every link for admins. Expired links are left out until they are purged.
+pub async fn list_shares(user: AuthenticatedUser, shares: ShareLinks) -> Result<impl warp::Reply, warp::Rejection> {
+    let shares = shares.lock().await;
+    let now = Utc::now();
+    let created_by = if user.role >= UserRole::Admin { None } else { Some(user.username.as_str()) };
+    let list: Vec<serde_json::Value> = shares.list(created_by).iter()
+        .filter(|share| !share.is_expired(now))
+        .map(|share| share_status(&shares, share))
+        .collect();
+    Ok(warp::reply::json(&list))
+}
+
+/// Revoke a link. Only the user that made it, or an admin, can do this.
+pub async fn revoke_share(user: AuthenticatedUser, id: String, shares: ShareLinks) -> Result<impl warp::Reply, warp::Rejection> {
+    let mut shares = shares.lock().await;
+    let share = shares.get(&id).ok_or_else(|| warp::reject::custom(rejections::NotFound))?;
+    if share.created_by != user.username && user.role < UserRole::Admin {
+        return Err(warp::reject::custom(rejections::Forbidden));
+    }
+    let share = shares.revoke(&id)
+        .map_err(|e| share_rejection(ShareError::Io(e.to_string())))?
+        .ok_or_else(|| warp::reject::custom(rejections::NotFound))?;
+    info!("User {} revoked the share of {:?}", user.username, share.path);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Stream a file as the body of a response, a chunk at a time
+fn file_body<R: tokio::io::AsyncRead + Unpin + Send + 'static>(file: R) -> Body {
+    let chunks = futures::stream::unfold(Some(file), |file| async move {
+        let mut file = file?;
+        let mut buf = vec![0; FILE_CHUNK_SIZE];
+        match file.read(&mut buf).await {
+            Ok(0) => None,
+            Ok(n) => {
+                buf.truncate(n);
+                Some((Ok(bytes::Bytes::from(buf)), Some(file)))
+            },
+            Err(e) => Some((Err(e), None)),
+        }
+    });
+    Body::wrap_stream(chunks)
+}
+
+/*
+The bytes asked for by a Range header, from the first to the last. None when the whole file should be sent, which is
+also what happens with ranges that can't be parsed or that ask for several parts. Err when the range starts past the
+end of the file.
+*/
+fn byte_range(header: &str, size: u64) -> Option<Result<(u64, u64), ()>> {
+    let (start, end) = header.trim().strip_prefix("bytes=")?.split_once('-')?;
+    if end.contains(',') {
+        return None;
+    }
+    let (start, end) = (start.trim(), end.trim());
+
+    if start.is_empty() {
+        let suffix: u64 = end.parse().ok()?;
+        if suffix == 0 || size == 0 {
+            return Some(Err(()));
+        }
+        return Some(Ok((size.saturating_sub(suffix), size - 1)));
+    }
+    let start: u64 = start.parse().ok()?;
+    let end: u64 = if end.is_empty() { u64::MAX } else { end.parse().ok()? };
+    if end < start {
+        return None;
+    }
+    if start >= size {
+        return Some(Err(()));
+    }
+    Some(Ok((start, end.min(size - 1))))
+}
+
+/*
+Send a file from under '/browse/', or the part of it asked for with a Range header. The file is opened through the
+serve point, so that what is sent is the file that was checked even if a link is swapped in along the way.
+*/
+pub async fn browse_file(user: AuthenticatedUser, sp: ServePoint, acl: AccessRules, tail: Tail, range: Option<String>)
+    -> Result<warp::http::Response<Body>, warp::Rejection> {
+    let path = decode_path(tail.as_str()).ok_or_else(|| warp::reject::custom(rejections::NotFound))?;
+    if !acl.can_read_in(&user, &sp, &path) {
+        debug!("User {} is not allowed to download {:?}", user.username, path);
+        return Err(warp::reject::custom(rejections::NotFound));
+    }
+    // Which also keeps out anything behind a symlink that the policy doesn't follow
+    let (file, real) = sp.open_file(&path).map_err(|_| warp::reject::custom(rejections::NotFound))?;
+    let mut file = fs::File::from_std(file);
+    let size = file.metadata().await.map_err(|_| warp::reject::custom(rejections::NotFound))?.len();
+
+    let response = warp::http::Response::builder()
+        .header("Content-Type", mime_guess::from_path(&real).first_or_octet_stream().as_ref())
+        .header("Accept-Ranges", "bytes");
+    let response = match range.and_then(|range| byte_range(&range, size)) {
+        None => response
+            .header("Content-Length", size)
+            .body(file_body(file)),
+        Some(Ok((start, end))) => {
+            file.seek(std::io::SeekFrom::Start(start)).await.map_err(|_| warp::reject::custom(rejections::OperationFailed))?;
+            response
+                .status(StatusCode::PARTIAL_CONTENT)
+                .header("Content-Range", format!("bytes {}-{}/{}", start, end, size))
+                .header("Content-Length", end - start + 1)
+                .body(file_body(file.take(end - start + 1)))
+        },
+        Some(Err(())) => response
+            .status(StatusCode::RANGE_NOT_SATISFIABLE)
+            .header("Content-Range", format!("bytes */{}", size))
+            .body(Body::empty()),
+    };
+    response.map_err(|_| warp::reject::custom(rejections::OperationFailed))
+}
+
+/*
+Download whatever a share link points to, a file as it is and a directory as an archive. The path is checked again
+in case it has been replaced with a link to somewhere outside of the share since the share was made. A link only ever
+gives what the user that made it can still read, so an archive leaves out whatever the ACL keeps them out of, and a
+link made by a user that has since been removed stops working. A download is only counted once everything is ready
+to be sent.
+*/
+#[allow(clippy::too_many_arguments)]
+async fn download_share(token: String, sp: Sp, shares: ShareLinks, users: UserMap, acl: AccessRules, limits: ArchiveLimits,
+    format: ArchiveFormat) -> Result<warp::http::Response<Body>, warp::Rejection> {
+    let share = shares.lock().await.find(&token, Utc::now()).map_err(share_rejection)?.clone();
+    let sp = sp.lock().await.clone();
+    if !sp.is_subdir(&share.path) {
+        return Err(warp::reject::custom(rejections::NotFound));
+    }
+    let creator = users.accounts.lock().await.get(&share.created_by).cloned()
+        .filter(|creator| acl.can_read_in(creator, &sp, &share.path))
+        .ok_or_else(|| warp::reject::custom(rejections::NotFound))?;
+    let path = sp.real_path(&share.path)
+        .and_then(|path| path.canonicalize().ok())
+        .ok_or_else(|| warp::reject::custom(rejections::NotFound))?;
+    let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("download").to_owned();
+
+    let response = if share.is_dir {
+        let prefix = name.clone();
+        let dir = path.clone();
+        let entries = collect_archive(limits, move || {
+            let mut entries = archive::collect_entries(&sp, &dir, &prefix);
+            entries.retain(|entry| acl.can_read_in(&creator, &sp, &entry.path));
+            entries
+        }).await?;
+        shares.lock().await.record_download(&token, Utc::now()).map_err(share_rejection)?;
+        archive_reply(format, &name, entries)?
+    } else {
+        let (file, path) = sp.open_file(&share.path).map_err(|_| warp::reject::custom(rejections::NotFound))?;
+        let file = fs::File::from_std(file);
+        let size = file.metadata().await.map_err(|_| warp::reject::custom(rejections::NotFound))?.len();
+        shares.lock().await.record_download(&token, Utc::now()).map_err(share_rejection)?;
+        warp::http::Response::builder()
+            .header("Content-Type", mime_guess::from_path(&path).first_or_octet_stream().as_ref())
+            .header("Content-Length", size)
+            .header("Content-Disposition", attachment(&name))
+            .body(file_body(file))
+            .map_err(|_| warp::reject::custom(rejections::OperationFailed))?
+    };
+    info!("Share {} of {:?} was downloaded", share.id, share.path);
+    Ok(response)
+}
+
+/// Follow a share link. Links with a password ask for it instead.
+pub async fn open_share(token: String, sp: Sp, shares: ShareLinks, users: UserMap, acl: AccessRules, limits: ArchiveLimits,
+    query: ArchiveQuery) -> Result<impl warp::Reply, warp::Rejection> {
+    let needs_password = shares.lock().await.find(&token, Utc::now()).map_err(share_rejection)?.password_hash.is_some();
+    if needs_password {
+        return Err(warp::reject::custom(rejections::SharePasswordRequired));
+    }
+    download_share(token, sp, shares, users, acl, limits, query.format.unwrap_or(ArchiveFormat::Zip)).await
+}
+
+/*
+Follow a share link with its password, which is posted as a form. Wrong passwords are counted against the address
+they came from and the share, the same way failed logins are, so the password can't be guessed quickly.
+*/
+#[allow(clippy::too_many_arguments)]
+pub async fn unlock_share(token: String, sp: Sp, shares: ShareLinks, users: UserMap, acl: AccessRules, limits: ArchiveLimits,
+    addr: Option<SocketAddr>, query: ArchiveQuery, form: bytes::Bytes) -> Result<impl warp::Reply, warp::Rejection> {
+    let password = parse(&form)
+        .find(|(key, _)| key == "password")
+        .map(|(_, value)| value.into_owned())
+        .unwrap_or_default();
+
+    let share = shares.lock().await.find(&token, Utc::now()).map_err(share_rejection)?.clone();
+    if let Some(hash) = share.password_hash.clone() {
+        let ip = addr.map(|addr| addr.ip());
+        let delay = users.lockouts.lock().await.check_share(ip, &share.id, Instant::now()).map_err(|locked_for| {
+            debug!("Refused an attempt to unlock share {} from {:?}, locked out for {:?}", share.id, ip, locked_for);
+            warp::reject::custom(rejections::TooManyAttempts { retry_after: locked_for.as_secs().max(1) })
+        })?;
+        if delay > Duration::from_secs(0) {
+            tokio::time::delay_for(delay).await;
+        }
+
+        let correct = task::spawn_blocking(move || crate::verify(&hash, password.as_bytes()))
+            .await
+            .map_err(|_| warp::reject::custom(rejections::OperationFailed))?;
+        if !correct {
+            info!("Wrong password for share {} from {:?}", share.id, ip);
+            users.lockouts.lock().await.record_share_failure(ip, &share.id, Instant::now());
+            return Err(warp::reject::custom(rejections::SharePasswordRequired));
+        }
+    }
+    download_share(token, sp, shares, users, acl, limits, query.format.unwrap_or(ArchiveFormat::Zip)).await
+}
+
+/// Move an entry into or out of the trash, which can mean copying all of it, on a thread where blocking is fine
+async fn run_trash_move(pending: PendingMove) -> Result<(PendingMove, std::io::Result<()>), warp::Rejection> {
+    task::spawn_blocking(move || {
+        let result = pending.run();
+        (pending, result)
+    }).await.map_err(|_| warp::reject::custom(rejections::OperationFailed))
+}
+
+/// Deleting only moves the entry to the trash, from where an admin can still restore it
+pub async fn delete_entry(user: AuthenticatedUser, sp: Sp, acl: AccessRules, trash: TrashBin, req: DeleteRequest) -> Result<impl warp::Reply, warp::Rejection> {
+    let path = relative_path(&req.path);
+    let recursive = req.recursive.unwrap_or(false);
+    let pending = {
+        let sp = sp.lock().await;
+        check_access(&acl, &user, &sp, &path)?;
+        trash.lock().await
+            .start_delete(&sp, &path, recursive, &user.username)
+            .map_err(fs_op_rejection)?
+    };
+    let (pending, result) = run_trash_move(pending).await?;
+    let item = trash.lock().await.finish_delete(pending, result).map_err(fs_op_rejection)?;
+    info!("User {} moved {:?} to the trash", user.username, path);
+
+    Ok(warp::reply::json(&item))
+}
+
+pub async fn list_trash(_: AuthenticatedUser, trash: TrashBin) -> Result<impl warp::Reply, warp::Rejection> {
+    let items = trash.lock().await.items();
+    Ok(warp::reply::json(&items))
+}
+
+pub async fn restore_trash_item(user: AuthenticatedUser, id: String, sp: Sp, trash: TrashBin) -> Result<impl warp::Reply, warp::Rejection> {
+    let pending = {
+        let sp = sp.lock().await;
+        trash.lock().await.start_restore(&sp, &id).map_err(fs_op_rejection)?
+    };
+    let (pending, result) = run_trash_move(pending).await?;
+    let item = trash.lock().await.finish_restore(pending, result).map_err(fs_op_rejection)?;
+    info!("User {} restored {:?} from the trash", user.username, item.original_path);
+    Ok(warp::reply::json(&item))
+}
+
+pub async fn purge_trash_item(user: AuthenticatedUser, id: String, trash: TrashBin) -> Result<impl warp::Reply, warp::Rejection> {
+    let pending = trash.lock().await.start_purge(&id).map_err(fs_op_rejection)?;
+    let (pending, result) = task::spawn_blocking(move || {
+        let result = pending.run();
+        (pending, result)
+    }).await.map_err(|_| warp::reject::custom(rejections::OperationFailed))?;
+    let item = trash.lock().await.finish_purge(pending, result).map_err(fs_op_rejection)?;
+    info!("User {} purged {:?} from the trash", user.username, item.original_path);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Where to go after logging in. Only paths on this server are allowed, so the login page can't be used to send
+/// somebody elsewhere.
+fn login_next(next: Option<&str>) -> String {
+    match next {
+        Some(next) if short_links::is_local_target(next) => next.to_owned(),
+        _ => String::from("/browse/"),
+    }
+}
+
+async fn login_page<'a>(hba: &Hba<'a>, data: serde_json::Value, status: StatusCode) -> Result<impl warp::Reply, warp::Rejection> {
+    let render = hba.hba.lock().await.render("login.html", &data).map_err(|e| {
+        error!("Could not render the login page: {}", e);
+        warp::reject::custom(rejections::OperationFailed)
+    })?;
+    Ok(warp::reply::with_status(warp::reply::html(render), status))
+}
+
+pub async fn render_login<'a>(hba: Hba<'a>, query: LoginQuery) -> Result<impl warp::Reply, warp::Rejection> {
+    let data = serde_json::json!({ "next": login_next(query.next.as_deref()) });
+    login_page(&hba, data, StatusCode::OK).await
+}
+
+/*
+Check a username and password, for Basic auth and the login page. Attempts that fail are counted against the address
+they came from and the username, and are held back or refused once there have been too many. A username that doesn't
+exist is checked against a made up hash, so that it takes as long to fail as a wrong password does.
+*/
+pub async fn authenticate(users: &UserMap, ip: Option<IpAddr>, username: &str, password: &str) -> Result<AuthenticatedUser, warp::Rejection> {
+    let delay = users.lockouts.lock().await.check(ip, username, Instant::now()).map_err(|locked_for| {
+        debug!("Refused an attempt to log in as {} from {:?}, locked out for {:?}", username, ip, locked_for);
+        warp::reject::custom(rejections::TooManyAttempts { retry_after: locked_for.as_secs().max(1) })
+    })?;
+
+    let account = users.accounts.lock().await.get(username).cloned();
+    if let Some(user) = &account {
+        if users.verified.lock().await.check(username, &user.password, password, Instant::now()) {
+            return Ok(user.clone());
+        }
+    }
+
+    if delay > Duration::from_secs(0) {
+        tokio::time::delay_for(delay).await;
+    }
+    debug!("Verifying password for user {}", username);
+    let hash = account.as_ref().map_or_else(|| crate::DUMMY_HASH.clone(), |user| user.password.clone());
+    let attempt = password.to_owned();
+    let correct = task::spawn_blocking(move || crate::verify(&hash, attempt.as_bytes())).await.unwrap_or(false);
+
+    match account {
+        Some(user) if correct => {
+            users.verified.lock().await.remember(username, &user.password, password, Instant::now());
+            users.lockouts.lock().await.record_success(username);
+            Ok(user)
+        },
+        _ => {
+            info!("Failed login for {} from {:?}", username, ip);
+            users.lockouts.lock().await.record_failure(ip, username, Instant::now());
+            Err(warp::reject::custom(rejections::InvalidCredentials))
+        },
+    }
+}
+
+/// The cookie for a session, which is only ever sent over https when the login came that way through a proxy
+fn session_cookie(value: &str, max_age: i64, secure: bool) -> String {
+    let secure = if secure { "; Secure" } else { "" };
+    format!("{}={}; Path=/; HttpOnly; SameSite=Strict; Max-Age={}{}", SESSION_COOKIE, value, max_age, secure)
+}
+
+/// Check the username and password posted from the login page, and start a session if they're right
+pub async fn login<'a>(users: UserMap, hba: Hba<'a>, addr: Option<SocketAddr>, proto: Option<String>, form: bytes::Bytes)
+    -> Result<impl warp::Reply, warp::Rejection> {
+    let form: HashMap<String, String> = parse(&form).into_owned().collect();
+    let username = form.get("username").cloned().unwrap_or_default();
+    let password = form.get("password").cloned().unwrap_or_default();
+    let next = login_next(form.get("next").map(|next| next.as_str()));
+
+    if let Err(rejection) = authenticate(&users, addr.map(|addr| addr.ip()), &username, &password).await {
+        let (error, status) = match rejection.find::<rejections::TooManyAttempts>() {
+            Some(_) => ("Too many failed attempts, try again later", StatusCode::TOO_MANY_REQUESTS),
+            None => ("Incorrect username or password", StatusCode::UNAUTHORIZED),
+        };
+        let data = serde_json::json!({ "next": next, "username": username, "error": error });
+        return login_page(&hba, data, status).await
+            .map(|reply| Box::new(reply) as Box<dyn warp::Reply>);
+    }
+
+    let mut sessions = users.sessions.lock().await;
+    let cookie = sessions.create(&username, Utc::now());
+    info!("User {} logged in", username);
+    let secure = proto.is_some_and(|proto| proto.eq_ignore_ascii_case("https"));
+    let cookie = session_cookie(&cookie, sessions.lifetime().num_seconds(), secure);
+    let reply = warp::reply::with_header(warp::reply(), "Location", next);
+    let reply = warp::reply::with_header(reply, "Set-Cookie", cookie);
+    Ok(Box::new(warp::reply::with_status(reply, StatusCode::SEE_OTHER)))
+}
+
+/// End the session, and clear its cookie
+pub async fn logout(users: UserMap, cookie: Option<String>) -> Result<impl warp::Reply, warp::Rejection> {
+    if let Some(cookie) = cookie {
+        if let Some(session) = users.sessions.lock().await.remove(&cookie) {
+            info!("User {} logged out", session.username);
+        }
+    }
+    let cookie = session_cookie("", 0, false);
+    let reply = warp::reply::with_header(warp::reply(), "Location", "/login");
+    let reply = warp::reply::with_header(reply, "Set-Cookie", cookie);
+    Ok(warp::reply::with_status(reply, StatusCode::SEE_OTHER))
+}
+
+fn generate_room_code() -> String {
+    let mut rng = rand::thread_rng();
+    (0..ROOM_CODE_LEN)
+        .map(|_| {
+            let idx = rng.gen_range(0, CHARSET.len());
+            CHARSET[idx] as char
+        })
+        .collect()
+}
+
+pub async fn create_room(user: AuthenticatedUser, rooms_arc: Rooms, cleaner: RoomCleaner, links: Links, b64url: UrlQuery) -> Result<impl warp::Reply, warp::Rejection> {
+    use std::str::from_utf8;
+    let mut code;
+    let decoded = decode(b64url.url.as_bytes()).map_err(|_| warp::reject())?;
+    let url = from_utf8(decoded.as_slice()).map_err(|_| warp::reject())?;
+
+    // Extra scope to limit length of rooms and links mutex lock
+    {
+        let mut rooms = rooms_arc.lock().await;
+        let mut links = links.lock().await;
+
+        loop {
+            code = generate_room_code();
+            if !rooms.contains_key(&code) && !links.contains(&code) {
+                break;
+            }
+        }
+        let url_with_query = format!("{}?cinema=1&room={}", url, code);
+        let expires_at = Utc::now() + chrono::Duration::hours(ROOM_LINK_LIFETIME);
+        links.create(Some(&code), &url_with_query, &user.username, Some(expires_at)).map_err(link_rejection)?;
+        let room = Room::new(code.clone());
+        rooms.insert(code.clone(), room);
+    }
+
+    let mut resp_map = HashMap::new();
+    resp_map.insert("room", code.clone());
+
+    // Start the task to delete the room, in case no one joins it.
+    task::spawn(async {
+        delete_from_rooms(rooms_arc, cleaner, code).await;//.await;
+    });
+
+    Ok(warp::reply::json(&resp_map))
+}
+
+pub async fn check_room(_: AuthenticatedUser, rooms: Rooms, room_code: RoomCodeQuery) -> Result<impl warp::Reply, warp::Rejection> {
+    let mut resp_map = HashMap::new();
+    let rooms = rooms.lock().await;
+    if rooms.contains_key(&room_code.room) {
+        resp_map.insert("exists", true);
+    } else {
+        resp_map.insert("exists", false);
+    }
+    Ok(warp::reply::json(&resp_map))
+}
+
+pub async fn wwf_lookup_redirect(
+    _: AuthenticatedUser,
+    code: String,
+    links: Links
+) -> Result<impl warp::Reply, warp::Rejection> {
+    use std::str::FromStr;
+
+    let url = links.lock().await.resolve(&code, Utc::now()).ok_or_else(|| warp::reject::custom(rejections::NotFound))?;
+    let uri = Uri::from_str(&url).map_err(|_| warp::reject::custom(rejections::OperationFailed))?;
+    Ok(warp::redirect(uri))
+}
+
+fn link_rejection(e: LinkError) -> warp::Rejection {
+    match e {
+        LinkError::InvalidSlug | LinkError::InvalidTarget => warp::reject::custom(rejections::InvalidLink),
+        LinkError::SlugTaken => warp::reject::custom(rejections::SlugTaken),
+        LinkError::Io(e) => {
+            error!("Could not save the short links: {}", e);
+            warp::reject::custom(rejections::OperationFailed)
+        },
+    }
+}
+
+fn link_status(link: &ShortLink) -> serde_json::Value {
+    serde_json::json!({
+        "slug": link.slug,
+        "url": format!("/wwf/{}", link.slug),
+        "target": link.target,
+        "created_by": link.created_by,
+        "created_at": link.created_at.to_rfc3339(),
+        "expires": link.expires_at.map(|expires_at| expires_at.to_rfc3339()),
+        "hits": link.hits,
+    })
+}
+
+/// The url of an entry on the '/browse/' pages, given its path relative to the share root
+fn browse_url(path: &std::path::Path, is_dir: bool) -> String {
+    let mut url = String::from("/browse");
+    for part in path.iter() {
+        url.push('/');
+        url.extend(percent_encoding::utf8_percent_encode(&part.to_string_lossy(), PATH_SEGMENT));
+    }
+    if is_dir {
+        url.push('/');
+    }
+    url
+}
+
+/// Make a short link to something in the share
+pub async fn create_link(user: AuthenticatedUser, sp: Sp, acl: AccessRules, links: Links, req: NewLinkRequest) -> Result<impl warp::Reply, warp::Rejection> {
+    let path = relative_path(&req.path);
+    let sp = sp.lock().await.clone();
+    // Checked first, so that whether a hidden path exists isn't given away
+    check_access(&acl, &user, &sp, &path)?;
+    if !sp.is_subdir(&path) {
+        return Err(warp::reject::custom(rejections::NotFound));
+    }
+    let is_dir = entry_is_dir(&sp, &path)?;
+
+    let expires_at = match req.expires_in {
+        Some(seconds) if seconds <= 0 => return Err(warp::reject::custom(rejections::InvalidLink)),
+        Some(seconds) => Some(Utc::now() + chrono::Duration::seconds(seconds)),
+        None => None,
+    };
+
+    let link = links.lock().await
+        .create(req.slug.as_deref(), &browse_url(&path, is_dir), &user.username, expires_at)
+        .map_err(link_rejection)?;
+    info!("User {} made the short link {} to {}", user.username, link.slug, link.target);
+
+    let reply = warp::reply::json(&link_status(&link));
+    Ok(warp::reply::with_status(reply, StatusCode::CREATED))
+}
+
+pub async fn list_links(_: AuthenticatedUser, links: Links) -> Result<impl warp::Reply, warp::Rejection> {
+    let mut links = links.lock().await;
+    links.purge_expired(Utc::now());
+    let list: Vec<serde_json::Value> = links.list().iter().map(link_status).collect();
+    Ok(warp::reply::json(&list))
+}
+
+pub async fn delete_link(user: AuthenticatedUser, slug: String, links: Links) -> Result<impl warp::Reply, warp::Rejection> {
+    let link = links.lock().await
+        .delete(&slug)
+        .map_err(|e| link_rejection(LinkError::Io(e.to_string())))?
+        .ok_or_else(|| warp::reject::custom(rejections::NotFound))?;
+    info!("User {} deleted the short link {} to {}", user.username, link.slug, link.target);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn list_lockouts(_: AuthenticatedUser, users: UserMap) -> Result<impl warp::Reply, warp::Rejection> {
+    let list: Vec<serde_json::Value> = users.lockouts.lock().await.list(Instant::now()).iter()
+        .map(|attempts| {
+            let (ip, username, share) = match &attempts.key {
+                LockoutKey::Ip(ip) => (Some(ip.to_string()), None, None),
+                LockoutKey::User(username) => (None, Some(username.clone()), None),
+                LockoutKey::Share(id) => (None, None, Some(id.clone())),
+            };
+            serde_json::json!({
+                "ip": ip,
+                "username": username,
+                "share": share,
+                "failures": attempts.failures,
+                "locked_for": attempts.locked_for.map(|locked_for| locked_for.as_secs()),
+            })
+        })
+        .collect();
+    Ok(warp::reply::json(&list))
+}
+
+/// Clear the failed attempts for an address, a username or a share, or for everyone
+pub async fn clear_lockouts(user: AuthenticatedUser, users: UserMap, query: LockoutQuery) -> Result<impl warp::Reply, warp::Rejection> {
+    let key = match (query.ip, query.username, query.share) {
+        (Some(ip), None, None) => Some(LockoutKey::Ip(ip.parse().map_err(|_| warp::reject::custom(rejections::InvalidQuery))?)),
+        (None, Some(username), None) => Some(LockoutKey::User(username)),
+        (None, None, Some(id)) => Some(LockoutKey::Share(id)),
+        (None, None, None) => None,
+        _ => return Err(warp::reject::custom(rejections::InvalidQuery)),
+    };
+
+    let cleared = users.lockouts.lock().await.clear(key.as_ref());
+    match &key {
+        Some(_) if cleared == 0 => return Err(warp::reject::custom(rejections::NotFound)),
+        Some(key) => info!("User {} cleared the failed attempts for {}", user.username, key),
+        None => info!("User {} cleared the failed attempts for everyone", user.username),
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+const ROLES: [UserRole; 3] = [UserRole::ReadOnly, UserRole::Uploader, UserRole::Admin];
+
+/// 'message' says what was just changed, and 'error' why it couldn't be. 'token' goes in every form.
+async fn users_page<'a>(
+    hba: &Hba<'a>,
+    admin: &AuthenticatedUser,
+    token: &str,
+    file: &CredentialsFile,
+    message: Option<String>,
+    error: Option<String>,
+    status: StatusCode,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let roles = |selected: Option<UserRole>| -> Vec<serde_json::Value> {
+        ROLES.iter()
+            .map(|role| serde_json::json!({ "name": role.to_string(), "selected": Some(*role) == selected }))
+            .collect()
+    };
+    let accounts: Vec<serde_json::Value> = file.list().into_iter()
+        .map(|(user, disabled)| serde_json::json!({
+            "username": user.username,
+            "roles": roles(Some(user.role)),
+            "disabled": disabled,
+            "groups": user.groups.join(", "),
+            "is_self": user.username == admin.username,
+        }))
+        .collect();
+    let data = serde_json::json!({
+        "users": accounts, "roles": roles(None), "message": message, "error": error, "token": token,
+    });
+
+    let render = hba.hba.lock().await.render("users.html", &data).map_err(|e| {
+        error!("Could not render the users page: {}", e);
+        warp::reject::custom(rejections::OperationFailed)
+    })?;
+    Ok(warp::reply::with_status(warp::reply::html(render), status))
+}
+
+fn load_credentials(path: &std::path::Path) -> Result<CredentialsFile, warp::Rejection> {
+    CredentialsFile::load(path).map_err(|e| {
+        error!("{}", e);
+        warp::reject::custom(rejections::OperationFailed)
+    })
+}
+
+pub async fn render_users<'a>(admin: AuthenticatedUser, users: UserMap, users_file: UsersFile, hba: Hba<'a>) -> Result<impl warp::Reply, warp::Rejection> {
+    let token = users.sessions.lock().await.form_token(&admin.username);
+    let file = load_credentials(&users_file.lock().await)?;
+    users_page(&hba, &admin, &token, &file, None, None, StatusCode::OK).await
+}
+
+async fn hash_new_password(password: Option<&String>) -> Result<String, String> {
+    let password = password.filter(|password| !password.is_empty()).ok_or("The password can't be empty")?.clone();
+    task::spawn_blocking(move || crate::hash(password.as_bytes()))
+        .await
+        .map_err(|_| String::from("Could not hash the password"))
+}
+
+/// Make the change to 'file' that was posted from the users page, saying what was done
+async fn apply_user_change(admin: &AuthenticatedUser, file: &mut CredentialsFile, form: &HashMap<String, String>) -> Result<String, String> {
+    let username = form.get("username").map(|username| username.trim()).unwrap_or_default();
+    let role = || form.get("role").map(|role| role.as_str()).unwrap_or_default().parse::<UserRole>();
+    let is_self = username == admin.username;
+    if file.get(username).is_none() && form.get("action").map(|action| action.as_str()) != Some("add") {
+        return Err(format!("There is no user called {}", username));
+    }
+
+    match form.get("action").map(|action| action.as_str()) {
+        Some("add") => {
+            let role = role()?;
+            // Checked before hashing the password, which would be wasted otherwise
+            if !credentials::is_valid_username(username) {
+                return Err(format!("'{}' can't be used as a username", username));
+            }
+            if file.get(username).is_some() {
+                return Err(format!("There is already a user called {}", username));
+            }
+            let password = hash_new_password(form.get("password")).await?;
+            file.add(AuthenticatedUser { username: username.to_owned(), role, password, groups: Vec::new() })?;
+            Ok(format!("Added {} as {}", username, role))
+        },
+        Some("password") => {
+            let password = hash_new_password(form.get("password")).await?;
+            file.set_password(username, &password)?;
+            Ok(format!("Changed the password of {}", username))
+        },
+        Some("role") => {
+            let role = role()?;
+            // Otherwise the last admin could lock everyone out of this page
+            if is_self && role != UserRole::Admin {
+                return Err(String::from("You can't take away your own Admin role"));
+            }
+            file.set_role(username, role)?;
+            Ok(format!("{} is now {}", username, role))
+        },
+        Some("disable") if is_self => Err(String::from("You can't disable your own account")),
+        Some("disable") => {
+            file.set_disabled(username, true)?;
+            Ok(format!("Disabled {}", username))
+        },
+        Some("enable") => {
+            file.set_disabled(username, false)?;
+            Ok(format!("Enabled {}", username))
+        },
+        _ => Err(String::from("Unknown change")),
+    }
+}
+
+/// Change a user from the users page, writing the credentials file and swapping the live users for the ones in it
+pub async fn change_user<'a>(
+    admin: AuthenticatedUser,
+    users: UserMap,
+    users_file: UsersFile,
+    acl: AccessRules,
+    hba: Hba<'a>,
+    form: bytes::Bytes,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let form: HashMap<String, String> = parse(&form).into_owned().collect();
+    let token = form.get("token").cloned().unwrap_or_default();
+    if !users.sessions.lock().await.check_form_token(&admin.username, &token) {
+        warn!("Refused a change to the users by {} that wasn't posted from the users page", admin.username);
+        return Err(warp::reject::custom(rejections::Forbidden));
+    }
+    let path = users_file.lock().await;
+    let mut file = load_credentials(&path)?;
+
+    let (message, error, status) = match apply_user_change(&admin, &mut file, &form).await {
+        Ok(message) => match file.save(&path) {
+            Ok(()) => {
+                info!("{} (by {})", message, admin.username);
+                if let Err(e) = credentials::reload(&users, &path, &acl).await {
+                    error!("Could not reload the users: {}", e);
+                }
+                (Some(message), None, StatusCode::OK)
+            },
+            Err(e) => {
+                error!("{}", e);
+                file = load_credentials(&path)?;
+                (None, Some(String::from("Could not save the credentials file")), StatusCode::INTERNAL_SERVER_ERROR)
+            },
+        },
+        Err(e) => (None, Some(e), StatusCode::BAD_REQUEST),
+    };
+    users_page(&hba, &admin, &token, &file, message, error, status).await
+}
+
+// pub async fn get_catalogue(
+//     _: AuthenticatedUser,
+//     c: DbClientArc
+// ) -> Result<impl warp::Reply, warp::Rejection> {
+//     let client = c.lock().await;
+
+//     match db::get_catalogue(&client).await {
+//         Ok(catalogue) => return Ok(warp::reply::json(&catalogue)),
+//         Err(e) => error!("Error: {}", e),
+//     };
+//     Err(warp::reject())
 // }
\ No newline at end of file
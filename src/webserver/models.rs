@@ -1,179 +1,355 @@
-use std::sync::Arc;
-use std::collections::HashMap;
-use tokio::sync::{mpsc, Mutex};
-// use tokio_postgres::Client;
-use std::path::PathBuf;
-use handlebars::Handlebars;
-use warp::ws::Message;
-use serde::{Deserialize, Serialize};
-use futures::future::{AbortHandle};
-
-use crate::fs_utils::ServePoint;
-use crate::hb_helpers;
-use crate::webserver::messages::{PlayerState, StatsStruct};
-
-#[derive(Copy, Clone, Debug, PartialEq, PartialOrd)]
-pub enum UserRole {
-    ReadOnly,
-    Uploader,
-    Admin,
-}
-
-#[derive(Clone, Debug, PartialEq)]
-pub struct AuthenticatedUser {
-    pub username: String,
-    pub role: UserRole,
-    pub password: String,
-}
-
-impl AuthenticatedUser {
-    #[cfg(test)]
-    pub fn new(username: String, password: String, role: UserRole) -> Self {
-        AuthenticatedUser { username, role, password }
-    }
-}
-
-#[derive(Deserialize)]
-pub struct UrlQuery {
-    pub url: String,
-}
-
-#[derive(Deserialize)]
-pub struct RoomCodeQuery {
-    pub room: String,
-}
-
-pub type Sp = Arc<Mutex<ServePoint>>;
-pub type UserMap = Arc<Mutex<HashMap<String, AuthenticatedUser>>>;
-
-#[derive(Clone)]
-pub struct Hba<'a> {
-    pub hba: Arc<Mutex<Handlebars<'a>>>,
-}
-
-// For websockets
-pub type Sender = mpsc::UnboundedSender<Result<Message, warp::Error>>;
-pub type Rooms = Arc<Mutex<HashMap<String, Room>>>;
-pub type RoomCleaner = Arc<Mutex<HashMap<String, AbortHandle>>>;
-pub type Urls = Arc<Mutex<HashMap<String, String>>>;
-// pub type CatalogueArc = Arc<Mutex<Catalogue>>;
-// pub type DbClientArc= Arc<Mutex<Client>>;
-
-// For websockets
-pub struct Room {
-    pub id: String,
-    pub users_by_id: HashMap<usize, User>,
-    pub director: Option<String>,
-}
-
-impl Room {
-    pub fn new(id: String) -> Self {
-        return Room {
-            id,
-            users_by_id: HashMap::new(),
-            director: None,
-        }
-    }
-    
-    pub fn add_user(&mut self, id: usize, u: User) {
-        self.users_by_id.insert(id, u);
-    }
-
-    pub fn remove_user(&mut self, id: &usize) {
-        self.users_by_id.remove(id);
-    }
-}
-
-// For websockets
-#[derive(Clone)]
-pub struct User {
-    pub user_data: UserData,
-    pub sender: Sender,
-}
-
-#[derive(Clone, Debug, Serialize)]
-pub struct UserData {
-    pub id: usize,
-    pub name: String,
-    pub time: f64,
-    pub state: PlayerState,
-    pub director: bool,
-}
-
-impl UserData {
-    pub fn new_with_defaults(id: usize) -> Self {
-        return Self {
-            id: id,
-            name: "".to_owned(),
-            time: 0.0,
-            state: PlayerState::Paused,
-            director: false,
-        }
-    }
-
-    pub fn to_stats_struct<'a>(&'a self) -> StatsStruct<'a> {
-        return StatsStruct {
-            id: self.id,
-            name: &self.name,
-            time: self.time,
-            player_state: self.state.clone(),
-            director: self.director,
-        }
-    }
-}
-
-pub fn new_serve_point(path: PathBuf) -> Sp {
-    Arc::new(Mutex::new(ServePoint::new(path)))
-}
-
-pub fn new_users(users: HashMap<String, AuthenticatedUser>) -> UserMap {
-    Arc::new(Mutex::new(users))
-}
-
-pub fn new_handlebars_arc<'a>() -> Hba<'a> {
-    let mut hb = Handlebars::new();
-    // register the template
-    hb.register_template_string("listing.html", hb_helpers::LISTING_TEMPLATE).unwrap();
-    hb.register_template_string("cinema.html", hb_helpers::CINEMA_TEMPLATE).unwrap();
-
-    // Register the helpers
-    hb.register_helper("is_mp4", Box::new(hb_helpers::is_mp4));
-    hb.register_helper("icon_for_ext", Box::new(hb_helpers::icon_for_ext));
-    hb.register_helper("urlencode", Box::new(hb_helpers::urlencode));
-
-    Hba {
-        hba: Arc::new(Mutex::new(hb)),
-    }
-}
-
-pub fn new_room_cleaner() -> RoomCleaner {
-    Arc::new(Mutex::new(HashMap::new()))
-}
-
-// pub fn new_catalogue(catalogue: Catalogue) -> CatalogueArc {
-//     Arc::new(Mutex::new(catalogue))
-// }
-
-// pub fn new_db_client(client: Client) -> DbClientArc {
-//     Arc::new(Mutex::new(client))
-// }
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    #[test]
-    fn test_auth_roles_ordering() {
-        assert!(UserRole::Admin == UserRole::Admin);
-        assert!(UserRole::Admin > UserRole::Uploader);
-        assert!(UserRole::Admin > UserRole::ReadOnly);
-
-        assert!(UserRole::Uploader < UserRole::Admin);
-        assert!(UserRole::Uploader == UserRole::Uploader);
-        assert!(UserRole::Uploader > UserRole::ReadOnly);
-
-        assert!(UserRole::ReadOnly < UserRole::Admin);
-        assert!(UserRole::ReadOnly < UserRole::Uploader);
-        assert!(UserRole::ReadOnly == UserRole::ReadOnly);
-    }
+use std::sync::Arc;
+use std::collections::HashMap;
+use tokio::sync::{mpsc, Mutex};
+// use tokio_postgres::Client;
+use std::path::PathBuf;
+use handlebars::Handlebars;
+use warp::ws::Message;
+use serde::{Deserialize, Serialize};
+use futures::future::{AbortHandle};
+
+use crate::fs_utils::{ServePoint, SortKey, SortOrder};
+use crate::search::SearchMode;
+use crate::archive::ArchiveFormat;
+use crate::upload_sessions::UploadSessions;
+use crate::trash::Trash;
+use crate::index::SharedIndex;
+use crate::shares::Shares;
+use crate::short_links::ShortLinks;
+use crate::sessions::{CredentialCache, Sessions};
+use crate::lockouts::Lockouts;
+use crate::acl::Acl;
+use crate::hb_helpers;
+use crate::webserver::messages::{PlayerState, StatsStruct};
+
+#[derive(Copy, Clone, Debug, PartialEq, PartialOrd, Deserialize)]
+pub enum UserRole {
+    ReadOnly,
+    Uploader,
+    Admin,
+}
+
+impl std::str::FromStr for UserRole {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ReadOnly" => Ok(UserRole::ReadOnly),
+            "Uploader" => Ok(UserRole::Uploader),
+            "Admin" => Ok(UserRole::Admin),
+            _ => Err(format!("Unknown role '{}', expected ReadOnly, Uploader or Admin", s)),
+        }
+    }
+}
+
+/// The same names the credentials file uses
+impl std::fmt::Display for UserRole {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        std::fmt::Debug::fmt(self, f)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct AuthenticatedUser {
+    pub username: String,
+    pub role: UserRole,
+    pub password: String,
+    /// The groups the user is in, from the credentials file
+    pub groups: Vec<String>,
+}
+
+impl AuthenticatedUser {
+    #[cfg(test)]
+    pub fn new(username: String, password: String, role: UserRole) -> Self {
+        AuthenticatedUser { username, role, password, groups: Vec::new() }
+    }
+
+    pub fn in_group(&self, group: &str) -> bool {
+        self.groups.iter().any(|name| name == group)
+    }
+}
+
+#[derive(Deserialize)]
+pub struct UrlQuery {
+    pub url: String,
+}
+
+#[derive(Deserialize)]
+pub struct RoomCodeQuery {
+    pub room: String,
+}
+
+/// '?sort=name|size|mtime&order=asc|desc&filter=<glob>&page=N&per_page=M' on the directory listings
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct ListingQuery {
+    pub sort: Option<SortKey>,
+    pub order: Option<SortOrder>,
+    pub filter: Option<String>,
+    pub page: Option<usize>,
+    pub per_page: Option<usize>,
+}
+
+/// '?q=<name>&mode=substring|glob&case_sensitive=true&limit=N' on the search pages
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct SearchQuery {
+    pub q: Option<String>,
+    pub mode: Option<SearchMode>,
+    pub case_sensitive: Option<bool>,
+    pub limit: Option<usize>,
+}
+
+/// '?format=zip|tar|tar.gz' when downloading an archive
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct ArchiveQuery {
+    pub format: Option<ArchiveFormat>,
+}
+
+#[derive(Deserialize)]
+pub struct UploadQuery {
+    pub overwrite: Option<bool>,
+}
+
+#[derive(Deserialize)]
+pub struct MakeDirRequest {
+    pub path: String,
+}
+
+#[derive(Deserialize)]
+pub struct RenameRequest {
+    pub from: String,
+    pub to: String,
+}
+
+#[derive(Deserialize)]
+pub struct DeleteRequest {
+    pub path: String,
+    pub recursive: Option<bool>,
+}
+
+#[derive(Deserialize)]
+pub struct NewUploadRequest {
+    pub path: String,
+    pub size: u64,
+    pub overwrite: Option<bool>,
+}
+
+/// '?next=<path>' on the login page, where to go once logged in
+#[derive(Deserialize)]
+pub struct LoginQuery {
+    pub next: Option<String>,
+}
+
+/// '?ip=<address>' or '?username=<name>' when clearing lockouts. Every lockout is cleared without either.
+#[derive(Deserialize)]
+pub struct LockoutQuery {
+    pub ip: Option<String>,
+    pub username: Option<String>,
+    pub share: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct NewLinkRequest {
+    pub path: String,
+    /// A random slug is made up when there isn't one
+    pub slug: Option<String>,
+    /// How long the link lasts, in seconds. Links last forever without one.
+    pub expires_in: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct NewShareRequest {
+    pub path: String,
+    /// How long the link lasts, in seconds
+    pub expires_in: Option<i64>,
+    pub max_downloads: Option<u32>,
+    pub password: Option<String>,
+}
+
+pub type Sp = Arc<Mutex<ServePoint>>;
+/// The accounts from the credentials file, along with who has logged in, whose credentials were checked lately and
+/// who has been getting their password wrong
+pub struct Users {
+    pub accounts: Mutex<HashMap<String, AuthenticatedUser>>,
+    pub sessions: Mutex<Sessions>,
+    pub verified: Mutex<CredentialCache>,
+    pub lockouts: Mutex<Lockouts>,
+}
+
+pub type UserMap = Arc<Users>;
+/// Where the credentials file is, locked while it is being changed so that two changes don't overwrite each other
+pub type UsersFile = Arc<Mutex<PathBuf>>;
+
+#[derive(Clone)]
+pub struct Hba<'a> {
+    pub hba: Arc<Mutex<Handlebars<'a>>>,
+}
+
+// For websockets
+pub type Sender = mpsc::UnboundedSender<Result<Message, warp::Error>>;
+pub type Rooms = Arc<Mutex<HashMap<String, Room>>>;
+pub type RoomCleaner = Arc<Mutex<HashMap<String, AbortHandle>>>;
+pub type Links = Arc<Mutex<ShortLinks>>;
+pub type Uploads = Arc<Mutex<UploadSessions>>;
+pub type TrashBin = Arc<Mutex<Trash>>;
+pub type ShareLinks = Arc<Mutex<Shares>>;
+/// Who can see which directories of the share
+pub type AccessRules = Arc<Acl>;
+/// None when the index is turned off, otherwise there is one for each mount
+pub type Index = Option<Vec<SharedIndex>>;
+// pub type CatalogueArc = Arc<Mutex<Catalogue>>;
+// pub type DbClientArc= Arc<Mutex<Client>>;
+
+// For websockets
+pub struct Room {
+    #[allow(dead_code)]
+    pub id: String,
+    pub users_by_id: HashMap<usize, User>,
+    pub director: Option<String>,
+}
+
+impl Room {
+    pub fn new(id: String) -> Self {
+        return Room {
+            id,
+            users_by_id: HashMap::new(),
+            director: None,
+        }
+    }
+    
+    pub fn add_user(&mut self, id: usize, u: User) {
+        self.users_by_id.insert(id, u);
+    }
+
+    pub fn remove_user(&mut self, id: &usize) {
+        self.users_by_id.remove(id);
+    }
+}
+
+// For websockets
+#[derive(Clone)]
+pub struct User {
+    pub user_data: UserData,
+    pub sender: Sender,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct UserData {
+    pub id: usize,
+    pub name: String,
+    pub time: f64,
+    pub state: PlayerState,
+    pub director: bool,
+}
+
+impl UserData {
+    pub fn new_with_defaults(id: usize) -> Self {
+        return Self {
+            id: id,
+            name: "".to_owned(),
+            time: 0.0,
+            state: PlayerState::Paused,
+            director: false,
+        }
+    }
+
+    pub fn to_stats_struct<'a>(&'a self) -> StatsStruct<'a> {
+        return StatsStruct {
+            id: self.id,
+            name: &self.name,
+            time: self.time,
+            player_state: self.state.clone(),
+            director: self.director,
+        }
+    }
+}
+
+pub fn new_serve_point(sp: ServePoint) -> Sp {
+    Arc::new(Mutex::new(sp))
+}
+
+/// 'session_lifetime' is how long a login lasts, in seconds
+pub fn new_users(users: HashMap<String, AuthenticatedUser>, session_lifetime: u64) -> UserMap {
+    Arc::new(Users {
+        accounts: Mutex::new(users),
+        sessions: Mutex::new(Sessions::new(session_lifetime)),
+        verified: Mutex::new(CredentialCache::default()),
+        lockouts: Mutex::new(Lockouts::default()),
+    })
+}
+
+pub fn new_users_file(path: PathBuf) -> UsersFile {
+    Arc::new(Mutex::new(path))
+}
+
+pub fn new_handlebars_arc<'a>() -> Hba<'a> {
+    let mut hb = Handlebars::new();
+    // register the template
+    hb.register_template_string("listing.html", hb_helpers::LISTING_TEMPLATE).unwrap();
+    hb.register_template_string("cinema.html", hb_helpers::CINEMA_TEMPLATE).unwrap();
+    hb.register_template_string("search.html", hb_helpers::SEARCH_TEMPLATE).unwrap();
+    hb.register_template_string("login.html", hb_helpers::LOGIN_TEMPLATE).unwrap();
+    hb.register_template_string("users.html", hb_helpers::USERS_TEMPLATE).unwrap();
+
+    // Register the helpers
+    hb.register_helper("is_mp4", Box::new(hb_helpers::is_mp4));
+    hb.register_helper("icon_for_ext", Box::new(hb_helpers::icon_for_ext));
+    hb.register_helper("urlencode", Box::new(hb_helpers::urlencode));
+    hb.register_helper("format_time", Box::new(hb_helpers::format_time));
+    hb.register_helper("format_size", Box::new(hb_helpers::format_size));
+
+    Hba {
+        hba: Arc::new(Mutex::new(hb)),
+    }
+}
+
+pub fn new_uploads(uploads: UploadSessions) -> Uploads {
+    Arc::new(Mutex::new(uploads))
+}
+
+pub fn new_trash_bin(trash: Trash) -> TrashBin {
+    Arc::new(Mutex::new(trash))
+}
+
+pub fn new_links(links: ShortLinks) -> Links {
+    Arc::new(Mutex::new(links))
+}
+
+pub fn new_share_links(shares: Shares) -> ShareLinks {
+    Arc::new(Mutex::new(shares))
+}
+
+pub fn new_access_rules(acl: Acl) -> AccessRules {
+    Arc::new(acl)
+}
+
+pub fn new_room_cleaner() -> RoomCleaner {
+    Arc::new(Mutex::new(HashMap::new()))
+}
+
+// pub fn new_catalogue(catalogue: Catalogue) -> CatalogueArc {
+//     Arc::new(Mutex::new(catalogue))
+// }
+
+// pub fn new_db_client(client: Client) -> DbClientArc {
+//     Arc::new(Mutex::new(client))
+// }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_auth_roles_ordering() {
+        assert!(UserRole::Admin == UserRole::Admin);
+        assert!(UserRole::Admin > UserRole::Uploader);
+        assert!(UserRole::Admin > UserRole::ReadOnly);
+
+        assert!(UserRole::Uploader < UserRole::Admin);
+        assert!(UserRole::Uploader == UserRole::Uploader);
+        assert!(UserRole::Uploader > UserRole::ReadOnly);
+
+        assert!(UserRole::ReadOnly < UserRole::Admin);
+        assert!(UserRole::ReadOnly < UserRole::Uploader);
+        assert!(UserRole::ReadOnly == UserRole::ReadOnly);
+    }
 }
\ No newline at end of file
@@ -1,17 +1,129 @@
-use warp;
-
-#[derive(Debug)]
-pub struct InvalidCredentials;
-impl warp::reject::Reject for InvalidCredentials {}
-
-#[derive(Debug)]
-pub struct NotADirectory;
-impl warp::reject::Reject for NotADirectory {}
-
-#[derive(Debug)]
-pub struct NotAFile;
-impl warp::reject::Reject for NotAFile {}
-
-#[derive(Debug)]
-pub struct Forbidden;
-impl warp::reject::Reject for Forbidden {}
\ No newline at end of file
+use warp;
+use crate::auth_header::AuthHeaderError;
+
+#[derive(Debug)]
+pub struct InvalidCredentials;
+impl warp::reject::Reject for InvalidCredentials {}
+
+/// The Authorization header couldn't be parsed, or uses a scheme that isn't supported
+#[derive(Debug)]
+pub struct InvalidAuthorization {
+    pub error: AuthHeaderError,
+}
+impl warp::reject::Reject for InvalidAuthorization {}
+
+/// Too many attempts to log in have failed, from the same address or for the same user. They can try again in
+/// 'retry_after' seconds.
+#[derive(Debug)]
+pub struct TooManyAttempts {
+    pub retry_after: u64,
+}
+impl warp::reject::Reject for TooManyAttempts {}
+
+/// There are no credentials and no session. Browsers are sent to the login page at 'login', everything else is
+/// asked for Basic auth.
+#[derive(Debug)]
+pub struct LoginRequired {
+    pub login: Option<String>,
+}
+impl warp::reject::Reject for LoginRequired {}
+
+#[derive(Debug)]
+pub struct NotADirectory;
+impl warp::reject::Reject for NotADirectory {}
+
+#[allow(dead_code)]
+#[derive(Debug)]
+pub struct NotAFile;
+impl warp::reject::Reject for NotAFile {}
+
+#[derive(Debug)]
+pub struct Forbidden;
+impl warp::reject::Reject for Forbidden {}
+
+/// The path is in a mount that can't be changed
+#[derive(Debug)]
+pub struct ReadOnly;
+impl warp::reject::Reject for ReadOnly {}
+
+#[derive(Debug)]
+pub struct FileExists;
+impl warp::reject::Reject for FileExists {}
+
+#[derive(Debug)]
+pub struct PayloadTooLarge;
+impl warp::reject::Reject for PayloadTooLarge {}
+
+#[derive(Debug)]
+pub struct UploadFailed;
+impl warp::reject::Reject for UploadFailed {}
+
+#[derive(Debug)]
+pub struct NotFound;
+impl warp::reject::Reject for NotFound {}
+
+#[derive(Debug)]
+pub struct DirectoryNotEmpty;
+impl warp::reject::Reject for DirectoryNotEmpty {}
+
+#[derive(Debug)]
+pub struct InvalidPath;
+impl warp::reject::Reject for InvalidPath {}
+
+/// The query string couldn't be parsed, or had values that don't make sense
+#[derive(Debug)]
+pub struct InvalidQuery;
+impl warp::reject::Reject for InvalidQuery {}
+
+/// An archive would have more files or data in it than is allowed
+#[derive(Debug)]
+pub struct ArchiveTooLarge;
+impl warp::reject::Reject for ArchiveTooLarge {}
+
+/// The settings for a new share link don't make sense, e.g. it would never expire
+#[derive(Debug)]
+pub struct InvalidShare;
+impl warp::reject::Reject for InvalidShare {}
+
+/// The share link has expired or has been used up
+#[derive(Debug)]
+pub struct ShareExpired;
+impl warp::reject::Reject for ShareExpired {}
+
+/// The share link needs a password, or the wrong one was given
+#[derive(Debug)]
+pub struct SharePasswordRequired;
+impl warp::reject::Reject for SharePasswordRequired {}
+
+/// A short link can't be made with that slug or expiry
+#[derive(Debug)]
+pub struct InvalidLink;
+impl warp::reject::Reject for InvalidLink {}
+
+#[derive(Debug)]
+pub struct SlugTaken;
+impl warp::reject::Reject for SlugTaken {}
+
+#[derive(Debug)]
+pub struct OperationFailed;
+impl warp::reject::Reject for OperationFailed {}
+
+#[derive(Debug)]
+pub struct UploadNotFound;
+impl warp::reject::Reject for UploadNotFound {}
+
+/// The offset of a chunk didn't match the number of bytes received so far
+#[derive(Debug)]
+pub struct OffsetMismatch {
+    pub offset: u64,
+}
+impl warp::reject::Reject for OffsetMismatch {}
+
+/// Another request is already writing to the same upload session
+#[derive(Debug)]
+pub struct UploadBusy;
+impl warp::reject::Reject for UploadBusy {}
+
+#[derive(Debug)]
+pub struct UploadIncomplete;
+impl warp::reject::Reject for UploadIncomplete {}
\ No newline at end of file
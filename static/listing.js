@@ -0,0 +1,55 @@
+/* File management actions, only included in the listing for admins */
+
+function fsAction(action, body) {
+    fetch("/admin/fs/" + action, {
+        method: "POST",
+        credentials: "same-origin",
+        headers: { "Content-Type": "application/json" },
+        body: JSON.stringify(body),
+    }).then((response) => {
+        if (response.ok) {
+            window.location.reload();
+        } else {
+            response.text().then((msg) => alert(msg));
+        }
+    });
+}
+
+function trimSlash(path) {
+    return path.endsWith("/") ? path.slice(0, -1) : path;
+}
+
+window.addEventListener("load", () => {
+    document.querySelectorAll("button[data-action]").forEach((button) => {
+        const path = button.dataset.path;
+
+        button.addEventListener("click", () => {
+            switch (button.dataset.action) {
+                case "mkdir": {
+                    const name = prompt("Name of the new folder");
+                    if (name) {
+                        fsAction("mkdir", { path: path + name });
+                    }
+                    break;
+                }
+                case "rename": {
+                    const to = prompt("Move or rename to", trimSlash(path));
+                    if (to && to !== trimSlash(path)) {
+                        fsAction("rename", { from: path, to: to });
+                    }
+                    break;
+                }
+                case "delete": {
+                    const isDir = button.dataset.dir === "true";
+                    const question = isDir
+                        ? "Move the folder " + path + " and everything in it to the trash?"
+                        : "Move " + path + " to the trash?";
+                    if (confirm(question)) {
+                        fsAction("delete", { path: path, recursive: isDir });
+                    }
+                    break;
+                }
+            }
+        });
+    });
+});